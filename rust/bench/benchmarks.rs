@@ -166,6 +166,9 @@ fn storage_benchmarks(c: &mut Criterion) {
                     msg_type: ChatMessageType::Text,
                     timestamp: chrono::Utc::now().timestamp() as u64,
                     delivery_status: DeliveryStatus::Sent,
+                    reactions: Vec::new(),
+                    expires_at: None,
+                    sequence: 0,
                 };
 
                 storage.save_message("test_chat", &message).await.unwrap();
@@ -1,6 +1,8 @@
-use crate::chats::{Chat, ChatExportOptions, ChatInfo, ChatStatistics, MessageFilter};
-use crate::core::ENGINE;
-use crate::network::{ChatMessage, ChatMessageType, DeliveryStatus};
+use crate::chats::{
+    Chat, ChatExportOptions, ChatInfo, ChatStatistics, DraftMessage, MessageFilter,
+};
+use crate::core::{Engine, ENGINE};
+use crate::network::{ChatMessage, ChatMessageType, DeliveryStatus, MessageReaction};
 use flutter_rust_bridge::frb;
 
 #[frb]
@@ -13,6 +15,16 @@ pub async fn create_chat(name: String, is_group: bool) -> Result<Chat, String> {
         .map_err(|e| e.to_string())
 }
 
+#[frb]
+pub async fn create_direct_chat(peer_id: String, name: String) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .create_direct_chat(&peer_id, name)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[frb]
 pub async fn get_chat(chat_id: String) -> Result<Chat, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
@@ -29,6 +41,98 @@ pub async fn get_all_chats() -> Result<Vec<Chat>, String> {
     Ok(engine.chats().get_all_chats().await)
 }
 
+#[frb]
+pub async fn get_active_chats() -> Result<Vec<Chat>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.chats().get_active_chats().await)
+}
+
+#[frb]
+pub async fn get_archived_chats() -> Result<Vec<Chat>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.chats().get_archived_chats().await)
+}
+
+#[frb]
+pub async fn pin_chat(chat_id: String) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .pin_chat(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn unpin_chat(chat_id: String) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .unpin_chat(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn reorder_pinned_chats(chat_ids: Vec<String>) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .reorder_pinned_chats(&chat_ids)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn mute_chat(chat_id: String, duration_seconds: Option<u64>) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .mute_chat(&chat_id, duration_seconds)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn unmute_chat(chat_id: String) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .unmute_chat(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn is_chat_muted(chat_id: String) -> Result<bool, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .is_chat_muted(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn archive_chat(chat_id: String) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .archive_chat(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn unarchive_chat(chat_id: String) -> Result<Chat, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .unarchive_chat(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[frb]
 pub async fn send_message(
     chat_id: String,
@@ -38,6 +142,7 @@ pub async fn send_message(
     message_type: ChatMessageType,
 ) -> Result<String, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.network().record_user_activity().await;
     engine
         .chats()
         .send_message(&chat_id, &sender, &recipient, &content, message_type)
@@ -159,3 +264,161 @@ pub async fn get_failed_messages(chat_id: Option<String>) -> Result<Vec<ChatMess
 
     Ok(results.into_iter().flat_map(|r| r.messages).collect())
 }
+
+#[frb]
+pub async fn add_reaction(message_id: String, emoji: String) -> Result<ChatMessage, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    let message = engine
+        .chats()
+        .add_reaction(&message_id, &emoji)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Local state is authoritative; peers catch up when they are reachable
+    send_reaction(engine, &message_id, &emoji, true).await;
+    Ok(message)
+}
+
+#[frb]
+pub async fn remove_reaction(message_id: String, emoji: String) -> Result<ChatMessage, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    let message = engine
+        .chats()
+        .remove_reaction(&message_id, &emoji)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    send_reaction(engine, &message_id, &emoji, false).await;
+    Ok(message)
+}
+
+#[frb]
+pub async fn get_message_reactions(
+    chat_id: String,
+    message_id: String,
+) -> Result<Vec<MessageReaction>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .get_reactions(&chat_id, &message_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn send_reaction(engine: &Engine, message_id: &str, emoji: &str, added: bool) {
+    let recipients = engine
+        .chats()
+        .reaction_recipients(message_id)
+        .await
+        .unwrap_or_default();
+    for recipient in recipients {
+        let _ = engine
+            .network()
+            .send_reaction(&recipient, message_id, emoji, added)
+            .await;
+    }
+}
+
+#[frb]
+pub async fn set_disappearing_timer(chat_id: String, seconds: Option<u64>) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    let changed_at = engine
+        .chats()
+        .set_disappearing_timer(&chat_id, seconds)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let recipients = engine
+        .chats()
+        .chat_recipients(&chat_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    for recipient in recipients {
+        let _ = engine
+            .network()
+            .send_chat_settings(&recipient, &chat_id, seconds, changed_at)
+            .await;
+    }
+
+    Ok(())
+}
+
+#[frb]
+pub async fn get_disappearing_timer(chat_id: String) -> Result<Option<u64>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.chats().get_disappearing_timer(&chat_id).await)
+}
+
+#[frb]
+pub async fn save_draft(chat_id: String, content: String) -> Result<Option<DraftMessage>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .save_draft(&chat_id, &content)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn get_draft(chat_id: String) -> Result<Option<DraftMessage>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.chats().get_draft(&chat_id).await)
+}
+
+#[frb]
+pub async fn get_all_drafts() -> Result<Vec<DraftMessage>, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.chats().get_all_drafts().await)
+}
+
+#[frb]
+pub async fn clear_draft(chat_id: String) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .chats()
+        .clear_draft(&chat_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[frb]
+pub async fn mark_chat_read(chat_id: String, message_id: Option<String>) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    let to_acknowledge = engine
+        .chats()
+        .mark_read(&chat_id, message_id.as_deref())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    send_read_receipts(engine, &to_acknowledge).await;
+    Ok(())
+}
+
+#[frb]
+pub async fn mark_all_chats_read() -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    let to_acknowledge = engine
+        .chats()
+        .mark_all_read()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    send_read_receipts(engine, &to_acknowledge).await;
+    Ok(())
+}
+
+#[frb]
+pub async fn set_read_receipts_enabled(enabled: bool) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.chats().set_read_receipts_enabled(enabled);
+    Ok(())
+}
+
+async fn send_read_receipts(engine: &Engine, messages: &[ChatMessage]) {
+    for message in messages {
+        let _ = engine
+            .network()
+            .send_read_receipt(&message.from, &message.id)
+            .await;
+    }
+}
@@ -2,12 +2,22 @@ use crate::chats::{
     Chat, ChatExportOptions, ChatInfo, ChatSearchResult, ChatStatistics, DraftMessage,
     MessageFilter,
 };
-use crate::events::{AppEvent, EventBus, StorageEvent};
-use crate::network::{ChatMessage, ChatMessageType, DeliveryStatus};
+use crate::events::{AppEvent, EventBus, NetworkEvent, StorageEvent};
+use crate::network::{ChatMessage, ChatMessageType, DeliveryStatus, MessageReaction};
 use crate::storage::StorageManager;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+const MAX_EMOJI_LEN: usize = 32;
+pub const MIN_DISAPPEARING_TIMER: u64 = 5;
+pub const MAX_DISAPPEARING_TIMER: u64 = 4 * 7 * 24 * 60 * 60;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How far ahead of our clock a peer's settings change may claim to be.
+const MAX_CLOCK_SKEW: u64 = 60;
 
 #[derive(Debug)]
 pub enum ChatError {
@@ -38,6 +48,11 @@ pub struct Manager {
     storage: Arc<RwLock<StorageManager>>,
     event_bus: EventBus,
     statistics: Arc<RwLock<ChatStatistics>>,
+    event_listener: Option<JoinHandle<()>>,
+    expiry_task: Option<JoinHandle<()>>,
+    local_user: Option<String>,
+    local_peer_id: Option<String>,
+    read_receipts: AtomicBool,
 }
 
 impl Manager {
@@ -48,13 +63,40 @@ impl Manager {
             storage,
             event_bus,
             statistics: Arc::new(RwLock::new(ChatStatistics::new())),
+            event_listener: None,
+            expiry_task: None,
+            local_user: None,
+            local_peer_id: None,
+            read_receipts: AtomicBool::new(true),
         })
     }
 
+    /// Name the local user sends messages under; their own messages never count as unread.
+    pub fn set_local_user(&mut self, user: &str) {
+        self.local_user = Some(user.to_string());
+    }
+
+    /// Peer id the local user is known by; their reactions are kept under
+    /// it, the same way a peer's reactions are kept under theirs.
+    pub fn set_local_peer_id(&mut self, peer_id: &str) {
+        self.local_peer_id = Some(peer_id.to_string());
+    }
+
+    pub fn set_read_receipts_enabled(&self, enabled: bool) {
+        self.read_receipts.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn read_receipts_enabled(&self) -> bool {
+        self.read_receipts.load(Ordering::Relaxed)
+    }
+
     pub async fn initialize(&mut self) -> Result<(), ChatError> {
         // Load chats from storage
         self.load_chats().await?;
+        self.load_drafts().await?;
         self.update_statistics().await?;
+        self.start_event_listener();
+        self.start_expiry_task();
 
         self.event_bus
             .emit(AppEvent::Storage(StorageEvent::ChatHistoryLoaded {
@@ -70,6 +112,22 @@ impl Manager {
 
         let mut chats = self.chats.write().await;
         chats.insert(chat.id.clone(), chat.clone());
+        drop(chats);
+
+        self.save_chats().await?;
+        self.update_statistics().await?;
+
+        Ok(chat)
+    }
+
+    /// Starts a direct chat with `peer_id`, which is who its messages,
+    /// reactions and settings are exchanged with.
+    pub async fn create_direct_chat(&self, peer_id: &str, name: String) -> Result<Chat, ChatError> {
+        let chat = Chat::direct(peer_id.to_string(), name);
+
+        let mut chats = self.chats.write().await;
+        chats.insert(chat.id.clone(), chat.clone());
+        drop(chats);
 
         self.save_chats().await?;
         self.update_statistics().await?;
@@ -85,9 +143,144 @@ impl Manager {
             .ok_or_else(|| ChatError::ChatNotFound(chat_id.to_string()))
     }
 
+    /// All chats, pinned ones first in their pinned order, then the rest by
+    /// most recent activity.
     pub async fn get_all_chats(&self) -> Vec<Chat> {
         let chats = self.chats.read().await;
-        chats.values().cloned().collect()
+        let mut chats: Vec<Chat> = chats.values().cloned().collect();
+        Self::sort_chats(&mut chats);
+        chats
+    }
+
+    /// Chats that are not archived, in display order.
+    pub async fn get_active_chats(&self) -> Vec<Chat> {
+        let mut chats = self.get_all_chats().await;
+        chats.retain(|c| !c.is_archived);
+        chats
+    }
+
+    pub async fn get_archived_chats(&self) -> Vec<Chat> {
+        let mut chats = self.get_all_chats().await;
+        chats.retain(|c| c.is_archived);
+        chats
+    }
+
+    /// Pins a chat at the end of the pinned list. Pinning an archived chat
+    /// brings it back out of the archive.
+    pub async fn pin_chat(&self, chat_id: &str) -> Result<Chat, ChatError> {
+        let next_position = {
+            let chats = self.chats.read().await;
+            chats
+                .values()
+                .filter_map(|c| c.pinned_position)
+                .max()
+                .map_or(0, |p| p + 1)
+        };
+
+        self.update_chat(chat_id, |chat| {
+            if chat.pinned_position.is_none() {
+                chat.pinned_position = Some(next_position);
+            }
+            chat.is_archived = false;
+        })
+        .await
+    }
+
+    pub async fn unpin_chat(&self, chat_id: &str) -> Result<Chat, ChatError> {
+        let chat = self
+            .update_chat(chat_id, |chat| chat.pinned_position = None)
+            .await?;
+        self.compact_pinned_positions().await?;
+        Ok(chat)
+    }
+
+    /// Reorders pinned chats. `chat_ids` lists pinned chats in the desired
+    /// order; pinned chats left out keep their relative order after them.
+    pub async fn reorder_pinned_chats(&self, chat_ids: &[String]) -> Result<(), ChatError> {
+        let mut chats = self.chats.write().await;
+
+        for chat_id in chat_ids {
+            let chat = chats
+                .get(chat_id)
+                .ok_or_else(|| ChatError::ChatNotFound(chat_id.clone()))?;
+            if !chat.is_pinned() {
+                return Err(ChatError::InvalidInput(format!(
+                    "Chat {} is not pinned",
+                    chat_id
+                )));
+            }
+        }
+
+        let mut rest: Vec<&Chat> = chats
+            .values()
+            .filter(|c| c.is_pinned() && !chat_ids.contains(&c.id))
+            .collect();
+        rest.sort_by_key(|c| c.pinned_position);
+        let order: Vec<String> = chat_ids
+            .iter()
+            .cloned()
+            .chain(rest.into_iter().map(|c| c.id.clone()))
+            .collect();
+
+        for (position, chat_id) in order.iter().enumerate() {
+            if let Some(chat) = chats.get_mut(chat_id) {
+                chat.pinned_position = Some(position as u32);
+            }
+        }
+        drop(chats);
+
+        self.save_chats().await
+    }
+
+    /// Mutes notifications for a chat, for `duration` seconds or until
+    /// unmuted when `None`.
+    pub async fn mute_chat(&self, chat_id: &str, duration: Option<u64>) -> Result<Chat, ChatError> {
+        let muted_until = match duration {
+            Some(0) => {
+                return Err(ChatError::InvalidInput(
+                    "Mute duration must be positive".to_string(),
+                ))
+            }
+            Some(seconds) => Some(chrono::Utc::now() + chrono::Duration::seconds(seconds as i64)),
+            None => None,
+        };
+
+        self.update_chat(chat_id, |chat| {
+            chat.is_muted = true;
+            chat.muted_until = muted_until;
+        })
+        .await
+    }
+
+    pub async fn unmute_chat(&self, chat_id: &str) -> Result<Chat, ChatError> {
+        self.update_chat(chat_id, |chat| {
+            chat.is_muted = false;
+            chat.muted_until = None;
+        })
+        .await
+    }
+
+    /// Whether notifications for a chat are currently muted.
+    pub async fn is_chat_muted(&self, chat_id: &str) -> Result<bool, ChatError> {
+        let chat = self.get_chat(chat_id).await?;
+        Ok(chat.is_muted_at(chrono::Utc::now()))
+    }
+
+    /// Archives a chat. Archived chats lose their pin.
+    pub async fn archive_chat(&self, chat_id: &str) -> Result<Chat, ChatError> {
+        let chat = self
+            .update_chat(chat_id, |chat| {
+                chat.is_archived = true;
+                chat.pinned_position = None;
+            })
+            .await?;
+        self.compact_pinned_positions().await?;
+        Ok(chat)
+    }
+
+    pub async fn unarchive_chat(&self, chat_id: &str) -> Result<Chat, ChatError> {
+        self.update_chat(chat_id, |chat| chat.is_archived = false)
+            .await
     }
 
     pub async fn send_message(
@@ -112,6 +305,9 @@ impl Manager {
             msg_type: message_type,
             timestamp: chrono::Utc::now().timestamp() as u64,
             delivery_status: DeliveryStatus::Pending,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: 0,
         };
 
         // Save message to storage
@@ -125,6 +321,11 @@ impl Manager {
         chat.increment_message_count();
 
         drop(chats);
+        drop(storage);
+
+        if self.draft_messages.read().await.contains_key(chat_id) {
+            self.clear_draft(chat_id).await?;
+        }
 
         self.save_chats().await?;
         self.update_statistics().await?;
@@ -156,10 +357,10 @@ impl Manager {
         &self,
         filter: MessageFilter,
     ) -> Result<Vec<ChatSearchResult>, ChatError> {
-        let storage = self.storage.read().await;
         let mut results = Vec::new();
 
         if let Some(chat_id) = &filter.chat_id {
+            let storage = self.storage.read().await;
             let messages = storage
                 .get_messages(chat_id)
                 .await
@@ -176,8 +377,9 @@ impl Manager {
             }
         } else {
             // Search across all chats
-            let chats = self.chats.read().await;
-            for chat_id in chats.keys() {
+            let chat_ids: Vec<String> = self.chats.read().await.keys().cloned().collect();
+            let storage = self.storage.read().await;
+            for chat_id in &chat_ids {
                 let messages = storage
                     .get_messages(chat_id)
                     .await
@@ -202,15 +404,12 @@ impl Manager {
         let chat = self.get_chat(chat_id).await?;
 
         let storage = self.storage.read().await;
-        let messages = storage
-            .get_messages(chat_id)
-            .await
-            .map_err(|e| ChatError::StorageError(e.to_string()))?;
-
-        let unread_count = messages
-            .iter()
-            .filter(|m| matches!(m.delivery_status, DeliveryStatus::Delivered))
-            .count() as u64;
+        let unread_count = storage
+            .count_unread(chat_id, self.local_user.as_deref())
+            .await;
+        let disappearing_timer = storage.get_disappearing_timer(chat_id).await;
+        let is_pinned = chat.is_pinned();
+        let is_muted = chat.is_muted_at(chrono::Utc::now());
 
         Ok(ChatInfo {
             chat_id: chat.id,
@@ -219,6 +418,10 @@ impl Manager {
             message_count: chat.message_count,
             last_activity: chat.last_message_at,
             unread_count,
+            disappearing_timer,
+            is_pinned,
+            is_muted,
+            is_archived: chat.is_archived,
         })
     }
 
@@ -258,6 +461,193 @@ impl Manager {
         Ok(())
     }
 
+    pub async fn add_reaction(
+        &self,
+        message_id: &str,
+        emoji: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        Self::validate_emoji(emoji)?;
+        self.apply_reaction(message_id, emoji, true).await
+    }
+
+    pub async fn remove_reaction(
+        &self,
+        message_id: &str,
+        emoji: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        Self::validate_emoji(emoji)?;
+        self.apply_reaction(message_id, emoji, false).await
+    }
+
+    /// Peers that should hear about reactions to a message: the other
+    /// participants of its chat.
+    pub async fn reaction_recipients(&self, message_id: &str) -> Result<Vec<String>, ChatError> {
+        let chat_id = self
+            .storage
+            .read()
+            .await
+            .find_message_chat(message_id)
+            .await
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))?;
+        self.chat_recipients(&chat_id).await
+    }
+
+    pub async fn get_reactions(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Vec<MessageReaction>, ChatError> {
+        let storage = self.storage.read().await;
+        let messages = storage
+            .get_messages(chat_id)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+
+        messages
+            .into_iter()
+            .find(|m| m.id == message_id)
+            .map(|m| m.reactions)
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))
+    }
+
+    /// Saves the unsent text of a chat. Saving empty content clears the draft.
+    pub async fn save_draft(
+        &self,
+        chat_id: &str,
+        content: &str,
+    ) -> Result<Option<DraftMessage>, ChatError> {
+        if content.trim().is_empty() {
+            self.clear_draft(chat_id).await?;
+            return Ok(None);
+        }
+
+        self.get_chat(chat_id).await?;
+
+        let mut drafts = self.draft_messages.write().await;
+        let now = chrono::Utc::now();
+        let draft = drafts
+            .entry(chat_id.to_string())
+            .and_modify(|draft| {
+                draft.content = content.to_string();
+                draft.updated_at = now;
+            })
+            .or_insert_with(|| DraftMessage {
+                chat_id: chat_id.to_string(),
+                content: content.to_string(),
+                created_at: now,
+                updated_at: now,
+            })
+            .clone();
+
+        self.persist_drafts(&drafts).await?;
+        Ok(Some(draft))
+    }
+
+    pub async fn get_draft(&self, chat_id: &str) -> Option<DraftMessage> {
+        let drafts = self.draft_messages.read().await;
+        drafts.get(chat_id).cloned()
+    }
+
+    pub async fn get_all_drafts(&self) -> Vec<DraftMessage> {
+        let drafts = self.draft_messages.read().await;
+        drafts.values().cloned().collect()
+    }
+
+    pub async fn clear_draft(&self, chat_id: &str) -> Result<(), ChatError> {
+        let mut drafts = self.draft_messages.write().await;
+        if drafts.remove(chat_id).is_some() {
+            self.persist_drafts(&drafts).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the read marker of a chat up to `message_id`, or to the latest
+    /// message when `None`. Returns the newly read messages that were sent by
+    /// other participants and should be acknowledged with a read receipt;
+    /// the list is empty when read receipts are disabled.
+    pub async fn mark_read(
+        &self,
+        chat_id: &str,
+        message_id: Option<&str>,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        self.get_chat(chat_id).await?;
+
+        let storage = self.storage.read().await;
+        let newly_read = storage
+            .mark_read(chat_id, message_id)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+
+        if !self.read_receipts_enabled() {
+            return Ok(Vec::new());
+        }
+
+        Ok(newly_read
+            .into_iter()
+            .filter(|m| self.local_user.as_deref() != Some(m.from.as_str()))
+            .collect())
+    }
+
+    /// Marks every chat as read, returning the messages to acknowledge.
+    pub async fn mark_all_read(&self) -> Result<Vec<ChatMessage>, ChatError> {
+        let chat_ids: Vec<String> = self.chats.read().await.keys().cloned().collect();
+        let mut to_acknowledge = Vec::new();
+
+        for chat_id in chat_ids {
+            to_acknowledge.extend(self.mark_read(&chat_id, None).await?);
+        }
+
+        Ok(to_acknowledge)
+    }
+
+    pub async fn get_read_marker(&self, chat_id: &str) -> Option<String> {
+        let storage = self.storage.read().await;
+        storage.get_read_marker(chat_id).await
+    }
+
+    /// Sets the disappearing-message timer for a chat, in seconds. `None`
+    /// turns disappearing messages off. Returns the change timestamp that
+    /// should be sent to the other participants; nothing should be sent
+    /// when a newer change is already in place.
+    pub async fn set_disappearing_timer(
+        &self,
+        chat_id: &str,
+        timer: Option<u64>,
+    ) -> Result<u64, ChatError> {
+        Self::validate_disappearing_timer(timer)?;
+        self.get_chat(chat_id).await?;
+
+        let changed_at = chrono::Utc::now().timestamp() as u64;
+        let storage = self.storage.read().await;
+        let applied = storage
+            .set_disappearing_timer(chat_id, timer, changed_at)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+        if !applied {
+            return Err(ChatError::InvalidInput(
+                "Disappearing timer was changed more recently".to_string(),
+            ));
+        }
+
+        Ok(changed_at)
+    }
+
+    pub async fn get_disappearing_timer(&self, chat_id: &str) -> Option<u64> {
+        let storage = self.storage.read().await;
+        storage.get_disappearing_timer(chat_id).await
+    }
+
+    /// Peers that should receive control messages for a chat.
+    pub async fn chat_recipients(&self, chat_id: &str) -> Result<Vec<String>, ChatError> {
+        Ok(self.get_chat(chat_id).await?.participants)
+    }
+
+    /// Deletes expired disappearing messages now instead of waiting for the
+    /// background task. Returns the number of messages removed.
+    pub async fn purge_expired_messages(&self) -> Result<usize, ChatError> {
+        Self::expire_messages(&self.storage, &self.chats).await
+    }
+
     pub async fn get_statistics(&self) -> ChatStatistics {
         let stats = self.statistics.read().await;
         stats.clone()
@@ -284,18 +674,364 @@ impl Manager {
 
     // Private helper methods
 
+    fn start_event_listener(&mut self) {
+        if self.event_listener.is_some() {
+            return;
+        }
+
+        let storage = self.storage.clone();
+        let chats = self.chats.clone();
+        let local_user = self.local_user.clone();
+        let mut receiver = self.event_bus.subscribe();
+
+        self.event_listener = Some(tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(AppEvent::Network(NetworkEvent::MessageReceived { peer_id, message })) => {
+                        let _ = Self::store_received(&storage, &chats, &peer_id, message).await;
+                    }
+                    Ok(AppEvent::Network(NetworkEvent::ReactionReceived {
+                        peer_id,
+                        message_id,
+                        emoji,
+                        added,
+                    })) => {
+                        if Self::validate_emoji(&emoji).is_err() {
+                            continue;
+                        }
+                        // Only someone in the message's chat may react to it
+                        let chat_id = storage.read().await.find_message_chat(&message_id).await;
+                        let in_chat = match chat_id {
+                            Some(chat_id) => Self::in_chat(&chats, &chat_id, &peer_id).await,
+                            None => false,
+                        };
+                        if !in_chat {
+                            continue;
+                        }
+                        let storage = storage.read().await;
+                        let _ = storage
+                            .update_reaction(&message_id, &peer_id, &emoji, added)
+                            .await;
+                    }
+                    Ok(AppEvent::Network(NetworkEvent::ReceiptReceived {
+                        peer_id,
+                        message_id,
+                        status,
+                    })) => {
+                        // Only the peer one of our messages went to can acknowledge it
+                        let sent_to_peer = Self::sent_to(
+                            &storage,
+                            &chats,
+                            local_user.as_deref(),
+                            &message_id,
+                            &peer_id,
+                        )
+                        .await;
+                        if !sent_to_peer {
+                            continue;
+                        }
+                        let storage = storage.read().await;
+                        let _ = storage.update_message_status(&message_id, status).await;
+                    }
+                    Ok(AppEvent::Network(NetworkEvent::ChatSettingsReceived {
+                        peer_id,
+                        chat_id,
+                        disappearing_timer,
+                        changed_at,
+                    })) => {
+                        if Self::validate_disappearing_timer(disappearing_timer).is_err()
+                            || !Self::in_chat(&chats, &chat_id, &peer_id).await
+                        {
+                            continue;
+                        }
+                        // A clock far ahead must not lock the setting in
+                        let now = chrono::Utc::now().timestamp() as u64;
+                        let changed_at = changed_at.min(now + MAX_CLOCK_SKEW);
+                        let storage = storage.read().await;
+                        let _ = storage
+                            .set_disappearing_timer(&chat_id, disappearing_timer, changed_at)
+                            .await;
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }));
+    }
+
+    fn start_expiry_task(&mut self) {
+        if self.expiry_task.is_some() {
+            return;
+        }
+
+        let storage = self.storage.clone();
+        let chats = self.chats.clone();
+
+        self.expiry_task = Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let _ = Self::expire_messages(&storage, &chats).await;
+            }
+        }));
+    }
+
+    async fn expire_messages(
+        storage: &RwLock<StorageManager>,
+        chats: &RwLock<HashMap<String, Chat>>,
+    ) -> Result<usize, ChatError> {
+        let expired = storage
+            .read()
+            .await
+            .purge_expired_messages()
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut chats = chats.write().await;
+        let mut removed = 0;
+        for (chat_id, message_ids) in &expired {
+            if let Some(chat) = chats.get_mut(chat_id) {
+                chat.message_count = chat.message_count.saturating_sub(message_ids.len() as u64);
+            }
+            removed += message_ids.len();
+        }
+
+        Ok(removed)
+    }
+
+    /// Keeps a message delivered by `peer_id` in their direct chat, starting
+    /// one if there is none yet. It picks up the chat's disappearing timer
+    /// as it is stored.
+    async fn store_received(
+        storage: &RwLock<StorageManager>,
+        chats: &RwLock<HashMap<String, Chat>>,
+        peer_id: &str,
+        message: ChatMessage,
+    ) -> Result<(), ChatError> {
+        if storage
+            .read()
+            .await
+            .find_message_chat(&message.id)
+            .await
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        // Chats are always locked before storage, as everywhere else here
+        let chat_id = {
+            let mut chats = chats.write().await;
+            match chats
+                .values()
+                .find(|chat| !chat.is_group && chat.has_participant(peer_id))
+            {
+                Some(chat) => chat.id.clone(),
+                None => {
+                    let chat = Chat::direct(peer_id.to_string(), message.from.clone());
+                    let chat_id = chat.id.clone();
+                    chats.insert(chat_id.clone(), chat);
+                    chat_id
+                }
+            }
+        };
+
+        storage
+            .read()
+            .await
+            .save_message(&chat_id, &message)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+
+        let mut chats = chats.write().await;
+        if let Some(chat) = chats.get_mut(&chat_id) {
+            chat.increment_message_count();
+        }
+        let storage = storage.read().await;
+        storage
+            .save_chat_list(&chats)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))
+    }
+
+    /// Whether `message_id` is a message the local user sent in a chat
+    /// with `peer_id`.
+    async fn sent_to(
+        storage: &RwLock<StorageManager>,
+        chats: &RwLock<HashMap<String, Chat>>,
+        local_user: Option<&str>,
+        message_id: &str,
+        peer_id: &str,
+    ) -> bool {
+        let chat_id = match storage.read().await.find_message_chat(message_id).await {
+            Some(chat_id) => chat_id,
+            None => return false,
+        };
+        if !Self::in_chat(chats, &chat_id, peer_id).await {
+            return false;
+        }
+        let messages = storage
+            .read()
+            .await
+            .get_messages(&chat_id)
+            .await
+            .unwrap_or_default();
+        messages
+            .iter()
+            .any(|m| m.id == message_id && local_user == Some(m.from.as_str()))
+    }
+
+    async fn in_chat(chats: &RwLock<HashMap<String, Chat>>, chat_id: &str, peer_id: &str) -> bool {
+        chats
+            .read()
+            .await
+            .get(chat_id)
+            .is_some_and(|chat| chat.has_participant(peer_id))
+    }
+
+    fn validate_disappearing_timer(timer: Option<u64>) -> Result<(), ChatError> {
+        match timer {
+            Some(seconds)
+                if !(MIN_DISAPPEARING_TIMER..=MAX_DISAPPEARING_TIMER).contains(&seconds) =>
+            {
+                Err(ChatError::InvalidInput(format!(
+                    "Disappearing timer must be between {} and {} seconds",
+                    MIN_DISAPPEARING_TIMER, MAX_DISAPPEARING_TIMER
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn apply_reaction(
+        &self,
+        message_id: &str,
+        emoji: &str,
+        added: bool,
+    ) -> Result<ChatMessage, ChatError> {
+        let peer_id = self
+            .local_peer_id
+            .as_deref()
+            .ok_or_else(|| ChatError::InvalidInput("Local peer id is not set".to_string()))?;
+        let storage = self.storage.read().await;
+        storage
+            .update_reaction(message_id, peer_id, emoji, added)
+            .await
+            .map_err(|e| match e {
+                crate::storage::StorageError::NotFound(_) => {
+                    ChatError::MessageNotFound(message_id.to_string())
+                }
+                other => ChatError::StorageError(other.to_string()),
+            })
+    }
+
+    fn validate_emoji(emoji: &str) -> Result<(), ChatError> {
+        if emoji.trim().is_empty() {
+            return Err(ChatError::InvalidInput("Emoji cannot be empty".to_string()));
+        }
+        if emoji.len() > MAX_EMOJI_LEN {
+            return Err(ChatError::InvalidInput(format!(
+                "Emoji exceeds {} bytes",
+                MAX_EMOJI_LEN
+            )));
+        }
+        Ok(())
+    }
+
     async fn load_chats(&self) -> Result<(), ChatError> {
-        // This would load chats from storage
-        // For now, we'll create a simple implementation
+        let chats = self
+            .storage
+            .read()
+            .await
+            .load_chat_list()
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+
+        *self.chats.write().await = chats;
         Ok(())
     }
 
-    async fn save_chats(&self) -> Result<(), ChatError> {
-        // This would save chats to storage
-        // For now, we'll create a simple implementation
+    async fn load_drafts(&self) -> Result<(), ChatError> {
+        let drafts = self
+            .storage
+            .read()
+            .await
+            .load_drafts()
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))?;
+
+        *self.draft_messages.write().await = drafts;
         Ok(())
     }
 
+    async fn persist_drafts(
+        &self,
+        drafts: &HashMap<String, DraftMessage>,
+    ) -> Result<(), ChatError> {
+        let storage = self.storage.read().await;
+        storage
+            .save_drafts(drafts)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))
+    }
+
+    async fn save_chats(&self) -> Result<(), ChatError> {
+        let chats = self.chats.read().await;
+        let storage = self.storage.read().await;
+        storage
+            .save_chat_list(&chats)
+            .await
+            .map_err(|e| ChatError::StorageError(e.to_string()))
+    }
+
+    async fn update_chat<F>(&self, chat_id: &str, update: F) -> Result<Chat, ChatError>
+    where
+        F: FnOnce(&mut Chat),
+    {
+        let mut chats = self.chats.write().await;
+        let chat = chats
+            .get_mut(chat_id)
+            .ok_or_else(|| ChatError::ChatNotFound(chat_id.to_string()))?;
+        update(chat);
+        let chat = chat.clone();
+        drop(chats);
+
+        self.save_chats().await?;
+        Ok(chat)
+    }
+
+    /// Renumbers pinned chats so positions stay contiguous after an unpin.
+    async fn compact_pinned_positions(&self) -> Result<(), ChatError> {
+        let mut chats = self.chats.write().await;
+        let mut pinned: Vec<(u32, String)> = chats
+            .values()
+            .filter_map(|c| c.pinned_position.map(|p| (p, c.id.clone())))
+            .collect();
+        pinned.sort();
+
+        for (position, (_, chat_id)) in pinned.into_iter().enumerate() {
+            if let Some(chat) = chats.get_mut(&chat_id) {
+                chat.pinned_position = Some(position as u32);
+            }
+        }
+        drop(chats);
+
+        self.save_chats().await
+    }
+
+    fn sort_chats(chats: &mut [Chat]) {
+        chats.sort_by(|a, b| match (a.pinned_position, b.pinned_position) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => b.last_activity().cmp(&a.last_activity()),
+        });
+    }
+
     async fn update_statistics(&self) -> Result<(), ChatError> {
         let chats = self.chats.read().await;
         let mut stats = self.statistics.write().await;
@@ -366,3 +1102,14 @@ impl Manager {
             .collect()
     }
 }
+
+impl Drop for Manager {
+    fn drop(&mut self) {
+        if let Some(handle) = self.event_listener.take() {
+            handle.abort();
+        }
+        if let Some(handle) = self.expiry_task.take() {
+            handle.abort();
+        }
+    }
+}
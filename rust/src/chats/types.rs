@@ -12,6 +12,16 @@ pub struct Chat {
     pub last_message_at: Option<DateTime<Utc>>,
     pub message_count: u64,
     pub is_group: bool,
+    /// Position among pinned chats; `None` when the chat is not pinned.
+    #[serde(default)]
+    pub pinned_position: Option<u32>,
+    #[serde(default)]
+    pub is_muted: bool,
+    /// End of a temporary mute; `None` mutes until explicitly unmuted.
+    #[serde(default)]
+    pub muted_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub is_archived: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +32,10 @@ pub struct ChatInfo {
     pub message_count: u64,
     pub last_activity: Option<DateTime<Utc>>,
     pub unread_count: u64,
+    pub disappearing_timer: Option<u64>,
+    pub is_pinned: bool,
+    pub is_muted: bool,
+    pub is_archived: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -106,9 +120,34 @@ impl Chat {
             last_message_at: None,
             message_count: 0,
             is_group,
+            pinned_position: None,
+            is_muted: false,
+            muted_until: None,
+            is_archived: false,
         }
     }
 
+    /// A direct chat with the peer `peer_id`, shown under `name`.
+    pub fn direct(peer_id: String, name: String) -> Self {
+        let mut chat = Self::new(name, false);
+        chat.participants.push(peer_id);
+        chat
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned_position.is_some()
+    }
+
+    /// Whether notifications are muted at `now`. A mute whose expiry has
+    /// passed no longer counts.
+    pub fn is_muted_at(&self, now: DateTime<Utc>) -> bool {
+        self.is_muted && self.muted_until.is_none_or(|until| now < until)
+    }
+
+    pub fn last_activity(&self) -> DateTime<Utc> {
+        self.last_message_at.unwrap_or(self.created_at)
+    }
+
     pub fn add_participant(&mut self, participant: String) {
         if !self.participants.contains(&participant) {
             self.participants.push(participant);
@@ -119,6 +158,11 @@ impl Chat {
         self.participants.retain(|p| p != participant);
     }
 
+    /// Whether `peer_id` takes part in the chat.
+    pub fn has_participant(&self, peer_id: &str) -> bool {
+        self.participants.iter().any(|p| p == peer_id)
+    }
+
     pub fn update_last_activity(&mut self) {
         self.last_message_at = Some(Utc::now());
     }
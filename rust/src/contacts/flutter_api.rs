@@ -7,12 +7,17 @@ use flutter_rust_bridge::frb;
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn add_contact(contact: Contact) -> Result<(), String> {
-    Err("Not implemented - needs mutable engine access".to_string())
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.add_contact(contact).await.map_err(|e| e.to_string())
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn remove_contact(contact_id: String) -> Result<(), String> {
-    Err("Not implemented - needs mutable engine access".to_string())
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .remove_contact(&contact_id)
+        .await
+        .map_err(|e| e.to_string())
 }
 
 #[cfg_attr(feature = "flutter", frb)]
@@ -20,6 +25,8 @@ pub async fn get_contact(contact_id: String) -> Result<Contact, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .contacts()
+        .read()
+        .await
         .get_contact(&contact_id)
         .ok_or_else(|| "Contact not found".to_string())
 }
@@ -27,7 +34,7 @@ pub async fn get_contact(contact_id: String) -> Result<Contact, String> {
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn get_all_contacts() -> Result<Vec<Contact>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().get_contacts())
+    Ok(engine.contacts().read().await.get_contacts())
 }
 
 #[cfg_attr(feature = "flutter", frb)]
@@ -56,37 +63,68 @@ pub async fn unblock_contact(contact_id: String) -> Result<(), String> {
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn is_contact_blocked(contact_id: String) -> Result<bool, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().is_contact_blocked(&contact_id))
+    Ok(engine
+        .contacts()
+        .read()
+        .await
+        .is_contact_blocked(&contact_id))
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn set_favorite(contact_id: String, favorite: bool) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .set_favorite(&contact_id, favorite)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn is_favorite(contact_id: String) -> Result<bool, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.contacts().read().await.is_favorite(&contact_id))
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn get_contact_stats() -> Result<ContactStats, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().get_contact_stats())
+    Ok(engine.contacts().read().await.get_contact_stats())
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn find_contacts_by_name(name: String) -> Result<Vec<Contact>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().find_contacts_by_name(&name))
+    Ok(engine.contacts().read().await.find_contacts_by_name(&name))
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn find_contacts_by_address(address: String) -> Result<Vec<Contact>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().find_contacts_by_address(&address))
+    Ok(engine
+        .contacts()
+        .read()
+        .await
+        .find_contacts_by_address(&address))
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn get_contacts_by_trust_level(trust_level: TrustLevel) -> Result<Vec<Contact>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().get_contacts_by_trust_level(trust_level))
+    Ok(engine
+        .contacts()
+        .read()
+        .await
+        .get_contacts_by_trust_level(trust_level))
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn get_contacts_by_status(status: ContactStatus) -> Result<Vec<Contact>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.contacts().get_contacts_by_status(status))
+    Ok(engine
+        .contacts()
+        .read()
+        .await
+        .get_contacts_by_status(status))
 }
 
 #[cfg_attr(feature = "flutter", frb)]
@@ -99,12 +137,26 @@ pub async fn batch_unblock_contacts(contact_ids: Vec<String>) -> Result<u32, Str
     Err("Not implemented - needs mutable engine access".to_string())
 }
 
-#[frb]
+#[cfg_attr(feature = "flutter", frb)]
 pub async fn save_contacts() -> Result<(), String> {
-    Err("Not implemented - needs mutable engine access".to_string())
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .contacts()
+        .read()
+        .await
+        .save_contacts()
+        .await
+        .map_err(|e| e.to_string())
 }
 
 #[cfg_attr(feature = "flutter", frb)]
 pub async fn load_contacts() -> Result<(), String> {
-    Err("Not implemented - needs mutable engine access".to_string())
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .contacts()
+        .write()
+        .await
+        .load_contacts()
+        .await
+        .map_err(|e| e.to_string())
 }
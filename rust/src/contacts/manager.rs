@@ -1,10 +1,18 @@
 use crate::contacts::{
     ContactError, ContactInteractionStats, ContactIssueType, ContactValidationIssue, IssueSeverity,
 };
-use crate::network::{Contact, ContactStatus, TrustLevel};
-use std::collections::HashMap;
+use crate::events::{AppEvent, EventBus, NetworkEvent};
+use crate::network::{
+    Contact, ContactStatus, DiscoveredPeer, Endpoint, NetworkManager, TransportError, TrustLevel,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
 #[derive(Debug, Clone)]
 pub struct ContactStats {
@@ -15,13 +23,17 @@ pub struct ContactStats {
     pub pending_contacts: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContactBook {
     pub contacts: HashMap<String, Contact>,
+    #[serde(default)]
     pub blocked_contacts: HashMap<String, BlockedContactInfo>,
+    /// Contacts the user always wants connected, whatever their trust.
+    #[serde(default)]
+    pub favorites: HashSet<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockedContactInfo {
     pub blocked_at: chrono::DateTime<chrono::Utc>,
     pub reason: String,
@@ -33,10 +45,24 @@ pub struct ContactManager {
     data_path: PathBuf,
 }
 
+/// Where the contact book is kept under the profile directory.
+const CONTACT_BOOK_FILE: &str = "contact_book.json";
+
 impl ContactManager {
+    /// Opens the contact book saved under `data_path`, or an empty one.
     pub fn new(data_path: &Path) -> Result<Self, ContactError> {
+        let path = data_path.join(CONTACT_BOOK_FILE);
+        let contact_book = if path.exists() {
+            let data =
+                std::fs::read_to_string(&path).map_err(|e| ContactError::IoError(e.to_string()))?;
+            serde_json::from_str(&data)
+                .map_err(|e| ContactError::SerializationError(e.to_string()))?
+        } else {
+            ContactBook::new()
+        };
+
         Ok(Self {
-            contact_book: ContactBook::new(),
+            contact_book,
             data_path: data_path.to_path_buf(),
         })
     }
@@ -65,6 +91,26 @@ impl ContactManager {
         self.contact_book.update_contact_status(contact_id, status)
     }
 
+    /// Moves the endpoint a contact was last reached at to the front of
+    /// its addresses.
+    pub fn update_contact_address(
+        &mut self,
+        contact_id: &str,
+        address: Endpoint,
+    ) -> Result<(), ContactError> {
+        match self.contact_book.contacts.get_mut(contact_id) {
+            Some(contact) => {
+                contact.addresses.retain(|existing| *existing != address);
+                contact.addresses.insert(0, address);
+                Ok(())
+            }
+            None => Err(ContactError::ContactNotFound(format!(
+                "Contact with ID {} not found",
+                contact_id
+            ))),
+        }
+    }
+
     pub fn set_trust_level(
         &mut self,
         contact_id: &str,
@@ -93,6 +139,162 @@ impl ContactManager {
         self.contact_book.is_blocked(contact_id)
     }
 
+    /// Blocks a peer the network caught misbehaving, whether or not it is
+    /// a contact.
+    pub fn block_offender(&mut self, peer_id: &str, reason: &str) {
+        self.contact_book.block_offender(peer_id, reason);
+    }
+
+    pub fn get_blocked_info(&self, contact_id: &str) -> Option<BlockedContactInfo> {
+        self.contact_book.blocked_contacts.get(contact_id).cloned()
+    }
+
+    pub fn blocked_ids(&self) -> Vec<String> {
+        self.contact_book.blocked_contacts.keys().cloned().collect()
+    }
+
+    pub fn set_favorite(&mut self, contact_id: &str, favorite: bool) -> Result<(), ContactError> {
+        if !self.contact_book.contacts.contains_key(contact_id) {
+            return Err(ContactError::ContactNotFound(format!(
+                "Contact with ID {} not found",
+                contact_id
+            )));
+        }
+        if favorite {
+            self.contact_book.favorites.insert(contact_id.to_string());
+        } else {
+            self.contact_book.favorites.remove(contact_id);
+        }
+        Ok(())
+    }
+
+    pub fn is_favorite(&self, contact_id: &str) -> bool {
+        self.contact_book.favorites.contains(contact_id)
+    }
+
+    /// Contacts worth keeping connected: favourites and highly trusted
+    /// contacts that are not blocked and have somewhere to dial. Favourites
+    /// come first, then higher trust, then those seen most recently.
+    pub fn supervised_contacts(&self) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self
+            .get_contacts()
+            .into_iter()
+            .filter(|c| self.is_favorite(&c.id) || c.trust_level.rank() >= TrustLevel::High.rank())
+            .filter(|c| !self.is_contact_blocked(&c.id) && !c.addresses.is_empty())
+            .collect();
+        contacts.sort_by(|a, b| {
+            self.is_favorite(&b.id)
+                .cmp(&self.is_favorite(&a.id))
+                .then(b.trust_level.rank().cmp(&a.trust_level.rank()))
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        contacts
+    }
+
+    /// Records a connection made to a contact through `endpoint`, which
+    /// becomes the first one tried next time. An offline contact becomes
+    /// online; an away or busy status set by the peer itself is kept.
+    pub fn apply_connected(&mut self, contact_id: &str, endpoint: Endpoint) -> Option<Contact> {
+        if self.contact_book.is_blocked(contact_id) {
+            return None;
+        }
+
+        let contact = self.contact_book.contacts.get_mut(contact_id)?;
+        contact.addresses.retain(|existing| *existing != endpoint);
+        contact.addresses.insert(0, endpoint);
+        if matches!(contact.status, ContactStatus::Offline) {
+            contact.status = ContactStatus::Online;
+        }
+        contact.last_seen = Some(chrono::Utc::now());
+        Some(contact.clone())
+    }
+
+    /// Applies a presence update from a peer to the matching contact.
+    /// Updates from blocked or unknown peers are ignored.
+    pub fn apply_presence(&mut self, peer_id: &str, status: ContactStatus) -> Option<Contact> {
+        if self.contact_book.is_blocked(peer_id) {
+            return None;
+        }
+
+        let contact = self.contact_book.contacts.get_mut(peer_id)?;
+        contact.status = status;
+        contact.last_seen = Some(chrono::Utc::now());
+        Some(contact.clone())
+    }
+
+    /// Applies a LAN sighting of a peer to the matching contact: its address
+    /// follows the announcement, and an offline contact becomes online.
+    /// An away or busy status set by the peer itself is kept. Sightings
+    /// under a key other than the contact's are ignored.
+    pub fn apply_discovery(&mut self, peer: &DiscoveredPeer) -> Option<Contact> {
+        if self.contact_book.is_blocked(&peer.id) {
+            return None;
+        }
+
+        let contact = self.contact_book.contacts.get_mut(&peer.id)?;
+        if !contact.public_key.is_empty() && contact.public_key != peer.public_key {
+            return None;
+        }
+        let address = Endpoint::Tcp(SocketAddr::new(peer.address, peer.port));
+        contact.addresses.retain(|existing| *existing != address);
+        contact.addresses.insert(0, address);
+        if matches!(contact.status, ContactStatus::Offline) {
+            contact.status = ContactStatus::Online;
+        }
+        contact.last_seen = Some(chrono::Utc::now());
+        Some(contact.clone())
+    }
+
+    /// Keeps the contact book in sync with presence and discovery events
+    /// from the network.
+    pub fn spawn_presence_listener(
+        contacts: Arc<RwLock<ContactManager>>,
+        network: Arc<NetworkManager>,
+        event_bus: EventBus,
+    ) -> JoinHandle<()> {
+        let mut receiver = event_bus.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(AppEvent::Network(NetworkEvent::PresenceChanged { peer_id, status })) => {
+                        let updated = contacts.write().await.apply_presence(&peer_id, status);
+                        if let Some(contact) = updated {
+                            event_bus.emit_network(NetworkEvent::ContactStatusChanged { contact });
+                        }
+                    }
+                    Ok(AppEvent::Network(
+                        NetworkEvent::PeerDiscovered { peer } | NetworkEvent::PeerUpdated { peer },
+                    )) => {
+                        let updated = contacts.write().await.apply_discovery(&peer);
+                        if let Some(contact) = updated {
+                            event_bus.emit_network(NetworkEvent::ContactStatusChanged { contact });
+                        }
+                    }
+                    Ok(AppEvent::Network(NetworkEvent::PeerBlocked { peer_id, reason })) => {
+                        contacts.write().await.block_offender(&peer_id, &reason);
+                    }
+                    Ok(AppEvent::Network(NetworkEvent::PeerLost { peer_id })) => {
+                        // Out of sight on the LAN, but maybe reachable some other way
+                        if network.has_connection(&peer_id).await {
+                            continue;
+                        }
+                        let updated = contacts
+                            .write()
+                            .await
+                            .apply_presence(&peer_id, ContactStatus::Offline);
+                        if let Some(contact) = updated {
+                            event_bus.emit_network(NetworkEvent::ContactStatusChanged { contact });
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
     pub fn get_contact_stats(&self) -> ContactStats {
         let contacts = self.get_contacts();
         let total = contacts.len();
@@ -134,6 +336,24 @@ impl ContactManager {
             .collect()
     }
 
+    /// Contacts trusted at least `min_trust` and not blocked, i.e. those we
+    /// would let carry our traffic as a relay. A blocked contact can never
+    /// be a relay, so `Blocked` is refused as the minimum.
+    pub fn relay_candidates(&self, min_trust: &TrustLevel) -> Result<Vec<Contact>, ContactError> {
+        if *min_trust == TrustLevel::Blocked {
+            return Err(ContactError::InvalidTrustLevel(
+                "Relays cannot be chosen among blocked contacts".to_string(),
+            ));
+        }
+        Ok(self
+            .get_contacts()
+            .into_iter()
+            .filter(|c| c.trust_level.rank() >= min_trust.rank())
+            .filter(|c| c.trust_level != TrustLevel::Blocked)
+            .filter(|c| !self.is_contact_blocked(&c.id))
+            .collect())
+    }
+
     pub fn get_contacts_by_status(&self, status: ContactStatus) -> Vec<Contact> {
         self.get_contacts()
             .into_iter()
@@ -142,7 +362,7 @@ impl ContactManager {
     }
 
     pub async fn batch_block_contacts(
-        &self,
+        &mut self,
         contact_ids: Vec<String>,
     ) -> Result<u32, ContactError> {
         let mut blocked_count = 0;
@@ -161,7 +381,7 @@ impl ContactManager {
 
     // Метод для пакетной разблокировки контактов
     pub async fn batch_unblock_contacts(
-        &self,
+        &mut self,
         contact_ids: Vec<String>,
     ) -> Result<u32, ContactError> {
         let mut unblocked_count = 0;
@@ -192,7 +412,7 @@ impl ContactManager {
 
     // Метод для пометки контактов как offline
     pub async fn mark_contacts_offline(
-        &self,
+        &mut self,
         older_than_minutes: u32,
     ) -> Result<u32, ContactError> {
         let cutoff_time = chrono::Utc::now() - chrono::Duration::minutes(older_than_minutes as i64);
@@ -294,7 +514,7 @@ impl ContactManager {
                 });
             }
 
-            if contact.address.trim().is_empty() {
+            if contact.addresses.is_empty() {
                 issues.push(ContactValidationIssue {
                     contact_id: contact.id.clone(),
                     issue_type: ContactIssueType::EmptyAddress,
@@ -303,13 +523,15 @@ impl ContactManager {
                 });
             }
 
-            if !self.is_valid_address(&contact.address) {
-                issues.push(ContactValidationIssue {
-                    contact_id: contact.id.clone(),
-                    issue_type: ContactIssueType::InvalidAddress,
-                    description: format!("Invalid address format: {}", contact.address),
-                    severity: IssueSeverity::High,
-                });
+            for address in &contact.addresses {
+                if !self.is_valid_address(address) {
+                    issues.push(ContactValidationIssue {
+                        contact_id: contact.id.clone(),
+                        issue_type: ContactIssueType::InvalidAddress,
+                        description: format!("Invalid address format: {}", address),
+                        severity: IssueSeverity::High,
+                    });
+                }
             }
         }
 
@@ -317,28 +539,36 @@ impl ContactManager {
     }
 
     // Вспомогательный метод для проверки валидности адреса
-    fn is_valid_address(&self, address: &str) -> bool {
-        if address.is_empty() {
-            return false;
-        }
-
-        if !address.contains(':') {
-            return false;
-        }
-
-        let parts: Vec<&str> = address.split(':').collect();
-        if parts.len() != 2 {
-            return false;
+    fn is_valid_address(&self, address: &Endpoint) -> bool {
+        match address {
+            Endpoint::Tcp(address) | Endpoint::Quic(address) => {
+                address.port() != 0 && !address.ip().is_unspecified()
+            }
+            Endpoint::Tor { host, port } => !host.is_empty() && *port != 0,
+            Endpoint::Memory(name) => !name.is_empty(),
         }
-
-        parts[1].parse::<u16>().is_ok()
     }
 
+    /// Writes the contact book, blocks and favourites included, to disk.
     pub async fn save_contacts(&self) -> Result<(), ContactError> {
-        Ok(())
-    }
-
-    pub async fn load_contacts(&self) -> Result<(), ContactError> {
+        let content = serde_json::to_string_pretty(&self.contact_book)
+            .map_err(|e| ContactError::SerializationError(e.to_string()))?;
+        tokio::fs::write(self.data_path.join(CONTACT_BOOK_FILE), content)
+            .await
+            .map_err(|e| ContactError::IoError(e.to_string()))
+    }
+
+    /// Replaces the contact book in memory with the one on disk, if any.
+    pub async fn load_contacts(&mut self) -> Result<(), ContactError> {
+        let path = self.data_path.join(CONTACT_BOOK_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| ContactError::IoError(e.to_string()))?;
+        self.contact_book = serde_json::from_str(&content)
+            .map_err(|e| ContactError::SerializationError(e.to_string()))?;
         Ok(())
     }
 }
@@ -348,6 +578,7 @@ impl ContactBook {
         Self {
             contacts: HashMap::new(),
             blocked_contacts: HashMap::new(),
+            favorites: HashSet::new(),
         }
     }
 
@@ -366,6 +597,7 @@ impl ContactBook {
 
         self.contacts.remove(contact_id);
         self.blocked_contacts.remove(contact_id);
+        self.favorites.remove(contact_id);
         Ok(())
     }
 
@@ -412,6 +644,22 @@ impl ContactBook {
         Ok(())
     }
 
+    /// Records a block made automatically. A block already in place keeps
+    /// its reason.
+    pub fn block_offender(&mut self, peer_id: &str, reason: &str) {
+        if self.is_blocked(peer_id) {
+            return;
+        }
+        self.blocked_contacts.insert(
+            peer_id.to_string(),
+            BlockedContactInfo {
+                blocked_at: chrono::Utc::now(),
+                reason: reason.to_string(),
+                blocked_by_user: false,
+            },
+        );
+    }
+
     pub fn unblock_contact(&mut self, contact_id: &str) -> Result<(), ContactError> {
         if self.blocked_contacts.remove(contact_id).is_some() {
             Ok(())
@@ -444,7 +692,12 @@ impl ContactBook {
         let address_lower = address.to_lowercase();
         self.contacts
             .values()
-            .filter(|contact| contact.address.to_lowercase().contains(&address_lower))
+            .filter(|contact| {
+                contact
+                    .addresses
+                    .iter()
+                    .any(|a| a.to_string().to_lowercase().contains(&address_lower))
+            })
             .cloned()
             .collect()
     }
@@ -463,6 +716,8 @@ pub fn generate_sg_link(peer: &crate::core::Peer) -> Result<String, ContactError
         last_seen: chrono::Utc::now(),
         bytes_sent: 0,
         bytes_received: 0,
+        onion_address: peer.onion_address.clone(),
+        latency: None,
     };
 
     let json_data = serde_json::to_string(&peer_data)
@@ -500,13 +755,20 @@ pub fn parse_sg_link(sg_link: &str, current_peer_name: &str) -> Result<Contact,
         ));
     }
 
+    // A peer that publishes an onion service wants to be reached through it
+    let address = peer_data.onion_address.unwrap_or(peer_data.address);
+    let address = address
+        .parse()
+        .map_err(|e: TransportError| ContactError::InvalidContact(e.to_string()))?;
     let contact = Contact {
         id: peer_data.id,
         name: peer_data.name,
-        address: peer_data.address,
+        addresses: vec![address],
+        public_key: peer_data.public_key,
         status: ContactStatus::Offline,
         trust_level: TrustLevel::Pending,
         last_seen: Some(peer_data.last_seen),
     };
 
     Ok(contact)
+}
@@ -9,6 +9,7 @@ pub use crate::network::{Contact, ContactStatus, TrustLevel};
 #[derive(Debug)]
 pub enum ContactError {
     InvalidContact(String),
+    InvalidTrustLevel(String),
     ContactNotFound(String),
     ContactExists(String),
     SerializationError(String),
@@ -19,6 +20,7 @@ impl fmt::Display for ContactError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ContactError::InvalidContact(msg) => write!(f, "Invalid contact: {}", msg),
+            ContactError::InvalidTrustLevel(msg) => write!(f, "Invalid trust level: {}", msg),
             ContactError::ContactNotFound(msg) => write!(f, "Contact not found: {}", msg),
             ContactError::ContactExists(msg) => write!(f, "Contact exists: {}", msg),
             ContactError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
@@ -169,11 +171,3 @@ impl ContactGroup {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ContactStats {
-    pub total_contacts: usize,
-    pub online_contacts: usize,
-    pub trusted_contacts: usize,
-    pub blocked_contacts: usize,
-    pub pending_contacts: usize,
-}
@@ -1,8 +1,12 @@
+use crate::core::metrics::MetricsCollector;
 use crate::core::types::*;
 use crate::events::EventBus;
 use crate::{chats, contacts, crypto, network, storage};
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
 pub static ENGINE: OnceLock<Engine> = OnceLock::new();
 
@@ -10,12 +14,21 @@ pub struct Engine {
     profile: Profile,
     profile_path: PathBuf,
     chats_manager: chats::Manager,
-    contacts_manager: contacts::ContactManager,
-    network_manager: network::NetworkManager,
+    contacts_manager: Arc<RwLock<contacts::ContactManager>>,
+    network_manager: Arc<network::NetworkManager>,
     crypto_manager: crypto::SecurityManager,
-    storage_manager: storage::StorageManager,
+    storage_manager: Arc<RwLock<storage::StorageManager>>,
     config: Config,
     event_bus: EventBus,
+    presence_listener: Option<JoinHandle<()>>,
+    metrics: Arc<RwLock<MetricsCollector>>,
+    metrics_listener: Option<JoinHandle<()>>,
+    dht: Option<network::DhtNode>,
+    discovery: Option<network::NetworkDiscovery>,
+    tor_dialer: Option<network::Socks5Dialer>,
+    onion: Option<network::OnionService>,
+    supervisor: Option<network::ConnectionSupervisor>,
+    acceptor: Option<network::ConnectionAcceptor>,
 }
 
 impl Engine {
@@ -23,38 +36,51 @@ impl Engine {
         let config = Config::load(&profile_path).map_err(|e| CoreError::Config(e))?;
         let event_bus = EventBus::new();
 
-        let storage_manager_for_chats =
-            storage::StorageManager::new(&profile_path, event_bus.clone())
-                .map_err(|e| CoreError::Manager(e.to_string()))?;
-
         let storage_manager = storage::StorageManager::new(&profile_path, event_bus.clone())
             .map_err(|e| CoreError::Manager(e.to_string()))?;
+        let storage_manager = Arc::new(RwLock::new(storage_manager));
 
         let crypto_manager = crypto::SecurityManager::new(config.clone(), event_bus.clone())
             .map_err(|e| CoreError::Manager(e))?;
 
-        let network_manager = network::NetworkManager::new_default()
+        let local_peer = crate::core::Peer::new(
+            config.user_name.clone(),
+            format!("0.0.0.0:{}", config.network.port),
+        );
+        let network_manager = network::NetworkManager::new(local_peer, event_bus.clone())
             .map_err(|e| CoreError::Manager(e.to_string()))?;
 
         let contacts_manager = contacts::ContactManager::new(&profile_path)
             .map_err(|e| CoreError::Manager(e.to_string()))?;
+        let contacts_manager = Arc::new(RwLock::new(contacts_manager));
 
         let chats_manager = chats::Manager::new(
-            std::sync::Arc::new(tokio::sync::RwLock::new(storage_manager_for_chats)),
+            storage_manager.clone(),
             event_bus.clone(),
         )
         .map_err(|e| CoreError::Manager(e))?;
 
+        let metrics = Arc::new(RwLock::new(MetricsCollector::new(config.clone())));
+
         Ok(Self {
             profile,
             profile_path,
             chats_manager,
             contacts_manager,
-            network_manager,
+            network_manager: Arc::new(network_manager),
             crypto_manager,
             storage_manager,
             config,
             event_bus,
+            presence_listener: None,
+            metrics,
+            metrics_listener: None,
+            dht: None,
+            discovery: None,
+            tor_dialer: None,
+            onion: None,
+            supervisor: None,
+            acceptor: None,
         })
     }
 
@@ -65,6 +91,8 @@ impl Engine {
             .map_err(|e| CoreError::Config(e))?;
 
         self.storage_manager
+            .read()
+            .await
             .initialize()
             .await
             .map_err(|e| CoreError::Initialization(e))?;
@@ -74,25 +102,348 @@ impl Engine {
             .await
             .map_err(|e| CoreError::Initialization(e))?;
 
+        self.chats_manager.set_local_user(user_name);
+        let peer_id = self.network_manager.get_peer().await.id;
+        self.chats_manager.set_local_peer_id(&peer_id);
+        self.chats_manager
+            .set_read_receipts_enabled(self.config.privacy.send_read_receipts);
+        self.chats_manager
+            .initialize()
+            .await
+            .map_err(|e| CoreError::Initialization(e.to_string()))?;
+
+        let identity = self.crypto_manager.crypto.clone();
+        let public_key = identity.read().await.get_public_key().key_data;
+        self.network_mut()?.set_identity(identity);
+        self.network_mut()?.set_public_key(public_key);
+        // Contacts connect only once they prove they hold the key we know
+        for contact in self.contacts_manager.read().await.get_contacts() {
+            self.network_manager
+                .set_peer_key(&contact.id, contact.public_key)
+                .await;
+        }
+        if !self.config.network.mailbox_recipients.is_empty() {
+            let mut mailbox = network::Mailbox::open(
+                &self.profile_path.join("mailbox.json"),
+                network::MailboxConfig::default(),
+            )
+            .map_err(|e| CoreError::Initialization(e.to_string()))?;
+            let recipients = &self.config.network.mailbox_recipients;
+            for recipient in mailbox.served_recipients() {
+                if !recipients.contains(&recipient) {
+                    mailbox
+                        .stop_serving(&recipient)
+                        .map_err(|e| CoreError::Initialization(e.to_string()))?;
+                }
+            }
+            for recipient in recipients {
+                mailbox
+                    .serve(recipient)
+                    .map_err(|e| CoreError::Initialization(e.to_string()))?;
+            }
+            self.network_mut()?.host_mailbox(mailbox);
+        }
         self.network_manager
+            .set_mailboxes(self.config.network.mailboxes.clone())
+            .await;
+
+        let mut blocked = self.crypto_manager.get_blocked_peers();
+        blocked.extend(self.contacts_manager.read().await.blocked_ids());
+        for peer_id in blocked {
+            self.network_manager.block_peer(&peer_id).await;
+        }
+
+        let mut masking = network::TlsMasking::new();
+        if self.config.network.tls_masking {
+            masking
+                .enable()
+                .map_err(|e| CoreError::Initialization(e.to_string()))?;
+        }
+        let obfuscator = network::TrafficObfuscator::new(self.config.network.obfuscation_config())
+            .with_tls_masking(masking);
+        self.network_mut()?.set_obfuscation(obfuscator);
+
+        self.network_mut()?
             .start()
             .map_err(|e| CoreError::Initialization(e.to_string()))?;
+        self.network_mut()?.start_presence_monitor();
+
+        if self.config.network.use_tor {
+            let tor_config = self
+                .config
+                .network
+                .tor_config()
+                .map_err(CoreError::Config)?;
+            self.network_mut()?
+                .register_transport(Arc::new(network::TorTransport::new(
+                    network::Socks5Dialer::new(tor_config.clone()),
+                )));
+            self.tor_dialer = Some(network::Socks5Dialer::new(tor_config));
+        }
+        if self.config.network.publish_onion {
+            self.publish_onion().await?;
+        }
+
+        self.presence_listener = Some(contacts::ContactManager::spawn_presence_listener(
+            self.contacts_manager.clone(),
+            self.network_manager.clone(),
+            self.event_bus.clone(),
+        ));
+        self.metrics_listener = Some(MetricsCollector::spawn_event_listener(
+            self.metrics.clone(),
+            self.network_manager.clone(),
+            self.event_bus.clone(),
+        ));
+
+        if self.config.network.enable_dht && !self.config.network.use_tor {
+            self.start_dht().await?;
+        }
+        if self.config.network.enable_discovery && !self.config.network.use_tor {
+            self.start_discovery().await?;
+        }
+
+        self.start_acceptor().await?;
+
+        let mut supervisor = network::ConnectionSupervisor::new(
+            self.network_manager.clone(),
+            self.contacts_manager.clone(),
+            self.event_bus.clone(),
+            network::SupervisorConfig {
+                max_peers: self.config.network.max_peers,
+                ..network::SupervisorConfig::default()
+            },
+        );
+        supervisor.start();
+        self.supervisor = Some(supervisor);
+
+        Ok(())
+    }
+
+    /// The network manager for setting up, which is only possible while
+    /// the presence listener and connection supervisor are not running.
+    fn network_mut(&mut self) -> Result<&mut network::NetworkManager, CoreError> {
+        Arc::get_mut(&mut self.network_manager)
+            .ok_or_else(|| CoreError::Manager("Network manager is in use".to_string()))
+    }
+
+    /// Publishes our listening port as an onion service. The key is kept in
+    /// the profile so the address survives restarts.
+    async fn publish_onion(&mut self) -> Result<(), CoreError> {
+        let tor_config = self
+            .config
+            .network
+            .tor_config()
+            .map_err(CoreError::Config)?;
+        let key_path = self.profile_path.join("onion_key");
+        let saved_key = std::fs::read_to_string(&key_path).ok();
+
+        let port = self.config.network.port;
+        let onion = network::TorController::connect(&tor_config)
+            .await
+            .map_err(|e| CoreError::Initialization(e.to_string()))?
+            .publish_onion(
+                port,
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                saved_key.as_deref().map(str::trim),
+            )
+            .await
+            .map_err(|e| CoreError::Initialization(e.to_string()))?;
+
+        if saved_key.is_none() {
+            if let Some(key) = onion.private_key() {
+                std::fs::write(&key_path, key)
+                    .map_err(|e| CoreError::Initialization(e.to_string()))?;
+            }
+        }
+
+        self.network_mut()?.set_onion_address(Some(onion.address()));
+        self.onion = Some(onion);
+        Ok(())
+    }
+
+    async fn start_dht(&mut self) -> Result<(), CoreError> {
+        let peer_id = self.network_manager.get_peer().await.id;
+        let mut dht = network::DhtNode::bind(
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.config.network.port),
+            &peer_id,
+            self.crypto_manager.crypto.clone(),
+        )
+        .await
+        .map_err(|e| CoreError::Initialization(e.to_string()))?;
+        dht.start();
+
+        // Without reachable contacts there is nobody to publish to yet
+        let seeds =
+            network::bootstrap_addresses(&self.contacts_manager.read().await.get_contacts());
+        if dht.bootstrap(&seeds).await.is_ok() {
+            let endpoints = dht
+                .local_endpoints()
+                .map_err(|e| CoreError::Initialization(e.to_string()))?;
+            let _ = dht.publish(endpoints).await;
+        }
+
+        self.dht = Some(dht);
+        Ok(())
+    }
+
+    /// Listens on the configured port for connections peers open to us.
+    /// With Tor only the onion service reaches it, through localhost.
+    async fn start_acceptor(&mut self) -> Result<(), CoreError> {
+        let host = if self.config.network.use_tor {
+            Ipv4Addr::LOCALHOST
+        } else {
+            Ipv4Addr::UNSPECIFIED
+        };
+        let endpoint = network::Endpoint::Tcp(SocketAddr::new(
+            host.into(),
+            self.config.network.port,
+        ));
+        let listener = self
+            .network_manager
+            .listen(&endpoint)
+            .await
+            .map_err(|e| CoreError::Initialization(e.to_string()))?;
+
+        self.acceptor = Some(network::ConnectionAcceptor::start(
+            self.network_manager.clone(),
+            listener,
+        ));
+        Ok(())
+    }
+
+    async fn start_discovery(&mut self) -> Result<(), CoreError> {
+        let peer_id = self.network_manager.get_peer().await.id;
+        let mut discovery = network::NetworkDiscovery::new(
+            self.config.network.port,
+            peer_id,
+            self.config.user_name.clone(),
+            self.crypto_manager.crypto.clone(),
+        )
+        .with_config(self.config.network.discovery_config())
+        .with_event_bus(self.event_bus.clone());
+        // Announcements for a contact have to be signed with the key we know
+        for contact in self.contacts_manager.read().await.get_contacts() {
+            if !contact.public_key.is_empty() {
+                discovery
+                    .set_known_key(&contact.id, contact.public_key)
+                    .await;
+            }
+        }
+        discovery
+            .start_discovery()
+            .await
+            .map_err(|e| CoreError::Initialization(e.to_string()))?;
 
+        self.discovery = Some(discovery);
         Ok(())
     }
 
+    /// Stops the background services, then the network manager. When the
+    /// network manager is still shared, everything else is stopped anyway
+    /// and the error reports that the network was left running.
     pub async fn shutdown(&mut self) -> Result<(), CoreError> {
-        self.network_manager
+        if let Some(mut supervisor) = self.supervisor.take() {
+            supervisor.stop().await;
+        }
+        if let Some(mut acceptor) = self.acceptor.take() {
+            acceptor.stop().await;
+        }
+        if let Some(listener) = self.presence_listener.take() {
+            listener.abort();
+            let _ = listener.await;
+        }
+        if let Some(listener) = self.metrics_listener.take() {
+            listener.abort();
+            let _ = listener.await;
+        }
+        if let Some(mut dht) = self.dht.take() {
+            dht.stop().await;
+        }
+        if let Some(mut discovery) = self.discovery.take() {
+            discovery.stop_discovery().await;
+        }
+        let onion_removed = match self.onion.take() {
+            Some(onion) => {
+                let _ = onion.remove().await;
+                true
+            }
+            None => false,
+        };
+
+        // Last, so that a network manager still shared elsewhere cannot
+        // keep the services above running
+        let network = self.network_mut()?;
+        if onion_removed {
+            network.set_onion_address(None);
+        }
+        network
             .stop()
+            .map_err(|e| CoreError::Manager(e.to_string()))
+    }
+
+    /// Adds a contact, pinning its key for connections and discovery.
+    /// Adding one again from a fresh SG link replaces it, which is how a
+    /// contact saved without a key gets one.
+    pub async fn add_contact(&self, contact: network::Contact) -> Result<(), CoreError> {
+        if let Some(discovery) = &self.discovery {
+            if !contact.public_key.is_empty() {
+                discovery
+                    .set_known_key(&contact.id, contact.public_key.clone())
+                    .await;
+            }
+        }
+        self.network_manager
+            .set_peer_key(&contact.id, contact.public_key.clone())
+            .await;
+        let mut contacts = self.contacts_manager.write().await;
+        contacts
+            .add_contact(contact)
             .map_err(|e| CoreError::Manager(e.to_string()))?;
+        contacts
+            .save_contacts()
+            .await
+            .map_err(|e| CoreError::Manager(e.to_string()))
+    }
+
+    /// Removes a contact along with its key, which ends any connection
+    /// it still has.
+    pub async fn remove_contact(&self, contact_id: &str) -> Result<(), CoreError> {
+        {
+            let mut contacts = self.contacts_manager.write().await;
+            contacts
+                .remove_contact(contact_id)
+                .map_err(|e| CoreError::Manager(e.to_string()))?;
+            contacts
+                .save_contacts()
+                .await
+                .map_err(|e| CoreError::Manager(e.to_string()))?;
+        }
+        self.network_manager.remove_peer_key(contact_id).await;
+        self.network_manager.detach_connection(contact_id).await;
+        if let Some(discovery) = &self.discovery {
+            discovery.remove_known_key(contact_id).await;
+        }
         Ok(())
     }
 
+    /// Favourites are kept connected whatever their trust. The choice is
+    /// saved with the contact book.
+    pub async fn set_favorite(&self, contact_id: &str, favorite: bool) -> Result<(), CoreError> {
+        let mut contacts = self.contacts_manager.write().await;
+        contacts
+            .set_favorite(contact_id, favorite)
+            .map_err(|e| CoreError::Manager(e.to_string()))?;
+        contacts
+            .save_contacts()
+            .await
+            .map_err(|e| CoreError::Manager(e.to_string()))
+    }
+
     pub fn chats(&self) -> &chats::Manager {
         &self.chats_manager
     }
 
-    pub fn contacts(&self) -> &contacts::ContactManager {
+    pub fn contacts(&self) -> &Arc<RwLock<contacts::ContactManager>> {
         &self.contacts_manager
     }
 
@@ -100,7 +451,30 @@ impl Engine {
         &self.network_manager
     }
 
-    pub fn storage(&self) -> &storage::StorageManager {
+    /// Kept current from network events, including each peer's latency.
+    pub fn metrics(&self) -> &Arc<RwLock<MetricsCollector>> {
+        &self.metrics
+    }
+
+    pub fn dht(&self) -> Option<&network::DhtNode> {
+        self.dht.as_ref()
+    }
+
+    /// Running unless discovery is turned off or peers go through Tor.
+    pub fn discovery(&self) -> Option<&network::NetworkDiscovery> {
+        self.discovery.as_ref()
+    }
+
+    /// Set when peers are to be dialed through Tor.
+    pub fn tor_dialer(&self) -> Option<&network::Socks5Dialer> {
+        self.tor_dialer.as_ref()
+    }
+
+    pub fn onion_address(&self) -> Option<String> {
+        self.onion.as_ref().map(|onion| onion.address())
+    }
+
+    pub fn storage(&self) -> &Arc<RwLock<storage::StorageManager>> {
         &self.storage_manager
     }
 
@@ -133,6 +507,9 @@ impl Engine {
             msg_type,
             timestamp: Self::get_current_timestamp(),
             delivery_status: crate::network::DeliveryStatus::Pending,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: 0,
         }
     }
 }
\ No newline at end of file
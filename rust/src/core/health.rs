@@ -1,4 +1,4 @@
-use crate::core::config::Config;
+use crate::core::types::Config;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -1,7 +1,12 @@
 use crate::core::types::Config;
+use crate::events::{AppEvent, EventBus, NetworkEvent};
+use crate::network::{ContactStatus, LatencyStats, NetworkManager};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SystemMetrics {
@@ -14,6 +19,9 @@ pub struct SystemMetrics {
     pub error_count: u32,
     pub uptime_seconds: u64,
     pub timestamp: u64,
+    /// Latency to each connected peer, from keepalive pings.
+    #[serde(default)]
+    pub peer_latency: HashMap<String, LatencyStats>,
 }
 
 impl Default for SystemMetrics {
@@ -31,6 +39,7 @@ impl Default for SystemMetrics {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            peer_latency: HashMap::new(),
         }
     }
 }
@@ -105,6 +114,7 @@ impl MetricsCollector {
             error_count: self.current_metrics.error_count,
             uptime_seconds: uptime,
             timestamp,
+            peer_latency: std::mem::take(&mut self.current_metrics.peer_latency),
         };
 
         self.record_metric("cpu_usage", self.current_metrics.cpu_usage);
@@ -163,6 +173,67 @@ impl MetricsCollector {
         self.current_metrics.active_connections = count;
     }
 
+    pub fn record_peer_latency(&mut self, peer_id: &str, latency: LatencyStats) {
+        self.record_metric(&format!("rtt_ms:{}", peer_id), latency.last_rtt_ms as f64);
+        self.current_metrics
+            .peer_latency
+            .insert(peer_id.to_string(), latency);
+    }
+
+    pub fn forget_peer_latency(&mut self, peer_id: &str) {
+        self.current_metrics.peer_latency.remove(peer_id);
+    }
+
+    /// Keeps peer latency current from the network manager's events.
+    pub fn handle_network_event(&mut self, event: &NetworkEvent) {
+        match event {
+            NetworkEvent::LatencyMeasured {
+                peer_id, latency, ..
+            } => self.record_peer_latency(peer_id, latency.clone()),
+            NetworkEvent::ConnectionTimedOut { peer_id, .. }
+            | NetworkEvent::PresenceChanged {
+                peer_id,
+                status: ContactStatus::Offline,
+            } => self.forget_peer_latency(peer_id),
+            _ => {}
+        }
+    }
+
+    /// Feeds the network manager's events into `metrics`, along with the
+    /// number of peers connected after each of them.
+    pub fn spawn_event_listener(
+        metrics: Arc<RwLock<MetricsCollector>>,
+        network: Arc<NetworkManager>,
+        event_bus: EventBus,
+    ) -> JoinHandle<()> {
+        let mut receiver = event_bus.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(AppEvent::Network(event)) => {
+                        let mut metrics = metrics.write().await;
+                        metrics.handle_network_event(&event);
+                        metrics.set_active_connections(network.get_peer_count() as u32);
+                    }
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Mean of the smoothed round-trip times of all measured peers.
+    pub fn average_rtt_ms(&self) -> Option<f64> {
+        let latencies = &self.current_metrics.peer_latency;
+        if latencies.is_empty() {
+            return None;
+        }
+        let total: f64 = latencies.values().map(|l| l.smoothed_rtt_ms).sum();
+        Some(total / latencies.len() as f64)
+    }
+
     pub fn is_running(&self) -> bool {
         self.is_running
     }
@@ -8,6 +8,9 @@ pub struct Peer {
     pub address: String,
     pub public_key: Vec<u8>,
     pub port: u16,
+    /// Set while an onion service for this node is published.
+    #[serde(default)]
+    pub onion_address: Option<String>,
 }
 
 impl Peer {
@@ -19,6 +22,7 @@ impl Peer {
             address: host,
             public_key: vec![],
             port,
+            onion_address: None,
         }
     }
 
@@ -38,6 +42,7 @@ impl Peer {
             address: host,
             public_key: vec![],
             port,
+            onion_address: None,
         }
     }
 
@@ -48,6 +53,7 @@ impl Peer {
             address,
             public_key: vec![],
             port,
+            onion_address: None,
         }
     }
 
@@ -64,6 +70,10 @@ impl Peer {
         self.public_key = key;
     }
 
+    pub fn set_onion_address(&mut self, onion_address: Option<String>) {
+        self.onion_address = onion_address;
+    }
+
     pub fn get_short_id(&self) -> String {
         if self.id.len() > 8 {
             self.id[..8].to_string()
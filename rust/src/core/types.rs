@@ -1,3 +1,7 @@
+use crate::network::{
+    DiscoveryConfig, ObfuscationConfig, TorConfig, DEFAULT_ANNOUNCE_INTERVAL,
+    DEFAULT_DISCOVERY_PORT,
+};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::path::PathBuf;
@@ -11,6 +15,8 @@ pub struct Config {
     pub profile_id: String,
     pub network: NetworkConfig,
     pub storage: StorageConfig,
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +24,122 @@ pub struct NetworkConfig {
     pub port: u16,
     pub max_peers: usize,
     pub enable_discovery: bool,
+    #[serde(default = "default_discovery_port")]
+    pub discovery_port: u16,
+    #[serde(default = "default_discovery_interval")]
+    pub discovery_interval_secs: u64,
+    #[serde(default = "default_true")]
+    pub enable_ipv6_discovery: bool,
+    #[serde(default = "default_true")]
+    pub enable_mdns: bool,
+    /// Opt-in: look up the external IP through public HTTP services, which
+    /// reveals it to them. Reflexive discovery through peers is used otherwise.
+    #[serde(default)]
+    pub allow_http_ip_lookup: bool,
+    /// Always-on trusted peers that keep our mail while we are offline.
+    #[serde(default)]
+    pub mailboxes: Vec<String>,
+    /// Peers we keep mail for while they are offline.
+    #[serde(default)]
+    pub mailbox_recipients: Vec<String>,
+    /// Publish our endpoints in the peer DHT and find contacts through it
+    /// when their address changes.
+    #[serde(default)]
+    pub enable_dht: bool,
+    /// Frame traffic as TLS application data.
+    #[serde(default)]
+    pub tls_masking: bool,
+    /// Pad frames to fixed sizes and batch outgoing messages with a random
+    /// delay, hiding message sizes and timing.
+    #[serde(default)]
+    pub traffic_obfuscation: bool,
+    /// Keep sending frames at a constant rate while connected, idle or not.
+    /// Costs bandwidth even when nothing is said.
+    #[serde(default)]
+    pub cover_traffic: bool,
+    /// Dial peers through Tor's SOCKS port. UDP features such as the DHT
+    /// cannot go through Tor and stay off.
+    #[serde(default)]
+    pub use_tor: bool,
+    #[serde(default = "default_tor_socks_proxy")]
+    pub tor_socks_proxy: String,
+    #[serde(default = "default_tor_control_address")]
+    pub tor_control_address: String,
+    #[serde(default)]
+    pub tor_control_password: Option<String>,
+    /// Publish this node as an onion service and put the address in our
+    /// SG link.
+    #[serde(default)]
+    pub publish_onion: bool,
+}
+
+fn default_discovery_port() -> u16 {
+    DEFAULT_DISCOVERY_PORT
+}
+
+fn default_discovery_interval() -> u64 {
+    DEFAULT_ANNOUNCE_INTERVAL.as_secs()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_tor_socks_proxy() -> String {
+    "127.0.0.1:9050".to_string()
+}
+
+fn default_tor_control_address() -> String {
+    "127.0.0.1:9051".to_string()
+}
+
+impl NetworkConfig {
+    pub fn discovery_config(&self) -> DiscoveryConfig {
+        let announce_interval = Duration::from_secs(self.discovery_interval_secs.max(1));
+        DiscoveryConfig {
+            port: self.discovery_port,
+            announce_interval,
+            peer_timeout: announce_interval * 3,
+            enable_ipv6: self.enable_ipv6_discovery,
+            enable_mdns: self.enable_mdns,
+            allow_http_ip_lookup: self.allow_http_ip_lookup,
+            ..DiscoveryConfig::default()
+        }
+    }
+
+    pub fn tor_config(&self) -> Result<TorConfig, String> {
+        let socks_proxy = self
+            .tor_socks_proxy
+            .parse()
+            .map_err(|e| format!("Invalid Tor SOCKS address: {}", e))?;
+        let control_address = self
+            .tor_control_address
+            .parse()
+            .map_err(|e| format!("Invalid Tor control address: {}", e))?;
+        Ok(TorConfig {
+            socks_proxy,
+            control_address,
+            control_password: self.tor_control_password.clone(),
+            ..TorConfig::default()
+        })
+    }
+
+    pub fn obfuscation_config(&self) -> ObfuscationConfig {
+        if !self.traffic_obfuscation && !self.cover_traffic {
+            return ObfuscationConfig::disabled();
+        }
+        let defaults = ObfuscationConfig::default();
+        ObfuscationConfig {
+            padding: true,
+            cover_traffic: self.cover_traffic,
+            batch_jitter: if self.traffic_obfuscation {
+                defaults.batch_jitter
+            } else {
+                Duration::ZERO
+            },
+            ..defaults
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +148,19 @@ pub struct StorageConfig {
     pub enable_encryption: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrivacyConfig {
+    pub send_read_receipts: bool,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            send_read_receipts: true,
+        }
+    }
+}
+
 impl Config {
     pub fn load(profile_path: &PathBuf) -> Result<Self, String> {
         let config_file = profile_path.join("config.toml");
@@ -53,11 +188,28 @@ impl Config {
                 port: 8080 + rand::random::<u16>() % 1000, // Random port 8080-9080
                 max_peers: 50,
                 enable_discovery: true,
+                discovery_port: default_discovery_port(),
+                discovery_interval_secs: default_discovery_interval(),
+                enable_ipv6_discovery: default_true(),
+                enable_mdns: default_true(),
+                allow_http_ip_lookup: false,
+                mailboxes: Vec::new(),
+                mailbox_recipients: Vec::new(),
+                enable_dht: false,
+                tls_masking: false,
+                traffic_obfuscation: false,
+                cover_traffic: false,
+                use_tor: false,
+                tor_socks_proxy: default_tor_socks_proxy(),
+                tor_control_address: default_tor_control_address(),
+                tor_control_password: None,
+                publish_onion: false,
             },
             storage: StorageConfig {
                 data_path: profile_path.clone(),
                 enable_encryption: true,
             },
+            privacy: PrivacyConfig::default(),
         }
     }
 }
@@ -64,13 +64,13 @@ pub async fn is_peer_trusted(peer_id: String) -> Result<bool, String> {
 
 #[frb]
 pub async fn block_peer(peer_id: String) -> Result<(), String> {
-    let _engine = ENGINE.get().ok_or("Engine not initialized")?;
-    // This would need mutable access to engine
-    Err("Not implemented - needs mutable engine access".to_string())
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.network().block_peer(&peer_id).await;
+    Ok(())
 }
 
 #[frb]
 pub async fn is_peer_blocked(peer_id: String) -> Result<bool, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    Ok(engine.crypto().is_peer_blocked(&peer_id))
+    Ok(engine.crypto().is_peer_blocked(&peer_id) || engine.network().is_peer_blocked(&peer_id))
 }
@@ -1,11 +1,16 @@
 use crate::core::types::Config;
 use crate::crypto::types::*;
 use crate::events::types::{AppEvent, CryptoEvent, EventBus};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 
 #[derive(Debug)]
 pub enum CryptoError {
@@ -43,15 +48,13 @@ impl CryptoManager {
         Ok(manager)
     }
 
+    /// Generates a fresh Ed25519 identity key used for signing.
     pub fn generate_keypair(&mut self) -> Result<(), CryptoError> {
-        // Для демонстрации создаем фиктивные ключи
-        // В реальной реализации здесь должна быть настоящая криптография
-        let private_key_data = (0..32).collect::<Vec<u8>>();
-        let public_key_data = (32..64).collect::<Vec<u8>>();
+        let signing_key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
 
         let keypair = KeyPair {
-            private_key: PrivateKey::new(private_key_data),
-            public_key: PublicKey::new(public_key_data),
+            private_key: PrivateKey::new(signing_key.to_bytes().to_vec()),
+            public_key: PublicKey::new(signing_key.verifying_key().to_bytes().to_vec()),
         };
 
         self.keypair = Some(keypair);
@@ -94,19 +97,134 @@ impl CryptoManager {
     }
 
     pub fn sign_data(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        // Фиктивная подпись
-        let signature = data.iter().map(|b| b.wrapping_add(1)).collect();
-        Ok(signature)
+        Ok(self.signing_key()?.sign(data).to_bytes().to_vec())
+    }
+
+    fn signing_key(&self) -> Result<SigningKey, CryptoError> {
+        let keypair = self
+            .keypair
+            .as_ref()
+            .ok_or_else(|| CryptoError::SigningFailed("No keypair".to_string()))?;
+        let secret: [u8; 32] = keypair
+            .private_key
+            .key_data
+            .as_slice()
+            .try_into()
+            .map_err(|_| CryptoError::InvalidKey("Ed25519 key must be 32 bytes".to_string()))?;
+        Ok(SigningKey::from_bytes(&secret))
+    }
+
+    /// Encrypts `plaintext` so only the holder of the identity key
+    /// `recipient_key` can read it, signed by us inside the encryption so
+    /// whoever stores it can neither read nor forge it.
+    ///
+    /// Layout: ephemeral X25519 key (32) || ChaCha20-Poly1305 ciphertext of
+    /// sender key (32) || signature (64) || plaintext.
+    pub fn seal_for(&self, recipient_key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let recipient = Self::montgomery_key(recipient_key)?;
+        let signing_key = self.signing_key()?;
+        let signature = signing_key.sign(&Self::sealed_context(recipient_key, plaintext));
+
+        let mut inner = Vec::with_capacity(96 + plaintext.len());
+        inner.extend_from_slice(signing_key.verifying_key().as_bytes());
+        inner.extend_from_slice(&signature.to_bytes());
+        inner.extend_from_slice(plaintext);
+
+        let ephemeral = StaticSecret::from(rand::random::<[u8; 32]>());
+        let ephemeral_public = X25519PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient);
+        let cipher =
+            Self::sealed_cipher(shared.as_bytes(), ephemeral_public.as_bytes(), &recipient);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&[0u8; 12]), inner.as_slice())
+            .map_err(|e| CryptoError::EncryptionFailed(e.to_string()))?;
+
+        let mut sealed = ephemeral_public.as_bytes().to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Opens a message sealed for us, returning the sender's identity key
+    /// and the plaintext once the sender's signature checks out.
+    pub fn open_sealed(&self, sealed: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        if sealed.len() < 32 {
+            return Err(CryptoError::DecryptionFailed(
+                "Sealed message too short".to_string(),
+            ));
+        }
+        let (ephemeral_public, ciphertext) = sealed.split_at(32);
+        let ephemeral_public: [u8; 32] = ephemeral_public.try_into().unwrap();
+
+        let signing_key = self.signing_key()?;
+        let secret = StaticSecret::from(signing_key.to_scalar_bytes());
+        let own_public = X25519PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&X25519PublicKey::from(ephemeral_public));
+        let cipher = Self::sealed_cipher(shared.as_bytes(), &ephemeral_public, &own_public);
+        let inner = cipher
+            .decrypt(Nonce::from_slice(&[0u8; 12]), ciphertext)
+            .map_err(|_| CryptoError::DecryptionFailed("Not sealed for this key".to_string()))?;
+        if inner.len() < 96 {
+            return Err(CryptoError::DecryptionFailed(
+                "Sealed message too short".to_string(),
+            ));
+        }
+
+        let (sender_key, rest) = inner.split_at(32);
+        let (signature, plaintext) = rest.split_at(64);
+        let context = Self::sealed_context(signing_key.verifying_key().as_bytes(), plaintext);
+        if !Self::verify_with_key(sender_key, &context, signature) {
+            return Err(CryptoError::VerificationFailed(
+                "Bad sender signature".to_string(),
+            ));
+        }
+        Ok((sender_key.to_vec(), plaintext.to_vec()))
+    }
+
+    fn montgomery_key(identity_key: &[u8]) -> Result<X25519PublicKey, CryptoError> {
+        let key = VerifyingKey::try_from(identity_key)
+            .map_err(|e| CryptoError::InvalidKey(e.to_string()))?;
+        Ok(X25519PublicKey::from(key.to_montgomery().to_bytes()))
+    }
+
+    fn sealed_context(recipient_key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut context = b"shadowghost-sealed".to_vec();
+        context.extend_from_slice(recipient_key);
+        context.extend_from_slice(plaintext);
+        context
+    }
+
+    fn sealed_cipher(
+        shared: &[u8; 32],
+        ephemeral_public: &[u8; 32],
+        recipient: &X25519PublicKey,
+    ) -> ChaCha20Poly1305 {
+        let mut hasher = Sha256::new();
+        hasher.update(b"shadowghost-sealed-box");
+        hasher.update(shared);
+        hasher.update(ephemeral_public);
+        hasher.update(recipient.as_bytes());
+        ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
     }
 
     pub fn verify_signature(
         &self,
-        _data: &[u8],
-        _signature: &[u8],
-        _public_key: &PublicKey,
+        data: &[u8],
+        signature: &[u8],
+        public_key: &PublicKey,
     ) -> Result<bool, CryptoError> {
-        // Фиктивная проверка подписи
-        Ok(true)
+        Ok(Self::verify_with_key(&public_key.key_data, data, signature))
+    }
+
+    /// Verifies an Ed25519 signature against raw public key bytes. Malformed
+    /// keys or signatures simply fail verification.
+    pub fn verify_with_key(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        match (
+            VerifyingKey::try_from(public_key),
+            Signature::from_slice(signature),
+        ) {
+            (Ok(key), Ok(signature)) => key.verify(data, &signature).is_ok(),
+            _ => false,
+        }
     }
 
     pub fn derive_shared_secret(&self, _other_key: &PublicKey) -> Result<Vec<u8>, CryptoError> {
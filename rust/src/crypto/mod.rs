@@ -1,7 +1,6 @@
 pub mod flutter_api;
 pub mod manager;
 pub mod types;
-pub mod types;
 
 pub use manager::*;
 pub use types::*;
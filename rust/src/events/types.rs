@@ -1,4 +1,6 @@
-use crate::network::{ChatMessage, Contact};
+use crate::network::{
+    Candidate, ChatMessage, Contact, ContactStatus, DeliveryStatus, DiscoveredPeer, LatencyStats,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
 
@@ -9,11 +11,104 @@ pub enum NetworkEvent {
     },
     ServerStopped,
     MessageReceived {
+        peer_id: String,
         message: ChatMessage,
     },
     ContactAdded {
         contact: Contact,
     },
+    ReactionReceived {
+        peer_id: String,
+        message_id: String,
+        emoji: String,
+        added: bool,
+    },
+    PresenceChanged {
+        peer_id: String,
+        status: ContactStatus,
+    },
+    LocalPresenceChanged {
+        status: ContactStatus,
+    },
+    ReceiptReceived {
+        peer_id: String,
+        message_id: String,
+        status: DeliveryStatus,
+    },
+    ChatSettingsReceived {
+        peer_id: String,
+        chat_id: String,
+        disappearing_timer: Option<u64>,
+        changed_at: u64,
+    },
+    TypingChanged {
+        peer_id: String,
+        chat_id: String,
+        is_typing: bool,
+    },
+    ContactStatusChanged {
+        contact: Contact,
+    },
+    PeerDiscovered {
+        peer: DiscoveredPeer,
+    },
+    PeerUpdated {
+        peer: DiscoveredPeer,
+    },
+    PeerLost {
+        peer_id: String,
+    },
+    CandidatesReceived {
+        peer_id: String,
+        session_id: u64,
+        candidates: Vec<Candidate>,
+    },
+    DirectPathEstablished {
+        peer_id: String,
+        address: String,
+    },
+    RelayPathEstablished {
+        peer_id: String,
+        relay: String,
+    },
+    RelayQuotaExceeded {
+        relay: String,
+    },
+    MailboxDeposited {
+        mailbox: String,
+        envelope_id: String,
+    },
+    MailboxRejected {
+        mailbox: String,
+        envelope_id: String,
+        reason: String,
+    },
+    MailboxDelivered {
+        mailbox: String,
+        count: usize,
+    },
+    LatencyMeasured {
+        peer_id: String,
+        sequence: u64,
+        latency: LatencyStats,
+    },
+    ConnectionTimedOut {
+        peer_id: String,
+        missed: u32,
+    },
+    PeerBlocked {
+        peer_id: String,
+        reason: String,
+    },
+    PeerKeyMismatch {
+        peer_id: String,
+        address: String,
+        known_key: Vec<u8>,
+        announced_key: Vec<u8>,
+    },
+    ContactNeedsVerification {
+        peer_id: String,
+    },
     Error {
         error: String,
         context: Option<String>,
@@ -42,6 +137,10 @@ pub enum StorageEvent {
     CleanupCompleted {
         removed_items: usize,
     },
+    MessagesExpired {
+        chat_id: String,
+        message_ids: Vec<String>,
+    },
     BackupCreated {
         file_path: String,
     },
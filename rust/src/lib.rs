@@ -1,5 +1,4 @@
 pub mod chats;
-pub mod chats;
 pub mod contacts;
 pub mod core;
 pub mod crypto;
@@ -0,0 +1,100 @@
+use crate::network::manager::NetworkManager;
+use crate::network::transport::{Connection, Listener};
+use crate::network::types::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+/// How long to wait before accepting again after the listener failed, so
+/// a listener that keeps failing does not spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Takes the connections peers open to us and serves each one once the
+/// peer has proved who it is. Stopping it closes them all.
+pub struct ConnectionAcceptor {
+    local_endpoint: Endpoint,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ConnectionAcceptor {
+    /// Starts accepting on `listener`.
+    pub fn start(network: Arc<NetworkManager>, listener: Box<dyn Listener>) -> Self {
+        let local_endpoint = listener.local_endpoint();
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(Self::run(network, listener, shutdown.clone()));
+        Self {
+            local_endpoint,
+            shutdown,
+            task: Some(task),
+        }
+    }
+
+    pub fn local_endpoint(&self) -> &Endpoint {
+        &self.local_endpoint
+    }
+
+    /// Stops accepting and closes the connections accepted so far.
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    async fn run(
+        network: Arc<NetworkManager>,
+        mut listener: Box<dyn Listener>,
+        shutdown: CancellationToken,
+    ) {
+        let mut connections = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = connections.join_next() => {}
+                accepted = listener.accept() => match accepted {
+                    Ok(connection) => {
+                        connections.spawn(Self::serve(
+                            network.clone(),
+                            connection,
+                            shutdown.clone(),
+                        ));
+                    }
+                    Err(error) => {
+                        log::warn!("Accepting a connection failed: {}", error);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                },
+            }
+        }
+
+        while connections.join_next().await.is_some() {}
+    }
+
+    /// Serves one accepted connection. On shutdown the peer is detached,
+    /// so the connection winds down the way it does when the peer leaves.
+    async fn serve(
+        network: Arc<NetworkManager>,
+        mut connection: Connection,
+        shutdown: CancellationToken,
+    ) {
+        let peer_id = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            admitted = network.admit_dialer(&mut connection) => match admitted {
+                Ok(peer_id) => peer_id,
+                Err(_) => return,
+            },
+        };
+
+        let serving = network.serve(&peer_id, connection);
+        tokio::pin!(serving);
+        tokio::select! {
+            _ = &mut serving => return,
+            _ = shutdown.cancelled() => {}
+        }
+        network.detach_connection(&peer_id).await;
+        serving.await;
+    }
+}
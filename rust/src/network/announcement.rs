@@ -0,0 +1,112 @@
+use crate::crypto::{CryptoError, CryptoManager};
+use crate::network::types::AnnouncementMessage;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum clock difference, in seconds, between an announcement's timestamp
+/// and local time. Older (or future-dated) announcements are dropped.
+pub const MAX_ANNOUNCEMENT_AGE: u64 = 60;
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnouncementRejection {
+    Stale,
+    Replayed,
+    RateLimited,
+    BadSignature,
+    KeyMismatch,
+    PeerLimitReached,
+}
+
+impl fmt::Display for AnnouncementRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnouncementRejection::Stale => write!(f, "Announcement timestamp out of range"),
+            AnnouncementRejection::Replayed => write!(f, "Announcement nonce already seen"),
+            AnnouncementRejection::RateLimited => write!(f, "Too many announcements from source"),
+            AnnouncementRejection::BadSignature => write!(f, "Announcement signature invalid"),
+            AnnouncementRejection::KeyMismatch => write!(f, "Announcement key does not match peer"),
+            AnnouncementRejection::PeerLimitReached => write!(f, "Discovered peer limit reached"),
+        }
+    }
+}
+
+impl std::error::Error for AnnouncementRejection {}
+
+/// Freshness, replay and per-source rate checks for incoming announcements.
+pub struct AnnouncementGuard {
+    max_per_ip: u32,
+    seen_nonces: HashMap<(String, u64), u64>,
+    rate: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl AnnouncementGuard {
+    pub fn new(max_per_ip: u32) -> Self {
+        Self {
+            max_per_ip,
+            seen_nonces: HashMap::new(),
+            rate: HashMap::new(),
+        }
+    }
+
+    /// Accepts or rejects an announcement received from `from` at `now`
+    /// (seconds since the epoch). Cheap checks run first so that floods of
+    /// forged announcements are dropped before signature verification.
+    pub fn check(
+        &mut self,
+        announcement: &AnnouncementMessage,
+        from: IpAddr,
+        now: u64,
+    ) -> Result<(), AnnouncementRejection> {
+        self.prune(now);
+
+        if now.abs_diff(announcement.timestamp) > MAX_ANNOUNCEMENT_AGE {
+            return Err(AnnouncementRejection::Stale);
+        }
+
+        let key = (announcement.peer_id.clone(), announcement.nonce);
+        if self.seen_nonces.contains_key(&key) {
+            return Err(AnnouncementRejection::Replayed);
+        }
+
+        let (_, count) = self.rate.entry(from).or_insert((Instant::now(), 0));
+        *count += 1;
+        if *count > self.max_per_ip {
+            return Err(AnnouncementRejection::RateLimited);
+        }
+
+        if !verify_announcement(announcement) {
+            return Err(AnnouncementRejection::BadSignature);
+        }
+
+        self.seen_nonces.insert(key, announcement.timestamp);
+        Ok(())
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.seen_nonces
+            .retain(|_, timestamp| now.abs_diff(*timestamp) <= MAX_ANNOUNCEMENT_AGE);
+        self.rate
+            .retain(|_, (started, _)| started.elapsed() < RATE_LIMIT_WINDOW);
+    }
+}
+
+pub fn sign_announcement(
+    crypto: &CryptoManager,
+    announcement: &mut AnnouncementMessage,
+) -> Result<(), CryptoError> {
+    announcement.public_key = crypto.get_public_key().key_data;
+    announcement.signature = crypto.sign_data(&announcement.signing_payload())?;
+    Ok(())
+}
+
+/// Checks the signature against the public key the announcement carries.
+pub fn verify_announcement(announcement: &AnnouncementMessage) -> bool {
+    CryptoManager::verify_with_key(
+        &announcement.public_key,
+        &announcement.signing_payload(),
+        &announcement.signature,
+    )
+}
@@ -0,0 +1,208 @@
+use crate::crypto::CryptoManager;
+use crate::network::protocol::{MessagePayload, ProtocolMessage, SealedPayload};
+use crate::network::types::TransportError;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+/// How long either side of a connection waits for the other's next
+/// handshake message.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+pub const NONCE_LEN: usize = 32;
+pub const SHARE_LEN: usize = 32;
+
+/// A fresh nonce for the other side to sign.
+pub fn new_nonce() -> Vec<u8> {
+    rand::random::<[u8; NONCE_LEN]>().to_vec()
+}
+
+/// Everything both sides of a handshake have to agree on: who dialed whom,
+/// and the key shares each of them sent. Both signatures cover it, so a
+/// host in between cannot swap in shares of its own.
+pub struct Transcript<'a> {
+    pub dialer_id: &'a str,
+    pub acceptor_id: &'a str,
+    pub dialer_share: &'a [u8],
+    pub acceptor_share: &'a [u8],
+}
+
+impl Transcript<'_> {
+    fn put(&self, context: &mut Vec<u8>) {
+        for field in [
+            self.dialer_id.as_bytes(),
+            self.acceptor_id.as_bytes(),
+            self.dialer_share,
+            self.acceptor_share,
+        ] {
+            context.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            context.extend_from_slice(field);
+        }
+    }
+}
+
+/// What the side that accepted a connection signs to prove who it is: the
+/// nonce the dialer sent, and the transcript.
+pub fn accept_context(nonce: &[u8], transcript: &Transcript) -> Vec<u8> {
+    context(b"accept", nonce, transcript)
+}
+
+/// What the side that dialed signs to prove who it is: the nonce the other
+/// side sent back, and the transcript.
+pub fn dial_context(nonce: &[u8], transcript: &Transcript) -> Vec<u8> {
+    context(b"dial", nonce, transcript)
+}
+
+/// Checks a handshake signature against the identity key the peer is
+/// known by.
+pub fn verify(public_key: &[u8], context: &[u8], signature: &[u8]) -> bool {
+    CryptoManager::verify_with_key(public_key, context, signature)
+}
+
+fn context(role: &[u8], nonce: &[u8], transcript: &Transcript) -> Vec<u8> {
+    let mut context = b"shadowghost-auth-".to_vec();
+    context.extend_from_slice(role);
+    context.extend_from_slice(nonce);
+    transcript.put(&mut context);
+    context
+}
+
+/// One side's ephemeral X25519 key for a single connection. Its public
+/// half goes out in the hello or the challenge.
+pub struct KeyShare {
+    secret: StaticSecret,
+}
+
+impl KeyShare {
+    pub fn new() -> Self {
+        Self {
+            secret: StaticSecret::from(rand::random::<[u8; 32]>()),
+        }
+    }
+
+    pub fn public(&self) -> Vec<u8> {
+        X25519PublicKey::from(&self.secret).to_bytes().to_vec()
+    }
+
+    /// The connection's keys, agreed with the other side's share from the
+    /// transcript. Each direction gets a key of its own.
+    pub fn session(
+        &self,
+        transcript: &Transcript,
+        dialer: bool,
+    ) -> Result<Session, TransportError> {
+        let theirs = if dialer {
+            transcript.acceptor_share
+        } else {
+            transcript.dialer_share
+        };
+        let theirs: [u8; SHARE_LEN] = theirs
+            .try_into()
+            .map_err(|_| TransportError::Unauthenticated("Bad key share".to_string()))?;
+        let shared = self.secret.diffie_hellman(&X25519PublicKey::from(theirs));
+        if !shared.was_contributory() {
+            return Err(TransportError::Unauthenticated(
+                "Weak key share".to_string(),
+            ));
+        }
+
+        let key = |direction: &[u8]| {
+            let mut context = b"shadowghost-session-key".to_vec();
+            transcript.put(&mut context);
+            context.extend_from_slice(direction);
+
+            let mut hasher = Sha256::new();
+            hasher.update(shared.as_bytes());
+            hasher.update(&context);
+            ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+        };
+        let (send, receive) = if dialer {
+            (key(b"dialer"), key(b"acceptor"))
+        } else {
+            (key(b"acceptor"), key(b"dialer"))
+        };
+        Ok(Session {
+            sealer: Sealer {
+                cipher: send,
+                counter: AtomicU64::new(0),
+            },
+            opener: Opener {
+                cipher: receive,
+                next: 0,
+            },
+        })
+    }
+}
+
+impl Default for KeyShare {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The keys a connection is encrypted with once the handshake is done.
+pub struct Session {
+    pub(crate) sealer: Sealer,
+    pub(crate) opener: Opener,
+}
+
+/// Encrypts outgoing messages, numbering them as it goes.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: AtomicU64,
+}
+
+impl Sealer {
+    pub fn seal(&self, message: &ProtocolMessage) -> Result<ProtocolMessage, TransportError> {
+        let plaintext = message
+            .to_bytes()
+            .map_err(|e| TransportError::Malformed(e.to_string()))?;
+        let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce(counter)), plaintext.as_slice())
+            .expect("ChaCha20-Poly1305 encryption is infallible for in-memory buffers");
+        Ok(ProtocolMessage::create_sealed(SealedPayload {
+            counter,
+            ciphertext,
+        }))
+    }
+}
+
+/// Decrypts incoming messages, which have to arrive in the order they were
+/// sealed in. Anything altered, replayed, dropped or left unsealed fails.
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    next: u64,
+}
+
+impl Opener {
+    pub fn open(&mut self, message: ProtocolMessage) -> Result<ProtocolMessage, TransportError> {
+        let sealed = match message.payload {
+            MessagePayload::Sealed(sealed) if sealed.counter == self.next => sealed,
+            _ => return Err(unsealed()),
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce(sealed.counter)),
+                sealed.ciphertext.as_slice(),
+            )
+            .map_err(|_| unsealed())?;
+        self.next += 1;
+        ProtocolMessage::from_bytes(&plaintext)
+            .map_err(|e| TransportError::Malformed(e.to_string()))
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn unsealed() -> TransportError {
+    TransportError::Unauthenticated("Message not sealed with the session key".to_string())
+}
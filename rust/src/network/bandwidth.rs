@@ -0,0 +1,176 @@
+use crate::network::protocol::{MessagePayload, ProtocolMessage};
+use crate::network::rate_limit::TokenBucket;
+use crate::network::types::BandwidthConfig;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficDirection {
+    Upload,
+    Download,
+}
+
+/// Bytes carried to and from one peer, or all of them, framing included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+}
+
+#[derive(Debug)]
+struct Caps {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Caps {
+    fn new(upload: Option<u64>, download: Option<u64>, now: Instant) -> Self {
+        // A second's worth of burst; anything larger goes into debt
+        let bucket = |limit: Option<u64>| {
+            limit
+                .filter(|limit| *limit > 0)
+                .map(|limit| TokenBucket::new(limit as f64, limit as f64, now))
+        };
+        Self {
+            upload: bucket(upload),
+            download: bucket(download),
+        }
+    }
+
+    fn borrow(&mut self, direction: TrafficDirection, bytes: usize, now: Instant) -> Duration {
+        let bucket = match direction {
+            TrafficDirection::Upload => self.upload.as_mut(),
+            TrafficDirection::Download => self.download.as_mut(),
+        };
+        bucket.map_or(Duration::ZERO, |bucket| bucket.borrow(bytes as f64, now))
+    }
+}
+
+/// Upload and download caps, overall and per peer, and the traffic they
+/// applied to. Traffic is let through and the side moving it told how
+/// long to pause, so the caps hold on average whatever the frame sizes.
+///
+/// While metered, large messages are held back until the link is
+/// unmetered again.
+pub struct BandwidthLimiter {
+    config: BandwidthConfig,
+    metered: bool,
+    total: Caps,
+    peers: HashMap<String, Caps>,
+    traffic: Traffic,
+    peer_traffic: HashMap<String, Traffic>,
+    deferred: Vec<ProtocolMessage>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: BandwidthConfig) -> Self {
+        let total = Caps::new(config.upload_limit, config.download_limit, Instant::now());
+        Self {
+            config,
+            metered: false,
+            total,
+            peers: HashMap::new(),
+            traffic: Traffic::default(),
+            peer_traffic: HashMap::new(),
+            deferred: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &BandwidthConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: BandwidthConfig) {
+        self.total = Caps::new(config.upload_limit, config.download_limit, Instant::now());
+        self.peers.clear();
+        self.config = config;
+    }
+
+    /// Counts `bytes` moved in `direction`, to or from `peer_id` if known,
+    /// and returns how long to wait before moving more.
+    pub fn account(
+        &mut self,
+        peer_id: Option<&str>,
+        direction: TrafficDirection,
+        bytes: usize,
+        now: Instant,
+    ) -> Duration {
+        let mut delay = self.total.borrow(direction, bytes, now);
+        count(&mut self.traffic, direction, bytes);
+
+        if let Some(peer_id) = peer_id {
+            count(
+                self.peer_traffic.entry(peer_id.to_string()).or_default(),
+                direction,
+                bytes,
+            );
+            let config = &self.config;
+            let caps = self.peers.entry(peer_id.to_string()).or_insert_with(|| {
+                Caps::new(config.peer_upload_limit, config.peer_download_limit, now)
+            });
+            delay = delay.max(caps.borrow(direction, bytes, now));
+        }
+        delay
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        self.traffic
+    }
+
+    pub fn peer_traffic(&self, peer_id: &str) -> Traffic {
+        self.peer_traffic.get(peer_id).copied().unwrap_or_default()
+    }
+
+    pub fn reset_traffic(&mut self) {
+        self.traffic = Traffic::default();
+        self.peer_traffic.clear();
+    }
+
+    /// Drops the peer's caps once it is gone. Its traffic is kept.
+    pub fn forget_peer(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+
+    pub fn is_metered(&self) -> bool {
+        self.metered
+    }
+
+    /// Returns the messages held back, once the link is unmetered.
+    pub fn set_metered(&mut self, metered: bool) -> Vec<ProtocolMessage> {
+        self.metered = metered;
+        if metered {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.deferred)
+    }
+
+    /// Holds `message` back if the link is metered and the message is
+    /// large, otherwise hands it back to be sent.
+    pub fn defer(&mut self, message: ProtocolMessage) -> Option<ProtocolMessage> {
+        if !self.metered || !self.is_large(&message) {
+            return Some(message);
+        }
+        self.deferred.push(message);
+        None
+    }
+
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.len()
+    }
+
+    fn is_large(&self, message: &ProtocolMessage) -> bool {
+        if matches!(message.payload, MessagePayload::File(_)) {
+            return true;
+        }
+        message
+            .to_bytes()
+            .is_ok_and(|data| data.len() > self.config.large_message_size)
+    }
+}
+
+fn count(traffic: &mut Traffic, direction: TrafficDirection, bytes: usize) {
+    match direction {
+        TrafficDirection::Upload => traffic.sent += bytes as u64,
+        TrafficDirection::Download => traffic.received += bytes as u64,
+    }
+}
@@ -0,0 +1,949 @@
+use crate::contacts::ContactManager;
+use crate::crypto::CryptoManager;
+use crate::network::hole_punch::{
+    put_addr, put_bytes, put_str, put_u64, Reader, MAX_DATAGRAM_SIZE,
+};
+use crate::network::types::*;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+const PACKET_MAGIC: &[u8; 3] = b"SGD";
+
+/// Endpoints kept per record, so that a record always fits in one datagram.
+pub const MAX_RECORD_ENDPOINTS: usize = 8;
+
+/// Position in the DHT keyspace. Nodes and the records they publish are
+/// both keyed by the SHA-256 of the identity key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 32]);
+
+impl NodeId {
+    pub fn from_public_key(public_key: &[u8]) -> Self {
+        Self(Sha256::digest(public_key).into())
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; 32] {
+        let mut distance = [0u8; 32];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// Number of leading bits shared with `other`, which is the index of
+    /// the bucket it belongs in. `None` for ourselves.
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let mut shared = 0;
+        for byte in self.distance(other) {
+            if byte != 0 {
+                return Some(shared + byte.leading_zeros() as usize);
+            }
+            shared += 8;
+        }
+        None
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0[..8] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+/// Where a peer can currently be reached, signed with its identity key.
+/// Anyone may store or serve a record, but only the key owner can make one,
+/// and a newer `sequence` replaces older ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerRecord {
+    pub public_key: Vec<u8>,
+    pub peer_id: String,
+    pub endpoints: Vec<SocketAddr>,
+    pub sequence: u64,
+    pub expires_at: u64,
+    pub signature: Vec<u8>,
+}
+
+impl PeerRecord {
+    pub fn sign(
+        crypto: &CryptoManager,
+        peer_id: &str,
+        mut endpoints: Vec<SocketAddr>,
+        ttl: Duration,
+    ) -> Result<Self, DhtError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        endpoints.truncate(MAX_RECORD_ENDPOINTS);
+
+        let mut record = Self {
+            public_key: crypto.get_public_key().key_data,
+            peer_id: peer_id.to_string(),
+            endpoints,
+            sequence: now.as_millis() as u64,
+            expires_at: now.as_secs() + ttl.as_secs(),
+            signature: Vec::new(),
+        };
+        record.signature = crypto
+            .sign_data(&record.signing_payload())
+            .map_err(|e| DhtError::Signing(e.to_string()))?;
+        Ok(record)
+    }
+
+    pub fn key(&self) -> NodeId {
+        NodeId::from_public_key(&self.public_key)
+    }
+
+    pub fn verify(&self, now: u64) -> bool {
+        self.expires_at > now
+            && CryptoManager::verify_with_key(
+                &self.public_key,
+                &self.signing_payload(),
+                &self.signature,
+            )
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let mut payload = b"shadowghost-dht-record".to_vec();
+        put_bytes(&mut payload, &self.public_key);
+        put_str(&mut payload, &self.peer_id);
+        put_endpoints(&mut payload, &self.endpoints);
+        put_u64(&mut payload, self.sequence);
+        put_u64(&mut payload, self.expires_at);
+        payload
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        put_bytes(out, &self.public_key);
+        put_str(out, &self.peer_id);
+        put_endpoints(out, &self.endpoints);
+        put_u64(out, self.sequence);
+        put_u64(out, self.expires_at);
+        put_bytes(out, &self.signature);
+    }
+
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            public_key: reader.bytes()?,
+            peer_id: reader.string()?,
+            endpoints: read_endpoints(reader)?,
+            sequence: reader.u64()?,
+            expires_at: reader.u64()?,
+            signature: reader.bytes()?,
+        })
+    }
+}
+
+fn put_endpoints(out: &mut Vec<u8>, endpoints: &[SocketAddr]) {
+    let endpoints = &endpoints[..endpoints.len().min(MAX_RECORD_ENDPOINTS)];
+    out.push(endpoints.len() as u8);
+    for endpoint in endpoints {
+        put_addr(out, endpoint);
+    }
+}
+
+fn read_endpoints(reader: &mut Reader) -> Option<Vec<SocketAddr>> {
+    let count = reader.u8()? as usize;
+    if count > MAX_RECORD_ENDPOINTS {
+        return None;
+    }
+    (0..count).map(|_| reader.addr()).collect()
+}
+
+fn put_node_id(out: &mut Vec<u8>, id: &NodeId) {
+    out.extend_from_slice(&id.0);
+}
+
+fn read_node_id(reader: &mut Reader) -> Option<NodeId> {
+    Some(NodeId(reader.take(32)?.try_into().ok()?))
+}
+
+/// DHT datagrams. Every packet names its sender so the receiver can learn
+/// about it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DhtPacket {
+    Ping {
+        txn: u64,
+        sender: NodeId,
+    },
+    Pong {
+        txn: u64,
+        sender: NodeId,
+    },
+    FindNode {
+        txn: u64,
+        sender: NodeId,
+        target: NodeId,
+    },
+    Nodes {
+        txn: u64,
+        sender: NodeId,
+        nodes: Vec<NodeInfo>,
+    },
+    /// Like `FindNode`, but answered with the record if the node holds it.
+    FindValue {
+        txn: u64,
+        sender: NodeId,
+        key: NodeId,
+    },
+    Value {
+        txn: u64,
+        sender: NodeId,
+        record: PeerRecord,
+    },
+    Store {
+        txn: u64,
+        sender: NodeId,
+        record: PeerRecord,
+    },
+    Stored {
+        txn: u64,
+        sender: NodeId,
+        accepted: bool,
+    },
+}
+
+impl DhtPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = PACKET_MAGIC.to_vec();
+        match self {
+            DhtPacket::Ping { txn, sender } => {
+                out.push(0);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+            }
+            DhtPacket::Pong { txn, sender } => {
+                out.push(1);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+            }
+            DhtPacket::FindNode {
+                txn,
+                sender,
+                target,
+            } => {
+                out.push(2);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+                put_node_id(&mut out, target);
+            }
+            DhtPacket::Nodes { txn, sender, nodes } => {
+                out.push(3);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+                let nodes = &nodes[..nodes.len().min(u8::MAX as usize)];
+                out.push(nodes.len() as u8);
+                for node in nodes {
+                    put_node_id(&mut out, &node.id);
+                    put_addr(&mut out, &node.address);
+                }
+            }
+            DhtPacket::FindValue { txn, sender, key } => {
+                out.push(4);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+                put_node_id(&mut out, key);
+            }
+            DhtPacket::Value {
+                txn,
+                sender,
+                record,
+            } => {
+                out.push(5);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+                record.encode_into(&mut out);
+            }
+            DhtPacket::Store {
+                txn,
+                sender,
+                record,
+            } => {
+                out.push(6);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+                record.encode_into(&mut out);
+            }
+            DhtPacket::Stored {
+                txn,
+                sender,
+                accepted,
+            } => {
+                out.push(7);
+                put_u64(&mut out, *txn);
+                put_node_id(&mut out, sender);
+                out.push(*accepted as u8);
+            }
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader {
+            data: data.strip_prefix(PACKET_MAGIC)?,
+        };
+        let kind = reader.u8()?;
+        let txn = reader.u64()?;
+        let sender = read_node_id(&mut reader)?;
+        let packet = match kind {
+            0 => DhtPacket::Ping { txn, sender },
+            1 => DhtPacket::Pong { txn, sender },
+            2 => DhtPacket::FindNode {
+                txn,
+                sender,
+                target: read_node_id(&mut reader)?,
+            },
+            3 => {
+                let count = reader.u8()?;
+                let nodes = (0..count)
+                    .map(|_| {
+                        Some(NodeInfo {
+                            id: read_node_id(&mut reader)?,
+                            address: reader.addr()?,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                DhtPacket::Nodes { txn, sender, nodes }
+            }
+            4 => DhtPacket::FindValue {
+                txn,
+                sender,
+                key: read_node_id(&mut reader)?,
+            },
+            5 => DhtPacket::Value {
+                txn,
+                sender,
+                record: PeerRecord::decode_from(&mut reader)?,
+            },
+            6 => DhtPacket::Store {
+                txn,
+                sender,
+                record: PeerRecord::decode_from(&mut reader)?,
+            },
+            7 => DhtPacket::Stored {
+                txn,
+                sender,
+                accepted: reader.u8()? != 0,
+            },
+            _ => return None,
+        };
+        reader.data.is_empty().then_some(packet)
+    }
+
+    fn sender(&self) -> NodeId {
+        match self {
+            DhtPacket::Ping { sender, .. }
+            | DhtPacket::Pong { sender, .. }
+            | DhtPacket::FindNode { sender, .. }
+            | DhtPacket::Nodes { sender, .. }
+            | DhtPacket::FindValue { sender, .. }
+            | DhtPacket::Value { sender, .. }
+            | DhtPacket::Store { sender, .. }
+            | DhtPacket::Stored { sender, .. } => *sender,
+        }
+    }
+
+    /// Transaction id, if this packet answers a request.
+    fn response_txn(&self) -> Option<u64> {
+        match self {
+            DhtPacket::Pong { txn, .. }
+            | DhtPacket::Nodes { txn, .. }
+            | DhtPacket::Value { txn, .. }
+            | DhtPacket::Stored { txn, .. } => Some(*txn),
+            _ => None,
+        }
+    }
+}
+
+/// Known nodes grouped by how many leading bits they share with us. Each
+/// bucket keeps at most `k` nodes; when full, the nodes already there are
+/// kept, since nodes that have been up for long tend to stay up.
+pub struct RoutingTable {
+    own_id: NodeId,
+    k: usize,
+    buckets: Vec<VecDeque<NodeInfo>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId, k: usize) -> Self {
+        Self {
+            own_id,
+            k,
+            buckets: (0..256).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    /// Records that `node` was heard from. Returns whether it is in the table.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let index = match self.own_id.bucket_index(&node.id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket.iter().position(|n| n.id == node.id) {
+            bucket.remove(position);
+            bucket.push_back(node);
+            return true;
+        }
+        if bucket.len() >= self.k {
+            return false;
+        }
+        bucket.push_back(node);
+        true
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> bool {
+        match self.own_id.bucket_index(id) {
+            Some(index) => {
+                let bucket = &mut self.buckets[index];
+                let before = bucket.len();
+                bucket.retain(|n| n.id != *id);
+                bucket.len() != before
+            }
+            None => false,
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct DhtState {
+    own_id: NodeId,
+    peer_id: String,
+    identity: Arc<RwLock<CryptoManager>>,
+    config: DhtConfig,
+    socket: Arc<UdpSocket>,
+    table: Mutex<RoutingTable>,
+    records: Mutex<HashMap<NodeId, PeerRecord>>,
+    pending: Mutex<HashMap<u64, (SocketAddr, oneshot::Sender<DhtPacket>)>>,
+    /// Endpoints we publish, republished periodically once set.
+    published: Mutex<Option<Vec<SocketAddr>>>,
+}
+
+struct Lookup {
+    closest: Vec<NodeInfo>,
+    record: Option<PeerRecord>,
+}
+
+/// A Kademlia node that lets peers find each other by identity key when
+/// their addresses change. Each peer publishes a signed record of its
+/// endpoints to the `k` nodes closest to its key; contacts look it up
+/// again when the address they know stops working.
+pub struct DhtNode {
+    state: Arc<DhtState>,
+    shutdown: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl DhtNode {
+    pub async fn bind(
+        addr: SocketAddr,
+        peer_id: &str,
+        identity: Arc<RwLock<CryptoManager>>,
+    ) -> Result<Self, DhtError> {
+        Self::bind_with_config(addr, peer_id, identity, DhtConfig::default()).await
+    }
+
+    pub async fn bind_with_config(
+        addr: SocketAddr,
+        peer_id: &str,
+        identity: Arc<RwLock<CryptoManager>>,
+        config: DhtConfig,
+    ) -> Result<Self, DhtError> {
+        let own_id = NodeId::from_public_key(&identity.read().await.get_public_key().key_data);
+        Ok(Self {
+            state: Arc::new(DhtState {
+                own_id,
+                peer_id: peer_id.to_string(),
+                identity,
+                socket: Arc::new(UdpSocket::bind(addr).await?),
+                table: Mutex::new(RoutingTable::new(own_id, config.k)),
+                records: Mutex::new(HashMap::new()),
+                pending: Mutex::new(HashMap::new()),
+                published: Mutex::new(None),
+                config,
+            }),
+            shutdown: CancellationToken::new(),
+            tasks: Vec::new(),
+        })
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.state.own_id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        Ok(self.state.socket.local_addr()?)
+    }
+
+    /// Host endpoints worth publishing: the bound address, or every
+    /// non-loopback interface address when bound to the unspecified address.
+    pub fn local_endpoints(&self) -> Result<Vec<SocketAddr>, DhtError> {
+        let local = self.local_addr()?;
+        if !local.ip().is_unspecified() {
+            return Ok(vec![local]);
+        }
+
+        Ok(if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|iface| !iface.is_loopback() && iface.ip().is_ipv4() == local.is_ipv4())
+            .map(|iface| SocketAddr::new(iface.ip(), local.port()))
+            .collect())
+    }
+
+    pub fn routing_table_size(&self) -> usize {
+        self.state.table.lock().unwrap().len()
+    }
+
+    pub fn stored_records(&self) -> usize {
+        self.state.records.lock().unwrap().len()
+    }
+
+    pub fn start(&mut self) {
+        if !self.tasks.is_empty() {
+            return;
+        }
+
+        let state = self.state.clone();
+        let shutdown = self.shutdown.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, from) = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    received = state.socket.recv_from(&mut buffer) => match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    },
+                };
+                if let Some(packet) = DhtPacket::decode(&buffer[..len]) {
+                    if let Some(response) = Self::handle(&state, packet, from) {
+                        let _ = state.socket.send_to(&response.encode(), from).await;
+                    }
+                }
+            }
+        }));
+
+        let state = self.state.clone();
+        let shutdown = self.shutdown.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(state.config.republish_interval);
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = ticker.tick() => {
+                        Self::prune_records(&state);
+                        let _ = Self::publish_record(&state).await;
+                    }
+                }
+            }
+        }));
+    }
+
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+
+    /// Joins the DHT through nodes we already know, such as contacts, and
+    /// fills the routing table with the nodes around our own id. Returns the
+    /// number of nodes known afterwards.
+    pub async fn bootstrap(&self, seeds: &[SocketAddr]) -> Result<usize, DhtError> {
+        let mut pings = JoinSet::new();
+        for seed in seeds {
+            let state = self.state.clone();
+            let seed = *seed;
+            pings.spawn(async move {
+                Self::request(&state, seed, |txn| DhtPacket::Ping {
+                    txn,
+                    sender: state.own_id,
+                })
+                .await
+            });
+        }
+        while pings.join_next().await.is_some() {}
+
+        if self.routing_table_size() == 0 {
+            return Err(DhtError::NoPeers);
+        }
+        Self::lookup(&self.state, self.state.own_id, false).await;
+        Ok(self.routing_table_size())
+    }
+
+    /// Publishes a record of our current endpoints and keeps republishing
+    /// it. Returns how many nodes accepted it.
+    pub async fn publish(&self, endpoints: Vec<SocketAddr>) -> Result<usize, DhtError> {
+        *self.state.published.lock().unwrap() = Some(endpoints);
+        Self::publish_record(&self.state).await
+    }
+
+    /// Looks up the newest valid record for the peer owning `public_key`.
+    pub async fn resolve(&self, public_key: &[u8]) -> Result<PeerRecord, DhtError> {
+        let key = NodeId::from_public_key(public_key);
+        let now = current_timestamp();
+
+        let local = self
+            .state
+            .records
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|record| record.verify(now))
+            .cloned();
+        let found = Self::lookup(&self.state, key, true).await.record;
+
+        match (local, found) {
+            (Some(local), Some(found)) if local.sequence > found.sequence => Ok(local),
+            (_, Some(found)) => Ok(found),
+            (Some(local), None) => Ok(local),
+            (None, None) => Err(DhtError::NotFound),
+        }
+    }
+
+    /// Connects to a contact with `dial`. If the stored address fails, the
+    /// contact's current endpoints are looked up in the DHT and the first
+    /// one that works becomes the contact's new address.
+    pub async fn connect_contact<T, F, Fut>(
+        &self,
+        contacts: &RwLock<ContactManager>,
+        contact_id: &str,
+        public_key: &[u8],
+        dial: F,
+    ) -> Result<T, DhtError>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = std::io::Result<T>>,
+    {
+        let known = contacts
+            .read()
+            .await
+            .get_contact(contact_id)
+            .and_then(|contact| contact.tcp_address());
+        if let Some(address) = known {
+            if let Ok(connection) = dial(address).await {
+                return Ok(connection);
+            }
+        }
+
+        let record = self.resolve(public_key).await?;
+        if record.peer_id != contact_id {
+            return Err(DhtError::NotFound);
+        }
+        for endpoint in record.endpoints {
+            if Some(endpoint) == known {
+                continue;
+            }
+            if let Ok(connection) = dial(endpoint).await {
+                let _ = contacts
+                    .write()
+                    .await
+                    .update_contact_address(contact_id, Endpoint::Tcp(endpoint));
+                return Ok(connection);
+            }
+        }
+        Err(DhtError::Unreachable(contact_id.to_string()))
+    }
+
+    fn handle(state: &DhtState, packet: DhtPacket, from: SocketAddr) -> Option<DhtPacket> {
+        state.table.lock().unwrap().insert(NodeInfo {
+            id: packet.sender(),
+            address: from,
+        });
+
+        if let Some(txn) = packet.response_txn() {
+            let mut pending = state.pending.lock().unwrap();
+            if pending.get(&txn).map(|(to, _)| *to) == Some(from) {
+                if let Some((_, reply)) = pending.remove(&txn) {
+                    let _ = reply.send(packet);
+                }
+            }
+            return None;
+        }
+
+        let sender = state.own_id;
+        match packet {
+            DhtPacket::Ping { txn, .. } => Some(DhtPacket::Pong { txn, sender }),
+            DhtPacket::FindNode { txn, target, .. } => Some(DhtPacket::Nodes {
+                txn,
+                sender,
+                nodes: state.table.lock().unwrap().closest(&target, state.config.k),
+            }),
+            DhtPacket::FindValue { txn, key, .. } => {
+                let now = current_timestamp();
+                let record = state
+                    .records
+                    .lock()
+                    .unwrap()
+                    .get(&key)
+                    .filter(|record| record.verify(now))
+                    .cloned();
+                Some(match record {
+                    Some(record) => DhtPacket::Value {
+                        txn,
+                        sender,
+                        record,
+                    },
+                    None => DhtPacket::Nodes {
+                        txn,
+                        sender,
+                        nodes: state.table.lock().unwrap().closest(&key, state.config.k),
+                    },
+                })
+            }
+            DhtPacket::Store { txn, record, .. } => Some(DhtPacket::Stored {
+                txn,
+                sender,
+                accepted: Self::accept_record(state, record),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Keeps a record if it is authentic, not too long-lived, and not older
+    /// than the one already held for the same key.
+    fn accept_record(state: &DhtState, record: PeerRecord) -> bool {
+        let now = current_timestamp();
+        if !record.verify(now) || record.expires_at > now + state.config.max_record_ttl.as_secs() {
+            return false;
+        }
+
+        let key = record.key();
+        let mut records = state.records.lock().unwrap();
+        match records.get(&key) {
+            Some(existing) if existing.sequence > record.sequence => return false,
+            Some(_) => {}
+            None => {
+                if records.len() >= state.config.max_records {
+                    records.retain(|_, r| r.expires_at > now);
+                }
+                if records.len() >= state.config.max_records {
+                    return false;
+                }
+            }
+        }
+        records.insert(key, record);
+        true
+    }
+
+    fn prune_records(state: &DhtState) {
+        let now = current_timestamp();
+        state
+            .records
+            .lock()
+            .unwrap()
+            .retain(|_, record| record.expires_at > now);
+    }
+
+    async fn publish_record(state: &Arc<DhtState>) -> Result<usize, DhtError> {
+        let endpoints = state.published.lock().unwrap().clone();
+        let endpoints = match endpoints {
+            Some(endpoints) => endpoints,
+            None => return Ok(0),
+        };
+
+        let record = PeerRecord::sign(
+            &*state.identity.read().await,
+            &state.peer_id,
+            endpoints,
+            state.config.record_ttl,
+        )?;
+        state
+            .records
+            .lock()
+            .unwrap()
+            .insert(state.own_id, record.clone());
+
+        let closest = Self::lookup(state, state.own_id, false).await.closest;
+        if closest.is_empty() {
+            return Err(DhtError::NoPeers);
+        }
+
+        let mut stores = JoinSet::new();
+        for node in closest {
+            let state = state.clone();
+            let record = record.clone();
+            stores.spawn(async move {
+                Self::request(&state, node.address, |txn| DhtPacket::Store {
+                    txn,
+                    sender: state.own_id,
+                    record,
+                })
+                .await
+            });
+        }
+
+        let mut accepted = 0;
+        while let Some(response) = stores.join_next().await {
+            if let Ok(Some(DhtPacket::Stored { accepted: true, .. })) = response {
+                accepted += 1;
+            }
+        }
+        Ok(accepted)
+    }
+
+    /// Iterative Kademlia lookup: asks the closest known nodes for closer
+    /// ones until the `k` closest have all answered. With `want_value`, stops
+    /// as soon as a valid record for `target` turns up.
+    async fn lookup(state: &Arc<DhtState>, target: NodeId, want_value: bool) -> Lookup {
+        let k = state.config.k;
+        let mut shortlist = state.table.lock().unwrap().closest(&target, k);
+        let mut queried = HashSet::new();
+        let mut record: Option<PeerRecord> = None;
+
+        loop {
+            let batch: Vec<NodeInfo> = shortlist
+                .iter()
+                .filter(|node| !queried.contains(&node.id))
+                .take(state.config.alpha)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut requests = JoinSet::new();
+            for node in batch {
+                queried.insert(node.id);
+                let state = state.clone();
+                requests.spawn(async move {
+                    let sender = state.own_id;
+                    let response = Self::request(&state, node.address, |txn| {
+                        if want_value {
+                            DhtPacket::FindValue {
+                                txn,
+                                sender,
+                                key: target,
+                            }
+                        } else {
+                            DhtPacket::FindNode {
+                                txn,
+                                sender,
+                                target,
+                            }
+                        }
+                    })
+                    .await;
+                    (node, response)
+                });
+            }
+
+            let now = current_timestamp();
+            while let Some(Ok((node, response))) = requests.join_next().await {
+                match response {
+                    Some(DhtPacket::Nodes { nodes, .. }) => {
+                        for found in nodes {
+                            if found.id != state.own_id
+                                && !shortlist.iter().any(|n| n.id == found.id)
+                            {
+                                shortlist.push(found);
+                            }
+                        }
+                    }
+                    Some(DhtPacket::Value { record: found, .. })
+                        if found.key() == target && found.verify(now) =>
+                    {
+                        if record
+                            .as_ref()
+                            .is_none_or(|best| found.sequence > best.sequence)
+                        {
+                            record = Some(found);
+                        }
+                    }
+                    _ => {
+                        state.table.lock().unwrap().remove(&node.id);
+                        shortlist.retain(|n| n.id != node.id);
+                    }
+                }
+            }
+
+            if record.is_some() {
+                break;
+            }
+            shortlist.sort_by_key(|node| node.id.distance(&target));
+            shortlist.truncate(k);
+        }
+
+        Lookup {
+            closest: shortlist,
+            record,
+        }
+    }
+
+    /// Sends a request and waits for the matching response from `to`.
+    async fn request(
+        state: &DhtState,
+        to: SocketAddr,
+        build: impl FnOnce(u64) -> DhtPacket,
+    ) -> Option<DhtPacket> {
+        let txn: u64 = rand::random();
+        let (reply, response) = oneshot::channel();
+        state.pending.lock().unwrap().insert(txn, (to, reply));
+
+        let _ = state.socket.send_to(&build(txn).encode(), to).await;
+        let response = tokio::time::timeout(state.config.request_timeout, response)
+            .await
+            .ok()
+            .and_then(Result::ok);
+
+        state.pending.lock().unwrap().remove(&txn);
+        response
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Addresses of contacts to join the DHT through. Peers run their DHT node
+/// on the same port they advertise for chat.
+pub fn bootstrap_addresses(contacts: &[Contact]) -> Vec<SocketAddr> {
+    contacts
+        .iter()
+        .filter(|contact| contact.trust_level != TrustLevel::Blocked)
+        .filter_map(Contact::tcp_address)
+        .collect()
+}
+
+fn current_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,381 @@
+use crate::network::hole_punch::{DatagramSocket, PunchPacket, MAX_DATAGRAM_SIZE};
+use crate::network::protocol::ProtocolMessage;
+use crate::network::types::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Payload bytes carried by one data packet; larger messages are split.
+pub const MAX_FRAGMENT_SIZE: usize = 1024;
+/// Largest message `DirectChannel::send` accepts.
+pub const MAX_DIRECT_MESSAGE_SIZE: usize = 256 * 1024;
+/// How far ahead of the next expected sequence number packets are buffered.
+const RECEIVE_WINDOW: u64 = 512;
+
+struct ChannelState {
+    socket: Arc<dyn DatagramSocket>,
+    remote: SocketAddr,
+    session: u64,
+    config: PunchConfig,
+    next_seq: AtomicU64,
+    unacked: std::sync::Mutex<HashSet<u64>>,
+    acked: Notify,
+    reflexive_requests: std::sync::Mutex<HashMap<u64, oneshot::Sender<SocketAddr>>>,
+}
+
+impl ChannelState {
+    async fn send_packet(&self, packet: &PunchPacket) {
+        let _ = self.socket.send_to(&packet.encode(), self.remote).await;
+    }
+}
+
+/// Reliable, ordered message channel over a punched UDP path.
+///
+/// Messages are split into sequenced fragments, retransmitted until
+/// acknowledged, and reassembled in order on the other side. Duplicates
+/// are dropped. The channel also answers late hole-punching probes and
+/// sends keepalives so the NAT mappings along the path stay open.
+pub struct DirectChannel {
+    state: Arc<ChannelState>,
+    remote_peer_id: String,
+    incoming: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    shutdown: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl DirectChannel {
+    pub(crate) fn open(
+        socket: Arc<dyn DatagramSocket>,
+        remote_peer_id: String,
+        session: u64,
+        remote: SocketAddr,
+        config: PunchConfig,
+    ) -> Self {
+        let state = Arc::new(ChannelState {
+            socket,
+            remote,
+            session,
+            config,
+            next_seq: AtomicU64::new(0),
+            unacked: std::sync::Mutex::new(HashSet::new()),
+            acked: Notify::new(),
+            reflexive_requests: std::sync::Mutex::new(HashMap::new()),
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+
+        let reader_state = state.clone();
+        let reader_shutdown = shutdown.clone();
+        let reader = tokio::spawn(async move {
+            Self::receive_loop(reader_state, sender, reader_shutdown).await;
+        });
+
+        let keepalive_state = state.clone();
+        let keepalive_shutdown = shutdown.clone();
+        let keepalive = tokio::spawn(async move {
+            Self::keepalive_loop(keepalive_state, keepalive_shutdown).await;
+        });
+
+        Self {
+            state,
+            remote_peer_id,
+            incoming: Mutex::new(receiver),
+            shutdown,
+            tasks: vec![reader, keepalive],
+        }
+    }
+
+    pub fn remote_peer_id(&self) -> &str {
+        &self.remote_peer_id
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.state.remote
+    }
+
+    pub fn session(&self) -> u64 {
+        self.state.session
+    }
+
+    /// Sends one message and waits until every fragment is acknowledged.
+    pub async fn send(&self, data: &[u8]) -> Result<(), PunchError> {
+        if data.len() > MAX_DIRECT_MESSAGE_SIZE {
+            return Err(PunchError::MessageTooLarge(data.len()));
+        }
+        if self.shutdown.is_cancelled() {
+            return Err(PunchError::ChannelClosed);
+        }
+
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(MAX_FRAGMENT_SIZE).collect()
+        };
+        // Fragments of one message get consecutive sequence numbers, so
+        // in-order delivery on the other side is enough to reassemble them
+        let first_seq = self
+            .state
+            .next_seq
+            .fetch_add(chunks.len() as u64, Ordering::SeqCst);
+        let last = chunks.len() - 1;
+        let fragments: Vec<(u64, PunchPacket)> = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let seq = first_seq + i as u64;
+                let packet = PunchPacket::Data {
+                    session: self.state.session,
+                    seq,
+                    more: i < last,
+                    payload: chunk.to_vec(),
+                };
+                (seq, packet)
+            })
+            .collect();
+
+        self.state
+            .unacked
+            .lock()
+            .unwrap()
+            .extend(fragments.iter().map(|(seq, _)| *seq));
+
+        let retransmit_timeout = self.state.config.retransmit_timeout;
+        let mut last_sent: Vec<Option<Instant>> = vec![None; fragments.len()];
+        let mut attempts = vec![0u32; fragments.len()];
+
+        loop {
+            // Registered before checking, so an ack that lands in between
+            // still wakes us up
+            let acked = self.state.acked.notified();
+            tokio::pin!(acked);
+            acked.as_mut().enable();
+
+            let now = Instant::now();
+            let mut next_resend = now + retransmit_timeout;
+            let mut outstanding = false;
+            for (i, (seq, packet)) in fragments.iter().enumerate() {
+                if !self.state.unacked.lock().unwrap().contains(seq) {
+                    continue;
+                }
+                outstanding = true;
+
+                match last_sent[i] {
+                    Some(sent) if now < sent + retransmit_timeout => {
+                        next_resend = next_resend.min(sent + retransmit_timeout);
+                    }
+                    _ => {
+                        if attempts[i] > self.state.config.max_retransmits {
+                            self.forget(&fragments);
+                            return Err(PunchError::Timeout);
+                        }
+                        attempts[i] += 1;
+                        last_sent[i] = Some(now);
+                        self.state.send_packet(packet).await;
+                    }
+                }
+            }
+            if !outstanding {
+                return Ok(());
+            }
+
+            tokio::select! {
+                _ = self.shutdown.cancelled() => {
+                    self.forget(&fragments);
+                    return Err(PunchError::ChannelClosed);
+                }
+                _ = tokio::time::sleep_until(next_resend) => {}
+                _ = &mut acked => {}
+            }
+        }
+    }
+
+    /// Asks the remote peer which address it sees us at, i.e. our public
+    /// address on this path.
+    pub async fn reflexive_address(&self) -> Result<SocketAddr, PunchError> {
+        let txn: u64 = rand::random();
+        let (sender, mut receiver) = oneshot::channel();
+        self.state
+            .reflexive_requests
+            .lock()
+            .unwrap()
+            .insert(txn, sender);
+
+        let request = PunchPacket::Bind { txn };
+        let mut result = Err(PunchError::Timeout);
+        for _ in 0..=self.state.config.max_retransmits {
+            self.state.send_packet(&request).await;
+            tokio::select! {
+                _ = self.shutdown.cancelled() => {
+                    result = Err(PunchError::ChannelClosed);
+                    break;
+                }
+                observed = &mut receiver => {
+                    result = observed.map_err(|_| PunchError::ChannelClosed);
+                    break;
+                }
+                _ = tokio::time::sleep(self.state.config.retransmit_timeout) => {}
+            }
+        }
+
+        self.state.reflexive_requests.lock().unwrap().remove(&txn);
+        result
+    }
+
+    fn forget(&self, fragments: &[(u64, PunchPacket)]) {
+        let mut unacked = self.state.unacked.lock().unwrap();
+        for (seq, _) in fragments {
+            unacked.remove(seq);
+        }
+    }
+
+    /// Next complete message, or `None` once the channel is closed.
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        let mut incoming = self.incoming.lock().await;
+        tokio::select! {
+            _ = self.shutdown.cancelled() => None,
+            message = incoming.recv() => message,
+        }
+    }
+
+    pub async fn send_message(&self, message: &ProtocolMessage) -> Result<(), PunchError> {
+        let data = message
+            .to_bytes()
+            .map_err(|e| PunchError::Io(e.to_string()))?;
+        self.send(&data).await
+    }
+
+    /// Next protocol message; undecodable messages are skipped.
+    pub async fn recv_message(&self) -> Option<ProtocolMessage> {
+        loop {
+            let data = self.recv().await?;
+            if let Ok(message) = ProtocolMessage::from_bytes(&data) {
+                return Some(message);
+            }
+        }
+    }
+
+    /// Stops the channel's tasks and waits for them to exit.
+    pub async fn close(&mut self) {
+        self.shutdown.cancel();
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+
+    async fn receive_loop(
+        state: Arc<ChannelState>,
+        delivered: mpsc::UnboundedSender<Vec<u8>>,
+        shutdown: CancellationToken,
+    ) {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+        let mut expected: u64 = 0;
+        let mut reorder: BTreeMap<u64, (bool, Vec<u8>)> = BTreeMap::new();
+        let mut partial: Vec<u8> = Vec::new();
+        let mut oversized = false;
+
+        loop {
+            let (len, from) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = state.socket.recv_from(&mut buffer) => match received {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+            };
+            let packet = PunchPacket::decode(&buffer[..len]);
+            if let Some(PunchPacket::Bind { txn }) = packet {
+                // Connected peers double as address mirrors for anyone
+                let response = PunchPacket::BindResponse {
+                    txn,
+                    observed: from,
+                };
+                let _ = state.socket.send_to(&response.encode(), from).await;
+                continue;
+            }
+            if from != state.remote {
+                continue;
+            }
+
+            match packet {
+                Some(PunchPacket::Data {
+                    session,
+                    seq,
+                    more,
+                    payload,
+                }) if session == state.session => {
+                    if seq >= expected + RECEIVE_WINDOW {
+                        // Too far ahead to buffer; the sender will retransmit
+                        continue;
+                    }
+                    state
+                        .send_packet(&PunchPacket::Ack {
+                            session: state.session,
+                            seq,
+                        })
+                        .await;
+                    if seq < expected {
+                        // Our ack was lost and this is a retransmission
+                        continue;
+                    }
+
+                    reorder.insert(seq, (more, payload));
+                    while let Some((more, payload)) = reorder.remove(&expected) {
+                        expected += 1;
+                        if partial.len() + payload.len() > MAX_DIRECT_MESSAGE_SIZE {
+                            oversized = true;
+                            partial.clear();
+                        } else if !oversized {
+                            partial.extend_from_slice(&payload);
+                        }
+                        if !more {
+                            let message = std::mem::take(&mut partial);
+                            if !std::mem::take(&mut oversized) {
+                                let _ = delivered.send(message);
+                            }
+                        }
+                    }
+                }
+                Some(PunchPacket::Ack { session, seq }) if session == state.session => {
+                    if state.unacked.lock().unwrap().remove(&seq) {
+                        state.acked.notify_waiters();
+                    }
+                }
+                Some(PunchPacket::BindResponse { txn, observed }) => {
+                    let waiter = state.reflexive_requests.lock().unwrap().remove(&txn);
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send(observed);
+                    }
+                }
+                Some(PunchPacket::Probe { session }) if session == state.session => {
+                    // The remote side may still be punching
+                    state.send_packet(&PunchPacket::ProbeAck { session }).await;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn keepalive_loop(state: Arc<ChannelState>, shutdown: CancellationToken) {
+        let mut ticker = tokio::time::interval(state.config.keepalive_interval);
+        let keepalive = PunchPacket::Keepalive {
+            session: state.session,
+        };
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = ticker.tick() => state.send_packet(&keepalive).await,
+            }
+        }
+    }
+}
+
+impl Drop for DirectChannel {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
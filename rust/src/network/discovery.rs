@@ -1,198 +1,693 @@
+use crate::crypto::{CryptoError, CryptoManager};
+use crate::events::types::{EventBus, NetworkEvent};
+use crate::network::announcement::{sign_announcement, AnnouncementGuard, AnnouncementRejection};
+use crate::network::mdns::MdnsDiscovery;
+use crate::network::reflexive::lookup_external_ip_http;
 use crate::network::types::*;
-use std::collections::HashMap;
-use std::net::IpAddr;
+use chrono::{DateTime, Utc};
+use if_addrs::{IfAddr, Ifv4Addr};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::net::UdpSocket;
 use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone)]
+struct DiscoverySockets {
+    listener_v4: Arc<UdpSocket>,
+    sender_v4: Arc<UdpSocket>,
+    listener_v6: Option<Arc<UdpSocket>>,
+    sender_v6: Option<Arc<UdpSocket>>,
+}
+
+/// Multicast groups joined so far, keyed by interface address (IPv4) or
+/// interface index (IPv6), so interfaces that come up later can be joined.
+#[derive(Default)]
+struct GroupMemberships {
+    v4: HashSet<Ipv4Addr>,
+    v6: HashSet<u32>,
+}
+
+/// Discovered peers plus the keys they are bound to. Every accepted
+/// announcement, whether from UDP or mDNS, goes through `insert`, and every
+/// change to the table is reported on the event bus.
+#[derive(Clone)]
+pub(crate) struct PeerTable {
+    peers: Arc<RwLock<HashMap<String, DiscoveredPeer>>>,
+    known_keys: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    last_discovery: Arc<RwLock<Option<DateTime<Utc>>>>,
+    event_bus: Option<EventBus>,
+    max_peers: usize,
+}
+
+impl PeerTable {
+    /// Adds or refreshes a peer. A peer id stays bound to the first key seen
+    /// for it, and a known contact's key always wins; a mismatch with a
+    /// contact's key raises `PeerKeyMismatch` instead of updating the address.
+    pub(crate) async fn insert(&self, peer: DiscoveredPeer) -> Result<(), AnnouncementRejection> {
+        if let Some(known_key) = self.known_keys.read().await.get(&peer.id) {
+            if *known_key != peer.public_key {
+                self.emit(NetworkEvent::PeerKeyMismatch {
+                    peer_id: peer.id.clone(),
+                    address: SocketAddr::new(peer.address, peer.port).to_string(),
+                    known_key: known_key.clone(),
+                    announced_key: peer.public_key.clone(),
+                });
+                return Err(AnnouncementRejection::KeyMismatch);
+            }
+        }
+
+        let mut peers = self.peers.write().await;
+        let event = match peers.get(&peer.id) {
+            Some(existing) if existing.public_key != peer.public_key => {
+                return Err(AnnouncementRejection::KeyMismatch);
+            }
+            None if peers.len() >= self.max_peers => {
+                return Err(AnnouncementRejection::PeerLimitReached);
+            }
+            None => Some(NetworkEvent::PeerDiscovered { peer: peer.clone() }),
+            // A refresh that only moves last_seen is not worth an event
+            Some(existing)
+                if *existing
+                    != (DiscoveredPeer {
+                        last_seen: existing.last_seen,
+                        ..peer.clone()
+                    }) =>
+            {
+                Some(NetworkEvent::PeerUpdated { peer: peer.clone() })
+            }
+            Some(_) => None,
+        };
+
+        peers.insert(peer.id.clone(), peer);
+        drop(peers);
+
+        *self.last_discovery.write().await = Some(Utc::now());
+        if let Some(event) = event {
+            self.emit(event);
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn remove(&self, peer_id: &str) {
+        if self.peers.write().await.remove(peer_id).is_some() {
+            self.emit(NetworkEvent::PeerLost {
+                peer_id: peer_id.to_string(),
+            });
+        }
+    }
+
+    /// Drops peers last seen `max_age` seconds or more before `now` and
+    /// returns their ids.
+    pub(crate) async fn expire(&self, max_age: u64, now: u64) -> Vec<String> {
+        let mut peers = self.peers.write().await;
+
+        let expired: Vec<String> = peers
+            .values()
+            .filter(|peer| now.saturating_sub(peer.last_seen) >= max_age)
+            .map(|peer| peer.id.clone())
+            .collect();
+
+        for peer_id in &expired {
+            peers.remove(peer_id);
+            self.emit(NetworkEvent::PeerLost {
+                peer_id: peer_id.clone(),
+            });
+        }
+        expired
+    }
+
+    pub(crate) async fn clear(&self) {
+        for (peer_id, _) in self.peers.write().await.drain() {
+            self.emit(NetworkEvent::PeerLost { peer_id });
+        }
+    }
+
+    fn emit(&self, event: NetworkEvent) {
+        if let Some(event_bus) = &self.event_bus {
+            event_bus.emit_network(event);
+        }
+    }
+}
 
 pub struct NetworkDiscovery {
     local_port: u16,
     local_peer_id: String,
     local_peer_name: String,
+    config: DiscoveryConfig,
+    identity: Arc<RwLock<CryptoManager>>,
     discovered_peers: Arc<RwLock<HashMap<String, DiscoveredPeer>>>,
-    is_running: Arc<Mutex<bool>>,
-    announcement_socket: Option<Arc<UdpSocket>>,
-    listening_socket: Option<Arc<UdpSocket>>,
-    discovery_handle: Option<tokio::task::JoinHandle<()>>,
-    announcement_handle: Option<tokio::task::JoinHandle<()>>,
-    public_key: Vec<u8>,
+    known_keys: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    last_discovery: Arc<RwLock<Option<DateTime<Utc>>>>,
+    started_at: Option<Instant>,
+    event_bus: Option<EventBus>,
+    running: AtomicBool,
+    /// Cancelled on stop; a fresh token is issued for every start.
+    shutdown: CancellationToken,
+    sockets: Option<DiscoverySockets>,
+    mdns: Option<MdnsDiscovery>,
+    tasks: Vec<JoinHandle<()>>,
 }
 
 impl NetworkDiscovery {
-    pub fn new(local_port: u16, peer_id: String, peer_name: String, public_key: Vec<u8>) -> Self {
+    /// Creates discovery for the local peer. Announcements are signed with
+    /// the identity key held by `identity`.
+    pub fn new(
+        local_port: u16,
+        peer_id: String,
+        peer_name: String,
+        identity: Arc<RwLock<CryptoManager>>,
+    ) -> Self {
         Self {
             local_port,
             local_peer_id: peer_id,
             local_peer_name: peer_name,
+            config: DiscoveryConfig::default(),
+            identity,
             discovered_peers: Arc::new(RwLock::new(HashMap::new())),
-            is_running: Arc::new(Mutex::new(false)),
-            announcement_socket: None,
-            listening_socket: None,
-            discovery_handle: None,
-            announcement_handle: None,
-            public_key,
+            known_keys: Arc::new(RwLock::new(HashMap::new())),
+            last_discovery: Arc::new(RwLock::new(None)),
+            started_at: None,
+            event_bus: None,
+            running: AtomicBool::new(false),
+            shutdown: CancellationToken::new(),
+            sockets: None,
+            mdns: None,
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn with_config(mut self, config: DiscoveryConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_event_bus(mut self, event_bus: EventBus) -> Self {
+        self.event_bus = Some(event_bus);
+        self
+    }
+
+    pub fn config(&self) -> &DiscoveryConfig {
+        &self.config
+    }
+
+    /// Pins the public key of a known contact. Announcements for that peer id
+    /// signed with any other key are rejected and reported.
+    pub async fn set_known_key(&self, peer_id: &str, public_key: Vec<u8>) {
+        self.known_keys
+            .write()
+            .await
+            .insert(peer_id.to_string(), public_key);
+    }
+
+    pub async fn remove_known_key(&self, peer_id: &str) {
+        self.known_keys.write().await.remove(peer_id);
+    }
+
+    fn peer_table(&self) -> PeerTable {
+        PeerTable {
+            peers: self.discovered_peers.clone(),
+            known_keys: self.known_keys.clone(),
+            last_discovery: self.last_discovery.clone(),
+            event_bus: self.event_bus.clone(),
+            max_peers: self.config.max_peers,
         }
     }
 
+    /// Opens the discovery sockets and starts the listener, broadcaster and
+    /// expiry tasks. Does nothing if discovery is already running.
     pub async fn start_discovery(
         &mut self,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut is_running = self.is_running.lock().await;
-        if *is_running {
+        if self.is_running() {
             return Ok(());
         }
 
-        let listening_socket = UdpSocket::bind("0.0.0.0:9999").await?;
-        let announcement_socket = UdpSocket::bind("0.0.0.0:0").await?;
-        announcement_socket.set_broadcast(true)?;
-
-        self.listening_socket = Some(Arc::new(listening_socket));
-        self.announcement_socket = Some(Arc::new(announcement_socket));
+        let sockets = Self::open_sockets(&self.config)?;
+        let mut memberships = GroupMemberships::default();
+        Self::join_groups(&sockets, &self.config, &mut memberships);
 
-        let peers_clone = self.discovered_peers.clone();
-        let running_clone = self.is_running.clone();
-        let listening_socket_clone = self.listening_socket.as_ref().unwrap().clone();
+        // Signing happens before any task is spawned so a failure leaves
+        // nothing behind.
+        let mdns_record = if self.config.enable_mdns {
+            Some(Self::fresh_announcement(&self.identity, &self.build_announcement()).await?)
+        } else {
+            None
+        };
 
-        let discovery_handle = tokio::spawn(async move {
-            Self::discovery_listener(peers_clone, running_clone, listening_socket_clone).await;
-        });
+        let shutdown = CancellationToken::new();
+
+        let guard = Arc::new(Mutex::new(AnnouncementGuard::new(
+            self.config.max_announcements_per_ip,
+        )));
+        let mut listeners = vec![sockets.listener_v4.clone()];
+        listeners.extend(sockets.listener_v6.clone());
+        for socket in listeners {
+            let table = self.peer_table();
+            let guard = guard.clone();
+            let shutdown_clone = shutdown.clone();
+            let local_peer_id = self.local_peer_id.clone();
+
+            self.tasks.push(tokio::spawn(async move {
+                Self::discovery_listener(table, guard, shutdown_clone, socket, local_peer_id).await;
+            }));
+        }
 
-        let announcement_socket_clone = self.announcement_socket.as_ref().unwrap().clone();
-        let running_clone = self.is_running.clone();
-        let peer_id = self.local_peer_id.clone();
-        let peer_name = self.local_peer_name.clone();
-        let port = self.local_port;
-        let public_key = self.public_key.clone();
+        let sockets_clone = sockets.clone();
+        let config = self.config.clone();
+        let shutdown_clone = shutdown.clone();
+        let identity = self.identity.clone();
+        let announcement = self.build_announcement();
 
-        let announcement_handle = tokio::spawn(async move {
+        self.tasks.push(tokio::spawn(async move {
             Self::announcement_broadcaster(
-                announcement_socket_clone,
-                running_clone,
-                peer_id,
-                peer_name,
-                port,
-                public_key,
+                sockets_clone,
+                config,
+                memberships,
+                shutdown_clone,
+                identity,
+                announcement,
             )
             .await;
-        });
-
-        self.discovery_handle = Some(discovery_handle);
-        self.announcement_handle = Some(announcement_handle);
+        }));
+
+        let table = self.peer_table();
+        let peer_timeout = self.config.peer_timeout;
+        let shutdown_clone = shutdown.clone();
+        self.tasks.push(tokio::spawn(async move {
+            Self::peer_expiry(table, peer_timeout, shutdown_clone).await;
+        }));
+
+        if let Some(record) = mdns_record {
+            match MdnsDiscovery::start(
+                &record,
+                self.identity.clone(),
+                self.config.announce_interval,
+                self.peer_table(),
+                guard,
+            ) {
+                Ok(mdns) => self.mdns = Some(mdns),
+                Err(e) => log::warn!("mDNS discovery unavailable: {}", e),
+            }
+        }
 
-        *is_running = true;
+        self.sockets = Some(sockets);
+        self.shutdown = shutdown;
+        self.started_at = Some(Instant::now());
+        self.running.store(true, Ordering::SeqCst);
         Ok(())
     }
 
+    /// Cancels the discovery tasks and waits for them to exit, then reports
+    /// every known peer as lost. Does nothing if discovery is not running.
     pub async fn stop_discovery(&mut self) {
-        let mut is_running = self.is_running.lock().await;
-        *is_running = false;
+        if !self.running.swap(false, Ordering::SeqCst) {
+            return;
+        }
 
-        if let Some(handle) = self.discovery_handle.take() {
-            handle.abort();
+        self.shutdown.cancel();
+        for handle in self.tasks.drain(..) {
+            let _ = handle.await;
         }
 
-        if let Some(handle) = self.announcement_handle.take() {
-            handle.abort();
+        if let Some(mut mdns) = self.mdns.take() {
+            mdns.stop();
         }
 
-        self.discovered_peers.write().await.clear();
+        self.sockets = None;
+        self.started_at = None;
+        self.peer_table().clear().await;
+    }
+
+    /// Broadcast address of an IPv4 subnet, or `None` for point-to-point
+    /// (/31) and host (/32) prefixes that have no broadcast address.
+    pub fn subnet_broadcast(ip: Ipv4Addr, netmask: Ipv4Addr) -> Option<Ipv4Addr> {
+        let mask = u32::from(netmask);
+        if mask >= 0xffff_fffe {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from(ip) | !mask))
+    }
+
+    /// Directed broadcast addresses of every non-loopback IPv4 interface.
+    pub fn broadcast_addresses() -> Vec<Ipv4Addr> {
+        let mut addresses = Vec::new();
+        for iface in Self::ipv4_interfaces() {
+            let broadcast = iface
+                .broadcast
+                .or_else(|| Self::subnet_broadcast(iface.ip, iface.netmask));
+            if let Some(broadcast) = broadcast {
+                if !addresses.contains(&broadcast) {
+                    addresses.push(broadcast);
+                }
+            }
+        }
+        addresses
+    }
+
+    fn ipv4_interfaces() -> Vec<Ifv4Addr> {
+        if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|iface| match iface.addr {
+                IfAddr::V4(addr) if !addr.is_loopback() => Some(addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Indexes of interfaces with an IPv6 link-local address, which is what
+    /// link-local multicast needs to be scoped to.
+    fn ipv6_interface_indexes() -> Vec<u32> {
+        let mut indexes = Vec::new();
+        for iface in if_addrs::get_if_addrs().unwrap_or_default() {
+            if let (IfAddr::V6(addr), Some(index)) = (&iface.addr, iface.index) {
+                if addr.is_link_local() && !indexes.contains(&index) {
+                    indexes.push(index);
+                }
+            }
+        }
+        indexes
+    }
+
+    /// Binds a UDP socket that other instances on this host can share.
+    fn bind_reusable(addr: SocketAddr) -> std::io::Result<Socket> {
+        let domain = if addr.is_ipv4() {
+            Domain::IPV4
+        } else {
+            Domain::IPV6
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    }
+
+    fn into_tokio(socket: Socket) -> std::io::Result<Arc<UdpSocket>> {
+        Ok(Arc::new(UdpSocket::from_std(socket.into())?))
+    }
+
+    fn open_sockets(config: &DiscoveryConfig) -> std::io::Result<DiscoverySockets> {
+        let listener_v4 =
+            Self::bind_reusable(SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port)))?;
+        listener_v4.set_multicast_loop_v4(true)?;
+
+        let sender_v4 = Self::bind_reusable(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+        sender_v4.set_broadcast(true)?;
+        sender_v4.set_multicast_loop_v4(true)?;
+        sender_v4.set_multicast_ttl_v4(1)?;
+
+        let (listener_v6, sender_v6) = if config.enable_ipv6 {
+            match Self::open_ipv6_sockets(config) {
+                Ok((listener, sender)) => (Some(listener), Some(sender)),
+                Err(e) => {
+                    log::debug!("IPv6 discovery unavailable: {}", e);
+                    (None, None)
+                }
+            }
+        } else {
+            (None, None)
+        };
+
+        Ok(DiscoverySockets {
+            listener_v4: Self::into_tokio(listener_v4)?,
+            sender_v4: Self::into_tokio(sender_v4)?,
+            listener_v6,
+            sender_v6,
+        })
+    }
+
+    fn open_ipv6_sockets(
+        config: &DiscoveryConfig,
+    ) -> std::io::Result<(Arc<UdpSocket>, Arc<UdpSocket>)> {
+        let listener = Self::bind_reusable(SocketAddr::from((Ipv6Addr::UNSPECIFIED, config.port)))?;
+        listener.set_multicast_loop_v6(true)?;
+
+        let sender = Self::bind_reusable(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))?;
+        sender.set_multicast_loop_v6(true)?;
+        sender.set_multicast_hops_v6(1)?;
+
+        Ok((Self::into_tokio(listener)?, Self::into_tokio(sender)?))
+    }
+
+    /// Joins the discovery groups on every interface not joined yet. Falls
+    /// back to the default interface when there is no usable IPv4 interface.
+    fn join_groups(
+        sockets: &DiscoverySockets,
+        config: &DiscoveryConfig,
+        memberships: &mut GroupMemberships,
+    ) {
+        let mut interfaces: Vec<Ipv4Addr> =
+            Self::ipv4_interfaces().into_iter().map(|i| i.ip).collect();
+        if interfaces.is_empty() {
+            interfaces.push(Ipv4Addr::UNSPECIFIED);
+        }
+
+        for ip in interfaces {
+            if memberships.v4.contains(&ip) {
+                continue;
+            }
+            match sockets
+                .listener_v4
+                .join_multicast_v4(config.multicast_v4, ip)
+            {
+                Ok(()) => {
+                    memberships.v4.insert(ip);
+                }
+                Err(e) => log::debug!("Failed to join discovery group on {}: {}", ip, e),
+            }
+        }
+
+        if let Some(listener) = &sockets.listener_v6 {
+            for index in Self::ipv6_interface_indexes() {
+                if memberships.v6.contains(&index) {
+                    continue;
+                }
+                if listener
+                    .join_multicast_v6(&config.multicast_v6, index)
+                    .is_ok()
+                {
+                    memberships.v6.insert(index);
+                }
+            }
+        }
+    }
+
+    /// Sends one announcement to the multicast group and the directed
+    /// broadcast address of every interface, plus IPv6 link-local multicast.
+    async fn send_announcement(
+        sockets: &DiscoverySockets,
+        config: &DiscoveryConfig,
+        payload: &[u8],
+    ) -> usize {
+        let mut sent = 0;
+        let interfaces = Self::ipv4_interfaces();
+
+        if interfaces.is_empty() {
+            let targets = [config.multicast_v4, Ipv4Addr::BROADCAST];
+            for target in targets {
+                let addr = SocketAddrV4::new(target, config.port);
+                if sockets.sender_v4.send_to(payload, addr).await.is_ok() {
+                    sent += 1;
+                }
+            }
+        }
+
+        for iface in &interfaces {
+            let group = SocketAddrV4::new(config.multicast_v4, config.port);
+            if SockRef::from(&*sockets.sender_v4)
+                .set_multicast_if_v4(&iface.ip)
+                .is_ok()
+                && sockets.sender_v4.send_to(payload, group).await.is_ok()
+            {
+                sent += 1;
+            }
+
+            let broadcast = iface
+                .broadcast
+                .or_else(|| Self::subnet_broadcast(iface.ip, iface.netmask));
+            if let Some(broadcast) = broadcast {
+                let addr = SocketAddrV4::new(broadcast, config.port);
+                if sockets.sender_v4.send_to(payload, addr).await.is_ok() {
+                    sent += 1;
+                }
+            }
+        }
+
+        if let Some(sender) = &sockets.sender_v6 {
+            for index in Self::ipv6_interface_indexes() {
+                let addr = SocketAddrV6::new(config.multicast_v6, config.port, 0, index);
+                if sender.send_to(payload, addr).await.is_ok() {
+                    sent += 1;
+                }
+            }
+        }
+
+        sent
+    }
+
+    /// Unsigned announcement template; timestamp, nonce and signature are
+    /// filled in per send.
+    fn build_announcement(&self) -> AnnouncementMessage {
+        AnnouncementMessage {
+            peer_id: self.local_peer_id.clone(),
+            peer_name: self.local_peer_name.clone(),
+            port: self.local_port,
+            public_key: Vec::new(),
+            protocol_version: 1,
+            capabilities: vec!["chat".to_string(), "file_transfer".to_string()],
+            timestamp: 0,
+            nonce: 0,
+            signature: Vec::new(),
+        }
+    }
+
+    async fn sign(
+        identity: &Arc<RwLock<CryptoManager>>,
+        mut announcement: AnnouncementMessage,
+    ) -> Result<AnnouncementMessage, CryptoError> {
+        sign_announcement(&*identity.read().await, &mut announcement)?;
+        Ok(announcement)
+    }
+
+    /// Signs a fresh copy of the template with the current time and a new nonce.
+    pub(crate) async fn fresh_announcement(
+        identity: &Arc<RwLock<CryptoManager>>,
+        template: &AnnouncementMessage,
+    ) -> Result<AnnouncementMessage, CryptoError> {
+        let announcement = AnnouncementMessage {
+            timestamp: Self::now(),
+            nonce: rand::random(),
+            ..template.clone()
+        };
+        Self::sign(identity, announcement).await
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
     }
 
     async fn discovery_listener(
-        peers: Arc<RwLock<HashMap<String, DiscoveredPeer>>>,
-        is_running: Arc<Mutex<bool>>,
+        table: PeerTable,
+        guard: Arc<Mutex<AnnouncementGuard>>,
+        shutdown: CancellationToken,
         socket: Arc<UdpSocket>,
+        local_peer_id: String,
     ) {
         let mut buffer = [0u8; 2048];
 
-        while *is_running.lock().await {
-            match tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer)).await
-            {
-                Ok(Ok((len, addr))) => {
-                    if let Ok(message_str) = std::str::from_utf8(&buffer[..len]) {
-                        if let Ok(announcement) =
-                            serde_json::from_str::<AnnouncementMessage>(message_str)
+        loop {
+            let received = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = socket.recv_from(&mut buffer) => received,
+            };
+
+            match received {
+                Ok((len, addr)) => {
+                    if let Ok(announcement) =
+                        serde_json::from_slice::<AnnouncementMessage>(&buffer[..len])
+                    {
+                        if announcement.peer_id == local_peer_id {
+                            continue;
+                        }
+                        if guard
+                            .lock()
+                            .await
+                            .check(&announcement, addr.ip(), Self::now())
+                            .is_err()
                         {
-                            Self::process_announcement(announcement, addr.ip(), peers.clone())
-                                .await;
+                            continue;
                         }
+                        let _ = Self::process_announcement(announcement, addr.ip(), &table).await;
                     }
                 }
-                Ok(Err(e)) => eprintln!("Discovery receive error: {}", e),
-                Err(_) => continue,
+                Err(e) => log::debug!("Discovery receive error: {}", e),
             }
         }
     }
 
     async fn announcement_broadcaster(
-        socket: Arc<UdpSocket>,
-        is_running: Arc<Mutex<bool>>,
-        peer_id: String,
-        peer_name: String,
-        port: u16,
-        public_key: Vec<u8>,
+        sockets: DiscoverySockets,
+        config: DiscoveryConfig,
+        mut memberships: GroupMemberships,
+        shutdown: CancellationToken,
+        identity: Arc<RwLock<CryptoManager>>,
+        template: AnnouncementMessage,
     ) {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        let mut interval = tokio::time::interval(config.announce_interval);
 
-        while *is_running.lock().await {
-            interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
 
-            let announcement = AnnouncementMessage {
-                peer_id: peer_id.clone(),
-                peer_name: peer_name.clone(),
-                port,
-                public_key: public_key.clone(),
-                protocol_version: 1,
-                capabilities: vec!["chat".to_string(), "file_transfer".to_string()],
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
+            // Pick up interfaces that appeared since the last round
+            Self::join_groups(&sockets, &config, &mut memberships);
+
+            let announcement = match Self::fresh_announcement(&identity, &template).await {
+                Ok(announcement) => announcement,
+                Err(e) => {
+                    log::warn!("Failed to sign discovery announcement: {}", e);
+                    continue;
+                }
             };
 
             if let Ok(message_json) = serde_json::to_string(&announcement) {
-                let broadcast_addresses = ["255.255.255.255:9999", "224.0.0.1:9999"];
+                Self::send_announcement(&sockets, &config, message_json.as_bytes()).await;
+            }
+        }
+    }
 
-                for addr in &broadcast_addresses {
-                    let _ = socket.send_to(message_json.as_bytes(), addr).await;
-                }
+    /// Periodically drops peers that stopped announcing themselves.
+    async fn peer_expiry(table: PeerTable, peer_timeout: Duration, shutdown: CancellationToken) {
+        let period = (peer_timeout / 3).max(Duration::from_millis(100));
+        let mut interval = tokio::time::interval(period);
 
-                for subnet in &[
-                    "192.168.1.255:9999",
-                    "10.0.0.255:9999",
-                    "172.16.255.255:9999",
-                ] {
-                    let _ = socket.send_to(message_json.as_bytes(), subnet).await;
-                }
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
             }
+            table.expire(peer_timeout.as_secs(), Self::now()).await;
         }
     }
 
     async fn process_announcement(
         announcement: AnnouncementMessage,
         from_ip: IpAddr,
-        peers: Arc<RwLock<HashMap<String, DiscoveredPeer>>>,
-    ) {
+        table: &PeerTable,
+    ) -> Result<(), AnnouncementRejection> {
         let discovered_peer = DiscoveredPeer {
-            id: announcement.peer_id.clone(),
+            id: announcement.peer_id,
             address: from_ip,
             port: announcement.port,
             name: announcement.peer_name,
-            last_seen: announcement.timestamp,
+            last_seen: Self::now(),
             public_key: announcement.public_key,
             protocol_version: announcement.protocol_version,
             capabilities: announcement.capabilities,
         };
 
-        let mut peers_map = peers.write().await;
-        peers_map.insert(announcement.peer_id, discovered_peer);
+        table.insert(discovered_peer).await
     }
 
     pub fn is_running(&self) -> bool {
-        tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current().block_on(async { *self.is_running.lock().await })
-        })
+        self.running.load(Ordering::SeqCst)
     }
 
     pub async fn get_discovered_peers(&self) -> Vec<DiscoveredPeer> {
@@ -205,77 +700,38 @@ impl NetworkDiscovery {
     }
 
     pub async fn announce_presence(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(socket) = &self.announcement_socket {
-            let announcement = AnnouncementMessage {
-                peer_id: self.local_peer_id.clone(),
-                peer_name: self.local_peer_name.clone(),
-                port: self.local_port,
-                public_key: self.public_key.clone(),
-                protocol_version: 1,
-                capabilities: vec!["chat".to_string(), "file_transfer".to_string()],
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
+        if let Some(sockets) = &self.sockets {
+            let announcement =
+                Self::fresh_announcement(&self.identity, &self.build_announcement()).await?;
 
             let message_json = serde_json::to_string(&announcement)?;
-            socket
-                .send_to(message_json.as_bytes(), "255.255.255.255:9999")
-                .await?;
+            if Self::send_announcement(sockets, &self.config, message_json.as_bytes()).await == 0 {
+                return Err("No interface accepted the discovery announcement".into());
+            }
         }
         Ok(())
     }
 
-    pub async fn cleanup_old_peers(&self, max_age_seconds: u64) {
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let mut peers = self.discovered_peers.write().await;
-
-        peers.retain(|_, peer| current_time - peer.last_seen < max_age_seconds);
-    }
-
-    pub async fn get_external_ip() -> Result<IpAddr, Box<dyn std::error::Error + Send + Sync>> {
-        let services = [
-            "https://api.ipify.org",
-            "https://ipinfo.io/ip",
-            "https://httpbin.org/ip",
-        ];
-
-        for service in &services {
-            match reqwest::get(*service).await {
-                Ok(response) => {
-                    if let Ok(text) = response.text().await {
-                        if service.contains("httpbin") {
-                            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) {
-                                if let Some(origin) = json.get("origin") {
-                                    if let Some(ip_str) = origin.as_str() {
-                                        let ip_parts: Vec<&str> = ip_str.split(',').collect();
-                                        if let Ok(ip) = ip_parts[0].trim().parse::<IpAddr>() {
-                                            return Ok(ip);
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            let cleaned_text = text.trim();
-                            if let Ok(ip) = cleaned_text.parse::<IpAddr>() {
-                                return Ok(ip);
-                            }
-                        }
-                    }
-                }
-                Err(_) => continue,
-            }
-        }
+    /// Drops peers not heard from in `max_age_seconds`, reporting each as
+    /// lost. Discovery runs this on its own every `peer_timeout / 3`.
+    pub async fn cleanup_old_peers(&self, max_age_seconds: u64) -> Vec<String> {
+        self.peer_table().expire(max_age_seconds, Self::now()).await
+    }
 
-        Err("Failed to determine external IP".into())
+    /// External IP as reported by public HTTP services. Disabled unless
+    /// `allow_http_ip_lookup` is set, since every lookup tells a third party
+    /// that this address runs ShadowGhost; prefer `ReflexiveDiscovery`.
+    pub async fn get_external_ip(
+        &self,
+    ) -> Result<IpAddr, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.config.allow_http_ip_lookup {
+            return Err("HTTP IP lookup is disabled".into());
+        }
+        lookup_external_ip_http().await
     }
 
-    pub async fn test_connectivity() -> bool {
-        Self::get_external_ip().await.is_ok()
+    pub async fn test_connectivity(&self) -> bool {
+        self.get_external_ip().await.is_ok()
     }
 
     pub async fn get_peer_count(&self) -> usize {
@@ -304,15 +760,10 @@ impl NetworkDiscovery {
     pub async fn get_discovery_statistics(&self) -> DiscoveryStatistics {
         let peers = self.discovered_peers.read().await;
         let total_peers = peers.len();
+        let current_time = Self::now();
         let active_peers = peers
             .values()
-            .filter(|p| {
-                let current_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                current_time - p.last_seen < 300
-            })
+            .filter(|p| current_time.saturating_sub(p.last_seen) < 300)
             .count();
 
         let capabilities: std::collections::HashSet<String> = peers
@@ -325,8 +776,18 @@ impl NetworkDiscovery {
             total_discovered: total_peers,
             active_peers,
             unique_capabilities: capabilities.len(),
-            discovery_uptime: 0,
-            last_discovery: chrono::Utc::now(),
+            discovery_uptime: self
+                .started_at
+                .map_or(0, |started_at| started_at.elapsed().as_secs()),
+            last_discovery: *self.last_discovery.read().await,
         }
     }
 }
+
+impl Drop for NetworkDiscovery {
+    fn drop(&mut self) {
+        // Tasks hold their own handles to the sockets and peer table, so
+        // make sure they wind down even without an explicit stop
+        self.shutdown.cancel();
+    }
+}
@@ -1,5 +1,5 @@
 use crate::core::ENGINE;
-use crate::network::{ChatMessage, Contact, NetworkStats};
+use crate::network::{BandwidthConfig, ContactStatus, NetworkStats};
 
 // Для решения проблемы с flutter_rust_bridge, используем feature gate
 #[cfg(feature = "flutter")]
@@ -52,6 +52,78 @@ pub async fn is_network_running() -> Result<bool, String> {
     Ok(engine.network().is_running())
 }
 
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn set_presence_status(status: ContactStatus) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.network().set_presence_status(status).await;
+    Ok(())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn get_presence_status() -> Result<ContactStatus, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.network().get_presence_status().await)
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn report_user_activity() -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.network().record_user_activity().await;
+    Ok(())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn set_auto_away_timeout(seconds: u64) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .network()
+        .set_auto_away_after(std::time::Duration::from_secs(seconds))
+        .await;
+    Ok(())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn send_typing_indicator(
+    contact_id: String,
+    chat_id: String,
+    is_typing: bool,
+) -> Result<bool, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine
+        .network()
+        .send_typing(&contact_id, &chat_id, is_typing)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn set_bandwidth_limits(limits: BandwidthConfig) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.network().set_bandwidth_limits(limits);
+    Ok(())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn get_bandwidth_limits() -> Result<BandwidthConfig, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.network().bandwidth_limits())
+}
+
+/// Set by the app as the device moves between metered and unmetered
+/// networks.
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn set_metered_connection(metered: bool) -> Result<(), String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    engine.network().set_metered(metered).await;
+    Ok(())
+}
+
+#[cfg_attr(feature = "flutter", frb)]
+pub async fn is_metered_connection() -> Result<bool, String> {
+    let engine = ENGINE.get().ok_or("Engine not initialized")?;
+    Ok(engine.network().is_metered())
+}
+
 // Альтернативная реализация без flutter_rust_bridge для тестирования
 #[cfg(not(feature = "flutter"))]
 pub mod fallback {
@@ -79,6 +151,14 @@ pub mod fallback {
             messages_sent: 0,
             messages_received: 0,
             total_connections: 0,
+            payload_bytes_sent: 0,
+            padding_bytes_sent: 0,
+            cover_bytes_sent: 0,
+            connections_refused: 0,
+            messages_dropped: 0,
+            rate_limit_violations: 0,
+            peers_banned: 0,
+            peers_blocked: 0,
         })
     }
 }
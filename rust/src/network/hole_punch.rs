@@ -0,0 +1,805 @@
+use crate::events::{EventBus, NetworkEvent};
+use crate::network::direct_channel::DirectChannel;
+use crate::network::reflexive::ReflexiveDiscovery;
+use crate::network::types::*;
+use if_addrs::IfAddr;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Largest datagram the NAT traversal code sends or expects.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// A UDP-like socket. Implemented for tokio's `UdpSocket` and for the
+/// in-process NAT emulator, so punching can be exercised without real NATs.
+pub trait DatagramSocket: Send + Sync {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr)
+        -> BoxFuture<'a, io::Result<usize>>;
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8])
+        -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(UdpSocket::send_to(self, buf, target))
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+const PACKET_MAGIC: &[u8; 3] = b"SGP";
+
+/// Datagrams exchanged with rendezvous servers and with the remote peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PunchPacket {
+    /// Asks a rendezvous server which address the request came from.
+    Bind {
+        txn: u64,
+    },
+    BindResponse {
+        txn: u64,
+        observed: SocketAddr,
+    },
+    Register {
+        peer_id: String,
+        candidates: Vec<Candidate>,
+    },
+    Registered {
+        observed: SocketAddr,
+    },
+    /// Asks the rendezvous server to introduce `peer_id` to `target`.
+    Introduce {
+        peer_id: String,
+        target: String,
+    },
+    Introduction {
+        peer_id: String,
+        session: u64,
+        candidates: Vec<Candidate>,
+    },
+    UnknownPeer {
+        target: String,
+    },
+    Probe {
+        session: u64,
+    },
+    ProbeAck {
+        session: u64,
+    },
+    Data {
+        session: u64,
+        seq: u64,
+        more: bool,
+        payload: Vec<u8>,
+    },
+    Ack {
+        session: u64,
+        seq: u64,
+    },
+    Keepalive {
+        session: u64,
+    },
+}
+
+impl PunchPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = PACKET_MAGIC.to_vec();
+        match self {
+            PunchPacket::Bind { txn } => {
+                out.push(0);
+                put_u64(&mut out, *txn);
+            }
+            PunchPacket::BindResponse { txn, observed } => {
+                out.push(1);
+                put_u64(&mut out, *txn);
+                put_addr(&mut out, observed);
+            }
+            PunchPacket::Register {
+                peer_id,
+                candidates,
+            } => {
+                out.push(2);
+                put_str(&mut out, peer_id);
+                put_candidates(&mut out, candidates);
+            }
+            PunchPacket::Registered { observed } => {
+                out.push(3);
+                put_addr(&mut out, observed);
+            }
+            PunchPacket::Introduce { peer_id, target } => {
+                out.push(4);
+                put_str(&mut out, peer_id);
+                put_str(&mut out, target);
+            }
+            PunchPacket::Introduction {
+                peer_id,
+                session,
+                candidates,
+            } => {
+                out.push(5);
+                put_str(&mut out, peer_id);
+                put_u64(&mut out, *session);
+                put_candidates(&mut out, candidates);
+            }
+            PunchPacket::UnknownPeer { target } => {
+                out.push(6);
+                put_str(&mut out, target);
+            }
+            PunchPacket::Probe { session } => {
+                out.push(7);
+                put_u64(&mut out, *session);
+            }
+            PunchPacket::ProbeAck { session } => {
+                out.push(8);
+                put_u64(&mut out, *session);
+            }
+            PunchPacket::Data {
+                session,
+                seq,
+                more,
+                payload,
+            } => {
+                out.push(9);
+                put_u64(&mut out, *session);
+                put_u64(&mut out, *seq);
+                out.push(*more as u8);
+                out.extend_from_slice(payload);
+            }
+            PunchPacket::Ack { session, seq } => {
+                out.push(10);
+                put_u64(&mut out, *session);
+                put_u64(&mut out, *seq);
+            }
+            PunchPacket::Keepalive { session } => {
+                out.push(11);
+                put_u64(&mut out, *session);
+            }
+        }
+        out
+    }
+
+    /// Parses a datagram, returning `None` for anything that is not a
+    /// well-formed packet.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader {
+            data: data.strip_prefix(PACKET_MAGIC)?,
+        };
+        let packet = match reader.u8()? {
+            0 => PunchPacket::Bind { txn: reader.u64()? },
+            1 => PunchPacket::BindResponse {
+                txn: reader.u64()?,
+                observed: reader.addr()?,
+            },
+            2 => PunchPacket::Register {
+                peer_id: reader.string()?,
+                candidates: reader.candidates()?,
+            },
+            3 => PunchPacket::Registered {
+                observed: reader.addr()?,
+            },
+            4 => PunchPacket::Introduce {
+                peer_id: reader.string()?,
+                target: reader.string()?,
+            },
+            5 => PunchPacket::Introduction {
+                peer_id: reader.string()?,
+                session: reader.u64()?,
+                candidates: reader.candidates()?,
+            },
+            6 => PunchPacket::UnknownPeer {
+                target: reader.string()?,
+            },
+            7 => PunchPacket::Probe {
+                session: reader.u64()?,
+            },
+            8 => PunchPacket::ProbeAck {
+                session: reader.u64()?,
+            },
+            9 => {
+                return Some(PunchPacket::Data {
+                    session: reader.u64()?,
+                    seq: reader.u64()?,
+                    more: reader.u8()? != 0,
+                    payload: reader.data.to_vec(),
+                })
+            }
+            10 => PunchPacket::Ack {
+                session: reader.u64()?,
+                seq: reader.u64()?,
+            },
+            11 => PunchPacket::Keepalive {
+                session: reader.u64()?,
+            },
+            _ => return None,
+        };
+        reader.data.is_empty().then_some(packet)
+    }
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_str(out: &mut Vec<u8>, value: &str) {
+    let bytes = &value.as_bytes()[..value.len().min(u8::MAX as usize)];
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+}
+
+/// Length-prefixed byte string, at most `u16::MAX` bytes.
+pub(crate) fn put_bytes(out: &mut Vec<u8>, value: &[u8]) {
+    let bytes = &value[..value.len().min(u16::MAX as usize)];
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) fn put_addr(out: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+pub(crate) fn put_candidates(out: &mut Vec<u8>, candidates: &[Candidate]) {
+    let candidates = &candidates[..candidates.len().min(u8::MAX as usize)];
+    out.push(candidates.len() as u8);
+    for candidate in candidates {
+        out.push(match candidate.kind {
+            CandidateKind::Host => 0,
+            CandidateKind::ServerReflexive => 1,
+            CandidateKind::PeerReflexive => 2,
+        });
+        put_addr(out, &candidate.address);
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes(self.take(2)?.try_into().ok()?) as usize;
+        Some(self.take(len)?.to_vec())
+    }
+
+    pub(crate) fn string(&mut self) -> Option<String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    pub(crate) fn addr(&mut self) -> Option<SocketAddr> {
+        let ip = match self.u8()? {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(self.take(4)?).ok()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(self.take(16)?).ok()?)),
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(self.take(2)?.try_into().ok()?);
+        Some(SocketAddr::new(ip, port))
+    }
+
+    pub(crate) fn candidates(&mut self) -> Option<Vec<Candidate>> {
+        let count = self.u8()?;
+        (0..count)
+            .map(|_| {
+                let kind = match self.u8()? {
+                    0 => CandidateKind::Host,
+                    1 => CandidateKind::ServerReflexive,
+                    2 => CandidateKind::PeerReflexive,
+                    _ => return None,
+                };
+                Some(Candidate {
+                    kind,
+                    address: self.addr()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Establishes direct UDP paths to peers behind NATs.
+///
+/// Candidates are gathered locally and from a rendezvous server, exchanged
+/// with the remote peer (over an existing connection or through the
+/// rendezvous server), and then both sides probe every remote candidate at
+/// the same time until one probe is acknowledged. The socket is then handed
+/// to a [`DirectChannel`].
+pub struct HolePuncher {
+    socket: Arc<dyn DatagramSocket>,
+    peer_id: String,
+    config: PunchConfig,
+    event_bus: Option<EventBus>,
+}
+
+impl HolePuncher {
+    pub fn new(socket: Arc<dyn DatagramSocket>, peer_id: String) -> Self {
+        Self {
+            socket,
+            peer_id,
+            config: PunchConfig::default(),
+            event_bus: None,
+        }
+    }
+
+    /// Binds a plain UDP socket for punching.
+    pub async fn bind(addr: SocketAddr, peer_id: String) -> Result<Self, PunchError> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self::new(Arc::new(socket), peer_id))
+    }
+
+    pub fn with_config(mut self, config: PunchConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_event_bus(mut self, event_bus: EventBus) -> Self {
+        self.event_bus = Some(event_bus);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, PunchError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn new_session_id() -> u64 {
+        rand::random()
+    }
+
+    /// Asks a rendezvous server for the public address of this socket.
+    pub async fn server_reflexive(&self, server: SocketAddr) -> Result<SocketAddr, PunchError> {
+        let txn = rand::random();
+        let request = PunchPacket::Bind { txn }.encode();
+        self.request(server, &request, |packet| match packet {
+            PunchPacket::BindResponse {
+                txn: response_txn,
+                observed,
+            } if response_txn == txn => Some(Ok(observed)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Asks several peer mirrors for this socket's public address and
+    /// classifies the NAT. Punching against a symmetric NAT only works if
+    /// the other side is not behind one too.
+    pub async fn discover_reflexive(
+        &self,
+        mirrors: &[SocketAddr],
+        config: ReflexiveConfig,
+    ) -> Result<ReflexiveReport, PunchError> {
+        ReflexiveDiscovery::new(self.socket.clone())
+            .with_config(config)
+            .discover(mirrors)
+            .await
+    }
+
+    /// Host candidates for every local interface the socket listens on,
+    /// plus the server-reflexive address when a rendezvous server is given.
+    pub async fn gather_candidates(
+        &self,
+        server: Option<SocketAddr>,
+    ) -> Result<Vec<Candidate>, PunchError> {
+        let mut candidates = self.host_candidates()?;
+        if let Some(server) = server {
+            let address = self.server_reflexive(server).await?;
+            if !candidates.iter().any(|c| c.address == address) {
+                candidates.push(Candidate {
+                    kind: CandidateKind::ServerReflexive,
+                    address,
+                });
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn host_candidates(&self) -> Result<Vec<Candidate>, PunchError> {
+        let local = self.socket.local_addr()?;
+        let host = |ip| Candidate {
+            kind: CandidateKind::Host,
+            address: SocketAddr::new(ip, local.port()),
+        };
+
+        if !local.ip().is_unspecified() {
+            return Ok(vec![host(local.ip())]);
+        }
+
+        Ok(if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .filter_map(|iface| match iface.addr {
+                IfAddr::V4(addr) if local.is_ipv4() => Some(IpAddr::V4(addr.ip)),
+                IfAddr::V6(addr) if local.is_ipv6() => Some(IpAddr::V6(addr.ip)),
+                _ => None,
+            })
+            .map(host)
+            .collect())
+    }
+
+    /// Probes every remote candidate until one answers, then upgrades the
+    /// socket to a reliable channel. The other side must run `punch` with the
+    /// same session id at roughly the same time.
+    pub async fn punch(
+        self,
+        remote_peer_id: &str,
+        session: u64,
+        remote_candidates: &[Candidate],
+    ) -> Result<DirectChannel, PunchError> {
+        let mut targets: Vec<SocketAddr> = Vec::new();
+        for candidate in remote_candidates {
+            if !targets.contains(&candidate.address) {
+                targets.push(candidate.address);
+            }
+        }
+        if targets.is_empty() {
+            return Err(PunchError::NoCandidates);
+        }
+
+        let probe = PunchPacket::Probe { session }.encode();
+        let probe_ack = PunchPacket::ProbeAck { session }.encode();
+        let deadline = tokio::time::sleep(self.config.punch_timeout);
+        tokio::pin!(deadline);
+        let mut ticker = tokio::time::interval(self.config.probe_interval);
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        let remote = loop {
+            tokio::select! {
+                _ = &mut deadline => return Err(PunchError::Timeout),
+                _ = ticker.tick() => {
+                    for target in &targets {
+                        // Unreachable candidates are expected; keep probing the rest
+                        let _ = self.socket.send_to(&probe, *target).await;
+                    }
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    match PunchPacket::decode(&buffer[..len]) {
+                        Some(PunchPacket::Probe { session: s }) if s == session => {
+                            let _ = self.socket.send_to(&probe_ack, from).await;
+                            // The probe came through a mapping we did not know
+                            // about; answer there from now on as well
+                            if !targets.contains(&from) {
+                                targets.push(from);
+                            }
+                        }
+                        Some(PunchPacket::ProbeAck { session: s }) if s == session => break from,
+                        _ => {}
+                    }
+                }
+            }
+        };
+
+        if let Some(event_bus) = &self.event_bus {
+            event_bus.emit_network(NetworkEvent::DirectPathEstablished {
+                peer_id: remote_peer_id.to_string(),
+                address: remote.to_string(),
+            });
+        }
+
+        Ok(DirectChannel::open(
+            self.socket,
+            remote_peer_id.to_string(),
+            session,
+            remote,
+            self.config,
+        ))
+    }
+
+    /// Registers with a rendezvous server, asks it to introduce `target`, and
+    /// punches to the candidates it hands back.
+    pub async fn connect_via_rendezvous(
+        self,
+        server: SocketAddr,
+        target: &str,
+    ) -> Result<DirectChannel, PunchError> {
+        self.register(server).await?;
+
+        let request = PunchPacket::Introduce {
+            peer_id: self.peer_id.clone(),
+            target: target.to_string(),
+        }
+        .encode();
+        let (peer_id, session, candidates) = self
+            .request(server, &request, |packet| match packet {
+                PunchPacket::Introduction {
+                    peer_id,
+                    session,
+                    candidates,
+                } if peer_id == target => Some(Ok((peer_id, session, candidates))),
+                PunchPacket::UnknownPeer { target: unknown } if unknown == target => {
+                    Some(Err(PunchError::UnknownPeer(unknown)))
+                }
+                _ => None,
+            })
+            .await?;
+
+        self.punch(&peer_id, session, &candidates).await
+    }
+
+    /// Stays registered with a rendezvous server until another peer asks to
+    /// be introduced, then punches to it. Waits indefinitely.
+    pub async fn accept_via_rendezvous(
+        self,
+        server: SocketAddr,
+    ) -> Result<DirectChannel, PunchError> {
+        let register = PunchPacket::Register {
+            peer_id: self.peer_id.clone(),
+            candidates: self.host_candidates()?,
+        }
+        .encode();
+        let mut ticker = tokio::time::interval(self.config.keepalive_interval);
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        let (peer_id, session, candidates) = loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    self.socket.send_to(&register, server).await?;
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    if from != server {
+                        continue;
+                    }
+                    if let Some(PunchPacket::Introduction { peer_id, session, candidates }) =
+                        PunchPacket::decode(&buffer[..len])
+                    {
+                        break (peer_id, session, candidates);
+                    }
+                }
+            }
+        };
+
+        self.punch(&peer_id, session, &candidates).await
+    }
+
+    async fn register(&self, server: SocketAddr) -> Result<SocketAddr, PunchError> {
+        let request = PunchPacket::Register {
+            peer_id: self.peer_id.clone(),
+            candidates: self.host_candidates()?,
+        }
+        .encode();
+        self.request(server, &request, |packet| match packet {
+            PunchPacket::Registered { observed } => Some(Ok(observed)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Sends `request` to `server` until `accept` recognises a reply, resending
+    /// every probe interval and giving up after the punch timeout.
+    async fn request<T>(
+        &self,
+        server: SocketAddr,
+        request: &[u8],
+        accept: impl Fn(PunchPacket) -> Option<Result<T, PunchError>>,
+    ) -> Result<T, PunchError> {
+        let deadline = Instant::now() + self.config.punch_timeout;
+        let mut ticker = tokio::time::interval(self.config.probe_interval * 5);
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => return Err(PunchError::Timeout),
+                _ = ticker.tick() => {
+                    self.socket.send_to(request, server).await?;
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    if from != server {
+                        continue;
+                    }
+                    if let Some(result) = PunchPacket::decode(&buffer[..len]).and_then(&accept) {
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Registration {
+    observed: SocketAddr,
+    candidates: Vec<Candidate>,
+}
+
+/// A rendezvous peer: tells clients their public address and introduces
+/// registered peers to each other so they can punch.
+pub struct RendezvousServer {
+    socket: Arc<UdpSocket>,
+    registrations: Arc<RwLock<HashMap<String, Registration>>>,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl RendezvousServer {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, PunchError> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            registrations: Arc::new(RwLock::new(HashMap::new())),
+            shutdown: CancellationToken::new(),
+            task: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, PunchError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn start(&mut self) {
+        if self.task.is_some() {
+            return;
+        }
+
+        let socket = self.socket.clone();
+        let registrations = self.registrations.clone();
+        let shutdown = self.shutdown.clone();
+        self.task = Some(tokio::spawn(async move {
+            Self::serve(socket, registrations, shutdown).await;
+        }));
+    }
+
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    pub async fn registered_peers(&self) -> Vec<String> {
+        self.registrations.read().await.keys().cloned().collect()
+    }
+
+    async fn serve(
+        socket: Arc<UdpSocket>,
+        registrations: Arc<RwLock<HashMap<String, Registration>>>,
+        shutdown: CancellationToken,
+    ) {
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, from) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = socket.recv_from(&mut buffer) => match received {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+            };
+
+            let reply = match PunchPacket::decode(&buffer[..len]) {
+                Some(PunchPacket::Bind { txn }) => PunchPacket::BindResponse {
+                    txn,
+                    observed: from,
+                },
+                Some(PunchPacket::Register {
+                    peer_id,
+                    candidates,
+                }) => {
+                    registrations.write().await.insert(
+                        peer_id,
+                        Registration {
+                            observed: from,
+                            candidates,
+                        },
+                    );
+                    PunchPacket::Registered { observed: from }
+                }
+                Some(PunchPacket::Introduce { peer_id, target }) => {
+                    let registrations = registrations.read().await;
+                    // Only the address that registered a peer id may ask on its behalf
+                    let requester = match registrations.get(&peer_id) {
+                        Some(requester) if requester.observed == from => requester,
+                        _ => continue,
+                    };
+                    let target_registration = match registrations.get(&target) {
+                        Some(target_registration) => target_registration,
+                        None => {
+                            let _ = socket
+                                .send_to(&PunchPacket::UnknownPeer { target }.encode(), from)
+                                .await;
+                            continue;
+                        }
+                    };
+
+                    let session = HolePuncher::new_session_id();
+                    let introduction = PunchPacket::Introduction {
+                        peer_id,
+                        session,
+                        candidates: Self::reachable_at(requester),
+                    };
+                    let _ = socket
+                        .send_to(&introduction.encode(), target_registration.observed)
+                        .await;
+
+                    PunchPacket::Introduction {
+                        peer_id: target,
+                        session,
+                        candidates: Self::reachable_at(target_registration),
+                    }
+                }
+                _ => continue,
+            };
+
+            let _ = socket.send_to(&reply.encode(), from).await;
+        }
+    }
+
+    /// A registered peer's own candidates plus the address it registered from.
+    fn reachable_at(registration: &Registration) -> Vec<Candidate> {
+        let mut candidates = registration.candidates.clone();
+        if !candidates
+            .iter()
+            .any(|c| c.address == registration.observed)
+        {
+            candidates.push(Candidate {
+                kind: CandidateKind::ServerReflexive,
+                address: registration.observed,
+            });
+        }
+        candidates
+    }
+}
+
+impl Drop for RendezvousServer {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
@@ -0,0 +1,101 @@
+use crate::network::types::{KeepaliveConfig, LatencyStats};
+use std::time::Duration;
+
+/// How long [`NetworkManager::ping`](crate::network::NetworkManager::ping)
+/// waits for the pong.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keepalive state of one connection: the pings sent over it, how many in a
+/// row went unanswered and the latency measured from the pongs.
+#[derive(Debug)]
+pub struct Keepalive {
+    config: KeepaliveConfig,
+    next_sequence: u64,
+    acknowledged: u64,
+    missed: u32,
+    latency: Option<LatencyStats>,
+}
+
+impl Keepalive {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        Self {
+            config,
+            next_sequence: 1,
+            acknowledged: 0,
+            missed: 0,
+            latency: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Sequence number for a ping sent now.
+    pub fn take_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        sequence
+    }
+
+    /// Called every interval. Counts the previous ping as missed if it is
+    /// still unanswered and returns the sequence of the next one, or `None`
+    /// once too many were missed and the connection should be dropped.
+    pub fn next_ping(&mut self) -> Option<u64> {
+        if self.acknowledged + 1 < self.next_sequence {
+            self.missed += 1;
+        }
+        if self.missed >= self.config.max_missed {
+            return None;
+        }
+        Some(self.take_sequence())
+    }
+
+    /// Times the pong to ping `sequence`, which was sent at `sent_at_ms`.
+    /// Pongs to pings never sent, or older than one already answered, are
+    /// ignored.
+    pub fn record_pong(
+        &mut self,
+        sequence: u64,
+        sent_at_ms: u64,
+        now_ms: u64,
+    ) -> Option<&LatencyStats> {
+        if sequence <= self.acknowledged || sequence >= self.next_sequence {
+            return None;
+        }
+        self.acknowledged = sequence;
+        self.missed = 0;
+
+        let rtt = now_ms.saturating_sub(sent_at_ms);
+        let latency = match self.latency.take() {
+            Some(previous) => {
+                let rtt_ms = rtt as f64;
+                let delta = (rtt_ms - previous.last_rtt_ms as f64).abs();
+                LatencyStats {
+                    last_rtt_ms: rtt,
+                    smoothed_rtt_ms: previous.smoothed_rtt_ms
+                        + (rtt_ms - previous.smoothed_rtt_ms) / 8.0,
+                    jitter_ms: previous.jitter_ms + (delta - previous.jitter_ms) / 16.0,
+                    samples: previous.samples + 1,
+                }
+            }
+            None => LatencyStats {
+                last_rtt_ms: rtt,
+                smoothed_rtt_ms: rtt as f64,
+                jitter_ms: 0.0,
+                samples: 1,
+            },
+        };
+        self.latency = Some(latency);
+        self.latency.as_ref()
+    }
+
+    pub fn latency(&self) -> Option<&LatencyStats> {
+        self.latency.as_ref()
+    }
+
+    /// Pings in a row that went unanswered.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
@@ -0,0 +1,199 @@
+use crate::network::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MailboxState {
+    served: HashSet<String>,
+    envelopes: HashMap<String, Vec<SealedEnvelope>>,
+}
+
+/// Store-and-forward storage kept by an always-on peer for contacts that
+/// are often offline. Only recipients the owner agreed to serve can receive
+/// mail, and every recipient gets its own count and size quota.
+///
+/// Envelopes are opaque: they were sealed for the recipient, so the host can
+/// drop or delay them, but cannot read or forge them.
+pub struct Mailbox {
+    config: MailboxConfig,
+    path: Option<PathBuf>,
+    state: MailboxState,
+}
+
+impl Mailbox {
+    /// A mailbox that only lives in memory.
+    pub fn new(config: MailboxConfig) -> Self {
+        Self {
+            config,
+            path: None,
+            state: MailboxState::default(),
+        }
+    }
+
+    /// A mailbox persisted as JSON at `path`, loading what is already there.
+    pub fn open(path: &Path, config: MailboxConfig) -> Result<Self, MailboxError> {
+        let state = if path.exists() {
+            let data =
+                std::fs::read_to_string(path).map_err(|e| MailboxError::Storage(e.to_string()))?;
+            serde_json::from_str(&data).map_err(|e| MailboxError::Storage(e.to_string()))?
+        } else {
+            MailboxState::default()
+        };
+
+        Ok(Self {
+            config,
+            path: Some(path.to_path_buf()),
+            state,
+        })
+    }
+
+    pub fn config(&self) -> &MailboxConfig {
+        &self.config
+    }
+
+    pub fn serve(&mut self, recipient_id: &str) -> Result<(), MailboxError> {
+        if self.state.served.insert(recipient_id.to_string()) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Stops accepting mail for a recipient and drops what is held for them.
+    pub fn stop_serving(&mut self, recipient_id: &str) -> Result<(), MailboxError> {
+        let served = self.state.served.remove(recipient_id);
+        let held = self.state.envelopes.remove(recipient_id).is_some();
+        if served || held {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn serves(&self, recipient_id: &str) -> bool {
+        self.state.served.contains(recipient_id)
+    }
+
+    pub fn served_recipients(&self) -> Vec<String> {
+        self.state.served.iter().cloned().collect()
+    }
+
+    /// Stores an envelope until its recipient collects it. Envelopes that ask
+    /// to be kept longer than `max_ttl` are shortened; depositing the same
+    /// envelope twice is not an error.
+    pub fn deposit(&mut self, mut envelope: SealedEnvelope, now: u64) -> Result<(), MailboxError> {
+        if !self.serves(&envelope.recipient_id) {
+            return Err(MailboxError::NotServed(envelope.recipient_id));
+        }
+        if envelope.size() > self.config.max_envelope_size {
+            return Err(MailboxError::TooLarge);
+        }
+        if envelope.expires_at <= now {
+            return Err(MailboxError::Expired);
+        }
+        envelope.expires_at = envelope.expires_at.min(now + self.config.max_ttl.as_secs());
+
+        self.drop_expired(now);
+
+        let held = self
+            .state
+            .envelopes
+            .get(&envelope.recipient_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if held.iter().any(|e| e.id == envelope.id) {
+            return Ok(());
+        }
+
+        let held_bytes: u64 = held.iter().map(SealedEnvelope::size).sum();
+        if held.len() >= self.config.max_envelopes_per_recipient
+            || held_bytes + envelope.size() > self.config.max_bytes_per_recipient
+            || self.stored_bytes() + envelope.size() > self.config.max_total_bytes
+        {
+            return Err(MailboxError::Full);
+        }
+
+        self.state
+            .envelopes
+            .entry(envelope.recipient_id.clone())
+            .or_default()
+            .push(envelope);
+        self.save()
+    }
+
+    /// Unexpired envelopes held for a recipient, oldest first. They stay
+    /// stored until the recipient acknowledges them.
+    pub fn pending_for(&self, recipient_id: &str, now: u64) -> Vec<SealedEnvelope> {
+        self.state
+            .envelopes
+            .get(recipient_id)
+            .map(|held| {
+                held.iter()
+                    .filter(|e| e.expires_at > now)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes envelopes the recipient confirmed, returning how many were held.
+    pub fn acknowledge(
+        &mut self,
+        recipient_id: &str,
+        envelope_ids: &[String],
+    ) -> Result<usize, MailboxError> {
+        let removed = match self.state.envelopes.get_mut(recipient_id) {
+            Some(held) => {
+                let before = held.len();
+                held.retain(|e| !envelope_ids.contains(&e.id));
+                before - held.len()
+            }
+            None => 0,
+        };
+        self.state.envelopes.retain(|_, held| !held.is_empty());
+
+        if removed > 0 {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn purge_expired(&mut self, now: u64) -> Result<usize, MailboxError> {
+        let removed = self.drop_expired(now);
+        if removed > 0 {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn envelope_count(&self) -> usize {
+        self.state.envelopes.values().map(Vec::len).sum()
+    }
+
+    pub fn stored_bytes(&self) -> u64 {
+        self.state
+            .envelopes
+            .values()
+            .flatten()
+            .map(SealedEnvelope::size)
+            .sum()
+    }
+
+    fn drop_expired(&mut self, now: u64) -> usize {
+        let before = self.envelope_count();
+        for held in self.state.envelopes.values_mut() {
+            held.retain(|e| e.expires_at > now);
+        }
+        self.state.envelopes.retain(|_, held| !held.is_empty());
+        before - self.envelope_count()
+    }
+
+    fn save(&self) -> Result<(), MailboxError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data =
+            serde_json::to_string(&self.state).map_err(|e| MailboxError::Storage(e.to_string()))?;
+        std::fs::write(path, data).map_err(|e| MailboxError::Storage(e.to_string()))
+    }
+}
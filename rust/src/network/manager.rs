@@ -1,11 +1,59 @@
 use crate::core::Peer;
-use crate::events::EventBus;
+use crate::crypto::CryptoManager;
+use crate::events::{AppEvent, EventBus, NetworkEvent};
+use crate::network::auth::{self, KeyShare, Transcript, AUTH_TIMEOUT};
+use crate::network::bandwidth::{BandwidthLimiter, TrafficDirection};
+use crate::network::keepalive::{Keepalive, PING_TIMEOUT};
+use crate::network::mailbox::Mailbox;
+use crate::network::obfuscation::{ObfuscatedLink, TrafficObfuscator};
+use crate::network::outbox::Outbox;
+use crate::network::presence::{PresenceTracker, IDLE_CHECK_INTERVAL};
+use crate::network::protocol::{AuthPayload, MailboxPayload, MessagePayload, ProtocolMessage};
+use crate::network::rate_limit::{RateLimitStats, RateLimiter};
+use crate::network::sequencing::Sequencer;
+use crate::network::transport::{Connection, Listener, TcpTransport, Transport};
 use crate::network::types::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::task::JoinHandle;
+
+type ConnectionMap = HashMap<String, mpsc::UnboundedSender<ProtocolMessage>>;
+type KeepaliveMap = HashMap<String, KeepaliveEntry>;
+
+/// Keepalive state of a connection, and the task pinging over it.
+struct KeepaliveEntry {
+    state: Keepalive,
+    task: JoinHandle<()>,
+}
+
+const MAX_RECENT_MESSAGE_IDS: usize = 10_000;
+
+/// Ids of recently received messages, so a message that arrives both
+/// directly and through a mailbox is delivered once.
+#[derive(Default)]
+struct RecentIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl RecentIds {
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > MAX_RECENT_MESSAGE_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
 
 #[derive(Debug)]
 pub enum NetworkError {
@@ -30,18 +78,35 @@ pub struct NetworkManager {
     peer: Peer,
     event_bus: EventBus,
     is_active: bool,
-    connected_peers: HashMap<String, PeerData>,
+    connected_peers: Arc<Mutex<HashMap<String, PeerData>>>,
     stats: NetworkStats,
     chats: Arc<RwLock<HashMap<String, Vec<ChatMessage>>>>,
+    connections: Arc<RwLock<ConnectionMap>>,
+    presence: Arc<RwLock<PresenceTracker>>,
+    presence_task: Option<JoinHandle<()>>,
+    identity: Option<Arc<RwLock<CryptoManager>>>,
+    peer_keys: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    outbox: Arc<RwLock<Outbox>>,
+    mailbox: Option<Arc<RwLock<Mailbox>>>,
+    own_mailboxes: Arc<RwLock<Vec<String>>>,
+    peer_mailboxes: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    received: Arc<RwLock<RecentIds>>,
+    obfuscator: Arc<TrafficObfuscator>,
+    transports: HashMap<&'static str, Arc<dyn Transport>>,
+    keepalive: KeepaliveConfig,
+    keepalives: Arc<RwLock<KeepaliveMap>>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    bandwidth: Arc<Mutex<BandwidthLimiter>>,
+    sequencer: Arc<Mutex<Sequencer>>,
 }
 
 impl NetworkManager {
     pub fn new(peer: Peer, event_bus: EventBus) -> Result<Self, NetworkError> {
-        Ok(Self {
+        let mut manager = Self {
             peer,
             event_bus,
             is_active: false,
-            connected_peers: HashMap::new(),
+            connected_peers: Arc::new(Mutex::new(HashMap::new())),
             stats: NetworkStats {
                 connected_peers: 0,
                 total_messages_sent: 0,
@@ -52,9 +117,38 @@ impl NetworkManager {
                 messages_sent: 0,
                 messages_received: 0,
                 total_connections: 0,
+                payload_bytes_sent: 0,
+                padding_bytes_sent: 0,
+                cover_bytes_sent: 0,
+                connections_refused: 0,
+                messages_dropped: 0,
+                rate_limit_violations: 0,
+                peers_banned: 0,
+                peers_blocked: 0,
             },
             chats: Arc::new(RwLock::new(HashMap::new())),
-        })
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            presence: Arc::new(RwLock::new(PresenceTracker::default())),
+            presence_task: None,
+            identity: None,
+            peer_keys: Arc::new(RwLock::new(HashMap::new())),
+            outbox: Arc::new(RwLock::new(Outbox::new())),
+            mailbox: None,
+            own_mailboxes: Arc::new(RwLock::new(Vec::new())),
+            peer_mailboxes: Arc::new(RwLock::new(HashMap::new())),
+            received: Arc::new(RwLock::new(RecentIds::default())),
+            obfuscator: Arc::new(TrafficObfuscator::new(ObfuscationConfig::disabled())),
+            transports: HashMap::new(),
+            keepalive: KeepaliveConfig::default(),
+            keepalives: Arc::new(RwLock::new(HashMap::new())),
+            limiter: Arc::new(Mutex::new(RateLimiter::new(RateLimitConfig::default()))),
+            bandwidth: Arc::new(Mutex::new(
+                BandwidthLimiter::new(BandwidthConfig::default()),
+            )),
+            sequencer: Arc::new(Mutex::new(Sequencer::new(SequencingConfig::default()))),
+        };
+        manager.register_transport(Arc::new(TcpTransport::new()));
+        Ok(manager)
     }
 
     pub fn new_default() -> Result<Self, NetworkError> {
@@ -69,9 +163,29 @@ impl NetworkManager {
     }
 
     pub fn stop(&mut self) -> Result<(), NetworkError> {
+        if let Some(task) = self.presence_task.take() {
+            task.abort();
+        }
+
+        // Best effort: let connected peers know we are going away
+        if let Ok(connections) = self.connections.try_read() {
+            for (peer_id, sender) in connections.iter() {
+                let _ = sender.send(ProtocolMessage::create_presence(
+                    self.peer.id.clone(),
+                    peer_id.clone(),
+                    ContactStatus::Offline,
+                ));
+            }
+        }
+
+        if let Ok(mut keepalives) = self.keepalives.try_write() {
+            for (_, entry) in keepalives.drain() {
+                entry.task.abort();
+            }
+        }
+
         self.is_active = false;
-        self.connected_peers.clear();
-        self.stats.connected_peers = 0;
+        self.connected_peers.lock().unwrap().clear();
         Ok(())
     }
 
@@ -87,6 +201,16 @@ impl NetworkManager {
         self.peer.clone()
     }
 
+    /// Identity key we are known by, handed out in our SG link.
+    pub fn set_public_key(&mut self, public_key: Vec<u8>) {
+        self.peer.public_key = public_key;
+    }
+
+    /// The onion address shared in our SG link, while one is published.
+    pub fn set_onion_address(&mut self, onion_address: Option<String>) {
+        self.peer.set_onion_address(onion_address);
+    }
+
     pub async fn start_server(&mut self) -> Result<(), NetworkError> {
         self.is_active = true;
         Ok(())
@@ -97,11 +221,33 @@ impl NetworkManager {
     }
 
     pub fn get_stats(&self) -> Result<NetworkStats, NetworkError> {
-        Ok(self.stats.clone())
+        Ok(self.current_stats())
     }
 
     pub async fn get_network_stats(&self) -> Result<NetworkStats, NetworkError> {
-        Ok(self.stats.clone())
+        Ok(self.current_stats())
+    }
+
+    fn current_stats(&self) -> NetworkStats {
+        let obfuscation = self.obfuscator.stats();
+        let limits = self.rate_limit_stats();
+        let traffic = self.bandwidth.lock().unwrap().traffic();
+        let connected = self.get_peer_count() as u32;
+        NetworkStats {
+            connected_peers: connected,
+            total_connections: connected,
+            bytes_sent: self.stats.bytes_sent + traffic.sent,
+            bytes_received: self.stats.bytes_received + traffic.received,
+            payload_bytes_sent: self.stats.payload_bytes_sent + obfuscation.payload_bytes,
+            padding_bytes_sent: self.stats.padding_bytes_sent + obfuscation.padding_bytes,
+            cover_bytes_sent: self.stats.cover_bytes_sent + obfuscation.cover_bytes,
+            connections_refused: limits.connections_refused,
+            messages_dropped: limits.messages_dropped,
+            rate_limit_violations: limits.violations,
+            peers_banned: limits.bans,
+            peers_blocked: limits.blocks,
+            ..self.stats.clone()
+        }
     }
 
     pub async fn send_chat_message(
@@ -126,11 +272,24 @@ impl NetworkManager {
                 .unwrap()
                 .as_secs(),
             delivery_status: DeliveryStatus::Sent,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: 0,
         };
 
         let mut chats = self.chats.write().await;
         let chat_key = format!("chat_{}", contact.name);
         chats.entry(chat_key).or_insert_with(Vec::new).push(message);
+        drop(chats);
+
+        let mut text = ProtocolMessage::create_text_message(
+            self.peer.id.clone(),
+            contact.id.clone(),
+            content.to_string(),
+            message_id.clone(),
+        );
+        self.sequencer.lock().unwrap().stamp(&mut text);
+        self.send_or_queue(text).await?;
 
         Ok(message_id)
     }
@@ -157,11 +316,24 @@ impl NetworkManager {
                 .unwrap()
                 .as_secs(),
             delivery_status: DeliveryStatus::Sent,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: 0,
         };
 
         let mut chats = self.chats.write().await;
         let chat_key = format!("chat_{}", contact_name);
         chats.entry(chat_key).or_insert_with(Vec::new).push(message);
+        drop(chats);
+
+        let mut text = ProtocolMessage::create_text_message(
+            self.peer.id.clone(),
+            self.resolve_peer_id(contact_name),
+            content.to_string(),
+            message_id.clone(),
+        );
+        self.sequencer.lock().unwrap().stamp(&mut text);
+        self.send_or_queue(text).await?;
 
         self.stats.total_messages_sent += 1;
         self.stats.messages_sent += 1;
@@ -188,31 +360,80 @@ impl NetworkManager {
     }
 
     pub fn get_connected_peers(&self) -> Vec<PeerData> {
-        self.connected_peers.values().cloned().collect()
+        let keepalives = self.keepalives.try_read().ok();
+        let bandwidth = self.bandwidth.lock().unwrap();
+        self.connected_peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|peer| PeerData {
+                bytes_sent: peer.bytes_sent + bandwidth.peer_traffic(&peer.id).sent,
+                bytes_received: peer.bytes_received + bandwidth.peer_traffic(&peer.id).received,
+                latency: keepalives
+                    .as_ref()
+                    .and_then(|k| k.get(&peer.id))
+                    .and_then(|entry| entry.state.latency().cloned())
+                    .or_else(|| peer.latency.clone()),
+                ..peer.clone()
+            })
+            .collect()
     }
 
-    pub fn add_peer(&mut self, peer_data: PeerData) {
-        self.connected_peers.insert(peer_data.id.clone(), peer_data);
-        self.stats.connected_peers = self.connected_peers.len() as u32;
-        self.stats.total_connections = self.stats.connected_peers;
+    pub fn add_peer(&self, peer_data: PeerData) {
+        self.connected_peers
+            .lock()
+            .unwrap()
+            .insert(peer_data.id.clone(), peer_data);
     }
 
-    pub fn remove_peer(&mut self, peer_id: &str) {
-        self.connected_peers.remove(peer_id);
-        self.stats.connected_peers = self.connected_peers.len() as u32;
-        self.stats.total_connections = self.stats.connected_peers;
+    pub fn remove_peer(&self, peer_id: &str) {
+        self.connected_peers.lock().unwrap().remove(peer_id);
     }
 
-    pub fn get_peer_by_id(&self, peer_id: &str) -> Option<&PeerData> {
-        self.connected_peers.get(peer_id)
+    pub fn get_peer_by_id(&self, peer_id: &str) -> Option<PeerData> {
+        self.connected_peers.lock().unwrap().get(peer_id).cloned()
     }
 
     pub fn is_peer_connected(&self, peer_id: &str) -> bool {
-        self.connected_peers.contains_key(peer_id)
+        self.connected_peers.lock().unwrap().contains_key(peer_id)
     }
 
     pub fn get_peer_count(&self) -> usize {
-        self.connected_peers.len()
+        self.connected_peers.lock().unwrap().len()
+    }
+
+    /// Records a peer as connected from now on. A name given earlier
+    /// through `add_peer` is kept; otherwise the peer goes by its id.
+    async fn record_peer(&self, peer_id: &str, address: Option<String>) {
+        let public_key = self
+            .peer_keys
+            .read()
+            .await
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_default();
+        let now = chrono::Utc::now();
+        let mut peers = self.connected_peers.lock().unwrap();
+        let previous = peers.remove(peer_id);
+        let (name, known_address, onion_address) = match previous {
+            Some(peer) => (peer.name, peer.address, peer.onion_address),
+            None => (peer_id.to_string(), String::new(), None),
+        };
+        peers.insert(
+            peer_id.to_string(),
+            PeerData {
+                id: peer_id.to_string(),
+                name,
+                address: address.unwrap_or(known_address),
+                public_key,
+                connected_at: now,
+                last_seen: now,
+                bytes_sent: 0,
+                bytes_received: 0,
+                onion_address,
+                latency: None,
+            },
+        );
     }
 
     pub fn update_stats(&mut self, bytes_sent: u64, bytes_received: u64) {
@@ -231,8 +452,9 @@ impl NetworkManager {
     }
 
     pub fn reset_stats(&mut self) {
+        self.bandwidth.lock().unwrap().reset_traffic();
         self.stats = NetworkStats {
-            connected_peers: self.connected_peers.len() as u32,
+            connected_peers: 0,
             total_messages_sent: 0,
             total_messages_received: 0,
             bytes_sent: 0,
@@ -240,10 +462,1505 @@ impl NetworkManager {
             uptime_seconds: 0,
             messages_sent: 0,
             messages_received: 0,
-            total_connections: self.connected_peers.len() as u32,
+            total_connections: 0,
+            payload_bytes_sent: 0,
+            padding_bytes_sent: 0,
+            cover_bytes_sent: 0,
+            connections_refused: 0,
+            messages_dropped: 0,
+            rate_limit_violations: 0,
+            peers_banned: 0,
+            peers_blocked: 0,
         };
     }
 
+    pub async fn attach_connection(
+        &self,
+        peer_id: &str,
+    ) -> mpsc::UnboundedReceiver<ProtocolMessage> {
+        self.attach(peer_id, None).await
+    }
+
+    async fn attach(
+        &self,
+        peer_id: &str,
+        address: Option<String>,
+    ) -> mpsc::UnboundedReceiver<ProtocolMessage> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.record_peer(peer_id, address).await;
+
+        // Announce our presence as soon as the connection is established
+        let status = self.presence.read().await.effective_status();
+        let _ = sender.send(ProtocolMessage::create_presence(
+            self.peer.id.clone(),
+            peer_id.to_string(),
+            status,
+        ));
+
+        let mailboxes = self.own_mailboxes.read().await.clone();
+        if !mailboxes.is_empty() {
+            let _ = sender.send(ProtocolMessage::create_mailbox(
+                self.peer.id.clone(),
+                peer_id.to_string(),
+                MailboxPayload::Advertise { mailboxes },
+            ));
+        }
+
+        self.start_keepalive(peer_id, &sender).await;
+        self.connections
+            .write()
+            .await
+            .insert(peer_id.to_string(), sender);
+
+        // Hand over what waited for this peer, and what waits for others
+        // that keep their mail with it
+        for message in self.outbox.write().await.take(peer_id) {
+            self.send_on_connection(message).await;
+        }
+        let recipients: Vec<String> = self
+            .peer_mailboxes
+            .read()
+            .await
+            .iter()
+            .filter(|(_, mailboxes)| mailboxes.iter().any(|m| m == peer_id))
+            .map(|(recipient, _)| recipient.clone())
+            .collect();
+        for recipient in recipients {
+            self.deposit_queued(&recipient).await;
+        }
+        self.deliver_mail(peer_id).await;
+
+        receiver
+    }
+
+    /// Padding, cover traffic, batching and TLS masking for connections
+    /// served from now on.
+    pub fn set_obfuscation(&mut self, obfuscator: TrafficObfuscator) {
+        self.stats = self.current_stats();
+        obfuscator.set_metered(self.is_metered());
+        self.obfuscator = Arc::new(obfuscator);
+    }
+
+    pub fn obfuscator(&self) -> Arc<TrafficObfuscator> {
+        self.obfuscator.clone()
+    }
+
+    /// Makes `transport` handle the endpoints of its schemes, replacing
+    /// whichever transport handled them before. TCP is registered from the
+    /// start.
+    pub fn register_transport(&mut self, transport: Arc<dyn Transport>) {
+        for scheme in transport.schemes() {
+            self.transports.insert(scheme, transport.clone());
+        }
+    }
+
+    pub fn transport_for(&self, endpoint: &Endpoint) -> Option<Arc<dyn Transport>> {
+        self.transports.get(endpoint.scheme()).cloned()
+    }
+
+    pub async fn listen(&self, endpoint: &Endpoint) -> Result<Box<dyn Listener>, TransportError> {
+        match self.transport_for(endpoint) {
+            Some(transport) => transport.listen(endpoint).await,
+            None => Err(TransportError::UnsupportedScheme(
+                endpoint.scheme().to_string(),
+            )),
+        }
+    }
+
+    /// Dials `endpoints` in order and returns the first connection made.
+    /// Endpoints without a registered transport are skipped.
+    pub async fn dial(&self, endpoints: &[Endpoint]) -> Result<Connection, TransportError> {
+        let mut last_error = TransportError::InvalidEndpoint("No endpoints".to_string());
+        for endpoint in endpoints {
+            let result = match self.transport_for(endpoint) {
+                Some(transport) => transport.dial(endpoint).await,
+                None => Err(TransportError::UnsupportedScheme(
+                    endpoint.scheme().to_string(),
+                )),
+            };
+            match result {
+                Ok(connection) => return Ok(connection),
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Authenticates `peer_id` over a connection we dialed, unless that is
+    /// done already, and carries its traffic until either side closes it
+    /// or the peer is detached. Messages over the rate limits are dropped,
+    /// and the connection is closed once the peer is banned or sends a
+    /// malformed frame. Either direction pauses as long as it takes to
+    /// stay within the bandwidth caps.
+    pub async fn serve_connection(
+        &self,
+        peer_id: &str,
+        mut connection: Connection,
+    ) -> Result<(), TransportError> {
+        if self.is_peer_blocked(peer_id) {
+            return Err(TransportError::Refused(RateLimitError::Blocked));
+        }
+        if connection.authenticated_peer() != Some(peer_id) {
+            self.authenticate(peer_id, &mut connection).await?;
+        }
+        self.serve(peer_id, connection).await;
+        Ok(())
+    }
+
+    /// Proves who we are to the peer we dialed, checks that it holds the
+    /// identity key we know for `peer_id`, and encrypts the connection with
+    /// the key agreed on along the way.
+    pub async fn authenticate(
+        &self,
+        peer_id: &str,
+        connection: &mut Connection,
+    ) -> Result<(), TransportError> {
+        let peer_key = self.known_key(peer_id).await?;
+        connection.set_obfuscator(self.obfuscator.clone());
+        let nonce = auth::new_nonce();
+        let share = KeyShare::new();
+        let our_share = share.public();
+        self.send_auth(
+            connection,
+            Some(peer_id),
+            peer_id,
+            AuthPayload::Hello {
+                nonce: nonce.clone(),
+                share: our_share.clone(),
+            },
+        )
+        .await?;
+
+        let (challenge, their_share, signature) =
+            match self.recv_auth(connection, Some(peer_id)).await? {
+                (
+                    sender,
+                    AuthPayload::Challenge {
+                        nonce,
+                        share,
+                        signature,
+                    },
+                ) if sender == peer_id
+                    && nonce.len() == auth::NONCE_LEN
+                    && share.len() == auth::SHARE_LEN =>
+                {
+                    (nonce, share, signature)
+                }
+                _ => return Err(unauthenticated("Expected a challenge")),
+            };
+        let transcript = Transcript {
+            dialer_id: &self.peer.id,
+            acceptor_id: peer_id,
+            dialer_share: &our_share,
+            acceptor_share: &their_share,
+        };
+        if !auth::verify(
+            &peer_key,
+            &auth::accept_context(&nonce, &transcript),
+            &signature,
+        ) {
+            return Err(unauthenticated("Bad challenge signature"));
+        }
+        let session = share.session(&transcript, true)?;
+
+        let signature = self
+            .sign_auth(&auth::dial_context(&challenge, &transcript))
+            .await?;
+        self.send_auth(
+            connection,
+            Some(peer_id),
+            peer_id,
+            AuthPayload::Proof { signature },
+        )
+        .await?;
+        connection.set_session(session);
+        connection.set_authenticated_peer(peer_id);
+        Ok(())
+    }
+
+    /// Carries an authenticated connection's traffic. Outgoing messages
+    /// are batched, padded and masked by the obfuscator on their way out,
+    /// with cover frames in between if it sends them.
+    pub(crate) async fn serve(&self, peer_id: &str, mut connection: Connection) {
+        let ip = connection
+            .remote_endpoint()
+            .socket_addr()
+            .map(|address| address.ip());
+        let address = connection.remote_endpoint().to_string();
+        connection.set_obfuscator(self.obfuscator.clone());
+        let (mut reader, mut writer) = connection.split();
+        reader.set_max_frame_size(self.limiter.lock().unwrap().config().max_frame_size);
+        let mut outgoing = self.attach(peer_id, Some(address)).await;
+        // Sealed before they are framed, so the link never sees plaintext
+        if let Some(sealer) = writer.sealer() {
+            let (sealed, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(message) = outgoing.recv().await {
+                    let message = match sealer.seal(&message) {
+                        Ok(message) => message,
+                        Err(_) => continue,
+                    };
+                    if sealed.send(message).is_err() {
+                        break;
+                    }
+                }
+            });
+            outgoing = receiver;
+        }
+        let (wire, mut frames) = mpsc::unbounded_channel();
+        let _link = ObfuscatedLink::spawn(self.obfuscator.clone(), outgoing, wire);
+
+        // Reads are not cancel safe, so they get a task of their own
+        let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+        let bandwidth = self.bandwidth.clone();
+        let reading_from = peer_id.to_string();
+        let read_task = tokio::spawn(async move {
+            loop {
+                let frame = reader.recv_frame().await;
+                let delay = match &frame {
+                    Ok(Some((_, size))) => bandwidth.lock().unwrap().account(
+                        Some(&reading_from),
+                        TrafficDirection::Download,
+                        *size,
+                        Instant::now(),
+                    ),
+                    _ => Duration::ZERO,
+                };
+                let last = !matches!(frame, Ok(Some(_)));
+                if incoming_tx.send(frame).is_err() || last {
+                    break;
+                }
+                tokio::time::sleep(delay).await;
+            }
+        });
+
+        let mut paused_until = None;
+        let replaced = 'serving: loop {
+            let paused = paused_until.take();
+            tokio::select! {
+                frame = async {
+                    if let Some(until) = paused {
+                        tokio::time::sleep_until(until).await;
+                    }
+                    frames.recv().await
+                } => match frame {
+                    Some(frame) => match writer.send_frame(&frame).await {
+                        Ok(size) => {
+                            let delay = self.bandwidth.lock().unwrap().account(
+                                Some(peer_id),
+                                TrafficDirection::Upload,
+                                size,
+                                Instant::now(),
+                            );
+                            if !delay.is_zero() {
+                                paused_until = Some(tokio::time::Instant::now() + delay);
+                            }
+                        }
+                        Err(_) => break false,
+                    },
+                    // Detached, or a newer connection took over
+                    None => break true,
+                },
+                frame = incoming.recv() => match frame {
+                    Some(Ok(Some((messages, mut size)))) => {
+                        // The frame's bytes go to its first message, and a
+                        // cover frame counts as a message of its own
+                        let mut messages: Vec<_> = messages.into_iter().map(Some).collect();
+                        if messages.is_empty() {
+                            messages.push(None);
+                        }
+                        for message in messages {
+                            let now = Instant::now();
+                            let charged = std::mem::take(&mut size);
+                            match self.rate_limit(|l| l.admit_message(peer_id, ip, charged, now)) {
+                                Ok(()) => {}
+                                Err(RateLimitError::Banned { .. } | RateLimitError::Blocked) => {
+                                    break 'serving false
+                                }
+                                Err(_) => continue,
+                            }
+                            // Whatever claims to come from someone else is dropped
+                            match message {
+                                Some(message) if message.sender_id == peer_id => {
+                                    self.handle_incoming_message(message).await
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    Some(Err(TransportError::Malformed(_))) => {
+                        let now = Instant::now();
+                        self.rate_limit(|l| l.report(peer_id, RateLimitError::MalformedFrame, now));
+                        break false;
+                    }
+                    _ => break false,
+                },
+            }
+        };
+
+        read_task.abort();
+        let _ = writer.close().await;
+        if !replaced {
+            self.detach_connection(peer_id).await;
+        }
+    }
+
+    /// Serves a connection a peer opened to us, once it has proved that
+    /// it holds the identity key we know for the peer it claims to be.
+    /// Busy addresses are refused before anything is read, and peers that
+    /// are banned or blocked as soon as their hello names them, without
+    /// counting against them. A failed handshake counts against the
+    /// address only.
+    pub async fn accept_connection(
+        &self,
+        mut connection: Connection,
+    ) -> Result<(), TransportError> {
+        let peer_id = self.admit_dialer(&mut connection).await?;
+        self.serve(&peer_id, connection).await;
+        Ok(())
+    }
+
+    /// Everything [`accept_connection`](Self::accept_connection) checks
+    /// before it serves a connection. Returns the peer that dialed.
+    pub(crate) async fn admit_dialer(
+        &self,
+        connection: &mut Connection,
+    ) -> Result<String, TransportError> {
+        let ip = connection
+            .remote_endpoint()
+            .socket_addr()
+            .map(|address| address.ip());
+        self.rate_limit(|limiter| limiter.admit_connection(ip, Instant::now()))
+            .map_err(TransportError::Refused)?;
+        connection.set_max_frame_size(self.limiter.lock().unwrap().config().max_frame_size);
+        connection.set_obfuscator(self.obfuscator.clone());
+
+        let authenticated = self.authenticate_dialer(connection).await;
+        let violation = match &authenticated {
+            Err(TransportError::Malformed(_)) => Some(RateLimitError::MalformedFrame),
+            Err(TransportError::Unauthenticated(_)) => Some(RateLimitError::FailedHandshake),
+            _ => None,
+        };
+        if let Some(violation) = violation {
+            let now = Instant::now();
+            self.rate_limit(|limiter| limiter.report_ip(ip, violation, now));
+        }
+        authenticated
+    }
+
+    /// Checks that whoever dialed holds the identity key of the peer it
+    /// claims to be, encrypts the connection with the key agreed on along
+    /// the way, and returns that peer.
+    async fn authenticate_dialer(
+        &self,
+        connection: &mut Connection,
+    ) -> Result<String, TransportError> {
+        let (peer_id, nonce, their_share) = match self.recv_auth(connection, None).await? {
+            (sender, AuthPayload::Hello { nonce, share })
+                if nonce.len() == auth::NONCE_LEN && share.len() == auth::SHARE_LEN =>
+            {
+                (sender, nonce, share)
+            }
+            _ => return Err(unauthenticated("Expected a hello")),
+        };
+        // Blocked and banned peers get nothing signed for them
+        self.rate_limit(|limiter| limiter.admit_peer(&peer_id, Instant::now()))
+            .map_err(TransportError::Refused)?;
+        let peer_key = self.known_key(&peer_id).await?;
+        let share = KeyShare::new();
+        let our_share = share.public();
+        let transcript = Transcript {
+            dialer_id: &peer_id,
+            acceptor_id: &self.peer.id,
+            dialer_share: &their_share,
+            acceptor_share: &our_share,
+        };
+        let session = share.session(&transcript, false)?;
+        let signature = self
+            .sign_auth(&auth::accept_context(&nonce, &transcript))
+            .await?;
+        let challenge = auth::new_nonce();
+        self.send_auth(
+            connection,
+            None,
+            &peer_id,
+            AuthPayload::Challenge {
+                nonce: challenge.clone(),
+                share: our_share.clone(),
+                signature,
+            },
+        )
+        .await?;
+
+        let signature = match self.recv_auth(connection, None).await? {
+            (sender, AuthPayload::Proof { signature }) if sender == peer_id => signature,
+            _ => return Err(unauthenticated("Expected a proof")),
+        };
+        if !auth::verify(
+            &peer_key,
+            &auth::dial_context(&challenge, &transcript),
+            &signature,
+        ) {
+            return Err(unauthenticated("Bad proof signature"));
+        }
+        connection.set_session(session);
+        connection.set_authenticated_peer(&peer_id);
+        Ok(peer_id)
+    }
+
+    /// The identity key a peer has to prove it holds. Contacts saved before
+    /// keys were kept have none, and are reported as needing verification.
+    async fn known_key(&self, peer_id: &str) -> Result<Vec<u8>, TransportError> {
+        match self.peer_keys.read().await.get(peer_id) {
+            Some(key) if !key.is_empty() => Ok(key.clone()),
+            Some(_) => {
+                self.event_bus
+                    .emit_network(NetworkEvent::ContactNeedsVerification {
+                        peer_id: peer_id.to_string(),
+                    });
+                Err(unauthenticated("Contact has to be verified again"))
+            }
+            None => Err(unauthenticated("No identity key known for peer")),
+        }
+    }
+
+    async fn sign_auth(&self, context: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or_else(|| unauthenticated("No identity to sign with"))?;
+        identity
+            .read()
+            .await
+            .sign_data(context)
+            .map_err(|e| unauthenticated(&e.to_string()))
+    }
+
+    /// Sends a handshake message, counting it against the peer's traffic
+    /// once it is known who the peer is.
+    async fn send_auth(
+        &self,
+        connection: &mut Connection,
+        peer_id: Option<&str>,
+        recipient_id: &str,
+        payload: AuthPayload,
+    ) -> Result<(), TransportError> {
+        let message =
+            ProtocolMessage::create_auth(self.peer.id.clone(), recipient_id.to_string(), payload);
+        let size = connection.send_sized(&message).await?;
+        self.bandwidth.lock().unwrap().account(
+            peer_id,
+            TrafficDirection::Upload,
+            size,
+            Instant::now(),
+        );
+        Ok(())
+    }
+
+    /// The next handshake message and who it claims to come from.
+    async fn recv_auth(
+        &self,
+        connection: &mut Connection,
+        peer_id: Option<&str>,
+    ) -> Result<(String, AuthPayload), TransportError> {
+        let (message, size) = tokio::time::timeout(AUTH_TIMEOUT, connection.recv_sized())
+            .await
+            .map_err(|_| unauthenticated("Timed out"))??
+            .ok_or(TransportError::Closed)?;
+        self.bandwidth.lock().unwrap().account(
+            peer_id,
+            TrafficDirection::Download,
+            size,
+            Instant::now(),
+        );
+        match message.payload {
+            MessagePayload::Auth(payload) => Ok((message.sender_id, payload)),
+            _ => Err(unauthenticated("Expected a handshake message")),
+        }
+    }
+
+    pub async fn detach_connection(&self, peer_id: &str) {
+        if let Some(entry) = self.keepalives.write().await.remove(peer_id) {
+            entry.task.abort();
+        }
+        self.bandwidth.lock().unwrap().forget_peer(peer_id);
+        self.remove_peer(peer_id);
+        let removed = self.connections.write().await.remove(peer_id).is_some();
+        self.presence.write().await.forget_peer(peer_id);
+
+        if removed {
+            self.event_bus.emit_network(NetworkEvent::PresenceChanged {
+                peer_id: peer_id.to_string(),
+                status: ContactStatus::Offline,
+            });
+        }
+    }
+
+    pub async fn has_connection(&self, peer_id: &str) -> bool {
+        self.connections.read().await.contains_key(peer_id)
+    }
+
+    /// Connection, message and byte limits from now on. Bans and blocks
+    /// in force are kept.
+    pub fn set_rate_limits(&self, config: RateLimitConfig) {
+        self.limiter.lock().unwrap().set_config(config);
+    }
+
+    pub fn rate_limit_stats(&self) -> RateLimitStats {
+        self.limiter.lock().unwrap().stats()
+    }
+
+    /// Drops the peer's connection, refuses the ones it opens from now on
+    /// and ignores whatever else reaches us from it.
+    pub async fn block_peer(&self, peer_id: &str) {
+        self.limiter.lock().unwrap().block(peer_id);
+        self.detach_connection(peer_id).await;
+    }
+
+    pub fn unblock_peer(&self, peer_id: &str) {
+        self.limiter.lock().unwrap().unblock(peer_id);
+    }
+
+    pub fn is_peer_blocked(&self, peer_id: &str) -> bool {
+        self.limiter.lock().unwrap().is_blocked(peer_id)
+    }
+
+    /// Upload and download caps from now on.
+    pub fn set_bandwidth_limits(&self, config: BandwidthConfig) {
+        self.bandwidth.lock().unwrap().set_config(config);
+    }
+
+    pub fn bandwidth_limits(&self) -> BandwidthConfig {
+        self.bandwidth.lock().unwrap().config().clone()
+    }
+
+    /// On a metered link, large messages and file chunks are held back and
+    /// no cover traffic is sent. Once unmetered again, what was held back
+    /// goes out, or waits in the outbox if its recipient has gone. Returns
+    /// how many messages were released.
+    pub async fn set_metered(&self, metered: bool) -> usize {
+        self.obfuscator.set_metered(metered);
+        let released = self.bandwidth.lock().unwrap().set_metered(metered);
+        let count = released.len();
+        for message in released {
+            if !self.send_on_connection(message.clone()).await {
+                self.outbox.write().await.push(message);
+            }
+        }
+        count
+    }
+
+    pub fn is_metered(&self) -> bool {
+        self.bandwidth.lock().unwrap().is_metered()
+    }
+
+    /// Messages held back until the link is unmetered.
+    pub fn deferred_len(&self) -> usize {
+        self.bandwidth.lock().unwrap().deferred_len()
+    }
+
+    /// Reorder window and gap timeout for chat messages from now on.
+    pub fn set_sequencing(&self, config: SequencingConfig) {
+        self.sequencer.lock().unwrap().set_config(config);
+    }
+
+    /// Chat messages from `peer_id` held back until a gap before them
+    /// closes or times out.
+    pub fn held_back_len(&self, peer_id: &str) -> usize {
+        self.sequencer.lock().unwrap().held_len(peer_id)
+    }
+
+    /// Runs `check` against the rate limiter, announcing the peers it
+    /// blocked for repeated bans.
+    fn rate_limit<T>(&self, check: impl FnOnce(&mut RateLimiter) -> T) -> T {
+        let (result, blocked) = {
+            let mut limiter = self.limiter.lock().unwrap();
+            let result = check(&mut limiter);
+            (result, limiter.take_newly_blocked())
+        };
+        for (peer_id, reason) in blocked {
+            self.event_bus
+                .emit_network(NetworkEvent::PeerBlocked { peer_id, reason });
+        }
+        result
+    }
+
+    /// Keepalive settings for connections attached from now on.
+    pub fn set_keepalive(&mut self, config: KeepaliveConfig) {
+        self.keepalive = config;
+    }
+
+    /// Pings `sender`'s connection every interval, and drops the connection
+    /// once too many pings in a row go unanswered. Replaces the keepalive
+    /// of an earlier connection to the same peer.
+    async fn start_keepalive(
+        &self,
+        peer_id: &str,
+        sender: &mpsc::UnboundedSender<ProtocolMessage>,
+    ) {
+        let mut keepalives = self.keepalives.write().await;
+
+        let weak_sender = sender.downgrade();
+        let peer_id = peer_id.to_string();
+        let own_id = self.peer.id.clone();
+        let interval = self.keepalive.interval;
+        let state = self.keepalives.clone();
+        let connections = self.connections.clone();
+        let connected_peers = self.connected_peers.clone();
+        let presence = self.presence.clone();
+        let event_bus = self.event_bus.clone();
+        let task_peer_id = peer_id.clone();
+
+        let task = tokio::spawn(async move {
+            let peer_id = task_peer_id;
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticker = tokio::time::interval_at(start, interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            let missed = loop {
+                ticker.tick().await;
+                let next = match state.write().await.get_mut(&peer_id) {
+                    Some(entry) => entry.state.next_ping(),
+                    None => return,
+                };
+                let sequence = match next {
+                    Some(sequence) => sequence,
+                    None => break state.read().await.get(&peer_id).map(|e| e.state.missed()),
+                };
+                let sender = match weak_sender.upgrade() {
+                    Some(sender) => sender,
+                    None => return,
+                };
+                let ping = ProtocolMessage::create_ping_with_sequence(
+                    own_id.clone(),
+                    peer_id.clone(),
+                    sequence,
+                );
+                if sender.send(ping).is_err() {
+                    return;
+                }
+            };
+
+            // Only drop the connection this task was pinging, not one that
+            // replaced it
+            let mut connections = connections.write().await;
+            let current = match (connections.get(&peer_id), weak_sender.upgrade()) {
+                (Some(current), Some(ours)) => current.same_channel(&ours),
+                _ => false,
+            };
+            if !current {
+                return;
+            }
+            connections.remove(&peer_id);
+            drop(connections);
+
+            connected_peers.lock().unwrap().remove(&peer_id);
+            state.write().await.remove(&peer_id);
+            presence.write().await.forget_peer(&peer_id);
+            event_bus.emit_network(NetworkEvent::ConnectionTimedOut {
+                peer_id: peer_id.clone(),
+                missed: missed.unwrap_or_default(),
+            });
+            event_bus.emit_network(NetworkEvent::PresenceChanged {
+                peer_id,
+                status: ContactStatus::Offline,
+            });
+        });
+
+        let entry = KeepaliveEntry {
+            state: Keepalive::new(self.keepalive.clone()),
+            task,
+        };
+        if let Some(previous) = keepalives.insert(peer_id, entry) {
+            previous.task.abort();
+        }
+    }
+
+    /// Pings a connected peer right away and waits for the pong. Returns
+    /// the peer's latency including this sample.
+    pub async fn ping(&self, peer: &str) -> Result<LatencyStats, NetworkError> {
+        let peer_id = self.resolve_peer_id(peer);
+        let sequence = match self.keepalives.write().await.get_mut(&peer_id) {
+            Some(entry) => entry.state.take_sequence(),
+            None => {
+                return Err(NetworkError::SendFailed(format!(
+                    "No connection to peer {}",
+                    peer_id
+                )))
+            }
+        };
+
+        let mut events = self.event_bus.subscribe();
+        self.send_protocol_message(ProtocolMessage::create_ping_with_sequence(
+            self.peer.id.clone(),
+            peer_id.clone(),
+            sequence,
+        ))
+        .await?;
+
+        let pong = tokio::time::timeout(PING_TIMEOUT, async {
+            loop {
+                match events.recv().await {
+                    Ok(AppEvent::Network(NetworkEvent::LatencyMeasured {
+                        peer_id: from,
+                        sequence: answered,
+                        latency,
+                    })) if from == peer_id && answered == sequence => return Some(latency),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await;
+
+        match pong {
+            Ok(Some(latency)) => Ok(latency),
+            _ => Err(NetworkError::ConnectionFailed(format!(
+                "No pong from {} within {:?}",
+                peer_id, PING_TIMEOUT
+            ))),
+        }
+    }
+
+    /// Latency to a connected peer, once a keepalive pong came back.
+    pub async fn latency_of(&self, peer_id: &str) -> Option<LatencyStats> {
+        self.keepalives
+            .read()
+            .await
+            .get(peer_id)
+            .and_then(|entry| entry.state.latency().cloned())
+    }
+
+    pub fn resolve_peer_id(&self, name_or_id: &str) -> String {
+        self.connected_peers
+            .lock()
+            .unwrap()
+            .values()
+            .find(|p| p.id == name_or_id || p.name == name_or_id)
+            .map(|p| p.id.clone())
+            .unwrap_or_else(|| name_or_id.to_string())
+    }
+
+    pub async fn send_protocol_message(
+        &self,
+        message: ProtocolMessage,
+    ) -> Result<(), NetworkError> {
+        if !self.is_active {
+            return Err(NetworkError::SendFailed("Network not active".to_string()));
+        }
+
+        let connections = self.connections.read().await;
+        let sender = connections.get(&message.recipient_id).ok_or_else(|| {
+            NetworkError::SendFailed(format!("No connection to peer {}", message.recipient_id))
+        })?;
+
+        match self.bandwidth.lock().unwrap().defer(message) {
+            Some(message) => sender
+                .send(message)
+                .map_err(|e| NetworkError::SendFailed(e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a message if the recipient is connected. Otherwise it waits in
+    /// the outbox, and a sealed copy is deposited with each of the
+    /// recipient's mailboxes we are connected to.
+    pub async fn send_or_queue(
+        &self,
+        message: ProtocolMessage,
+    ) -> Result<DeliveryRoute, NetworkError> {
+        if !self.is_active {
+            return Err(NetworkError::SendFailed("Network not active".to_string()));
+        }
+
+        if self.has_connection(&message.recipient_id).await {
+            self.send_protocol_message(message).await?;
+            return Ok(DeliveryRoute::Direct);
+        }
+
+        let recipient_id = message.recipient_id.clone();
+        self.outbox.write().await.push(message);
+        let mailboxes = self.deposit_queued(&recipient_id).await;
+        Ok(DeliveryRoute::Queued { mailboxes })
+    }
+
+    pub async fn outbox_len(&self) -> usize {
+        self.outbox.read().await.len()
+    }
+
+    /// Identity used to seal mail for others and open mail for us.
+    pub fn set_identity(&mut self, identity: Arc<RwLock<CryptoManager>>) {
+        self.identity = Some(identity);
+    }
+
+    /// Records a peer's identity key. Mail is only sealed for, and accepted
+    /// from, peers whose key is known.
+    pub async fn set_peer_key(&self, peer_id: &str, public_key: Vec<u8>) {
+        self.peer_keys
+            .write()
+            .await
+            .insert(peer_id.to_string(), public_key);
+    }
+
+    /// Forgets a peer's identity key, once it is no longer a contact. It
+    /// can no longer connect, and mail is no longer sealed for it.
+    pub async fn remove_peer_key(&self, peer_id: &str) {
+        self.peer_keys.write().await.remove(peer_id);
+    }
+
+    /// Keeps mail for other peers in `mailbox` while they are offline.
+    pub fn host_mailbox(&mut self, mailbox: Mailbox) {
+        self.mailbox = Some(Arc::new(RwLock::new(mailbox)));
+    }
+
+    pub fn mailbox(&self) -> Option<Arc<RwLock<Mailbox>>> {
+        self.mailbox.clone()
+    }
+
+    /// Sets the peers that keep our mail while we are offline, and tells
+    /// everyone connected about them.
+    pub async fn set_mailboxes(&self, mailboxes: Vec<String>) -> usize {
+        *self.own_mailboxes.write().await = mailboxes.clone();
+
+        let connections = self.connections.read().await;
+        connections
+            .iter()
+            .filter(|(peer_id, sender)| {
+                sender
+                    .send(ProtocolMessage::create_mailbox(
+                        self.peer.id.clone(),
+                        peer_id.to_string(),
+                        MailboxPayload::Advertise {
+                            mailboxes: mailboxes.clone(),
+                        },
+                    ))
+                    .is_ok()
+            })
+            .count()
+    }
+
+    pub async fn mailboxes_of(&self, peer_id: &str) -> Vec<String> {
+        self.peer_mailboxes
+            .read()
+            .await
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn send_on_connection(&self, message: ProtocolMessage) -> bool {
+        let connections = self.connections.read().await;
+        let sender = match connections.get(&message.recipient_id) {
+            Some(sender) => sender,
+            None => return false,
+        };
+        match self.bandwidth.lock().unwrap().defer(message) {
+            Some(message) => sender.send(message).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Deposits queued messages for `recipient_id` with each of its
+    /// connected mailboxes that does not have them yet. Returns the
+    /// mailboxes something was deposited with.
+    async fn deposit_queued(&self, recipient_id: &str) -> Vec<String> {
+        let (identity, recipient_key) = match (
+            &self.identity,
+            self.peer_keys.read().await.get(recipient_id).cloned(),
+        ) {
+            (Some(identity), Some(key)) => (identity, key),
+            _ => return Vec::new(),
+        };
+
+        let mut used = Vec::new();
+        for mailbox in self.mailboxes_of(recipient_id).await {
+            if mailbox == recipient_id || !self.has_connection(&mailbox).await {
+                continue;
+            }
+
+            let queued = self
+                .outbox
+                .write()
+                .await
+                .take_for_deposit(recipient_id, &mailbox);
+            if queued.is_empty() {
+                continue;
+            }
+
+            let expires_at = current_timestamp() + MAILBOX_MESSAGE_TTL.as_secs();
+            for message in queued {
+                let plaintext = match message.to_bytes() {
+                    Ok(plaintext) => plaintext,
+                    Err(_) => continue,
+                };
+                let sealed = match identity.read().await.seal_for(&recipient_key, &plaintext) {
+                    Ok(sealed) => sealed,
+                    Err(_) => continue,
+                };
+                let envelope = SealedEnvelope {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    recipient_id: recipient_id.to_string(),
+                    sealed,
+                    expires_at,
+                };
+                self.send_on_connection(ProtocolMessage::create_mailbox(
+                    self.peer.id.clone(),
+                    mailbox.clone(),
+                    MailboxPayload::Deposit { envelope },
+                ))
+                .await;
+            }
+            used.push(mailbox);
+        }
+        used
+    }
+
+    /// Sends a connected peer the mail we hold for it. Returns the number of
+    /// envelopes sent; they stay stored until the peer acknowledges them.
+    async fn deliver_mail(&self, peer_id: &str) -> usize {
+        let mailbox = match &self.mailbox {
+            Some(mailbox) => mailbox,
+            None => return 0,
+        };
+
+        let envelopes = mailbox
+            .read()
+            .await
+            .pending_for(peer_id, current_timestamp());
+        let count = envelopes.len();
+        if count > 0 {
+            self.send_on_connection(ProtocolMessage::create_mailbox(
+                self.peer.id.clone(),
+                peer_id.to_string(),
+                MailboxPayload::Deliver { envelopes },
+            ))
+            .await;
+        }
+        count
+    }
+
+    /// Handles a mailbox message from `from`, the peer whose connection it
+    /// came in on. Where a peer keeps its mail, and which of it can go,
+    /// is only taken from that peer over its own connection.
+    async fn handle_mailbox_message(&self, from: &str, payload: &MailboxPayload) {
+        match payload {
+            MailboxPayload::Advertise { mailboxes } => {
+                if !self.has_connection(from).await {
+                    return;
+                }
+                self.peer_mailboxes
+                    .write()
+                    .await
+                    .insert(from.to_string(), mailboxes.clone());
+            }
+            MailboxPayload::Deposit { envelope } => {
+                let result = match &self.mailbox {
+                    Some(mailbox) => mailbox
+                        .write()
+                        .await
+                        .deposit(envelope.clone(), current_timestamp()),
+                    None => Err(MailboxError::NotServed(envelope.recipient_id.clone())),
+                };
+
+                let reply = match &result {
+                    Ok(()) => MailboxPayload::Deposited {
+                        envelope_id: envelope.id.clone(),
+                    },
+                    Err(e) => MailboxPayload::Rejected {
+                        envelope_id: envelope.id.clone(),
+                        reason: e.to_string(),
+                    },
+                };
+                self.send_on_connection(ProtocolMessage::create_mailbox(
+                    self.peer.id.clone(),
+                    from.to_string(),
+                    reply,
+                ))
+                .await;
+
+                if result.is_ok() {
+                    self.deliver_mail(&envelope.recipient_id).await;
+                }
+            }
+            MailboxPayload::Deposited { envelope_id } => {
+                self.event_bus.emit_network(NetworkEvent::MailboxDeposited {
+                    mailbox: from.to_string(),
+                    envelope_id: envelope_id.clone(),
+                });
+            }
+            MailboxPayload::Rejected {
+                envelope_id,
+                reason,
+            } => {
+                self.event_bus.emit_network(NetworkEvent::MailboxRejected {
+                    mailbox: from.to_string(),
+                    envelope_id: envelope_id.clone(),
+                    reason: reason.clone(),
+                });
+            }
+            MailboxPayload::Deliver { envelopes } => {
+                let messages = self.open_envelopes(envelopes).await;
+                let envelope_ids = envelopes
+                    .iter()
+                    .filter(|e| e.recipient_id == self.peer.id)
+                    .map(|e| e.id.clone())
+                    .collect();
+                self.send_on_connection(ProtocolMessage::create_mailbox(
+                    self.peer.id.clone(),
+                    from.to_string(),
+                    MailboxPayload::Acknowledge { envelope_ids },
+                ))
+                .await;
+
+                self.event_bus.emit_network(NetworkEvent::MailboxDelivered {
+                    mailbox: from.to_string(),
+                    count: messages.len(),
+                });
+                for message in messages {
+                    Box::pin(self.handle_incoming_message(message)).await;
+                }
+            }
+            MailboxPayload::Acknowledge { envelope_ids } => {
+                if !self.has_connection(from).await {
+                    return;
+                }
+                // Only envelopes addressed to the sender itself are dropped
+                if let Some(mailbox) = &self.mailbox {
+                    let _ = mailbox.write().await.acknowledge(from, envelope_ids);
+                }
+            }
+        }
+    }
+
+    /// Opens envelopes addressed to us, keeping only messages sealed by the
+    /// peer they claim to come from.
+    async fn open_envelopes(&self, envelopes: &[SealedEnvelope]) -> Vec<ProtocolMessage> {
+        let identity = match &self.identity {
+            Some(identity) => identity.read().await,
+            None => return Vec::new(),
+        };
+        let peer_keys = self.peer_keys.read().await;
+
+        envelopes
+            .iter()
+            .filter(|envelope| envelope.recipient_id == self.peer.id)
+            .filter_map(|envelope| identity.open_sealed(&envelope.sealed).ok())
+            .filter_map(|(sender_key, plaintext)| {
+                let message = ProtocolMessage::from_bytes(&plaintext).ok()?;
+                let authentic = peer_keys.get(&message.sender_id) == Some(&sender_key)
+                    && message.recipient_id == self.peer.id
+                    && !matches!(message.payload, MessagePayload::Mailbox(_));
+                authentic.then_some(message)
+            })
+            .collect()
+    }
+
+    pub async fn send_reaction(
+        &self,
+        recipient: &str,
+        message_id: &str,
+        emoji: &str,
+        added: bool,
+    ) -> Result<(), NetworkError> {
+        let message = ProtocolMessage::create_reaction(
+            self.peer.id.clone(),
+            self.resolve_peer_id(recipient),
+            message_id.to_string(),
+            emoji.to_string(),
+            added,
+        );
+        self.send_protocol_message(message).await
+    }
+
+    pub async fn send_read_receipt(
+        &self,
+        recipient: &str,
+        message_id: &str,
+    ) -> Result<(), NetworkError> {
+        let message = ProtocolMessage::read_receipt(
+            self.peer.id.clone(),
+            self.resolve_peer_id(recipient),
+            message_id.to_string(),
+        );
+        self.send_protocol_message(message).await
+    }
+
+    pub async fn send_chat_settings(
+        &self,
+        recipient: &str,
+        chat_id: &str,
+        disappearing_timer: Option<u64>,
+        changed_at: u64,
+    ) -> Result<(), NetworkError> {
+        let message = ProtocolMessage::create_chat_settings(
+            self.peer.id.clone(),
+            self.resolve_peer_id(recipient),
+            chat_id.to_string(),
+            disappearing_timer,
+            changed_at,
+        );
+        self.send_protocol_message(message).await
+    }
+
+    /// Sends our hole-punching candidates to a peer over the existing connection.
+    pub async fn send_candidates(
+        &self,
+        recipient: &str,
+        session_id: u64,
+        candidates: Vec<Candidate>,
+    ) -> Result<(), NetworkError> {
+        let message = ProtocolMessage::create_candidates(
+            self.peer.id.clone(),
+            self.resolve_peer_id(recipient),
+            session_id,
+            candidates,
+        );
+        self.send_protocol_message(message).await
+    }
+
+    pub async fn get_presence_status(&self) -> ContactStatus {
+        self.presence.read().await.effective_status()
+    }
+
+    pub async fn set_presence_status(&self, status: ContactStatus) -> usize {
+        let changed = self.presence.write().await.set_status(status);
+        if !changed {
+            return 0;
+        }
+
+        let status = self.get_presence_status().await;
+        self.event_bus
+            .emit_network(NetworkEvent::LocalPresenceChanged {
+                status: status.clone(),
+            });
+        Self::broadcast_presence(&self.connections, &self.peer.id, status).await
+    }
+
+    /// Marks the user as active, returning from auto-away if needed.
+    pub async fn record_user_activity(&self) {
+        let change = self.presence.write().await.record_activity();
+        if let Some(status) = change {
+            self.event_bus
+                .emit_network(NetworkEvent::LocalPresenceChanged {
+                    status: status.clone(),
+                });
+            Self::broadcast_presence(&self.connections, &self.peer.id, status).await;
+        }
+    }
+
+    pub async fn set_auto_away_after(&self, away_after: Duration) {
+        self.presence.write().await.set_away_after(away_after);
+    }
+
+    /// Runs one idle check immediately; the monitor task does the same periodically.
+    pub async fn check_idle(&self) -> Option<ContactStatus> {
+        Self::apply_idle_check(
+            &self.presence,
+            &self.connections,
+            &self.event_bus,
+            &self.peer.id,
+        )
+        .await
+    }
+
+    pub fn start_presence_monitor(&mut self) {
+        if self.presence_task.is_some() {
+            return;
+        }
+
+        let presence = self.presence.clone();
+        let connections = self.connections.clone();
+        let event_bus = self.event_bus.clone();
+        let sender_id = self.peer.id.clone();
+
+        self.presence_task = Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(IDLE_CHECK_INTERVAL);
+            loop {
+                ticker.tick().await;
+                Self::apply_idle_check(&presence, &connections, &event_bus, &sender_id).await;
+            }
+        }));
+    }
+
+    /// Sends an ephemeral typing indicator. Indicators are throttled and never
+    /// stored; returns whether one was actually sent.
+    pub async fn send_typing(
+        &self,
+        recipient: &str,
+        chat_id: &str,
+        is_typing: bool,
+    ) -> Result<bool, NetworkError> {
+        let recipient_id = self.resolve_peer_id(recipient);
+        self.record_user_activity().await;
+
+        let allowed = self
+            .presence
+            .write()
+            .await
+            .allow_typing(&recipient_id, chat_id, is_typing);
+        if !allowed {
+            return Ok(false);
+        }
+
+        let message = ProtocolMessage::create_typing(
+            self.peer.id.clone(),
+            recipient_id,
+            chat_id.to_string(),
+            is_typing,
+        );
+        self.send_protocol_message(message).await?;
+        Ok(true)
+    }
+
+    async fn apply_idle_check(
+        presence: &RwLock<PresenceTracker>,
+        connections: &RwLock<ConnectionMap>,
+        event_bus: &EventBus,
+        sender_id: &str,
+    ) -> Option<ContactStatus> {
+        let status = presence.write().await.check_idle()?;
+        event_bus.emit_network(NetworkEvent::LocalPresenceChanged {
+            status: status.clone(),
+        });
+        Self::broadcast_presence(connections, sender_id, status.clone()).await;
+        Some(status)
+    }
+
+    async fn broadcast_presence(
+        connections: &RwLock<ConnectionMap>,
+        sender_id: &str,
+        status: ContactStatus,
+    ) -> usize {
+        let connections = connections.read().await;
+        connections
+            .iter()
+            .filter(|(peer_id, sender)| {
+                sender
+                    .send(ProtocolMessage::create_presence(
+                        sender_id.to_string(),
+                        peer_id.to_string(),
+                        status.clone(),
+                    ))
+                    .is_ok()
+            })
+            .count()
+    }
+
+    /// Handles a message from a peer. Callers make sure it came in on the
+    /// connection of the peer it names as its sender.
+    pub async fn handle_incoming_message(&self, message: ProtocolMessage) {
+        if !message.is_valid() || self.is_peer_blocked(&message.sender_id) {
+            return;
+        }
+
+        let from = self
+            .connected_peers
+            .lock()
+            .unwrap()
+            .get(&message.sender_id)
+            .map(|p| p.name.clone())
+            .unwrap_or_else(|| message.sender_id.clone());
+
+        match message.get_payload() {
+            MessagePayload::Text(_) => {
+                if !self.received.write().await.insert(&message.message_id) {
+                    return;
+                }
+
+                let arrival = self
+                    .sequencer
+                    .lock()
+                    .unwrap()
+                    .receive(message.clone(), Instant::now());
+                if !arrival.missing.is_empty() {
+                    self.send_on_connection(ProtocolMessage::create_retransmit_request(
+                        self.peer.id.clone(),
+                        message.sender_id.clone(),
+                        arrival.missing,
+                    ))
+                    .await;
+                }
+                if arrival.gap_opened {
+                    self.watch_gap(&message.sender_id, &from);
+                }
+                for text in arrival.deliver {
+                    Self::deliver_text(
+                        &self.chats,
+                        &self.event_bus,
+                        &message.sender_id,
+                        &from,
+                        &self.peer.name,
+                        &text,
+                    )
+                    .await;
+                }
+            }
+            MessagePayload::Retransmit(request) => {
+                let resent = self
+                    .sequencer
+                    .lock()
+                    .unwrap()
+                    .retransmissions(&message.sender_id, &request.sequences);
+                for text in resent {
+                    if !self.send_on_connection(text.clone()).await {
+                        self.outbox.write().await.push(text);
+                    }
+                }
+            }
+            MessagePayload::Mailbox(payload) => {
+                self.handle_mailbox_message(&message.sender_id, payload)
+                    .await;
+            }
+            MessagePayload::Reaction(reaction) => {
+                self.event_bus.emit_network(NetworkEvent::ReactionReceived {
+                    peer_id: message.sender_id.clone(),
+                    message_id: reaction.message_id.clone(),
+                    emoji: reaction.emoji.clone(),
+                    added: reaction.added,
+                });
+            }
+            MessagePayload::Presence(presence) => {
+                self.event_bus.emit_network(NetworkEvent::PresenceChanged {
+                    peer_id: message.sender_id.clone(),
+                    status: presence.status.clone(),
+                });
+            }
+            MessagePayload::Ack(ack) => {
+                let status = match ack.status.as_str() {
+                    "read" => DeliveryStatus::Read,
+                    "delivered" => DeliveryStatus::Delivered,
+                    _ => return,
+                };
+                self.event_bus.emit_network(NetworkEvent::ReceiptReceived {
+                    peer_id: message.sender_id.clone(),
+                    message_id: ack.original_message_id.clone(),
+                    status,
+                });
+            }
+            MessagePayload::ChatSettings(settings) => {
+                self.event_bus
+                    .emit_network(NetworkEvent::ChatSettingsReceived {
+                        peer_id: message.sender_id.clone(),
+                        chat_id: settings.chat_id.clone(),
+                        disappearing_timer: settings.disappearing_timer,
+                        changed_at: settings.changed_at,
+                    });
+            }
+            MessagePayload::Typing(typing) => {
+                self.event_bus.emit_network(NetworkEvent::TypingChanged {
+                    peer_id: message.sender_id.clone(),
+                    chat_id: typing.chat_id.clone(),
+                    is_typing: typing.is_typing,
+                });
+            }
+            MessagePayload::Ping(ping) => {
+                self.send_on_connection(ProtocolMessage::create_pong_with_sequence(
+                    self.peer.id.clone(),
+                    message.sender_id.clone(),
+                    ping.timestamp,
+                    ping.sequence,
+                ))
+                .await;
+            }
+            MessagePayload::Pong(pong) => {
+                let latency = self
+                    .keepalives
+                    .write()
+                    .await
+                    .get_mut(&message.sender_id)
+                    .and_then(|entry| {
+                        entry
+                            .state
+                            .record_pong(pong.sequence, pong.original_timestamp, current_millis())
+                            .cloned()
+                    });
+                if let Some(latency) = latency {
+                    self.event_bus.emit_network(NetworkEvent::LatencyMeasured {
+                        peer_id: message.sender_id.clone(),
+                        sequence: pong.sequence,
+                        latency,
+                    });
+                }
+            }
+            MessagePayload::Candidates(offer) => {
+                self.event_bus
+                    .emit_network(NetworkEvent::CandidatesReceived {
+                        peer_id: message.sender_id.clone(),
+                        session_id: offer.session_id,
+                        candidates: offer.candidates.clone(),
+                    });
+            }
+            _ => {}
+        }
+    }
+
+    /// Stores a text message from `from` and lets the app know about it.
+    /// One that arrived late goes before those its sender sent after it.
+    async fn deliver_text(
+        chats: &RwLock<HashMap<String, Vec<ChatMessage>>>,
+        event_bus: &EventBus,
+        peer_id: &str,
+        from: &str,
+        to: &str,
+        message: &ProtocolMessage,
+    ) {
+        let text = match message.get_payload() {
+            MessagePayload::Text(text) => text,
+            _ => return,
+        };
+        let chat_message = ChatMessage {
+            id: text.message_id.clone(),
+            from: from.to_string(),
+            to: to.to_string(),
+            content: text.content.clone(),
+            msg_type: ChatMessageType::Text,
+            timestamp: message.timestamp,
+            delivery_status: DeliveryStatus::Delivered,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: message.get_sequence_number(),
+        };
+
+        let mut chats = chats.write().await;
+        let messages = chats
+            .entry(format!("chat_{}", from))
+            .or_insert_with(Vec::new);
+        let position = messages
+            .iter()
+            .position(|existing| chat_message.sent_before(existing))
+            .unwrap_or(messages.len());
+        messages.insert(position, chat_message.clone());
+        drop(chats);
+
+        event_bus.emit_network(NetworkEvent::MessageReceived {
+            peer_id: peer_id.to_string(),
+            message: chat_message,
+        });
+    }
+
+    /// Delivers the messages from `peer_id` held behind a gap once the gap
+    /// has been open for `gap_timeout`, unless it closes first.
+    fn watch_gap(&self, peer_id: &str, from: &str) {
+        let sequencer = self.sequencer.clone();
+        let chats = self.chats.clone();
+        let event_bus = self.event_bus.clone();
+        let to = self.peer.name.clone();
+        let peer_id = peer_id.to_string();
+        let from = from.to_string();
+        let timeout = sequencer.lock().unwrap().config().gap_timeout;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let released = sequencer
+                .lock()
+                .unwrap()
+                .release_expired(&peer_id, Instant::now());
+            for text in released {
+                Self::deliver_text(&chats, &event_bus, &peer_id, &from, &to, &text).await;
+            }
+        });
+    }
+
     pub async fn simulate_message_received(
         &self,
         from: &str,
@@ -260,6 +1977,9 @@ impl NetworkManager {
                 .unwrap()
                 .as_secs(),
             delivery_status: DeliveryStatus::Delivered,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: 0,
         };
 
         let mut chats = self.chats.write().await;
@@ -271,8 +1991,29 @@ impl NetworkManager {
 
         use crate::events::{AppEvent, NetworkEvent};
         self.event_bus
-            .emit(AppEvent::Network(NetworkEvent::MessageReceived { message }));
+            .emit(AppEvent::Network(NetworkEvent::MessageReceived {
+                peer_id: from.to_string(),
+                message,
+            }));
 
         Ok(())
     }
 }
+
+fn unauthenticated(reason: &str) -> TransportError {
+    TransportError::Unauthenticated(reason.to_string())
+}
+
+fn current_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn current_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
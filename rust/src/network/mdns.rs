@@ -0,0 +1,227 @@
+use crate::crypto::CryptoManager;
+use crate::network::announcement::AnnouncementGuard;
+use crate::network::discovery::{NetworkDiscovery, PeerTable};
+use crate::network::types::*;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+
+pub const MDNS_SERVICE_TYPE: &str = "_shadowghost._tcp.local.";
+
+const TXT_PEER_ID: &str = "id";
+const TXT_PEER_NAME: &str = "name";
+const TXT_PROTOCOL_VERSION: &str = "ver";
+const TXT_CAPABILITIES: &str = "caps";
+const TXT_PUBLIC_KEY: &str = "pk";
+const TXT_TIMESTAMP: &str = "ts";
+const TXT_NONCE: &str = "nonce";
+const TXT_SIGNATURE: &str = "sig";
+
+/// DNS-SD advertisement and browsing for `_shadowghost._tcp.local`.
+///
+/// Resolved services are merged into the same peer table as the UDP
+/// announcements. The TXT record carries the identity key, a timestamp and
+/// nonce, and a signature over the record's fields. It is re-signed every
+/// announce interval, and resolved records go through the same freshness,
+/// replay and signature checks as UDP announcements.
+pub struct MdnsDiscovery {
+    daemon: ServiceDaemon,
+    fullname: String,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MdnsDiscovery {
+    /// Starts advertising `record`, which must already be signed, and
+    /// browsing for other instances. The record is signed afresh with
+    /// `identity` every `refresh_interval`.
+    pub(crate) fn start(
+        record: &AnnouncementMessage,
+        identity: Arc<RwLock<CryptoManager>>,
+        refresh_interval: Duration,
+        table: PeerTable,
+        guard: Arc<Mutex<AnnouncementGuard>>,
+    ) -> Result<Self, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+
+        let service = Self::service_info(record)?;
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service)?;
+
+        let events = daemon.browse(MDNS_SERVICE_TYPE)?;
+        let local_peer_id = record.peer_id.clone();
+
+        let refresher = daemon.clone();
+        let template = record.clone();
+        let refresh_handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            // The first tick is immediate, and the record was just registered
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match NetworkDiscovery::fresh_announcement(&identity, &template).await {
+                    Ok(record) => {
+                        if let Ok(service) = Self::service_info(&record) {
+                            let _ = refresher.register(service);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to sign mDNS record: {}", e),
+                }
+            }
+        });
+
+        let browse_handle = tokio::spawn(async move {
+            while let Ok(event) = events.recv_async().await {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => {
+                        if let Some(peer) =
+                            Self::peer_from_service(&info, &guard, &local_peer_id).await
+                        {
+                            let _ = table.insert(peer).await;
+                        }
+                    }
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        if let Some(peer_id) = Self::instance_name(&fullname) {
+                            if peer_id != local_peer_id {
+                                table.remove(peer_id).await;
+                            }
+                        }
+                    }
+                    ServiceEvent::SearchStopped(_) => break,
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(Self {
+            daemon,
+            fullname,
+            tasks: vec![refresh_handle, browse_handle],
+        })
+    }
+
+    /// Withdraws the advertisement (sending a goodbye) and stops browsing.
+    pub fn stop(&mut self) {
+        if self.tasks.is_empty() {
+            return;
+        }
+        for handle in self.tasks.drain(..) {
+            handle.abort();
+        }
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.stop_browse(MDNS_SERVICE_TYPE);
+        let _ = self.daemon.shutdown();
+    }
+
+    /// Service record for the local peer. The instance name is the peer id,
+    /// and addresses follow the host's interfaces automatically.
+    pub fn service_info(record: &AnnouncementMessage) -> Result<ServiceInfo, mdns_sd::Error> {
+        let host_name = format!("{}.local.", record.peer_id);
+        let version = record.protocol_version.to_string();
+        let capabilities = record.capabilities.join(",");
+        let public_key = STANDARD.encode(&record.public_key);
+        let timestamp = record.timestamp.to_string();
+        let nonce = record.nonce.to_string();
+        let signature = STANDARD.encode(&record.signature);
+        let properties = [
+            (TXT_PEER_ID, record.peer_id.as_str()),
+            (TXT_PEER_NAME, record.peer_name.as_str()),
+            (TXT_PROTOCOL_VERSION, version.as_str()),
+            (TXT_CAPABILITIES, capabilities.as_str()),
+            (TXT_PUBLIC_KEY, public_key.as_str()),
+            (TXT_TIMESTAMP, timestamp.as_str()),
+            (TXT_NONCE, nonce.as_str()),
+            (TXT_SIGNATURE, signature.as_str()),
+        ];
+
+        Ok(ServiceInfo::new(
+            MDNS_SERVICE_TYPE,
+            &record.peer_id,
+            &host_name,
+            "",
+            record.port,
+            &properties[..],
+        )?
+        .enable_addr_auto())
+    }
+
+    /// Rebuilds the signed record from a resolved service. Checking it is
+    /// left to the `AnnouncementGuard`, as for UDP announcements.
+    pub fn record_from_service(info: &ServiceInfo) -> Option<AnnouncementMessage> {
+        let decode = |key| {
+            info.get_property_val_str(key)
+                .and_then(|value| STANDARD.decode(value).ok())
+        };
+
+        let record = AnnouncementMessage {
+            peer_id: info.get_property_val_str(TXT_PEER_ID)?.to_string(),
+            peer_name: info.get_property_val_str(TXT_PEER_NAME)?.to_string(),
+            port: info.get_port(),
+            public_key: decode(TXT_PUBLIC_KEY)?,
+            protocol_version: info
+                .get_property_val_str(TXT_PROTOCOL_VERSION)?
+                .parse()
+                .ok()?,
+            capabilities: info
+                .get_property_val_str(TXT_CAPABILITIES)?
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(str::to_string)
+                .collect(),
+            timestamp: info.get_property_val_str(TXT_TIMESTAMP)?.parse().ok()?,
+            nonce: info.get_property_val_str(TXT_NONCE)?.parse().ok()?,
+            signature: decode(TXT_SIGNATURE)?,
+        };
+
+        Some(record)
+    }
+
+    /// Converts another peer's resolved service into a peer, preferring an
+    /// IPv4 address, if `guard` lets its record through.
+    async fn peer_from_service(
+        info: &ServiceInfo,
+        guard: &Mutex<AnnouncementGuard>,
+        local_peer_id: &str,
+    ) -> Option<DiscoveredPeer> {
+        let record = Self::record_from_service(info)?;
+        if record.peer_id == local_peer_id {
+            return None;
+        }
+        let address: IpAddr = info
+            .get_addresses()
+            .iter()
+            .min_by_key(|addr| addr.is_ipv6())
+            .copied()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        guard.lock().await.check(&record, address, now).ok()?;
+
+        Some(DiscoveredPeer {
+            id: record.peer_id,
+            address,
+            port: record.port,
+            name: record.peer_name,
+            last_seen: now,
+            public_key: record.public_key,
+            protocol_version: record.protocol_version,
+            capabilities: record.capabilities,
+        })
+    }
+
+    fn instance_name(fullname: &str) -> Option<&str> {
+        fullname
+            .strip_suffix(MDNS_SERVICE_TYPE)
+            .and_then(|name| name.strip_suffix('.'))
+    }
+}
+
+impl Drop for MdnsDiscovery {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -1,12 +1,64 @@
+pub mod acceptor;
+pub mod announcement;
+pub mod auth;
+pub mod bandwidth;
+pub mod dht;
+pub mod direct_channel;
 pub mod discovery;
 pub mod flutter_api;
+pub mod hole_punch;
+pub mod keepalive;
+pub mod mailbox;
 pub mod manager;
+pub mod mdns;
+pub mod nat_emulator;
+pub mod obfuscation;
+pub mod outbox;
+pub mod presence;
 pub mod protocol;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod rate_limit;
+pub mod reflexive;
+pub mod relay;
+pub mod sequencing;
+pub mod supervisor;
 pub mod tls_masking;
+pub mod tor;
+pub mod transport;
 pub mod types;
 
+pub use acceptor::ConnectionAcceptor;
+pub use announcement::{AnnouncementGuard, AnnouncementRejection};
+pub use bandwidth::{BandwidthLimiter, Traffic, TrafficDirection};
+pub use dht::{bootstrap_addresses, DhtNode, DhtPacket, NodeId, NodeInfo, PeerRecord, RoutingTable};
+pub use direct_channel::DirectChannel;
 pub use discovery::NetworkDiscovery;
+pub use hole_punch::{DatagramSocket, HolePuncher, PunchPacket, RendezvousServer};
+pub use keepalive::{Keepalive, PING_TIMEOUT};
+pub use mailbox::Mailbox;
 pub use manager::NetworkManager;
-pub use protocol::{MessagePayload, MessageType, ProtocolMessage};
-pub use tls_masking::TlsMasking;
+pub use mdns::{MdnsDiscovery, MDNS_SERVICE_TYPE};
+pub use nat_emulator::{NatEmulator, NatSocket, NatType};
+pub use obfuscation::{ObfuscatedLink, ObfuscationStats, TrafficObfuscator};
+pub use outbox::Outbox;
+pub use presence::PresenceTracker;
+pub use protocol::{
+    AuthPayload, CandidatesPayload, ChatSettingsPayload, MailboxPayload, MessagePayload,
+    MessageType, PresencePayload, ProtocolMessage, ReactionPayload, RetransmitPayload,
+    SealedPayload, TypingPayload,
+};
+#[cfg(feature = "quic")]
+pub use quic::{CertificateFingerprint, QuicConnection, QuicLink, QuicTransport};
+pub use rate_limit::{RateLimitStats, RateLimiter, TokenBucket};
+pub use reflexive::{AddressMirror, ReflexiveDiscovery};
+pub use relay::{select_relay, RelayClient, RelayPacket, RelayServer, RelayedLink};
+pub use sequencing::{Arrival, Sequencer};
+pub use supervisor::ConnectionSupervisor;
+pub use tls_masking::{parse_records, TlsMasking, TlsRecord, MAX_RECORD_SIZE};
+pub use tor::{OnionService, Socks5Dialer, TorController};
+pub use transport::{
+    Connection, ConnectionReader, ConnectionWriter, Listener, MemoryTransport, TcpTransport,
+    TorTransport, Transport,
+};
 pub use types::*;
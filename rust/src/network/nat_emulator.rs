@@ -0,0 +1,219 @@
+use crate::network::hole_punch::{BoxFuture, DatagramSocket};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+static NEXT_SUBNET: AtomicU8 = AtomicU8::new(1);
+
+/// Mapping and filtering behaviour, after RFC 4787.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// One public port per host socket; anyone may send to it.
+    FullCone,
+    /// One public port per host socket; only IPs the host has sent to may reply.
+    AddressRestricted,
+    /// One public port per host socket; only exact endpoints the host has
+    /// sent to may reply.
+    PortRestricted,
+    /// A new public port for every destination, accepting replies only from it.
+    Symmetric,
+}
+
+/// An in-process NAT for exercising hole punching without network namespaces.
+///
+/// Hosts behind the NAT get a [`NatSocket`] with a private `10.x.0.y`
+/// address. Outgoing datagrams leave through real loopback sockets that play
+/// the part of the NAT's public ports, and replies are filtered according to
+/// the NAT type before they reach the host. Datagrams to private addresses
+/// are dropped, as they would be on the internet.
+pub struct NatEmulator {
+    nat_type: NatType,
+    subnet: u8,
+    next_host: AtomicU16,
+    packet_loss: f64,
+}
+
+impl NatEmulator {
+    pub fn new(nat_type: NatType) -> Self {
+        Self {
+            nat_type,
+            subnet: NEXT_SUBNET.fetch_add(1, Ordering::SeqCst),
+            next_host: AtomicU16::new(2),
+            packet_loss: 0.0,
+        }
+    }
+
+    /// Drops this fraction of outgoing datagrams at random.
+    pub fn with_packet_loss(mut self, rate: f64) -> Self {
+        self.packet_loss = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn nat_type(&self) -> NatType {
+        self.nat_type
+    }
+
+    /// Attaches a new host socket behind this NAT.
+    pub fn bind(&self) -> NatSocket {
+        let host = self.next_host.fetch_add(1, Ordering::SeqCst);
+        let private_ip = Ipv4Addr::new(10, self.subnet, (host >> 8) as u8, host as u8);
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        NatSocket {
+            private_addr: SocketAddr::new(IpAddr::V4(private_ip), 40000),
+            nat_type: self.nat_type,
+            packet_loss: self.packet_loss,
+            mappings: tokio::sync::Mutex::new(HashMap::new()),
+            inbox_sender: sender,
+            inbox: tokio::sync::Mutex::new(receiver),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+struct Mapping {
+    socket: Arc<UdpSocket>,
+    /// Endpoints the host has sent to through this mapping.
+    contacted: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+/// A host socket behind a [`NatEmulator`].
+pub struct NatSocket {
+    private_addr: SocketAddr,
+    nat_type: NatType,
+    packet_loss: f64,
+    /// Keyed by destination for symmetric NATs, otherwise a single entry.
+    mappings: tokio::sync::Mutex<HashMap<Option<SocketAddr>, Mapping>>,
+    inbox_sender: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl NatSocket {
+    /// Public addresses currently allocated to this host.
+    pub async fn public_addrs(&self) -> Vec<SocketAddr> {
+        self.mappings
+            .lock()
+            .await
+            .values()
+            .filter_map(|mapping| mapping.socket.local_addr().ok())
+            .collect()
+    }
+
+    async fn mapping_for(&self, target: SocketAddr) -> io::Result<Arc<UdpSocket>> {
+        let key = match self.nat_type {
+            NatType::Symmetric => Some(target),
+            _ => None,
+        };
+
+        let mut mappings = self.mappings.lock().await;
+        if let Some(mapping) = mappings.get(&key) {
+            mapping.contacted.lock().unwrap().insert(target);
+            return Ok(mapping.socket.clone());
+        }
+
+        let socket = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let contacted = Arc::new(Mutex::new(HashSet::from([target])));
+        let task = tokio::spawn(Self::forward_inbound(
+            socket.clone(),
+            contacted.clone(),
+            self.nat_type,
+            self.inbox_sender.clone(),
+        ));
+        self.tasks.lock().unwrap().push(task);
+
+        mappings.insert(
+            key,
+            Mapping {
+                socket: socket.clone(),
+                contacted,
+            },
+        );
+        Ok(socket)
+    }
+
+    async fn forward_inbound(
+        socket: Arc<UdpSocket>,
+        contacted: Arc<Mutex<HashSet<SocketAddr>>>,
+        nat_type: NatType,
+        inbox: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    ) {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+
+            let allowed = {
+                let contacted = contacted.lock().unwrap();
+                match nat_type {
+                    NatType::FullCone => true,
+                    NatType::AddressRestricted => contacted.iter().any(|a| a.ip() == from.ip()),
+                    NatType::PortRestricted | NatType::Symmetric => contacted.contains(&from),
+                }
+            };
+            if allowed && inbox.send((buffer[..len].to_vec(), from)).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn is_private(addr: &SocketAddr) -> bool {
+        match addr.ip() {
+            IpAddr::V4(ip) => ip.is_private(),
+            IpAddr::V6(_) => false,
+        }
+    }
+}
+
+impl DatagramSocket for NatSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            if Self::is_private(&target) || rand::random::<f64>() < self.packet_loss {
+                return Ok(buf.len());
+            }
+            let socket = self.mapping_for(target).await?;
+            socket.send_to(buf, target).await
+        })
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Box::pin(async move {
+            let (data, from) = self
+                .inbox
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((len, from))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.private_addr)
+    }
+}
+
+impl Drop for NatSocket {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
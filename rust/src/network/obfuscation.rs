@@ -0,0 +1,403 @@
+use crate::network::protocol::ProtocolMessage;
+use crate::network::tls_masking::TlsMasking;
+use crate::network::types::*;
+use rand::Rng;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+const FRAME_COVER: u8 = 0x00;
+const FRAME_DATA: u8 = 0x01;
+/// Frame kind, then the length of the whole frame and of its payload.
+const FRAME_HEADER_SIZE: usize = 9;
+
+/// Bytes sent so far, split by what they carried.
+#[derive(Debug, Default)]
+struct Counters {
+    payload: AtomicU64,
+    padding: AtomicU64,
+    cover: AtomicU64,
+}
+
+/// Byte totals of an obfuscator, as reported in [`NetworkStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ObfuscationStats {
+    pub payload_bytes: u64,
+    pub padding_bytes: u64,
+    pub cover_bytes: u64,
+}
+
+/// Turns batches of protocol messages into frames that say little about
+/// their content: sizes are rounded up to a few buckets and cover frames
+/// look the same as real ones. With TLS masking on, frames additionally go
+/// out as TLS application data.
+///
+/// Frames say how long they are, so a byte stream of them needs nothing
+/// else to delimit them; see [`read_frame`](Self::read_frame).
+pub struct TrafficObfuscator {
+    config: ObfuscationConfig,
+    masking: Option<TlsMasking>,
+    counters: Counters,
+    metered: AtomicBool,
+}
+
+impl TrafficObfuscator {
+    pub fn new(config: ObfuscationConfig) -> Self {
+        Self {
+            config,
+            masking: None,
+            counters: Counters::default(),
+            metered: AtomicBool::new(false),
+        }
+    }
+
+    /// Wraps every frame in TLS records once `masking` is enabled.
+    pub fn with_tls_masking(mut self, masking: TlsMasking) -> Self {
+        self.masking = Some(masking);
+        self
+    }
+
+    pub fn config(&self) -> &ObfuscationConfig {
+        &self.config
+    }
+
+    /// While metered, links send no cover frames.
+    pub fn set_metered(&self, metered: bool) {
+        self.metered.store(metered, Ordering::Relaxed);
+    }
+
+    pub fn is_metered(&self) -> bool {
+        self.metered.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> ObfuscationStats {
+        ObfuscationStats {
+            payload_bytes: self.counters.payload.load(Ordering::Relaxed),
+            padding_bytes: self.counters.padding.load(Ordering::Relaxed),
+            cover_bytes: self.counters.cover.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Size a frame holding `payload_len` bytes is padded to.
+    pub fn padded_size(&self, payload_len: usize) -> usize {
+        let needed = FRAME_HEADER_SIZE + payload_len;
+        if !self.config.padding {
+            return needed;
+        }
+
+        match self
+            .config
+            .bucket_sizes
+            .iter()
+            .find(|&&size| size >= needed)
+        {
+            Some(&size) => size,
+            None => match self.config.bucket_sizes.last() {
+                Some(&largest) if largest > 0 => needed.div_ceil(largest) * largest,
+                _ => needed,
+            },
+        }
+    }
+
+    /// One frame carrying all of `messages`.
+    pub async fn encode(&self, messages: &[ProtocolMessage]) -> Result<Vec<u8>, ObfuscationError> {
+        let mut payload = Vec::new();
+        for message in messages {
+            let bytes = message
+                .to_bytes()
+                .map_err(|e| ObfuscationError::Encoding(e.to_string()))?;
+            payload.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            payload.extend_from_slice(&bytes);
+        }
+
+        let frame = self.frame(FRAME_DATA, &payload).await?;
+        self.counters
+            .payload
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        self.counters
+            .padding
+            .fetch_add((frame.len() - payload.len()) as u64, Ordering::Relaxed);
+        Ok(frame)
+    }
+
+    /// A frame with nothing in it, the size of the smallest real frame.
+    pub async fn cover_frame(&self) -> Result<Vec<u8>, ObfuscationError> {
+        let frame = self.frame(FRAME_COVER, &[]).await?;
+        self.counters
+            .cover
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        Ok(frame)
+    }
+
+    /// The messages carried by a frame; none for cover frames.
+    pub async fn decode(&self, frame: &[u8]) -> Result<Vec<ProtocolMessage>, ObfuscationError> {
+        match &self.masking {
+            Some(masking) if masking.is_enabled() => {
+                let unmasked = masking
+                    .unwrap_connection(frame)
+                    .await
+                    .map_err(|e| ObfuscationError::Masking(e.to_string()))?;
+                Self::parse(&unmasked)
+            }
+            _ => Self::parse(frame),
+        }
+    }
+
+    /// Reads the next frame off a byte stream, returning the messages it
+    /// carries and how many bytes it took, or `None` if the stream ends
+    /// before one starts. Frames over `max_size` are refused as malformed.
+    pub async fn read_frame<R>(
+        &self,
+        reader: &mut R,
+        max_size: usize,
+    ) -> Result<Option<(Vec<ProtocolMessage>, usize)>, TransportError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let masking = self.masking.as_ref().filter(|masking| masking.is_enabled());
+        let mut frame = Vec::new();
+        let mut size = 0;
+        loop {
+            let length = match frame.get(1..5) {
+                Some(length) => {
+                    let length =
+                        u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+                    if !(FRAME_HEADER_SIZE..=max_size).contains(&length) {
+                        return Err(TransportError::Malformed(format!(
+                            "Frame of {} bytes",
+                            length
+                        )));
+                    }
+                    Some(length)
+                }
+                None => None,
+            };
+            if length.is_some_and(|length| frame.len() >= length) {
+                break;
+            }
+
+            // Masked frames come as whole records, each saying how long it is
+            let mut chunk = match masking {
+                Some(_) => vec![0u8; 5],
+                None => vec![0u8; length.unwrap_or(FRAME_HEADER_SIZE) - frame.len()],
+            };
+            match reader.read_exact(&mut chunk).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && size == 0 => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(masking) = masking {
+                let body = u16::from_be_bytes([chunk[3], chunk[4]]) as usize;
+                chunk.resize(5 + body, 0);
+                reader.read_exact(&mut chunk[5..]).await?;
+                size += chunk.len();
+                let unmasked = masking
+                    .unwrap_connection(&chunk)
+                    .await
+                    .map_err(|e| TransportError::Malformed(e.to_string()))?;
+                frame.extend_from_slice(&unmasked);
+            } else {
+                size += chunk.len();
+                frame.extend_from_slice(&chunk);
+            }
+        }
+
+        Self::parse(&frame)
+            .map(|messages| Some((messages, size)))
+            .map_err(|e| TransportError::Malformed(e.to_string()))
+    }
+
+    fn parse(frame: &[u8]) -> Result<Vec<ProtocolMessage>, ObfuscationError> {
+        if frame.len() < FRAME_HEADER_SIZE {
+            return Err(ObfuscationError::Malformed("Frame too short".to_string()));
+        }
+        let kind = frame[0];
+        let frame_length = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+        if frame_length != frame.len() {
+            return Err(ObfuscationError::Malformed(
+                "Frame length does not match".to_string(),
+            ));
+        }
+        let length = u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]) as usize;
+        let mut payload = match frame.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length) {
+            Some(payload) => payload,
+            None => {
+                return Err(ObfuscationError::Malformed(
+                    "Length exceeds frame".to_string(),
+                ))
+            }
+        };
+
+        match kind {
+            FRAME_COVER => return Ok(Vec::new()),
+            FRAME_DATA => {}
+            _ => {
+                return Err(ObfuscationError::Malformed(format!(
+                    "Unknown frame kind {}",
+                    kind
+                )))
+            }
+        }
+
+        let mut messages = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < 4 {
+                return Err(ObfuscationError::Malformed(
+                    "Truncated message length".to_string(),
+                ));
+            }
+            let length = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+            let end = 4 + length as usize;
+            if length as usize > MAX_MESSAGE_SIZE || payload.len() < end {
+                return Err(ObfuscationError::Malformed("Truncated message".to_string()));
+            }
+            let message = ProtocolMessage::from_bytes(&payload[4..end])
+                .map_err(|e| ObfuscationError::Malformed(e.to_string()))?;
+            messages.push(message);
+            payload = &payload[end..];
+        }
+        Ok(messages)
+    }
+
+    async fn frame(&self, kind: u8, payload: &[u8]) -> Result<Vec<u8>, ObfuscationError> {
+        let size = self.padded_size(payload.len());
+        let mut frame = Vec::with_capacity(size);
+        frame.push(kind);
+        frame.extend_from_slice(&(size as u32).to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        // Random rather than zero padding, so it blends in once masked
+        let mut padding = vec![0u8; size - frame.len()];
+        rand::rng().fill(&mut padding[..]);
+        frame.extend_from_slice(&padding);
+
+        match &self.masking {
+            Some(masking) if masking.is_enabled() => masking
+                .wrap_connection(&frame)
+                .await
+                .map_err(|e| ObfuscationError::Masking(e.to_string())),
+            _ => Ok(frame),
+        }
+    }
+}
+
+/// Moves one peer's outgoing messages onto the wire as obfuscated frames.
+///
+/// With cover traffic on, a frame leaves every `cover_interval` and carries
+/// whatever was queued since the last one, so the send pattern does not
+/// depend on activity. Otherwise the first queued message waits a random
+/// delay of up to `batch_jitter` and everything queued by then is sent in
+/// one frame.
+///
+/// Cover frames are skipped while the obfuscator is metered.
+pub struct ObfuscatedLink {
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ObfuscatedLink {
+    pub fn spawn(
+        obfuscator: Arc<TrafficObfuscator>,
+        mut outgoing: mpsc::UnboundedReceiver<ProtocolMessage>,
+        wire: mpsc::UnboundedSender<Vec<u8>>,
+    ) -> Self {
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+
+        let task = tokio::spawn(async move {
+            let config = obfuscator.config().clone();
+            let mut ticker =
+                tokio::time::interval(config.cover_interval.max(Duration::from_millis(1)));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                let mut batch = Vec::new();
+                if config.cover_traffic {
+                    tokio::select! {
+                        _ = token.cancelled() => break,
+                        _ = ticker.tick() => {}
+                    }
+                    let closed = drain(&mut outgoing, &mut batch);
+                    if closed && batch.is_empty() {
+                        break;
+                    }
+                } else {
+                    let first = tokio::select! {
+                        _ = token.cancelled() => break,
+                        message = outgoing.recv() => message,
+                    };
+                    match first {
+                        Some(message) => batch.push(message),
+                        None => break,
+                    }
+                    if !config.batch_jitter.is_zero() {
+                        let delay = rand::rng().random_range(Duration::ZERO..=config.batch_jitter);
+                        tokio::select! {
+                            _ = token.cancelled() => break,
+                            _ = tokio::time::sleep(delay) => {}
+                        }
+                    }
+                    drain(&mut outgoing, &mut batch);
+                }
+
+                // Cover traffic waits for an unmetered link
+                if batch.is_empty() && obfuscator.is_metered() {
+                    continue;
+                }
+                let frame = if batch.is_empty() {
+                    obfuscator.cover_frame().await
+                } else {
+                    obfuscator.encode(&batch).await
+                };
+                // Messages that cannot be encoded are dropped
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                if wire.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            shutdown,
+            task: Some(task),
+        }
+    }
+
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for ObfuscatedLink {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Moves everything already queued into `batch`. Returns whether the
+/// sending side is gone.
+fn drain(
+    outgoing: &mut mpsc::UnboundedReceiver<ProtocolMessage>,
+    batch: &mut Vec<ProtocolMessage>,
+) -> bool {
+    loop {
+        match outgoing.try_recv() {
+            Ok(message) => batch.push(message),
+            Err(mpsc::error::TryRecvError::Empty) => return false,
+            Err(mpsc::error::TryRecvError::Disconnected) => return true,
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::network::protocol::ProtocolMessage;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Per-peer queues will not grow beyond this; the oldest message goes first.
+pub const MAX_OUTBOX_PER_PEER: usize = 1000;
+
+struct QueuedMessage {
+    message: ProtocolMessage,
+    /// Mailboxes this message has already been deposited with.
+    deposited_with: HashSet<String>,
+}
+
+/// Messages for peers we have no connection to. They are handed over when
+/// the peer connects, and meanwhile deposited with its mailboxes.
+#[derive(Default)]
+pub struct Outbox {
+    queues: HashMap<String, VecDeque<QueuedMessage>>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, message: ProtocolMessage) {
+        let queue = self.queues.entry(message.recipient_id.clone()).or_default();
+        if queue.len() >= MAX_OUTBOX_PER_PEER {
+            queue.pop_front();
+        }
+        queue.push_back(QueuedMessage {
+            message,
+            deposited_with: HashSet::new(),
+        });
+    }
+
+    /// Removes and returns everything queued for a peer, oldest first.
+    pub fn take(&mut self, peer_id: &str) -> Vec<ProtocolMessage> {
+        self.queues
+            .remove(peer_id)
+            .map(|queue| queue.into_iter().map(|q| q.message).collect())
+            .unwrap_or_default()
+    }
+
+    /// Messages for `peer_id` not yet deposited with `mailbox`. They are
+    /// marked as deposited there, but stay queued for direct delivery.
+    pub fn take_for_deposit(&mut self, peer_id: &str, mailbox: &str) -> Vec<ProtocolMessage> {
+        match self.queues.get_mut(peer_id) {
+            Some(queue) => queue
+                .iter_mut()
+                .filter_map(|q| {
+                    q.deposited_with
+                        .insert(mailbox.to_string())
+                        .then(|| q.message.clone())
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn pending_for(&self, peer_id: &str) -> usize {
+        self.queues.get(peer_id).map(VecDeque::len).unwrap_or(0)
+    }
+
+    pub fn recipients(&self) -> Vec<String> {
+        self.queues.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
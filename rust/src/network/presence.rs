@@ -0,0 +1,118 @@
+use crate::network::types::ContactStatus;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_AWAY_AFTER: Duration = Duration::from_secs(300);
+pub const DEFAULT_TYPING_INTERVAL: Duration = Duration::from_secs(3);
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Local presence state: the status chosen by the user, the auto-away idle
+/// timer and the per-recipient throttle for typing indicators.
+#[derive(Debug)]
+pub struct PresenceTracker {
+    status: ContactStatus,
+    auto_away: bool,
+    last_activity: Instant,
+    away_after: Duration,
+    typing_interval: Duration,
+    typing_sent: HashMap<(String, String), Instant>,
+}
+
+impl PresenceTracker {
+    pub fn new(away_after: Duration, typing_interval: Duration) -> Self {
+        Self {
+            status: ContactStatus::Online,
+            auto_away: false,
+            last_activity: Instant::now(),
+            away_after,
+            typing_interval,
+            typing_sent: HashMap::new(),
+        }
+    }
+
+    /// Status as seen by peers, taking auto-away into account.
+    pub fn effective_status(&self) -> ContactStatus {
+        if self.auto_away {
+            ContactStatus::Away
+        } else {
+            self.status.clone()
+        }
+    }
+
+    pub fn status(&self) -> ContactStatus {
+        self.status.clone()
+    }
+
+    /// Sets the user-chosen status. Returns true if the status peers see changed.
+    pub fn set_status(&mut self, status: ContactStatus) -> bool {
+        let before = self.effective_status();
+        self.status = status;
+        self.auto_away = false;
+        self.last_activity = Instant::now();
+        before != self.effective_status()
+    }
+
+    /// Records user activity, leaving auto-away if it was active.
+    pub fn record_activity(&mut self) -> Option<ContactStatus> {
+        self.last_activity = Instant::now();
+        if self.auto_away {
+            self.auto_away = false;
+            Some(self.effective_status())
+        } else {
+            None
+        }
+    }
+
+    /// Switches to auto-away once the idle timeout has elapsed. Only an
+    /// `Online` user is moved; `Busy` and `Offline` are left as chosen.
+    pub fn check_idle(&mut self) -> Option<ContactStatus> {
+        if self.auto_away || self.status != ContactStatus::Online {
+            return None;
+        }
+
+        if self.last_activity.elapsed() >= self.away_after {
+            self.auto_away = true;
+            Some(ContactStatus::Away)
+        } else {
+            None
+        }
+    }
+
+    pub fn away_after(&self) -> Duration {
+        self.away_after
+    }
+
+    pub fn set_away_after(&mut self, away_after: Duration) {
+        self.away_after = away_after;
+    }
+
+    /// Decides whether a typing indicator may go out. Repeated "typing"
+    /// signals are throttled per recipient and chat; a "stopped" signal is
+    /// only sent if a "typing" signal preceded it.
+    pub fn allow_typing(&mut self, recipient: &str, chat_id: &str, is_typing: bool) -> bool {
+        let key = (recipient.to_string(), chat_id.to_string());
+
+        if !is_typing {
+            return self.typing_sent.remove(&key).is_some();
+        }
+
+        let now = Instant::now();
+        match self.typing_sent.get(&key) {
+            Some(last) if now.duration_since(*last) < self.typing_interval => false,
+            _ => {
+                self.typing_sent.insert(key, now);
+                true
+            }
+        }
+    }
+
+    pub fn forget_peer(&mut self, recipient: &str) {
+        self.typing_sent.retain(|(peer, _), _| peer != recipient);
+    }
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_AWAY_AFTER, DEFAULT_TYPING_INTERVAL)
+    }
+}
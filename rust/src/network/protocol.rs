@@ -1,3 +1,4 @@
+use crate::network::types::{Candidate, ContactStatus, SealedEnvelope};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -10,6 +11,14 @@ pub enum MessageType {
     Acknowledgment,
     KeyExchange,
     Status,
+    Reaction,
+    Typing,
+    ChatSettings,
+    Candidates,
+    Mailbox,
+    Retransmit,
+    Auth,
+    Sealed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,13 +49,16 @@ pub struct TextPayload {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PingPayload {
+    /// Milliseconds since the Unix epoch.
     pub timestamp: u64,
     pub sequence: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PongPayload {
+    /// The ping's timestamp, echoed back.
     pub original_timestamp: u64,
+    /// Milliseconds since the Unix epoch.
     pub response_timestamp: u64,
     pub sequence: u64,
 }
@@ -67,6 +79,113 @@ pub struct AckPayload {
     pub status: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionPayload {
+    pub message_id: String,
+    pub emoji: String,
+    pub added: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresencePayload {
+    pub status: ContactStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingPayload {
+    pub chat_id: String,
+    pub is_typing: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatSettingsPayload {
+    pub chat_id: String,
+    pub disappearing_timer: Option<u64>,
+    pub changed_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandidatesPayload {
+    pub session_id: u64,
+    pub candidates: Vec<Candidate>,
+}
+
+/// Sequence numbers of chat messages that never arrived, asked for again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetransmitPayload {
+    pub sequences: Vec<u64>,
+}
+
+/// Proof of identity when a connection opens. The dialer says hello with
+/// a nonce, the other side signs it and sends a nonce of its own, and the
+/// dialer signs that in turn. Both sides also send an ephemeral X25519
+/// share, which the signatures cover and the session key comes from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AuthPayload {
+    Hello {
+        nonce: Vec<u8>,
+        share: Vec<u8>,
+    },
+    Challenge {
+        nonce: Vec<u8>,
+        share: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    Proof {
+        signature: Vec<u8>,
+    },
+}
+
+/// A message encrypted with the session key of the connection it travels
+/// on. Counters start at zero in each direction and go up by one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedPayload {
+    pub counter: u64,
+    /// Base64 on the wire, which keeps sealing from tripling a message's
+    /// size in JSON.
+    #[serde(with = "base64_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Store-and-forward traffic between senders, mailbox hosts and recipients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MailboxPayload {
+    /// Peers that keep mail for the sender while it is offline.
+    Advertise {
+        mailboxes: Vec<String>,
+    },
+    Deposit {
+        envelope: SealedEnvelope,
+    },
+    Deposited {
+        envelope_id: String,
+    },
+    Rejected {
+        envelope_id: String,
+        reason: String,
+    },
+    Deliver {
+        envelopes: Vec<SealedEnvelope>,
+    },
+    Acknowledge {
+        envelope_ids: Vec<String>,
+    },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MessagePayload {
     Handshake(HandshakePayload),
@@ -75,6 +194,15 @@ pub enum MessagePayload {
     Pong(PongPayload),
     File(FilePayload),
     Ack(AckPayload),
+    Reaction(ReactionPayload),
+    Presence(PresencePayload),
+    Typing(TypingPayload),
+    ChatSettings(ChatSettingsPayload),
+    Candidates(CandidatesPayload),
+    Mailbox(MailboxPayload),
+    Retransmit(RetransmitPayload),
+    Auth(AuthPayload),
+    Sealed(SealedPayload),
     Empty,
 }
 
@@ -210,10 +338,20 @@ impl ProtocolMessage {
     }
 
     pub fn create_ping(sender_id: String, recipient_id: String) -> Self {
-        let timestamp = std::time::SystemTime::now()
+        Self::create_ping_with_sequence(sender_id, recipient_id, 0)
+    }
+
+    /// A keepalive ping. The payload carries the send time in milliseconds
+    /// so the pong can be timed precisely.
+    pub fn create_ping_with_sequence(
+        sender_id: String,
+        recipient_id: String,
+        sequence: u64,
+    ) -> Self {
+        let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+            .unwrap();
+        let timestamp = now.as_secs();
         let message_id = uuid::Uuid::new_v4().to_string();
 
         let header = MessageHeader {
@@ -226,8 +364,8 @@ impl ProtocolMessage {
         };
 
         let payload = MessagePayload::Ping(PingPayload {
-            timestamp,
-            sequence: 0,
+            timestamp: now.as_millis() as u64,
+            sequence,
         });
 
         Self {
@@ -244,10 +382,20 @@ impl ProtocolMessage {
     }
 
     pub fn create_pong(sender_id: String, recipient_id: String, original_timestamp: u64) -> Self {
-        let timestamp = std::time::SystemTime::now()
+        Self::create_pong_with_sequence(sender_id, recipient_id, original_timestamp, 0)
+    }
+
+    /// Answers a ping, echoing its timestamp and sequence.
+    pub fn create_pong_with_sequence(
+        sender_id: String,
+        recipient_id: String,
+        original_timestamp: u64,
+        sequence: u64,
+    ) -> Self {
+        let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+            .unwrap();
+        let timestamp = now.as_secs();
         let message_id = uuid::Uuid::new_v4().to_string();
 
         let header = MessageHeader {
@@ -261,8 +409,8 @@ impl ProtocolMessage {
 
         let payload = MessagePayload::Pong(PongPayload {
             original_timestamp,
-            response_timestamp: timestamp,
-            sequence: 0,
+            response_timestamp: now.as_millis() as u64,
+            sequence,
         });
 
         Self {
@@ -286,7 +434,7 @@ impl ProtocolMessage {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
-            .as_secs();
+            .as_millis() as u64;
         Self::create_pong(sender_id, recipient_id, timestamp)
     }
 
@@ -338,6 +486,322 @@ impl ProtocolMessage {
         }
     }
 
+    pub fn read_receipt(sender_id: String, recipient_id: String, message_id: String) -> Self {
+        let mut receipt = Self::acknowledgment(sender_id, recipient_id, message_id);
+        if let MessagePayload::Ack(ack) = &mut receipt.payload {
+            ack.status = "read".to_string();
+        }
+        receipt
+    }
+
+    pub fn create_reaction(
+        sender_id: String,
+        recipient_id: String,
+        message_id: String,
+        emoji: String,
+        added: bool,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let reaction_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Reaction,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: reaction_id.clone(),
+            sequence_number: 0,
+        };
+
+        let payload = MessagePayload::Reaction(ReactionPayload {
+            message_id,
+            emoji: emoji.clone(),
+            added,
+        });
+
+        Self {
+            header,
+            payload,
+            signature: None,
+            message_type: MessageType::Reaction,
+            sender_id,
+            recipient_id,
+            content: emoji.into_bytes(),
+            timestamp,
+            message_id: reaction_id,
+        }
+    }
+
+    pub fn create_presence(sender_id: String, recipient_id: String, status: ContactStatus) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Status,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Presence(PresencePayload { status }),
+            signature: None,
+            message_type: MessageType::Status,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    pub fn create_typing(
+        sender_id: String,
+        recipient_id: String,
+        chat_id: String,
+        is_typing: bool,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Typing,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Typing(TypingPayload { chat_id, is_typing }),
+            signature: None,
+            message_type: MessageType::Typing,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    pub fn create_chat_settings(
+        sender_id: String,
+        recipient_id: String,
+        chat_id: String,
+        disappearing_timer: Option<u64>,
+        changed_at: u64,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::ChatSettings,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        let payload = MessagePayload::ChatSettings(ChatSettingsPayload {
+            chat_id,
+            disappearing_timer,
+            changed_at,
+        });
+
+        Self {
+            header,
+            payload,
+            signature: None,
+            message_type: MessageType::ChatSettings,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    /// Offers the endpoints the sender can be reached at for hole punching.
+    pub fn create_candidates(
+        sender_id: String,
+        recipient_id: String,
+        session_id: u64,
+        candidates: Vec<Candidate>,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Candidates,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Candidates(CandidatesPayload {
+                session_id,
+                candidates,
+            }),
+            signature: None,
+            message_type: MessageType::Candidates,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    pub fn create_mailbox(
+        sender_id: String,
+        recipient_id: String,
+        payload: MailboxPayload,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Mailbox,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Mailbox(payload),
+            signature: None,
+            message_type: MessageType::Mailbox,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    pub fn create_retransmit_request(
+        sender_id: String,
+        recipient_id: String,
+        sequences: Vec<u64>,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Retransmit,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Retransmit(RetransmitPayload { sequences }),
+            signature: None,
+            message_type: MessageType::Retransmit,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    pub fn create_auth(sender_id: String, recipient_id: String, payload: AuthPayload) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message_id = uuid::Uuid::new_v4().to_string();
+
+        let header = MessageHeader {
+            message_type: MessageType::Auth,
+            sender_id: sender_id.clone(),
+            recipient_id: recipient_id.clone(),
+            timestamp,
+            message_id: message_id.clone(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Auth(payload),
+            signature: None,
+            message_type: MessageType::Auth,
+            sender_id,
+            recipient_id,
+            content: Vec::new(),
+            timestamp,
+            message_id,
+        }
+    }
+
+    /// A sealed message names no one and carries no id of its own; who it
+    /// is from is known from the connection it arrives on.
+    pub fn create_sealed(payload: SealedPayload) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let header = MessageHeader {
+            message_type: MessageType::Sealed,
+            sender_id: String::new(),
+            recipient_id: String::new(),
+            timestamp,
+            message_id: String::new(),
+            sequence_number: 0,
+        };
+
+        Self {
+            header,
+            payload: MessagePayload::Sealed(payload),
+            signature: None,
+            message_type: MessageType::Sealed,
+            sender_id: String::new(),
+            recipient_id: String::new(),
+            content: Vec::new(),
+            timestamp,
+            message_id: String::new(),
+        }
+    }
+
     pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let data = serde_json::to_vec(self)?;
         Ok(data)
@@ -411,6 +875,13 @@ impl ProtocolMessage {
             _ => None,
         }
     }
+
+    pub fn get_reaction(&self) -> Option<&ReactionPayload> {
+        match &self.payload {
+            MessagePayload::Reaction(reaction) => Some(reaction),
+            _ => None,
+        }
+    }
 }
 
 pub const PROTOCOL_VERSION: u8 = 1;
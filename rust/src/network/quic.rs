@@ -0,0 +1,729 @@
+use crate::network::hole_punch::BoxFuture;
+use crate::network::protocol::ProtocolMessage;
+use crate::network::transport::{Connection, Listener, Transport};
+use crate::network::types::*;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+const ALPN: &[u8] = b"shadowghost/1";
+/// Certificates are self-signed and the name in them is never checked.
+const CERTIFICATE_NAME: &str = "shadowghost";
+const STREAM_MESSAGES: u8 = 0x01;
+const STREAM_FILE: u8 = 0x02;
+const MAX_FILE_NAME: usize = 1024;
+
+/// SHA-256 of a peer's certificate, for pinning it on later connections.
+pub type CertificateFingerprint = [u8; 32];
+
+/// A UDP endpoint that dials and accepts QUIC connections.
+///
+/// Chat messages share one long-lived stream per connection while every
+/// file gets a stream of its own, so a large transfer never holds up a
+/// message queued behind it. Session tickets from earlier connections are
+/// kept for 0-RTT, and [`rebind`](Self::rebind) moves every open
+/// connection to a new socket when the local address changes.
+pub struct QuicTransport {
+    endpoint: quinn::Endpoint,
+    config: QuicConfig,
+    client_config: quinn::ClientConfig,
+    verifier: Arc<PinnedVerifier>,
+    sessions: Arc<ClientSessionMemoryCache>,
+    fingerprint: CertificateFingerprint,
+    shutdown: CancellationToken,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl QuicTransport {
+    /// Binds to `address` with a freshly generated certificate.
+    pub fn bind(address: SocketAddr, config: QuicConfig) -> Result<Self, QuicError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let certified = rcgen::generate_simple_self_signed(vec![CERTIFICATE_NAME.to_string()])
+            .map_err(|e| QuicError::Tls(e.to_string()))?;
+        let certificate = certified.cert.der().clone();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let fingerprint = Sha256::digest(certificate.as_ref()).into();
+
+        let mut server_crypto = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| QuicError::Tls(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], key)
+            .map_err(|e| QuicError::Tls(e.to_string()))?;
+        server_crypto.alpn_protocols = vec![ALPN.to_vec()];
+        if config.zero_rtt {
+            // quinn only accepts early data with the limit left unbounded
+            server_crypto.max_early_data_size = u32::MAX;
+        }
+        let server_crypto = quinn::crypto::rustls::QuicServerConfig::try_from(server_crypto)
+            .map_err(|e| QuicError::Tls(e.to_string()))?;
+
+        // rustls only resumes sessions made with the very same verifier, so
+        // there is one for all connections and pins are looked up by peer
+        let verifier = Arc::new(PinnedVerifier {
+            provider: provider.clone(),
+            pins: std::sync::Mutex::new(HashMap::new()),
+            mismatched: std::sync::Mutex::new(HashSet::new()),
+        });
+        let sessions = Arc::new(ClientSessionMemoryCache::new(256));
+
+        let mut client_crypto = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| QuicError::Tls(e.to_string()))?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        client_crypto.alpn_protocols = vec![ALPN.to_vec()];
+        client_crypto.enable_early_data = config.zero_rtt;
+        client_crypto.resumption = rustls::client::Resumption::store(sessions.clone());
+        let client_crypto = quinn::crypto::rustls::QuicClientConfig::try_from(client_crypto)
+            .map_err(|e| QuicError::Tls(e.to_string()))?;
+
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(config.keep_alive_interval));
+        transport.max_idle_timeout(Some(
+            quinn::IdleTimeout::try_from(config.idle_timeout)
+                .map_err(|e| QuicError::Io(e.to_string()))?,
+        ));
+        let transport = Arc::new(transport);
+
+        let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        client_config.transport_config(transport.clone());
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+        server_config.transport_config(transport);
+        let endpoint = quinn::Endpoint::server(server_config, address)?;
+
+        Ok(Self {
+            endpoint,
+            config,
+            client_config,
+            verifier,
+            sessions,
+            fingerprint,
+            shutdown: CancellationToken::new(),
+            watcher: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, QuicError> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Our certificate's fingerprint, for peers that want to pin it.
+    pub fn fingerprint(&self) -> CertificateFingerprint {
+        self.fingerprint
+    }
+
+    /// Connects to `address`, refusing the peer unless its certificate
+    /// matches `pinned` when given. Peers are authenticated by the protocol
+    /// handshake either way; pinning just fails faster.
+    pub async fn connect(
+        &self,
+        address: SocketAddr,
+        pinned: Option<CertificateFingerprint>,
+    ) -> Result<QuicConnection, QuicError> {
+        // Session tickets are stored per server name, so naming the peer by
+        // its address keeps one peer's ticket from being offered to another
+        let ip = address.ip();
+        let server_name = ServerName::from(ip);
+        if self.verifier.pin(ip, pinned) {
+            // Resuming skips the certificate check, so tickets from before
+            // the pin changed must not be used
+            while self.sessions.take_tls13_ticket(&server_name).is_some() {}
+        }
+
+        let connecting = self
+            .endpoint
+            .connect_with(self.client_config.clone(), address, &ip.to_string())
+            .map_err(|e| QuicError::Connect(e.to_string()))?;
+        self.establish(connecting).await.map_err(|error| {
+            if self.verifier.take_mismatch(ip) {
+                QuicError::CertificateMismatch
+            } else {
+                error
+            }
+        })
+    }
+
+    async fn establish(&self, connecting: quinn::Connecting) -> Result<QuicConnection, QuicError> {
+        let connecting = if self.config.zero_rtt {
+            match connecting.into_0rtt() {
+                Ok((connection, accepted)) => {
+                    let stream = open_messages(&connection).await;
+                    if accepted.await {
+                        let (send, recv) = stream?;
+                        return Ok(self.wrap(connection, send, recv, true));
+                    }
+                    // Streams opened in 0-RTT are gone once it is rejected
+                    let (send, recv) = open_messages(&connection).await?;
+                    return Ok(self.wrap(connection, send, recv, false));
+                }
+                Err(connecting) => connecting,
+            }
+        } else {
+            connecting
+        };
+
+        let connection = connecting
+            .await
+            .map_err(|e| QuicError::Connect(e.to_string()))?;
+        let (send, recv) = open_messages(&connection).await?;
+        Ok(self.wrap(connection, send, recv, false))
+    }
+
+    /// Waits for the next incoming connection.
+    pub async fn accept(&self) -> Result<QuicConnection, QuicError> {
+        accept_on(&self.endpoint, self.config.max_file_size).await
+    }
+
+    /// Moves the endpoint, and every connection on it, to a new socket.
+    /// Peers follow once they see packets from the new address.
+    pub fn rebind(&self, address: SocketAddr) -> Result<(), QuicError> {
+        let socket = UdpSocket::bind(address)?;
+        self.endpoint.rebind(socket)?;
+        Ok(())
+    }
+
+    /// Rebinds whenever the set of local interface addresses changes, e.g.
+    /// when a phone moves from Wi-Fi to mobile data.
+    pub fn start_migration_watch(&mut self) {
+        if self.watcher.is_some() {
+            return;
+        }
+
+        let transport = QuicRebinder {
+            endpoint: self.endpoint.clone(),
+        };
+        let interval = self.config.migration_check_interval;
+        let token = self.shutdown.clone();
+
+        self.watcher = Some(tokio::spawn(async move {
+            let mut known = local_addresses();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                let current = local_addresses();
+                if current != known {
+                    // Keep trying on the next tick if no socket can be had
+                    if transport.rebind().is_ok() {
+                        known = current;
+                    }
+                }
+            }
+        }));
+    }
+
+    pub async fn close(&mut self) {
+        self.shutdown.cancel();
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.await;
+        }
+        self.endpoint.close(0u32.into(), b"shutdown");
+        self.endpoint.wait_idle().await;
+    }
+
+    fn wrap(
+        &self,
+        connection: quinn::Connection,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+        zero_rtt: bool,
+    ) -> QuicConnection {
+        QuicConnection {
+            connection,
+            send: Mutex::new(send),
+            recv: Mutex::new(recv),
+            zero_rtt,
+            max_file_size: self.config.max_file_size,
+        }
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+struct QuicRebinder {
+    endpoint: quinn::Endpoint,
+}
+
+impl QuicRebinder {
+    fn rebind(&self) -> Result<(), QuicError> {
+        let unspecified = match self.endpoint.local_addr()? {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        self.endpoint.rebind(socket)?;
+        Ok(())
+    }
+}
+
+/// A QUIC connection to one peer.
+pub struct QuicConnection {
+    connection: quinn::Connection,
+    send: Mutex<quinn::SendStream>,
+    recv: Mutex<quinn::RecvStream>,
+    zero_rtt: bool,
+    max_file_size: u64,
+}
+
+impl QuicConnection {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    pub fn rtt(&self) -> std::time::Duration {
+        self.connection.rtt()
+    }
+
+    /// Whether the connection was resumed and the first messages went out
+    /// as 0-RTT data. Only known on the dialing side.
+    pub fn used_zero_rtt(&self) -> bool {
+        self.zero_rtt
+    }
+
+    /// Fingerprint of the certificate the peer presented.
+    pub fn peer_fingerprint(&self) -> Option<CertificateFingerprint> {
+        let identity = self.connection.peer_identity()?;
+        let certificates = identity.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+        let certificate = certificates.first()?;
+        Some(Sha256::digest(certificate.as_ref()).into())
+    }
+
+    /// Sends one message on the message stream.
+    pub async fn send(&self, data: &[u8]) -> Result<(), QuicError> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(QuicError::TooLarge(data.len() as u64));
+        }
+        let mut frame = Vec::with_capacity(4 + data.len());
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        self.send
+            .lock()
+            .await
+            .write_all(&frame)
+            .await
+            .map_err(|e| QuicError::Stream(e.to_string()))
+    }
+
+    /// Waits for the next message on the message stream.
+    pub async fn recv(&self) -> Result<Vec<u8>, QuicError> {
+        let mut recv = self.recv.lock().await;
+        let mut length = [0u8; 4];
+        recv.read_exact(&mut length).await.map_err(stream_error)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_MESSAGE_SIZE {
+            return Err(QuicError::TooLarge(length as u64));
+        }
+        let mut data = vec![0u8; length];
+        recv.read_exact(&mut data).await.map_err(stream_error)?;
+        Ok(data)
+    }
+
+    pub async fn send_message(&self, message: &ProtocolMessage) -> Result<(), QuicError> {
+        let bytes = message
+            .to_bytes()
+            .map_err(|e| QuicError::Stream(e.to_string()))?;
+        self.send(&bytes).await
+    }
+
+    pub async fn recv_message(&self) -> Result<ProtocolMessage, QuicError> {
+        let bytes = self.recv().await?;
+        ProtocolMessage::from_bytes(&bytes).map_err(|e| QuicError::Stream(e.to_string()))
+    }
+
+    /// Sends a file on a stream of its own, next to the message stream.
+    pub async fn send_file(&self, name: &str, data: &[u8]) -> Result<(), QuicError> {
+        if data.len() as u64 > self.max_file_size {
+            return Err(QuicError::TooLarge(data.len() as u64));
+        }
+        if name.len() > MAX_FILE_NAME {
+            return Err(QuicError::Stream("File name too long".to_string()));
+        }
+
+        let mut header = vec![STREAM_FILE];
+        header.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&(data.len() as u64).to_be_bytes());
+
+        let mut stream = self.connection.open_uni().await.map_err(connection_error)?;
+        stream
+            .write_all(&header)
+            .await
+            .map_err(|e| QuicError::Stream(e.to_string()))?;
+        stream
+            .write_all(data)
+            .await
+            .map_err(|e| QuicError::Stream(e.to_string()))?;
+        stream
+            .finish()
+            .map_err(|e| QuicError::Stream(e.to_string()))
+    }
+
+    /// Waits for the next file the peer sends, returning its name and
+    /// contents.
+    pub async fn accept_file(&self) -> Result<(String, Vec<u8>), QuicError> {
+        let mut stream = self
+            .connection
+            .accept_uni()
+            .await
+            .map_err(connection_error)?;
+
+        let mut header = [0u8; 3];
+        stream.read_exact(&mut header).await.map_err(stream_error)?;
+        if header[0] != STREAM_FILE {
+            return Err(QuicError::Stream(format!(
+                "Unexpected stream kind {}",
+                header[0]
+            )));
+        }
+        let name_len = u16::from_be_bytes([header[1], header[2]]) as usize;
+        if name_len > MAX_FILE_NAME {
+            return Err(QuicError::Stream("File name too long".to_string()));
+        }
+        let mut name = vec![0u8; name_len];
+        stream.read_exact(&mut name).await.map_err(stream_error)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| QuicError::Stream("File name is not UTF-8".to_string()))?;
+
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size).await.map_err(stream_error)?;
+        let size = u64::from_be_bytes(size);
+        if size > self.max_file_size {
+            return Err(QuicError::TooLarge(size));
+        }
+        let mut data = vec![0u8; size as usize];
+        stream.read_exact(&mut data).await.map_err(stream_error)?;
+        Ok((name, data))
+    }
+
+    pub fn close(&self) {
+        self.connection.close(0u32.into(), b"closed");
+    }
+
+    /// The message stream as a transport-neutral [`Connection`]. Files
+    /// cannot be exchanged once the connection is handed over.
+    pub fn into_connection(self) -> Connection {
+        Connection::new(
+            Endpoint::Quic(self.connection.remote_address()),
+            self.recv.into_inner(),
+            self.send.into_inner(),
+        )
+    }
+}
+
+/// A QUIC endpoint dials and accepts on the one socket it was bound to, so
+/// it listens there and nowhere else.
+impl Transport for QuicTransport {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["quic"]
+    }
+
+    fn listen<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, TransportError>> {
+        Box::pin(async move {
+            let local = self
+                .local_addr()
+                .map_err(|e| TransportError::Io(e.to_string()))?;
+            match endpoint {
+                Endpoint::Quic(address) if *address == local || address.port() == 0 => {
+                    Ok(Box::new(QuicListener {
+                        endpoint: self.endpoint.clone(),
+                        max_file_size: self.config.max_file_size,
+                    }) as Box<dyn Listener>)
+                }
+                _ => Err(TransportError::InvalidEndpoint(format!(
+                    "{} is not the QUIC socket at {}",
+                    endpoint, local
+                ))),
+            }
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            let address = match endpoint {
+                Endpoint::Quic(address) => *address,
+                other => {
+                    return Err(TransportError::UnsupportedScheme(
+                        other.scheme().to_string(),
+                    ))
+                }
+            };
+            let connection = self
+                .connect(address, None)
+                .await
+                .map_err(|e| TransportError::Io(e.to_string()))?;
+            Ok(connection.into_connection())
+        })
+    }
+}
+
+struct QuicListener {
+    endpoint: quinn::Endpoint,
+    max_file_size: u64,
+}
+
+impl Listener for QuicListener {
+    fn local_endpoint(&self) -> Endpoint {
+        match self.endpoint.local_addr() {
+            Ok(address) => Endpoint::Quic(address),
+            Err(_) => Endpoint::Quic(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+        }
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            accept_on(&self.endpoint, self.max_file_size)
+                .await
+                .map(QuicConnection::into_connection)
+                .map_err(|e| TransportError::Io(e.to_string()))
+        })
+    }
+}
+
+/// Carries one peer's messages over a QUIC connection, using the same
+/// channels as [`NetworkManager::attach_connection`](crate::network::NetworkManager::attach_connection):
+/// queued messages go out on the message stream, and messages from the
+/// peer come out of `incoming` for the manager to handle.
+pub struct QuicLink {
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl QuicLink {
+    pub fn spawn(
+        connection: Arc<QuicConnection>,
+        mut outgoing: mpsc::UnboundedReceiver<ProtocolMessage>,
+        incoming: mpsc::UnboundedSender<ProtocolMessage>,
+    ) -> Self {
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+
+        let task = tokio::spawn(async move {
+            let receiver = connection.clone();
+            let reader = async move {
+                // Framing survives a message that fails to parse, but not a
+                // stream error
+                while let Ok(bytes) = receiver.recv().await {
+                    let message = match ProtocolMessage::from_bytes(&bytes) {
+                        Ok(message) => message,
+                        Err(_) => continue,
+                    };
+                    if incoming.send(message).is_err() {
+                        break;
+                    }
+                }
+            };
+            let writer = async {
+                while let Some(message) = outgoing.recv().await {
+                    if connection.send_message(&message).await.is_err() {
+                        break;
+                    }
+                }
+            };
+
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = reader => {}
+                _ = writer => {}
+            }
+        });
+
+        Self {
+            shutdown,
+            task: Some(task),
+        }
+    }
+
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for QuicLink {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Waits for a connection on `endpoint` and the message stream the dialer
+/// opens right away.
+async fn accept_on(
+    endpoint: &quinn::Endpoint,
+    max_file_size: u64,
+) -> Result<QuicConnection, QuicError> {
+    let incoming = match endpoint.accept().await {
+        Some(incoming) => incoming,
+        None => return Err(QuicError::Connection("Endpoint closed".to_string())),
+    };
+    let connecting = incoming
+        .accept()
+        .map_err(|e| QuicError::Connect(e.to_string()))?;
+
+    // Lets early data from a resuming peer be read right away
+    let connection = match connecting.into_0rtt() {
+        Ok((connection, _)) => connection,
+        Err(connecting) => connecting
+            .await
+            .map_err(|e| QuicError::Connect(e.to_string()))?,
+    };
+
+    let (send, mut recv) = connection.accept_bi().await.map_err(connection_error)?;
+    let mut kind = [0u8; 1];
+    recv.read_exact(&mut kind).await.map_err(stream_error)?;
+    if kind[0] != STREAM_MESSAGES {
+        return Err(QuicError::Stream(format!(
+            "Unexpected stream kind {}",
+            kind[0]
+        )));
+    }
+    Ok(QuicConnection {
+        connection,
+        send: Mutex::new(send),
+        recv: Mutex::new(recv),
+        zero_rtt: false,
+        max_file_size,
+    })
+}
+
+/// Opens the message stream. Nothing reaches the peer until something is
+/// written, so the stream kind goes out right away.
+async fn open_messages(
+    connection: &quinn::Connection,
+) -> Result<(quinn::SendStream, quinn::RecvStream), QuicError> {
+    let (mut send, recv) = connection.open_bi().await.map_err(connection_error)?;
+    send.write_all(&[STREAM_MESSAGES])
+        .await
+        .map_err(|e| QuicError::Stream(e.to_string()))?;
+    Ok((send, recv))
+}
+
+fn connection_error(error: quinn::ConnectionError) -> QuicError {
+    QuicError::Connection(error.to_string())
+}
+
+fn stream_error(error: quinn::ReadExactError) -> QuicError {
+    QuicError::Stream(error.to_string())
+}
+
+fn local_addresses() -> BTreeSet<IpAddr> {
+    if_addrs::get_if_addrs()
+        .map(|interfaces| {
+            interfaces
+                .into_iter()
+                .filter(|interface| !interface.is_loopback())
+                .map(|interface| interface.ip())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Accepts any certificate from peers without a pin. Handshake signatures
+/// are still checked, so the peer does hold the key it presents.
+#[derive(Debug)]
+struct PinnedVerifier {
+    provider: Arc<CryptoProvider>,
+    pins: std::sync::Mutex<HashMap<IpAddr, Option<CertificateFingerprint>>>,
+    mismatched: std::sync::Mutex<HashSet<IpAddr>>,
+}
+
+impl PinnedVerifier {
+    /// Sets the pin for the next connection to `ip`, returning whether it
+    /// differs from the one earlier connections were made under.
+    fn pin(&self, ip: IpAddr, pinned: Option<CertificateFingerprint>) -> bool {
+        let previous = self.pins.lock().unwrap().insert(ip, pinned);
+        matches!(previous, Some(previous) if previous != pinned)
+    }
+
+    fn take_mismatch(&self, ip: IpAddr) -> bool {
+        self.mismatched.lock().unwrap().remove(&ip)
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let ip = match server_name {
+            ServerName::IpAddress(ip) => IpAddr::from(*ip),
+            _ => return Ok(ServerCertVerified::assertion()),
+        };
+        let pinned = self.pins.lock().unwrap().get(&ip).copied().flatten();
+        match pinned {
+            Some(pinned) if pinned != <[u8; 32]>::from(Sha256::digest(end_entity.as_ref())) => {
+                self.mismatched.lock().unwrap().insert(ip);
+                Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,388 @@
+use crate::network::types::{RateLimitConfig, RateLimitError};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Records are pruned once there are this many. Banned ones, and ones
+/// whose buckets are still refilling, are kept.
+const MAX_RECORDS: usize = 4096;
+
+/// Holds up to `capacity` tokens and gains `rate` of them per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// Takes `amount` tokens, if the bucket holds that many by `now`.
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    /// Takes `amount` tokens even if that leaves the bucket in debt, and
+    /// returns how long until it is out of debt again.
+    pub fn borrow(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    pub fn available(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+/// Counters of a rate limiter, as reported in [`NetworkStats`](crate::network::NetworkStats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitStats {
+    pub connections_refused: u64,
+    pub messages_dropped: u64,
+    pub violations: u64,
+    pub bans: u64,
+    pub blocks: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Peer(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Record {
+    connections: TokenBucket,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    bans: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Record {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            connections: TokenBucket::new(
+                config.connections_per_second,
+                config.connection_burst,
+                now,
+            ),
+            messages: TokenBucket::new(config.messages_per_second, config.message_burst, now),
+            bytes: TokenBucket::new(config.bytes_per_second, config.byte_burst, now),
+            violations: 0,
+            bans: 0,
+            banned_until: None,
+        }
+    }
+
+    fn ban_remaining(&mut self, now: Instant) -> Option<Duration> {
+        let until = self.banned_until?;
+        if until <= now {
+            self.banned_until = None;
+            return None;
+        }
+        Some(until - now)
+    }
+}
+
+/// Connection, message and byte limits per peer and per IP address.
+/// Every message or connection over a limit is a violation; enough of
+/// them earn a temporary ban, and a peer banned often enough is blocked
+/// until [`unblock`](Self::unblock)ed. Peers are only ever the ones that
+/// proved who they are, so what happens before that counts against the
+/// address alone, which can be banned but never blocked.
+///
+/// Loopback addresses are not limited by IP, since Tor hands every onion
+/// peer over from there.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    records: HashMap<Subject, Record>,
+    blocked: HashSet<String>,
+    newly_blocked: Vec<(String, String)>,
+    stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            records: HashMap::new(),
+            blocked: HashSet::new(),
+            newly_blocked: Vec::new(),
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Applies new limits with every bucket full again. Bans and blocks
+    /// in force are kept.
+    pub fn set_config(&mut self, config: RateLimitConfig) {
+        self.config = config;
+        self.records
+            .retain(|_, record| record.banned_until.is_some() || record.bans > 0);
+        let now = Instant::now();
+        for record in self.records.values_mut() {
+            let fresh = Record::new(&self.config, now);
+            record.connections = fresh.connections;
+            record.messages = fresh.messages;
+            record.bytes = fresh.bytes;
+        }
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats
+    }
+
+    pub fn block(&mut self, peer_id: &str) {
+        self.blocked.insert(peer_id.to_string());
+    }
+
+    /// Lifts a block, along with the ban history that led to it.
+    pub fn unblock(&mut self, peer_id: &str) {
+        self.blocked.remove(peer_id);
+        self.records.remove(&Subject::Peer(peer_id.to_string()));
+    }
+
+    pub fn is_blocked(&self, peer_id: &str) -> bool {
+        self.blocked.contains(peer_id)
+    }
+
+    /// Peers blocked for repeated bans since the last call, with the
+    /// reason for each.
+    pub fn take_newly_blocked(&mut self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.newly_blocked)
+    }
+
+    /// Checks a connection coming in from `ip` before anything is read
+    /// from it.
+    pub fn admit_connection(
+        &mut self,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), RateLimitError> {
+        let result = match Self::limited_ip(ip) {
+            Some(ip) => self.take(Subject::Ip(ip), now, |record, now| {
+                record
+                    .connections
+                    .try_take(1.0, now)
+                    .then_some(())
+                    .ok_or(RateLimitError::TooManyConnections)
+            }),
+            None => Ok(()),
+        };
+        if result.is_err() {
+            self.stats.connections_refused += 1;
+        }
+        result
+    }
+
+    /// Checks the peer a connection turned out to be from, once its first
+    /// message is in.
+    pub fn admit_peer(&mut self, peer_id: &str, now: Instant) -> Result<(), RateLimitError> {
+        let result = self.check_peer(peer_id, now);
+        if result.is_err() {
+            self.stats.connections_refused += 1;
+        }
+        result
+    }
+
+    /// Checks a message of `size` bytes from `peer_id`, connected from
+    /// `ip`. A ban or block means the connection should be closed, any
+    /// other error only that the message should be dropped.
+    pub fn admit_message(
+        &mut self,
+        peer_id: &str,
+        ip: Option<IpAddr>,
+        size: usize,
+        now: Instant,
+    ) -> Result<(), RateLimitError> {
+        let mut result = self.check_peer(peer_id, now);
+        let mut subjects = vec![Subject::Peer(peer_id.to_string())];
+        if let Some(ip) = Self::limited_ip(ip) {
+            subjects.push(Subject::Ip(ip));
+        }
+        for subject in subjects {
+            if result.is_err() {
+                break;
+            }
+            result = self.take(subject, now, |record, now| {
+                if !record.messages.try_take(1.0, now) {
+                    return Err(RateLimitError::TooManyMessages);
+                }
+                if !record.bytes.try_take(size as f64, now) {
+                    return Err(RateLimitError::TooManyBytes);
+                }
+                Ok(())
+            });
+        }
+        if result.is_err() {
+            self.stats.messages_dropped += 1;
+        }
+        result
+    }
+
+    /// Counts a violation the limiter could not see for itself, such as a
+    /// malformed frame, against `peer_id`. Returns the penalty, if any.
+    pub fn report(
+        &mut self,
+        peer_id: &str,
+        violation: RateLimitError,
+        now: Instant,
+    ) -> RateLimitError {
+        let subject = Subject::Peer(peer_id.to_string());
+        self.ensure_record(&subject, now);
+        self.violation(subject, violation, now)
+    }
+
+    /// Counts a violation by a connection from `ip` that has not proved
+    /// who it is yet, such as a failed handshake. Returns the penalty, if
+    /// any.
+    pub fn report_ip(
+        &mut self,
+        ip: Option<IpAddr>,
+        violation: RateLimitError,
+        now: Instant,
+    ) -> RateLimitError {
+        match Self::limited_ip(ip) {
+            Some(ip) => {
+                let subject = Subject::Ip(ip);
+                self.ensure_record(&subject, now);
+                self.violation(subject, violation, now)
+            }
+            None => {
+                self.stats.violations += 1;
+                violation
+            }
+        }
+    }
+
+    fn check_peer(&mut self, peer_id: &str, now: Instant) -> Result<(), RateLimitError> {
+        if self.blocked.contains(peer_id) {
+            return Err(RateLimitError::Blocked);
+        }
+        match self
+            .records
+            .get_mut(&Subject::Peer(peer_id.to_string()))
+            .and_then(|record| record.ban_remaining(now))
+        {
+            Some(remaining) => Err(RateLimitError::Banned { remaining }),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `check` on the subject's record unless it is banned, counting
+    /// a failed check as a violation.
+    fn take<F>(&mut self, subject: Subject, now: Instant, check: F) -> Result<(), RateLimitError>
+    where
+        F: FnOnce(&mut Record, Instant) -> Result<(), RateLimitError>,
+    {
+        self.ensure_record(&subject, now);
+        let record = match self.records.get_mut(&subject) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        if let Some(remaining) = record.ban_remaining(now) {
+            return Err(RateLimitError::Banned { remaining });
+        }
+        match check(record, now) {
+            Ok(()) => Ok(()),
+            Err(violation) => Err(self.violation(subject, violation, now)),
+        }
+    }
+
+    /// Counts `violation` against `subject`, banning it once it has too
+    /// many and blocking a peer once it has been banned too often.
+    fn violation(
+        &mut self,
+        subject: Subject,
+        violation: RateLimitError,
+        now: Instant,
+    ) -> RateLimitError {
+        self.stats.violations += 1;
+        let record = match self.records.get_mut(&subject) {
+            Some(record) => record,
+            None => return violation,
+        };
+        record.violations += 1;
+        if record.violations < self.config.violations_before_ban {
+            return violation;
+        }
+
+        record.violations = 0;
+        record.bans += 1;
+        record.banned_until = Some(now + self.config.ban_duration);
+        let bans = record.bans;
+        self.stats.bans += 1;
+
+        match subject {
+            Subject::Peer(peer_id) if bans >= self.config.bans_before_block => {
+                let reason = format!("Banned {} times, last for: {}", bans, violation);
+                self.stats.blocks += 1;
+                self.blocked.insert(peer_id.clone());
+                self.newly_blocked.push((peer_id, reason));
+                RateLimitError::Blocked
+            }
+            _ => RateLimitError::Banned {
+                remaining: self.config.ban_duration,
+            },
+        }
+    }
+
+    fn ensure_record(&mut self, subject: &Subject, now: Instant) {
+        if self.records.contains_key(subject) {
+            return;
+        }
+        if self.records.len() >= MAX_RECORDS {
+            self.prune(now);
+        }
+        self.records
+            .insert(subject.clone(), Record::new(&self.config, now));
+    }
+
+    /// Forgets records that would be created afresh the same: not banned,
+    /// and with every bucket full again.
+    fn prune(&mut self, now: Instant) {
+        let config = &self.config;
+        self.records.retain(|_, record| {
+            record.ban_remaining(now).is_some()
+                || record.bans > 0
+                || record.connections.available(now) < config.connection_burst
+                || record.messages.available(now) < config.message_burst
+                || record.bytes.available(now) < config.byte_burst
+        });
+    }
+
+    fn limited_ip(ip: Option<IpAddr>) -> Option<IpAddr> {
+        ip.filter(|ip| !ip.is_loopback())
+    }
+}
@@ -0,0 +1,270 @@
+use crate::network::hole_punch::{DatagramSocket, PunchPacket, MAX_DATAGRAM_SIZE};
+use crate::network::types::*;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Answers "what address do you see me at?" requests. Any peer can run one
+/// so that others learn their public ip:port without asking third parties.
+pub struct AddressMirror {
+    socket: Arc<UdpSocket>,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl AddressMirror {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, PunchError> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            shutdown: CancellationToken::new(),
+            task: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, PunchError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn start(&mut self) {
+        if self.task.is_some() {
+            return;
+        }
+
+        let socket = self.socket.clone();
+        let shutdown = self.shutdown.clone();
+        self.task = Some(tokio::spawn(async move {
+            let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, from) = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    received = socket.recv_from(&mut buffer) => match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    },
+                };
+                if let Some(response) = Self::answer(&buffer[..len], from) {
+                    let _ = socket.send_to(&response, from).await;
+                }
+            }
+        }));
+    }
+
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    /// Response to a mirror request, or `None` if `data` is not one.
+    pub fn answer(data: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        match PunchPacket::decode(data)? {
+            PunchPacket::Bind { txn } => Some(
+                PunchPacket::BindResponse {
+                    txn,
+                    observed: from,
+                }
+                .encode(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for AddressMirror {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Learns this socket's public address from several peer mirrors and
+/// classifies the NAT in front of it.
+///
+/// A single mirror can lie or be wrong, so an address is only reported when
+/// enough mirrors agree on it. Mirrors agreeing on the IP but not the port
+/// mean the NAT allocates a port per destination (symmetric NAT).
+pub struct ReflexiveDiscovery {
+    socket: Arc<dyn DatagramSocket>,
+    config: ReflexiveConfig,
+}
+
+impl ReflexiveDiscovery {
+    pub fn new(socket: Arc<dyn DatagramSocket>) -> Self {
+        Self {
+            socket,
+            config: ReflexiveConfig::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: ReflexiveConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Asks every mirror once (resending until the timeout) and returns the
+    /// answers that came back. The socket must not be read by anything else
+    /// meanwhile.
+    pub async fn query(&self, mirrors: &[SocketAddr]) -> Vec<MirrorObservation> {
+        let mut pending: HashMap<u64, SocketAddr> = HashMap::new();
+        for mirror in mirrors {
+            if !pending.values().any(|m| m == mirror) {
+                pending.insert(rand::random(), *mirror);
+            }
+        }
+
+        let mut observations = Vec::new();
+        let deadline = tokio::time::sleep(self.config.request_timeout);
+        tokio::pin!(deadline);
+        let mut ticker = tokio::time::interval(self.config.resend_interval);
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+        while !pending.is_empty() {
+            tokio::select! {
+                _ = &mut deadline => break,
+                _ = ticker.tick() => {
+                    for (txn, mirror) in &pending {
+                        let request = PunchPacket::Bind { txn: *txn }.encode();
+                        let _ = self.socket.send_to(&request, *mirror).await;
+                    }
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    if let Some(PunchPacket::BindResponse { txn, observed }) =
+                        PunchPacket::decode(&buffer[..len])
+                    {
+                        if pending.get(&txn) == Some(&from) {
+                            pending.remove(&txn);
+                            observations.push(MirrorObservation { mirror: from, observed });
+                        }
+                    }
+                }
+            }
+        }
+
+        observations
+    }
+
+    /// Queries the mirrors and evaluates their answers. Falls back to the
+    /// HTTP lookup for the IP only if that is explicitly allowed.
+    pub async fn discover(&self, mirrors: &[SocketAddr]) -> Result<ReflexiveReport, PunchError> {
+        let local_address = self.socket.local_addr()?;
+        let observations = self.query(mirrors).await;
+        let mut report = Self::evaluate(local_address, observations, self.config.min_agreement);
+
+        if report.external_ip.is_none() && self.config.allow_http_fallback {
+            report.external_ip = lookup_external_ip_http().await.ok();
+        }
+        Ok(report)
+    }
+
+    /// Derives the consensus address and NAT mapping from mirror answers.
+    pub fn evaluate(
+        local_address: SocketAddr,
+        observations: Vec<MirrorObservation>,
+        min_agreement: usize,
+    ) -> ReflexiveReport {
+        let public_address = Self::consensus(
+            observations.iter().map(|o| o.observed),
+            observations.len(),
+            min_agreement,
+        );
+        let external_ip = Self::consensus(
+            observations.iter().map(|o| o.observed.ip()),
+            observations.len(),
+            min_agreement,
+        );
+
+        let nat_mapping = match (public_address, external_ip) {
+            (Some(address), _) if Self::is_local(local_address, address) => NatMapping::NotNatted,
+            (Some(_), _) => NatMapping::EndpointIndependent,
+            (None, Some(_)) => NatMapping::Symmetric,
+            (None, None) if observations.len() >= min_agreement.max(2) => NatMapping::Inconsistent,
+            (None, None) => NatMapping::Unknown,
+        };
+
+        ReflexiveReport {
+            local_address,
+            observations,
+            public_address,
+            external_ip,
+            nat_mapping,
+        }
+    }
+
+    /// The most common value, if at least `min_agreement` answers and a
+    /// strict majority of them name it.
+    fn consensus<T: Eq + Hash + Copy>(
+        values: impl Iterator<Item = T>,
+        total: usize,
+        min_agreement: usize,
+    ) -> Option<T> {
+        let mut counts: HashMap<T, usize> = HashMap::new();
+        for value in values {
+            *counts.entry(value).or_insert(0) += 1;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .filter(|(_, count)| *count >= min_agreement.max(1) && *count * 2 > total)
+            .map(|(value, _)| value)
+    }
+
+    fn is_local(local_address: SocketAddr, observed: SocketAddr) -> bool {
+        if local_address.port() != observed.port() {
+            return false;
+        }
+        if !local_address.ip().is_unspecified() {
+            return local_address.ip() == observed.ip();
+        }
+        if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .iter()
+            .any(|iface| iface.ip() == observed.ip())
+    }
+}
+
+/// Asks public HTTP services for the external IP. This tells those services
+/// who is running ShadowGhost, so callers only use it when the user opted in.
+pub async fn lookup_external_ip_http() -> Result<IpAddr, Box<dyn std::error::Error + Send + Sync>> {
+    let services = [
+        "https://api.ipify.org",
+        "https://ipinfo.io/ip",
+        "https://httpbin.org/ip",
+    ];
+
+    for service in &services {
+        match reqwest::get(*service).await {
+            Ok(response) => {
+                if let Ok(text) = response.text().await {
+                    if service.contains("httpbin") {
+                        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) {
+                            if let Some(origin) = json.get("origin") {
+                                if let Some(ip_str) = origin.as_str() {
+                                    let ip_parts: Vec<&str> = ip_str.split(',').collect();
+                                    if let Ok(ip) = ip_parts[0].trim().parse::<IpAddr>() {
+                                        return Ok(ip);
+                                    }
+                                }
+                            }
+                        }
+                    } else {
+                        let cleaned_text = text.trim();
+                        if let Ok(ip) = cleaned_text.parse::<IpAddr>() {
+                            return Ok(ip);
+                        }
+                    }
+                }
+            }
+            Err(_) => continue,
+        }
+    }
+
+    Err("Failed to determine external IP".into())
+}
@@ -0,0 +1,1423 @@
+use crate::contacts::{ContactError, ContactManager};
+use crate::crypto::CryptoManager;
+use crate::events::{EventBus, NetworkEvent};
+use crate::network::direct_channel::DirectChannel;
+use crate::network::hole_punch::{
+    put_bytes, put_candidates, put_str, put_u64, BoxFuture, DatagramSocket, HolePuncher, Reader,
+};
+use crate::network::protocol::ProtocolMessage;
+use crate::network::reflexive::AddressMirror;
+use crate::network::types::*;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+const RELAY_MAGIC: &[u8; 3] = b"SGR";
+/// Relayed datagrams carry a channel packet plus framing and a tag, so they
+/// may exceed `MAX_DATAGRAM_SIZE`.
+const MAX_RELAY_DATAGRAM: usize = 2048;
+
+const FRAME_HELLO: u8 = 0;
+const FRAME_SEALED: u8 = 1;
+const SEALED_DATAGRAM: u8 = 0;
+const SEALED_CANDIDATES: u8 = 1;
+
+/// Datagrams exchanged between relay clients and a relay node.
+///
+/// `Send` and `Deliver` payloads are end-to-end frames the relay cannot
+/// read: a signed key exchange followed by ChaCha20-Poly1305 sealed data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayPacket {
+    /// Claims a relay allocation; signed with the peer's identity key.
+    Allocate {
+        peer_id: String,
+        timestamp: u64,
+        signature: Vec<u8>,
+    },
+    /// Bytes the peer may send per quota window.
+    Allocated {
+        quota: u64,
+    },
+    Denied {
+        reason: String,
+    },
+    Send {
+        target: String,
+        payload: Vec<u8>,
+    },
+    Deliver {
+        source: String,
+        payload: Vec<u8>,
+    },
+    UnknownTarget {
+        target: String,
+    },
+    QuotaExceeded {
+        target: String,
+    },
+}
+
+impl RelayPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = RELAY_MAGIC.to_vec();
+        match self {
+            RelayPacket::Allocate {
+                peer_id,
+                timestamp,
+                signature,
+            } => {
+                out.push(0);
+                put_str(&mut out, peer_id);
+                put_u64(&mut out, *timestamp);
+                put_bytes(&mut out, signature);
+            }
+            RelayPacket::Allocated { quota } => {
+                out.push(1);
+                put_u64(&mut out, *quota);
+            }
+            RelayPacket::Denied { reason } => {
+                out.push(2);
+                put_str(&mut out, reason);
+            }
+            RelayPacket::Send { target, payload } => {
+                out.push(3);
+                put_str(&mut out, target);
+                put_bytes(&mut out, payload);
+            }
+            RelayPacket::Deliver { source, payload } => {
+                out.push(4);
+                put_str(&mut out, source);
+                put_bytes(&mut out, payload);
+            }
+            RelayPacket::UnknownTarget { target } => {
+                out.push(5);
+                put_str(&mut out, target);
+            }
+            RelayPacket::QuotaExceeded { target } => {
+                out.push(6);
+                put_str(&mut out, target);
+            }
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader {
+            data: data.strip_prefix(RELAY_MAGIC)?,
+        };
+        let packet = match reader.u8()? {
+            0 => RelayPacket::Allocate {
+                peer_id: reader.string()?,
+                timestamp: reader.u64()?,
+                signature: reader.bytes()?,
+            },
+            1 => RelayPacket::Allocated {
+                quota: reader.u64()?,
+            },
+            2 => RelayPacket::Denied {
+                reason: reader.string()?,
+            },
+            3 => RelayPacket::Send {
+                target: reader.string()?,
+                payload: reader.bytes()?,
+            },
+            4 => RelayPacket::Deliver {
+                source: reader.string()?,
+                payload: reader.bytes()?,
+            },
+            5 => RelayPacket::UnknownTarget {
+                target: reader.string()?,
+            },
+            6 => RelayPacket::QuotaExceeded {
+                target: reader.string()?,
+            },
+            _ => return None,
+        };
+        reader.data.is_empty().then_some(packet)
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn allocation_payload(peer_id: &str, timestamp: u64) -> Vec<u8> {
+    let mut payload = b"shadowghost-relay-allocate".to_vec();
+    put_str(&mut payload, peer_id);
+    put_u64(&mut payload, timestamp);
+    payload
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    window_start: Instant,
+    bytes: u64,
+}
+
+impl Usage {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn current(&mut self, window: Duration) -> u64 {
+        if self.window_start.elapsed() >= window {
+            *self = Self::new();
+        }
+        self.bytes
+    }
+}
+
+struct Allocation {
+    address: SocketAddr,
+    timestamp: u64,
+    refreshed: Instant,
+    usage: Usage,
+}
+
+struct RelayState {
+    config: RelayConfig,
+    /// Identity keys of the peers this node agreed to relay for.
+    trusted: RwLock<HashMap<String, Vec<u8>>>,
+    allocations: RwLock<HashMap<String, Allocation>>,
+    total: std::sync::Mutex<Usage>,
+}
+
+/// Forwards end-to-end encrypted frames between trusted peers that cannot
+/// reach each other directly.
+///
+/// Relaying is opt-in on both ends: the node only allocates for peers whose
+/// identity keys it was given with `trust_peer`, and every peer is held to a
+/// byte quota per window, as is the relay as a whole. The relay also answers
+/// address-mirror requests so its clients can gather reflexive candidates
+/// for a later upgrade to a direct path.
+pub struct RelayServer {
+    socket: Arc<UdpSocket>,
+    state: Arc<RelayState>,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl RelayServer {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, RelayError> {
+        Self::bind_with_config(addr, RelayConfig::default()).await
+    }
+
+    pub async fn bind_with_config(
+        addr: SocketAddr,
+        config: RelayConfig,
+    ) -> Result<Self, RelayError> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            state: Arc::new(RelayState {
+                config,
+                trusted: RwLock::new(HashMap::new()),
+                allocations: RwLock::new(HashMap::new()),
+                total: std::sync::Mutex::new(Usage::new()),
+            }),
+            shutdown: CancellationToken::new(),
+            task: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, RelayError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Agrees to relay for `peer_id`, who must prove ownership of `public_key`.
+    pub async fn trust_peer(&self, peer_id: &str, public_key: Vec<u8>) {
+        self.state
+            .trusted
+            .write()
+            .await
+            .insert(peer_id.to_string(), public_key);
+    }
+
+    pub async fn revoke_peer(&self, peer_id: &str) {
+        self.state.trusted.write().await.remove(peer_id);
+        self.state.allocations.write().await.remove(peer_id);
+    }
+
+    pub async fn allocated_peers(&self) -> Vec<String> {
+        let timeout = self.state.config.allocation_timeout;
+        self.state
+            .allocations
+            .read()
+            .await
+            .iter()
+            .filter(|(_, allocation)| allocation.refreshed.elapsed() < timeout)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect()
+    }
+
+    /// Bytes relayed for `peer_id` in the current quota window.
+    pub async fn relayed_bytes(&self, peer_id: &str) -> u64 {
+        let window = self.state.config.quota_window;
+        self.state
+            .allocations
+            .write()
+            .await
+            .get_mut(peer_id)
+            .map(|allocation| allocation.usage.current(window))
+            .unwrap_or(0)
+    }
+
+    pub fn start(&mut self) {
+        if self.task.is_some() {
+            return;
+        }
+
+        let socket = self.socket.clone();
+        let state = self.state.clone();
+        let shutdown = self.shutdown.clone();
+        self.task = Some(tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAX_RELAY_DATAGRAM];
+            loop {
+                let (len, from) = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    received = socket.recv_from(&mut buffer) => match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    },
+                };
+
+                if let Some(response) = AddressMirror::answer(&buffer[..len], from) {
+                    let _ = socket.send_to(&response, from).await;
+                    continue;
+                }
+                if let Some(packet) = RelayPacket::decode(&buffer[..len]) {
+                    for (response, to) in Self::handle(&state, packet, from).await {
+                        let _ = socket.send_to(&response.encode(), to).await;
+                    }
+                }
+            }
+        }));
+    }
+
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    async fn handle(
+        state: &RelayState,
+        packet: RelayPacket,
+        from: SocketAddr,
+    ) -> Vec<(RelayPacket, SocketAddr)> {
+        let deny = |reason: &str| {
+            vec![(
+                RelayPacket::Denied {
+                    reason: reason.to_string(),
+                },
+                from,
+            )]
+        };
+
+        match packet {
+            RelayPacket::Allocate {
+                peer_id,
+                timestamp,
+                signature,
+            } => {
+                let key = match state.trusted.read().await.get(&peer_id) {
+                    Some(key) => key.clone(),
+                    None => return deny("peer not trusted by this relay"),
+                };
+                let payload = allocation_payload(&peer_id, timestamp);
+                if !CryptoManager::verify_with_key(&key, &payload, &signature) {
+                    return deny("invalid signature");
+                }
+                if unix_time().abs_diff(timestamp) > state.config.max_clock_skew.as_secs() {
+                    return deny("stale allocation request");
+                }
+
+                let mut allocations = state.allocations.write().await;
+                match allocations.get_mut(&peer_id) {
+                    Some(existing)
+                        if timestamp < existing.timestamp
+                            || (timestamp == existing.timestamp && from != existing.address) =>
+                    {
+                        return deny("replayed allocation request");
+                    }
+                    Some(existing) => {
+                        existing.address = from;
+                        existing.timestamp = timestamp;
+                        existing.refreshed = Instant::now();
+                    }
+                    None => {
+                        allocations.insert(
+                            peer_id,
+                            Allocation {
+                                address: from,
+                                timestamp,
+                                refreshed: Instant::now(),
+                                usage: Usage::new(),
+                            },
+                        );
+                    }
+                }
+                vec![(
+                    RelayPacket::Allocated {
+                        quota: state.config.max_bytes_per_peer,
+                    },
+                    from,
+                )]
+            }
+            RelayPacket::Send { target, payload } => {
+                let timeout = state.config.allocation_timeout;
+                let window = state.config.quota_window;
+                let mut allocations = state.allocations.write().await;
+
+                let source = allocations
+                    .iter()
+                    .find(|(_, a)| a.address == from && a.refreshed.elapsed() < timeout)
+                    .map(|(peer_id, _)| peer_id.clone());
+                let source = match source {
+                    Some(source) => source,
+                    None => return deny("not allocated"),
+                };
+                let destination = match allocations.get(&target) {
+                    Some(a) if a.refreshed.elapsed() < timeout => a.address,
+                    _ => return vec![(RelayPacket::UnknownTarget { target }, from)],
+                };
+
+                let len = payload.len() as u64;
+                let within_quota = {
+                    let mut total = state.total.lock().unwrap();
+                    let usage = &mut allocations.get_mut(&source).unwrap().usage;
+                    let allowed = usage.current(window) + len <= state.config.max_bytes_per_peer
+                        && total.current(window) + len <= state.config.max_total_bytes;
+                    if allowed {
+                        usage.bytes += len;
+                        total.bytes += len;
+                    }
+                    allowed
+                };
+                if !within_quota {
+                    return vec![(RelayPacket::QuotaExceeded { target }, from)];
+                }
+
+                vec![(RelayPacket::Deliver { source, payload }, destination)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Drop for RelayServer {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Key exchange message, signed with the sender's identity key so a relay
+/// cannot substitute its own ephemeral key.
+#[derive(Debug, Clone)]
+struct Hello {
+    session: u64,
+    ephemeral: [u8; 32],
+    signature: Vec<u8>,
+}
+
+enum Frame {
+    Hello(Hello),
+    Sealed {
+        session: u64,
+        counter: u64,
+        ciphertext: Vec<u8>,
+    },
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Frame::Hello(hello) => {
+                out.push(FRAME_HELLO);
+                put_u64(&mut out, hello.session);
+                out.extend_from_slice(&hello.ephemeral);
+                put_bytes(&mut out, &hello.signature);
+            }
+            Frame::Sealed {
+                session,
+                counter,
+                ciphertext,
+            } => {
+                out.push(FRAME_SEALED);
+                put_u64(&mut out, *session);
+                put_u64(&mut out, *counter);
+                out.extend_from_slice(ciphertext);
+            }
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader { data };
+        match reader.u8()? {
+            FRAME_HELLO => {
+                let session = reader.u64()?;
+                let ephemeral = reader.take(32)?.try_into().ok()?;
+                let signature = reader.bytes()?;
+                reader.data.is_empty().then_some(Frame::Hello(Hello {
+                    session,
+                    ephemeral,
+                    signature,
+                }))
+            }
+            FRAME_SEALED => Some(Frame::Sealed {
+                session: reader.u64()?,
+                counter: reader.u64()?,
+                ciphertext: reader.data.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn hello_payload(from: &str, to: &str, session: u64, ephemeral: &[u8; 32]) -> Vec<u8> {
+    let mut payload = b"shadowghost-relay-hello".to_vec();
+    put_str(&mut payload, from);
+    put_str(&mut payload, to);
+    put_u64(&mut payload, session);
+    payload.extend_from_slice(ephemeral);
+    payload
+}
+
+/// Sliding window over the last 64 counters, so replayed frames are dropped
+/// while mild reordering is tolerated.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+            return true;
+        }
+        let offset = self.highest - counter;
+        if offset >= 64 || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Per-direction ChaCha20-Poly1305 keys derived from an X25519 exchange.
+struct SessionCipher {
+    session: u64,
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    send_counter: AtomicU64,
+    replay: std::sync::Mutex<ReplayWindow>,
+}
+
+impl SessionCipher {
+    fn derive(
+        secret: &StaticSecret,
+        remote_ephemeral: [u8; 32],
+        session: u64,
+        initiator: &str,
+        responder: &str,
+        we_initiated: bool,
+    ) -> Result<Self, RelayError> {
+        let shared = secret.diffie_hellman(&X25519PublicKey::from(remote_ephemeral));
+        if !shared.was_contributory() {
+            return Err(RelayError::Handshake("weak ephemeral key".to_string()));
+        }
+
+        let key = |direction: &[u8]| {
+            let mut context = b"shadowghost-relay-key".to_vec();
+            put_str(&mut context, initiator);
+            put_str(&mut context, responder);
+            put_u64(&mut context, session);
+            context.extend_from_slice(direction);
+
+            let mut hasher = Sha256::new();
+            hasher.update(shared.as_bytes());
+            hasher.update(&context);
+            ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize()))
+        };
+        let (send, receive) = if we_initiated {
+            (key(b"initiator"), key(b"responder"))
+        } else {
+            (key(b"responder"), key(b"initiator"))
+        };
+
+        Ok(Self {
+            session,
+            send,
+            receive,
+            send_counter: AtomicU64::new(1),
+            replay: std::sync::Mutex::new(ReplayWindow::default()),
+        })
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.send_counter.fetch_add(1, Ordering::SeqCst);
+        let aad = self.session.to_be_bytes();
+        let ciphertext = self
+            .send
+            .encrypt(
+                Nonce::from_slice(&Self::nonce(counter)),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .expect("ChaCha20-Poly1305 encryption is infallible for in-memory buffers");
+        Frame::Sealed {
+            session: self.session,
+            counter,
+            ciphertext,
+        }
+        .encode()
+    }
+
+    fn open(&self, counter: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let aad = self.session.to_be_bytes();
+        let plaintext = self
+            .receive
+            .decrypt(
+                Nonce::from_slice(&Self::nonce(counter)),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        // Only authenticated frames may move the window
+        self.replay
+            .lock()
+            .unwrap()
+            .accept(counter)
+            .then_some(plaintext)
+    }
+}
+
+struct LinkEntry {
+    session: u64,
+    frames: mpsc::UnboundedSender<(u64, Vec<u8>)>,
+    /// Our handshake reply, resent if the initiator retransmits its hello.
+    hello_reply: Option<Vec<u8>>,
+}
+
+struct ClientState {
+    socket: Arc<dyn DatagramSocket>,
+    relay: SocketAddr,
+    peer_id: String,
+    pending: std::sync::Mutex<HashMap<(String, u64), mpsc::UnboundedSender<Hello>>>,
+    links: std::sync::Mutex<HashMap<String, LinkEntry>>,
+    hellos: mpsc::UnboundedSender<(String, Hello)>,
+    quota_exceeded: AtomicBool,
+}
+
+impl ClientState {
+    async fn send_to_peer(&self, target: &str, payload: Vec<u8>) -> io::Result<usize> {
+        let packet = RelayPacket::Send {
+            target: target.to_string(),
+            payload,
+        };
+        self.socket.send_to(&packet.encode(), self.relay).await
+    }
+}
+
+/// A peer's connection to one relay node.
+///
+/// After `register`, `connect` and `accept` set up end-to-end encrypted
+/// [`RelayedLink`]s to other peers allocated on the same relay.
+pub struct RelayClient {
+    state: Arc<ClientState>,
+    identity: Arc<RwLock<CryptoManager>>,
+    config: PunchConfig,
+    event_bus: Option<EventBus>,
+    incoming_hellos: Mutex<mpsc::UnboundedReceiver<(String, Hello)>>,
+    shutdown: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RelayClient {
+    pub fn new(
+        socket: Arc<dyn DatagramSocket>,
+        relay: SocketAddr,
+        peer_id: String,
+        identity: Arc<RwLock<CryptoManager>>,
+    ) -> Self {
+        let (hellos, incoming_hellos) = mpsc::unbounded_channel();
+        Self {
+            state: Arc::new(ClientState {
+                socket,
+                relay,
+                peer_id,
+                pending: std::sync::Mutex::new(HashMap::new()),
+                links: std::sync::Mutex::new(HashMap::new()),
+                hellos,
+                quota_exceeded: AtomicBool::new(false),
+            }),
+            identity,
+            config: PunchConfig::default(),
+            event_bus: None,
+            incoming_hellos: Mutex::new(incoming_hellos),
+            shutdown: CancellationToken::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Binds a plain UDP socket for talking to the relay.
+    pub async fn bind(
+        addr: SocketAddr,
+        relay: SocketAddr,
+        peer_id: String,
+        identity: Arc<RwLock<CryptoManager>>,
+    ) -> Result<Self, RelayError> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self::new(Arc::new(socket), relay, peer_id, identity))
+    }
+
+    pub fn with_config(mut self, config: PunchConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_event_bus(mut self, event_bus: EventBus) -> Self {
+        self.event_bus = Some(event_bus);
+        self
+    }
+
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.state.relay
+    }
+
+    /// Whether the relay has refused to forward something for lack of quota.
+    pub fn quota_exceeded(&self) -> bool {
+        self.state.quota_exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Allocates on the relay and starts serving incoming frames. Returns the
+    /// byte quota per window the relay granted.
+    pub async fn register(&mut self) -> Result<u64, RelayError> {
+        let request = Self::allocate_packet(&self.identity, &self.state.peer_id).await?;
+        let deadline = Instant::now() + self.config.punch_timeout;
+        let mut buffer = vec![0u8; MAX_RELAY_DATAGRAM];
+
+        let quota = loop {
+            if Instant::now() >= deadline {
+                return Err(RelayError::Timeout);
+            }
+            self.state
+                .socket
+                .send_to(&request, self.state.relay)
+                .await?;
+
+            let wait = self.config.retransmit_timeout * 5;
+            let received =
+                tokio::time::timeout(wait, self.state.socket.recv_from(&mut buffer)).await;
+            let (len, from) = match received {
+                Ok(received) => received?,
+                Err(_) => continue,
+            };
+            if from != self.state.relay {
+                continue;
+            }
+            match RelayPacket::decode(&buffer[..len]) {
+                Some(RelayPacket::Allocated { quota }) => break quota,
+                Some(RelayPacket::Denied { reason }) => return Err(RelayError::Denied(reason)),
+                _ => {}
+            }
+        };
+
+        if self.tasks.is_empty() {
+            let state = self.state.clone();
+            let shutdown = self.shutdown.clone();
+            let event_bus = self.event_bus.clone();
+            self.tasks.push(tokio::spawn(async move {
+                Self::receive_loop(state, event_bus, shutdown).await;
+            }));
+
+            let state = self.state.clone();
+            let identity = self.identity.clone();
+            let interval = self.config.keepalive_interval;
+            let shutdown = self.shutdown.clone();
+            self.tasks.push(tokio::spawn(async move {
+                Self::refresh_loop(state, identity, interval, shutdown).await;
+            }));
+        }
+
+        Ok(quota)
+    }
+
+    /// Opens an end-to-end encrypted link to `remote_peer_id` through the
+    /// relay. `remote_key` is the peer's identity key; a handshake signed by
+    /// any other key is ignored.
+    pub async fn connect(
+        &self,
+        remote_peer_id: &str,
+        remote_key: &[u8],
+    ) -> Result<RelayedLink, RelayError> {
+        let session: u64 = rand::random();
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let hello = self.hello(remote_peer_id, session, &secret).await?;
+
+        let (sender, mut replies) = mpsc::unbounded_channel();
+        let key = (remote_peer_id.to_string(), session);
+        self.state
+            .pending
+            .lock()
+            .unwrap()
+            .insert(key.clone(), sender);
+
+        let result = async {
+            let deadline = tokio::time::sleep(self.config.punch_timeout);
+            tokio::pin!(deadline);
+            let mut ticker = tokio::time::interval(self.config.retransmit_timeout);
+
+            loop {
+                tokio::select! {
+                    _ = &mut deadline => return Err(RelayError::Timeout),
+                    _ = ticker.tick() => {
+                        self.state.send_to_peer(remote_peer_id, hello.clone()).await?;
+                    }
+                    reply = replies.recv() => {
+                        let reply = match reply {
+                            Some(reply) => reply,
+                            None => return Err(RelayError::UnknownPeer(remote_peer_id.to_string())),
+                        };
+                        let payload = hello_payload(
+                            remote_peer_id,
+                            &self.state.peer_id,
+                            session,
+                            &reply.ephemeral,
+                        );
+                        if CryptoManager::verify_with_key(remote_key, &payload, &reply.signature) {
+                            return SessionCipher::derive(
+                                &secret,
+                                reply.ephemeral,
+                                session,
+                                &self.state.peer_id,
+                                remote_peer_id,
+                                true,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        .await;
+
+        self.state.pending.lock().unwrap().remove(&key);
+        Ok(self.open_link(remote_peer_id, result?, None, true))
+    }
+
+    /// Waits for a peer from `known_keys` to connect through the relay.
+    /// Handshakes from unknown peers or signed with the wrong key are ignored.
+    pub async fn accept(
+        &self,
+        known_keys: &HashMap<String, Vec<u8>>,
+    ) -> Result<RelayedLink, RelayError> {
+        let mut incoming = self.incoming_hellos.lock().await;
+        loop {
+            let (source, hello) = tokio::select! {
+                _ = self.shutdown.cancelled() => return Err(RelayError::Timeout),
+                received = incoming.recv() => match received {
+                    Some(received) => received,
+                    None => return Err(RelayError::Timeout),
+                },
+            };
+
+            let remote_key = match known_keys.get(&source) {
+                Some(key) => key,
+                None => continue,
+            };
+            let payload = hello_payload(
+                &source,
+                &self.state.peer_id,
+                hello.session,
+                &hello.ephemeral,
+            );
+            if !CryptoManager::verify_with_key(remote_key, &payload, &hello.signature) {
+                continue;
+            }
+
+            let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+            let cipher = match SessionCipher::derive(
+                &secret,
+                hello.ephemeral,
+                hello.session,
+                &source,
+                &self.state.peer_id,
+                false,
+            ) {
+                Ok(cipher) => cipher,
+                Err(_) => continue,
+            };
+            let reply = self.hello(&source, hello.session, &secret).await?;
+            // Registered before replying, so the first sealed frames find it
+            let link = self.open_link(&source, cipher, Some(reply.clone()), false);
+            self.state.send_to_peer(&source, reply).await?;
+            return Ok(link);
+        }
+    }
+
+    async fn hello(
+        &self,
+        remote_peer_id: &str,
+        session: u64,
+        secret: &StaticSecret,
+    ) -> Result<Vec<u8>, RelayError> {
+        let ephemeral = X25519PublicKey::from(secret).to_bytes();
+        let payload = hello_payload(&self.state.peer_id, remote_peer_id, session, &ephemeral);
+        let signature = self
+            .identity
+            .read()
+            .await
+            .sign_data(&payload)
+            .map_err(|e| RelayError::Handshake(e.to_string()))?;
+        Ok(Frame::Hello(Hello {
+            session,
+            ephemeral,
+            signature,
+        })
+        .encode())
+    }
+
+    fn open_link(
+        &self,
+        remote_peer_id: &str,
+        cipher: SessionCipher,
+        hello_reply: Option<Vec<u8>>,
+        initiator: bool,
+    ) -> RelayedLink {
+        let session = cipher.session;
+        let (frames, frames_receiver) = mpsc::unbounded_channel();
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        self.state.links.lock().unwrap().insert(
+            remote_peer_id.to_string(),
+            LinkEntry {
+                session,
+                frames,
+                hello_reply,
+            },
+        );
+
+        let socket = Arc::new(RelaySocket {
+            state: self.state.clone(),
+            remote_peer_id: remote_peer_id.to_string(),
+            cipher,
+            frames: Mutex::new(frames_receiver),
+            control,
+        });
+        let relayed = DirectChannel::open(
+            socket.clone(),
+            remote_peer_id.to_string(),
+            session,
+            self.state.relay,
+            self.config.clone(),
+        );
+
+        if let Some(event_bus) = &self.event_bus {
+            event_bus.emit_network(NetworkEvent::RelayPathEstablished {
+                peer_id: remote_peer_id.to_string(),
+                relay: self.state.relay.to_string(),
+            });
+        }
+
+        RelayedLink {
+            client: self.state.clone(),
+            remote_peer_id: remote_peer_id.to_string(),
+            session,
+            initiator,
+            socket,
+            relayed: Arc::new(relayed),
+            direct: Arc::new(std::sync::RwLock::new(None)),
+            upgraded: Arc::new(Notify::new()),
+            control: Some(control_receiver),
+            config: self.config.clone(),
+            event_bus: self.event_bus.clone(),
+            shutdown: CancellationToken::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    async fn allocate_packet(
+        identity: &RwLock<CryptoManager>,
+        peer_id: &str,
+    ) -> Result<Vec<u8>, RelayError> {
+        let timestamp = unix_time();
+        let signature = identity
+            .read()
+            .await
+            .sign_data(&allocation_payload(peer_id, timestamp))
+            .map_err(|e| RelayError::Handshake(e.to_string()))?;
+        Ok(RelayPacket::Allocate {
+            peer_id: peer_id.to_string(),
+            timestamp,
+            signature,
+        }
+        .encode())
+    }
+
+    async fn receive_loop(
+        state: Arc<ClientState>,
+        event_bus: Option<EventBus>,
+        shutdown: CancellationToken,
+    ) {
+        let mut buffer = vec![0u8; MAX_RELAY_DATAGRAM];
+        loop {
+            let (len, from) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = state.socket.recv_from(&mut buffer) => match received {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+            };
+            if from != state.relay {
+                continue;
+            }
+
+            match RelayPacket::decode(&buffer[..len]) {
+                Some(RelayPacket::Deliver { source, payload }) => {
+                    Self::handle_frame(&state, source, &payload).await;
+                }
+                Some(RelayPacket::UnknownTarget { target }) => {
+                    // Fails pending handshakes with that peer right away
+                    state
+                        .pending
+                        .lock()
+                        .unwrap()
+                        .retain(|(peer_id, _), _| *peer_id != target);
+                }
+                Some(RelayPacket::QuotaExceeded { .. }) => {
+                    if !state.quota_exceeded.swap(true, Ordering::SeqCst) {
+                        if let Some(event_bus) = &event_bus {
+                            event_bus.emit_network(NetworkEvent::RelayQuotaExceeded {
+                                relay: state.relay.to_string(),
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn handle_frame(state: &ClientState, source: String, payload: &[u8]) {
+        match Frame::decode(payload) {
+            Some(Frame::Hello(hello)) => {
+                let waiter = state
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .get(&(source.clone(), hello.session))
+                    .cloned();
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(hello);
+                    return;
+                }
+
+                let reply = state
+                    .links
+                    .lock()
+                    .unwrap()
+                    .get(&source)
+                    .filter(|link| link.session == hello.session)
+                    .map(|link| link.hello_reply.clone());
+                match reply {
+                    Some(Some(reply)) => {
+                        let _ = state.send_to_peer(&source, reply).await;
+                    }
+                    Some(None) => {}
+                    None => {
+                        let _ = state.hellos.send((source, hello));
+                    }
+                }
+            }
+            Some(Frame::Sealed {
+                session,
+                counter,
+                ciphertext,
+            }) => {
+                let links = state.links.lock().unwrap();
+                if let Some(link) = links.get(&source).filter(|l| l.session == session) {
+                    let _ = link.frames.send((counter, ciphertext));
+                }
+            }
+            None => {}
+        }
+    }
+
+    async fn refresh_loop(
+        state: Arc<ClientState>,
+        identity: Arc<RwLock<CryptoManager>>,
+        interval: Duration,
+        shutdown: CancellationToken,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = ticker.tick() => {
+                    if let Ok(request) = Self::allocate_packet(&identity, &state.peer_id).await {
+                        let _ = state.socket.send_to(&request, state.relay).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RelayClient {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Presents one relayed peer as a datagram socket, so a [`DirectChannel`]
+/// can provide ordering and retransmission on top. Everything sent is sealed
+/// before it reaches the relay.
+struct RelaySocket {
+    state: Arc<ClientState>,
+    remote_peer_id: String,
+    cipher: SessionCipher,
+    frames: Mutex<mpsc::UnboundedReceiver<(u64, Vec<u8>)>>,
+    control: mpsc::UnboundedSender<(u64, Vec<Candidate>)>,
+}
+
+impl RelaySocket {
+    async fn send_sealed(&self, kind: u8, body: &[u8]) -> io::Result<usize> {
+        let mut plaintext = Vec::with_capacity(body.len() + 1);
+        plaintext.push(kind);
+        plaintext.extend_from_slice(body);
+        let frame = self.cipher.seal(&plaintext);
+        self.state
+            .send_to_peer(&self.remote_peer_id, frame)
+            .await
+            .map(|_| body.len())
+    }
+
+    async fn send_candidates(&self, session: u64, candidates: &[Candidate]) -> io::Result<usize> {
+        let mut body = Vec::new();
+        put_u64(&mut body, session);
+        put_candidates(&mut body, candidates);
+        self.send_sealed(SEALED_CANDIDATES, &body).await
+    }
+}
+
+impl DatagramSocket for RelaySocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        _target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(self.send_sealed(SEALED_DATAGRAM, buf))
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Box::pin(async move {
+            let mut frames = self.frames.lock().await;
+            loop {
+                let (counter, ciphertext) = frames
+                    .recv()
+                    .await
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+                let plaintext = match self.cipher.open(counter, &ciphertext) {
+                    Some(plaintext) if !plaintext.is_empty() => plaintext,
+                    _ => continue,
+                };
+
+                match plaintext[0] {
+                    SEALED_DATAGRAM => {
+                        let body = &plaintext[1..];
+                        let len = body.len().min(buf.len());
+                        buf[..len].copy_from_slice(&body[..len]);
+                        return Ok((len, self.state.relay));
+                    }
+                    SEALED_CANDIDATES => {
+                        let mut reader = Reader {
+                            data: &plaintext[1..],
+                        };
+                        if let (Some(session), Some(candidates)) =
+                            (reader.u64(), reader.candidates())
+                        {
+                            let _ = self.control.send((session, candidates));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.state.socket.local_addr()
+    }
+}
+
+/// An end-to-end encrypted, reliable link to a peer through a relay, which
+/// moves to a direct path once hole punching succeeds.
+///
+/// Messages keep arriving over the relay after an upgrade, so nothing sent
+/// around the switch is lost.
+pub struct RelayedLink {
+    client: Arc<ClientState>,
+    remote_peer_id: String,
+    session: u64,
+    initiator: bool,
+    socket: Arc<RelaySocket>,
+    relayed: Arc<DirectChannel>,
+    direct: Arc<std::sync::RwLock<Option<Arc<DirectChannel>>>>,
+    upgraded: Arc<Notify>,
+    control: Option<mpsc::UnboundedReceiver<(u64, Vec<Candidate>)>>,
+    config: PunchConfig,
+    event_bus: Option<EventBus>,
+    shutdown: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RelayedLink {
+    pub fn remote_peer_id(&self) -> &str {
+        &self.remote_peer_id
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.direct.read().unwrap().is_some()
+    }
+
+    /// Remote address of the direct path, once there is one.
+    pub fn direct_addr(&self) -> Option<SocketAddr> {
+        self.direct
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|channel| channel.remote_addr())
+    }
+
+    /// Keeps trying to punch a direct path from `socket` in the background;
+    /// `mirror` (usually the relay itself) supplies the reflexive candidate.
+    /// Both ends must enable this. The side that connected retries every
+    /// `retry_interval` until a path is found; the other side answers.
+    pub fn enable_upgrade(
+        &mut self,
+        socket: Arc<dyn DatagramSocket>,
+        mirror: Option<SocketAddr>,
+        retry_interval: Duration,
+    ) {
+        let mut control = match self.control.take() {
+            Some(control) => control,
+            None => return,
+        };
+
+        let link = self.socket.clone();
+        let direct = self.direct.clone();
+        let upgraded = self.upgraded.clone();
+        let initiator = self.initiator;
+        let remote_peer_id = self.remote_peer_id.clone();
+        let local_peer_id = self.client.peer_id.clone();
+        let config = self.config.clone();
+        let event_bus = self.event_bus.clone();
+        let shutdown = self.shutdown.clone();
+
+        self.tasks.push(tokio::spawn(async move {
+            let puncher = || {
+                let puncher = HolePuncher::new(socket.clone(), local_peer_id.clone())
+                    .with_config(config.clone());
+                match &event_bus {
+                    Some(event_bus) => puncher.with_event_bus(event_bus.clone()),
+                    None => puncher,
+                }
+            };
+            let mut ticker = tokio::time::interval(retry_interval);
+            let mut offered: Option<(u64, Vec<Candidate>)> = None;
+
+            loop {
+                let attempt = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = ticker.tick(), if initiator => {
+                        let candidates = match puncher().gather_candidates(mirror).await {
+                            Ok(candidates) => candidates,
+                            Err(_) => continue,
+                        };
+                        let session = HolePuncher::new_session_id();
+                        let _ = link.send_candidates(session, &candidates).await;
+                        offered = Some((session, candidates));
+                        None
+                    }
+                    received = control.recv() => match received {
+                        Some(received) => Some(received),
+                        None => break,
+                    },
+                };
+
+                let (session, remote_candidates) = match attempt {
+                    Some(attempt) => attempt,
+                    None => continue,
+                };
+                if initiator {
+                    // Only answers to our latest offer count
+                    if offered.as_ref().map(|(s, _)| *s) != Some(session) {
+                        continue;
+                    }
+                } else {
+                    let candidates = match puncher().gather_candidates(mirror).await {
+                        Ok(candidates) => candidates,
+                        Err(_) => continue,
+                    };
+                    let _ = link.send_candidates(session, &candidates).await;
+                }
+
+                let punched = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    punched = puncher().punch(&remote_peer_id, session, &remote_candidates) => punched,
+                };
+                if let Ok(channel) = punched {
+                    *direct.write().unwrap() = Some(Arc::new(channel));
+                    upgraded.notify_waiters();
+                    break;
+                }
+            }
+        }));
+    }
+
+    /// Sends over the direct path if there is one, otherwise via the relay.
+    pub async fn send(&self, data: &[u8]) -> Result<(), PunchError> {
+        let direct = self.direct.read().unwrap().clone();
+        match direct {
+            Some(direct) => direct.send(data).await,
+            None => self.relayed.send(data).await,
+        }
+    }
+
+    /// Next message from either path, or `None` once the link is closed.
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        loop {
+            let upgraded = self.upgraded.notified();
+            tokio::pin!(upgraded);
+            upgraded.as_mut().enable();
+
+            let direct = self.direct.read().unwrap().clone();
+            match direct {
+                Some(direct) => {
+                    return tokio::select! {
+                        message = self.relayed.recv() => match message {
+                            Some(message) => Some(message),
+                            None => direct.recv().await,
+                        },
+                        message = direct.recv() => message,
+                    };
+                }
+                None => {
+                    tokio::select! {
+                        message = self.relayed.recv() => return message,
+                        _ = &mut upgraded => {}
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn send_message(&self, message: &ProtocolMessage) -> Result<(), PunchError> {
+        let data = message
+            .to_bytes()
+            .map_err(|e| PunchError::Io(e.to_string()))?;
+        self.send(&data).await
+    }
+
+    /// Next protocol message; undecodable messages are skipped.
+    pub async fn recv_message(&self) -> Option<ProtocolMessage> {
+        loop {
+            let data = self.recv().await?;
+            if let Ok(message) = ProtocolMessage::from_bytes(&data) {
+                return Some(message);
+            }
+        }
+    }
+
+    /// Stops the upgrade task and waits for it to exit.
+    pub async fn close(&mut self) {
+        self.shutdown.cancel();
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for RelayedLink {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+        let mut links = self.client.links.lock().unwrap();
+        if links
+            .get(&self.remote_peer_id)
+            .is_some_and(|link| link.session == self.session)
+        {
+            links.remove(&self.remote_peer_id);
+        }
+    }
+}
+
+/// Picks the contact best suited to relay traffic to `target`: online before
+/// offline, then by trust. Returns `None` when relaying is disabled or no
+/// contact is trusted enough, and an error when the policy's minimum trust
+/// is not one a relay can have.
+pub fn select_relay(
+    policy: &RelayPolicy,
+    contacts: &ContactManager,
+    target: &str,
+) -> Result<Option<(Contact, SocketAddr)>, ContactError> {
+    if !policy.enabled {
+        return Ok(None);
+    }
+
+    Ok(contacts
+        .relay_candidates(&policy.min_trust_level)?
+        .into_iter()
+        .filter(|contact| contact.id != target)
+        .filter_map(|contact| {
+            let address = contact.tcp_address()?;
+            Some((contact, address))
+        })
+        .max_by_key(|(contact, _)| {
+            (
+                contact.status == ContactStatus::Online,
+                contact.trust_level.rank(),
+                contact.last_seen,
+            )
+        }))
+}
@@ -0,0 +1,212 @@
+use crate::network::protocol::ProtocolMessage;
+use crate::network::types::SequencingConfig;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Messages sent to each peer that are kept in case they are asked for
+/// again.
+const HISTORY_LEN: usize = 256;
+
+/// What became of a message handed to [`Sequencer::receive`].
+#[derive(Debug, Default)]
+pub struct Arrival {
+    /// Messages to deliver now, in order.
+    pub deliver: Vec<ProtocolMessage>,
+    /// Sequence numbers found missing, to ask the sender for again.
+    pub missing: Vec<u64>,
+    /// A gap opened, to be given up on after `gap_timeout`.
+    pub gap_opened: bool,
+}
+
+#[derive(Debug)]
+struct Incoming {
+    next: u64,
+    held: BTreeMap<u64, ProtocolMessage>,
+    gap_since: Option<Instant>,
+}
+
+impl Incoming {
+    fn new(next: u64) -> Self {
+        Self {
+            next,
+            held: BTreeMap::new(),
+            gap_since: None,
+        }
+    }
+
+    /// Delivers the held messages that are next in line.
+    fn advance(&mut self, deliver: &mut Vec<ProtocolMessage>) {
+        while let Some(message) = self.held.remove(&self.next) {
+            deliver.push(message);
+            self.next += 1;
+        }
+    }
+
+    /// Gives up on every gap and delivers whatever is held.
+    fn release(&mut self, deliver: &mut Vec<ProtocolMessage>) {
+        let held = std::mem::take(&mut self.held);
+        if let Some(last) = held.keys().next_back() {
+            self.next = last + 1;
+        }
+        deliver.extend(held.into_values());
+        self.gap_since = None;
+    }
+}
+
+/// Per-conversation sequence numbers for chat messages.
+///
+/// Messages to each peer are numbered from the clock on, so the numbers
+/// keep rising across restarts, and the latest are kept to be sent again
+/// if asked for. Messages from each peer are delivered in order: those
+/// after a gap are held back and the missing ones asked for, until they
+/// turn up, the gap times out or the reorder window is overrun. One that
+/// turns up after its gap was given up on is delivered late.
+///
+/// Duplicates are for the caller to drop, by message id, before they get
+/// here.
+pub struct Sequencer {
+    config: SequencingConfig,
+    outgoing: HashMap<String, u64>,
+    history: HashMap<String, VecDeque<ProtocolMessage>>,
+    incoming: HashMap<String, Incoming>,
+}
+
+impl Sequencer {
+    pub fn new(config: SequencingConfig) -> Self {
+        Self {
+            config,
+            outgoing: HashMap::new(),
+            history: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SequencingConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: SequencingConfig) {
+        self.config = config;
+    }
+
+    /// Numbers `message` as the next in its conversation, and keeps it in
+    /// case it is asked for again.
+    pub fn stamp(&mut self, message: &mut ProtocolMessage) {
+        let next = self
+            .outgoing
+            .entry(message.recipient_id.clone())
+            .or_insert_with(first_sequence);
+        message.set_sequence_number(*next);
+        *next += 1;
+
+        let history = self
+            .history
+            .entry(message.recipient_id.clone())
+            .or_default();
+        history.push_back(message.clone());
+        if history.len() > HISTORY_LEN {
+            history.pop_front();
+        }
+    }
+
+    /// The messages sent to `peer_id` under `sequences` that are still
+    /// kept.
+    pub fn retransmissions(&self, peer_id: &str, sequences: &[u64]) -> Vec<ProtocolMessage> {
+        self.history
+            .get(peer_id)
+            .map(|history| {
+                history
+                    .iter()
+                    .filter(|message| sequences.contains(&message.get_sequence_number()))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Takes in a message from its sender. Unnumbered ones are delivered
+    /// as they come; the first numbered one from a peer sets where its
+    /// sequence starts.
+    pub fn receive(&mut self, message: ProtocolMessage, now: Instant) -> Arrival {
+        let mut arrival = Arrival::default();
+        let sequence = message.get_sequence_number();
+        if sequence == 0 {
+            arrival.deliver.push(message);
+            return arrival;
+        }
+
+        let incoming = self
+            .incoming
+            .entry(message.sender_id.clone())
+            .or_insert_with(|| Incoming::new(sequence));
+        if sequence < incoming.next {
+            arrival.deliver.push(message);
+            return arrival;
+        }
+        if sequence >= incoming.next + self.config.reorder_window {
+            incoming.release(&mut arrival.deliver);
+            incoming.next = sequence;
+        }
+
+        if sequence == incoming.next {
+            arrival.deliver.push(message);
+            incoming.next += 1;
+            incoming.advance(&mut arrival.deliver);
+            // What is still held sits behind a gap further on
+            incoming.gap_since = None;
+            if !incoming.held.is_empty() {
+                incoming.gap_since = Some(now);
+                arrival.gap_opened = true;
+            }
+            return arrival;
+        }
+
+        if incoming.held.contains_key(&sequence) {
+            return arrival;
+        }
+        // Those before the last one held were asked for when it came
+        let unasked = incoming
+            .held
+            .keys()
+            .next_back()
+            .map_or(incoming.next, |last| last + 1);
+        arrival.missing = (unasked..sequence).collect();
+        incoming.held.insert(sequence, message);
+        if incoming.gap_since.is_none() {
+            incoming.gap_since = Some(now);
+            arrival.gap_opened = true;
+        }
+        arrival
+    }
+
+    /// Gives up on the gaps in the messages from `peer_id` once they have
+    /// been open for `gap_timeout`, and returns the messages held behind
+    /// them.
+    pub fn release_expired(&mut self, peer_id: &str, now: Instant) -> Vec<ProtocolMessage> {
+        let mut deliver = Vec::new();
+        if let Some(incoming) = self.incoming.get_mut(peer_id) {
+            let expired = incoming.gap_since.is_some_and(|since| {
+                now.saturating_duration_since(since) >= self.config.gap_timeout
+            });
+            if expired {
+                incoming.release(&mut deliver);
+            }
+        }
+        deliver
+    }
+
+    /// Messages from `peer_id` held back behind a gap.
+    pub fn held_len(&self, peer_id: &str) -> usize {
+        self.incoming
+            .get(peer_id)
+            .map_or(0, |incoming| incoming.held.len())
+    }
+}
+
+/// Where a conversation's numbering starts: the time in milliseconds,
+/// which is past any number used before a restart.
+fn first_sequence() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |elapsed| elapsed.as_millis() as u64)
+}
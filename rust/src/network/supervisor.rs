@@ -0,0 +1,248 @@
+use crate::contacts::ContactManager;
+use crate::events::{EventBus, NetworkEvent};
+use crate::network::manager::NetworkManager;
+use crate::network::types::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+/// Keeps connections to favourite and highly trusted contacts open, up to
+/// a limit. Dropped connections are redialed with exponential backoff,
+/// starting from the endpoint that worked last, and the contacts' status
+/// and last-seen time follow the connections.
+pub struct ConnectionSupervisor {
+    state: Arc<SupervisorState>,
+    shutdown: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+struct SupervisorState {
+    network: Arc<NetworkManager>,
+    contacts: Arc<RwLock<ContactManager>>,
+    event_bus: EventBus,
+    config: SupervisorConfig,
+}
+
+enum Outcome {
+    Connected(String),
+    Failed(String),
+    Closed(String),
+}
+
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl ConnectionSupervisor {
+    pub fn new(
+        network: Arc<NetworkManager>,
+        contacts: Arc<RwLock<ContactManager>>,
+        event_bus: EventBus,
+        config: SupervisorConfig,
+    ) -> Self {
+        Self {
+            state: Arc::new(SupervisorState {
+                network,
+                contacts,
+                event_bus,
+                config,
+            }),
+            shutdown: CancellationToken::new(),
+            task: None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        if self.task.is_some() {
+            return;
+        }
+
+        let state = self.state.clone();
+        let shutdown = self.shutdown.clone();
+        self.task = Some(tokio::spawn(async move {
+            Self::run(state, shutdown).await;
+        }));
+    }
+
+    /// Stops supervising and closes the connections the supervisor made.
+    pub async fn stop(&mut self) {
+        self.shutdown.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    async fn run(state: Arc<SupervisorState>, shutdown: CancellationToken) {
+        let (outcomes_tx, mut outcomes) = mpsc::unbounded_channel();
+        let mut connections: HashMap<String, JoinHandle<()>> = HashMap::new();
+        let mut backoff: HashMap<String, Backoff> = HashMap::new();
+        let mut ticker = tokio::time::interval(state.config.check_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = ticker.tick() => {}
+                Some(outcome) = outcomes.recv() => {
+                    Self::record(&state, outcome, &mut connections, &mut backoff).await;
+                }
+            }
+            Self::supervise(&state, &mut connections, &backoff, &outcomes_tx).await;
+        }
+
+        for (peer_id, task) in connections.drain() {
+            task.abort();
+            let _ = task.await;
+            state.network.detach_connection(&peer_id).await;
+        }
+    }
+
+    async fn record(
+        state: &SupervisorState,
+        outcome: Outcome,
+        connections: &mut HashMap<String, JoinHandle<()>>,
+        backoff: &mut HashMap<String, Backoff>,
+    ) {
+        match outcome {
+            Outcome::Connected(peer_id) => {
+                backoff.remove(&peer_id);
+            }
+            Outcome::Failed(peer_id) => {
+                connections.remove(&peer_id);
+                let failures = backoff.get(&peer_id).map_or(0, |b| b.failures) + 1;
+                backoff.insert(
+                    peer_id,
+                    Backoff {
+                        failures,
+                        retry_at: Instant::now() + state.config.backoff(failures),
+                    },
+                );
+            }
+            Outcome::Closed(peer_id) => {
+                connections.remove(&peer_id);
+                // Replaced by a connection the peer opened to us
+                if state.network.has_connection(&peer_id).await {
+                    return;
+                }
+                let updated = state
+                    .contacts
+                    .write()
+                    .await
+                    .apply_presence(&peer_id, ContactStatus::Offline);
+                if let Some(contact) = updated {
+                    state
+                        .event_bus
+                        .emit_network(NetworkEvent::ContactStatusChanged { contact });
+                }
+                backoff.insert(
+                    peer_id,
+                    Backoff {
+                        failures: 1,
+                        retry_at: Instant::now() + state.config.backoff(1),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Dials supervised contacts that are not connected and not backing
+    /// off, while fewer than `max_peers` of them are connected or being
+    /// dialed.
+    async fn supervise(
+        state: &Arc<SupervisorState>,
+        connections: &mut HashMap<String, JoinHandle<()>>,
+        backoff: &HashMap<String, Backoff>,
+        outcomes: &mpsc::UnboundedSender<Outcome>,
+    ) {
+        let wanted = state.contacts.read().await.supervised_contacts();
+        let now = Instant::now();
+        let mut active = 0;
+
+        for contact in wanted {
+            if active >= state.config.max_peers {
+                break;
+            }
+            // Connections the peer opened to us count as well
+            if connections.contains_key(&contact.id)
+                || state.network.has_connection(&contact.id).await
+            {
+                active += 1;
+                continue;
+            }
+            if backoff.get(&contact.id).is_some_and(|b| b.retry_at > now) {
+                continue;
+            }
+
+            active += 1;
+            let peer_id = contact.id.clone();
+            let task = tokio::spawn(Self::connect(state.clone(), contact, outcomes.clone()));
+            connections.insert(peer_id, task);
+        }
+    }
+
+    /// Dials a contact's endpoints in order and serves the first
+    /// connection made, once the contact has proved who it is, until it
+    /// closes.
+    async fn connect(
+        state: Arc<SupervisorState>,
+        contact: Contact,
+        outcomes: mpsc::UnboundedSender<Outcome>,
+    ) {
+        let mut connection = None;
+        for endpoint in &contact.addresses {
+            let dialed = tokio::time::timeout(
+                state.config.connect_timeout,
+                state.network.dial(std::slice::from_ref(endpoint)),
+            )
+            .await;
+            if let Ok(Ok(dialed)) = dialed {
+                connection = Some(dialed);
+                break;
+            }
+        }
+        let mut connection = match connection {
+            Some(connection) => connection,
+            None => {
+                let _ = outcomes.send(Outcome::Failed(contact.id));
+                return;
+            }
+        };
+        let authenticated = tokio::time::timeout(
+            state.config.connect_timeout,
+            state.network.authenticate(&contact.id, &mut connection),
+        )
+        .await;
+        if !matches!(authenticated, Ok(Ok(()))) {
+            let _ = outcomes.send(Outcome::Failed(contact.id));
+            return;
+        }
+
+        let updated = state
+            .contacts
+            .write()
+            .await
+            .apply_connected(&contact.id, connection.remote_endpoint().clone());
+        if let Some(updated) = updated {
+            state
+                .event_bus
+                .emit_network(NetworkEvent::ContactStatusChanged { contact: updated });
+        }
+        let _ = outcomes.send(Outcome::Connected(contact.id.clone()));
+
+        let _ = state
+            .network
+            .serve_connection(&contact.id, connection)
+            .await;
+        let _ = outcomes.send(Outcome::Closed(contact.id));
+    }
+}
+
+impl Drop for ConnectionSupervisor {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
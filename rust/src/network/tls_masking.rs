@@ -1,3 +1,5 @@
+use rand::seq::IndexedRandom;
+use rand::Rng;
 use std::error::Error;
 use std::fmt;
 
@@ -20,105 +22,356 @@ impl fmt::Display for TlsError {
 
 impl Error for TlsError {}
 
+pub const CONTENT_CHANGE_CIPHER_SPEC: u8 = 0x14;
+pub const CONTENT_ALERT: u8 = 0x15;
+pub const CONTENT_HANDSHAKE: u8 = 0x16;
+pub const CONTENT_APPLICATION_DATA: u8 = 0x17;
+
+/// Largest record body we emit. TLS allows slightly more for ciphertext,
+/// but staying at 16 KiB keeps every record within the plaintext limit.
+pub const MAX_RECORD_SIZE: usize = 16384;
+
+/// What a TLS 1.3 record carries besides the payload: the inner content
+/// type byte and the AEAD tag.
+const RECORD_OVERHEAD: usize = 1 + AEAD_TAG_SIZE;
+const AEAD_TAG_SIZE: usize = 16;
+/// Receivers reject anything larger, as RFC 8446 requires.
+const MAX_CIPHERTEXT_SIZE: usize = MAX_RECORD_SIZE + 256;
+
+const TLS_1_0: u16 = 0x0301;
+const TLS_1_2: u16 = 0x0303;
+const TLS_1_3: u16 = 0x0304;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 0x002d;
+const EXT_KEY_SHARE: u16 = 0x0033;
+
+const GROUP_X25519: u16 = 0x001d;
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+
+/// Cipher suites in the order current browsers offer them.
+const CIPHER_SUITES: [u16; 9] = [
+    TLS_AES_128_GCM_SHA256,
+    0x1302,
+    0x1303,
+    0xc02b,
+    0xc02f,
+    0xc02c,
+    0xc030,
+    0xcca9,
+    0xcca8,
+];
+const SUPPORTED_GROUPS: [u16; 3] = [GROUP_X25519, 0x0017, 0x0018];
+const SIGNATURE_ALGORITHMS: [u16; 8] = [
+    0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+];
+
+/// Hosts used for SNI when none is configured.
+const DEFAULT_SERVER_NAMES: [&str; 4] = [
+    "www.google.com",
+    "www.cloudflare.com",
+    "www.microsoft.com",
+    "www.wikipedia.org",
+];
+
+/// A single record as it appears on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsRecord {
+    pub content_type: u8,
+    pub version: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Splits a byte stream into TLS records, checking that every header has a
+/// known content type and version and that its length field matches what
+/// follows. Trailing bytes that do not form a whole record are an error.
+pub fn parse_records(data: &[u8]) -> Result<Vec<TlsRecord>, TlsError> {
+    let mut records = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            return Err(TlsError::ConnectionError(
+                "Truncated record header".to_string(),
+            ));
+        }
+
+        let content_type = rest[0];
+        let version = u16::from_be_bytes([rest[1], rest[2]]);
+        let length = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+
+        if !(CONTENT_CHANGE_CIPHER_SPEC..=CONTENT_APPLICATION_DATA).contains(&content_type) {
+            return Err(TlsError::ConnectionError(format!(
+                "Unknown content type {:#04x}",
+                content_type
+            )));
+        }
+        if version != TLS_1_2 && version != TLS_1_0 {
+            return Err(TlsError::ConnectionError(format!(
+                "Unexpected record version {:#06x}",
+                version
+            )));
+        }
+        if length == 0 || length > MAX_CIPHERTEXT_SIZE {
+            return Err(TlsError::ConnectionError(format!(
+                "Invalid record length {}",
+                length
+            )));
+        }
+        if rest.len() < 5 + length {
+            return Err(TlsError::ConnectionError("Truncated record".to_string()));
+        }
+
+        records.push(TlsRecord {
+            content_type,
+            version,
+            payload: rest[5..5 + length].to_vec(),
+        });
+        rest = &rest[5 + length..];
+    }
+
+    Ok(records)
+}
+
+/// Makes traffic look like a TLS 1.3 session: a browser-like handshake,
+/// then application_data records sized and framed the way a real stack
+/// frames them. It only shapes the bytes; confidentiality still comes from
+/// the session encryption underneath.
 pub struct TlsMasking {
     enabled: bool,
-    fake_certificates: Vec<Vec<u8>>,
+    server_name: Option<String>,
 }
 
 impl TlsMasking {
     pub fn new() -> Self {
         Self {
             enabled: false,
-            fake_certificates: vec![],
+            server_name: None,
         }
     }
 
+    /// Uses `server_name` for SNI instead of a randomly picked popular host.
+    pub fn with_server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
     pub fn enable(&mut self) -> Result<(), TlsError> {
+        if let Some(name) = &self.server_name {
+            if name.is_empty() || name.len() > u16::MAX as usize - 5 {
+                return Err(TlsError::HandshakeFailed("Invalid server name".to_string()));
+            }
+        }
         self.enabled = true;
-        self.generate_fake_certificates()?;
         Ok(())
     }
 
     pub fn disable(&mut self) {
         self.enabled = false;
-        self.fake_certificates.clear();
     }
 
     pub fn is_enabled(&self) -> bool {
         self.enabled
     }
 
+    /// Frames `data` as application_data records of at most
+    /// [`MAX_RECORD_SIZE`] bytes, each ending in the inner content type and
+    /// a tag-sized trailer like a TLS 1.3 ciphertext.
     pub async fn wrap_connection(&self, data: &[u8]) -> Result<Vec<u8>, TlsError> {
         if !self.enabled {
             return Ok(data.to_vec());
         }
 
-        let mut wrapped = Vec::new();
-        wrapped.extend_from_slice(&self.create_tls_header());
-        wrapped.extend_from_slice(data);
-        wrapped.extend_from_slice(&self.create_tls_footer());
+        let chunk_size = MAX_RECORD_SIZE - RECORD_OVERHEAD;
+        let records = data.len().div_ceil(chunk_size);
+        let mut wrapped = Vec::with_capacity(data.len() + records * (5 + RECORD_OVERHEAD));
+        let mut rng = rand::rng();
+
+        for chunk in data.chunks(chunk_size) {
+            let mut tag = [0u8; AEAD_TAG_SIZE];
+            rng.fill(&mut tag);
+
+            write_record_header(
+                &mut wrapped,
+                CONTENT_APPLICATION_DATA,
+                TLS_1_2,
+                chunk.len() + RECORD_OVERHEAD,
+            );
+            wrapped.extend_from_slice(chunk);
+            wrapped.push(CONTENT_APPLICATION_DATA);
+            wrapped.extend_from_slice(&tag);
+        }
 
         Ok(wrapped)
     }
 
+    /// Reverses [`wrap_connection`](Self::wrap_connection), walking record
+    /// boundaries. Handshake and change_cipher_spec records are skipped so a
+    /// whole masked stream can be fed in; an alert ends the connection.
     pub async fn unwrap_connection(&self, data: &[u8]) -> Result<Vec<u8>, TlsError> {
         if !self.enabled {
             return Ok(data.to_vec());
         }
 
-        if data.len() < 20 {
-            return Err(TlsError::ConnectionError("Data too short".to_string()));
+        let mut payload = Vec::with_capacity(data.len());
+        for record in parse_records(data)? {
+            match record.content_type {
+                CONTENT_APPLICATION_DATA => {
+                    if record.payload.len() < RECORD_OVERHEAD {
+                        return Err(TlsError::ConnectionError("Record too short".to_string()));
+                    }
+                    let body_len = record.payload.len() - RECORD_OVERHEAD;
+                    if record.payload[body_len] != CONTENT_APPLICATION_DATA {
+                        return Err(TlsError::ConnectionError(
+                            "Unexpected inner content type".to_string(),
+                        ));
+                    }
+                    payload.extend_from_slice(&record.payload[..body_len]);
+                }
+                CONTENT_HANDSHAKE | CONTENT_CHANGE_CIPHER_SPEC => {}
+                _ => return Err(TlsError::ConnectionError("Peer sent an alert".to_string())),
+            }
         }
 
-        let header_size = 10;
-        let footer_size = 10;
+        Ok(payload)
+    }
+
+    pub fn create_fake_handshake(&self) -> Vec<u8> {
+        self.create_client_hello()
+    }
+
+    /// A ClientHello record shaped like a browser's: fresh random and
+    /// session id, SNI, ALPN and an x25519 key share.
+    pub fn create_client_hello(&self) -> Vec<u8> {
+        let mut rng = rand::rng();
+        let server_name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => DEFAULT_SERVER_NAMES
+                .choose(&mut rng)
+                .copied()
+                .unwrap_or(DEFAULT_SERVER_NAMES[0])
+                .to_string(),
+        };
+
+        let mut extensions = Vec::new();
+
+        let mut sni = Vec::new();
+        sni.push(0x00);
+        put_u16(&mut sni, server_name.len());
+        sni.extend_from_slice(server_name.as_bytes());
+        let mut sni_list = Vec::new();
+        put_u16(&mut sni_list, sni.len());
+        sni_list.extend_from_slice(&sni);
+        write_extension(&mut extensions, EXT_SERVER_NAME, &sni_list);
 
-        if data.len() < header_size + footer_size {
-            return Err(TlsError::ConnectionError("Invalid TLS frame".to_string()));
+        write_extension(
+            &mut extensions,
+            EXT_SUPPORTED_GROUPS,
+            &u16_list(&SUPPORTED_GROUPS),
+        );
+        write_extension(&mut extensions, EXT_EC_POINT_FORMATS, &[0x01, 0x00]);
+        write_extension(
+            &mut extensions,
+            EXT_SIGNATURE_ALGORITHMS,
+            &u16_list(&SIGNATURE_ALGORITHMS),
+        );
+
+        let mut protocols = Vec::new();
+        for protocol in ["h2", "http/1.1"] {
+            protocols.push(protocol.len() as u8);
+            protocols.extend_from_slice(protocol.as_bytes());
         }
+        let mut alpn = Vec::new();
+        put_u16(&mut alpn, protocols.len());
+        alpn.extend_from_slice(&protocols);
+        write_extension(&mut extensions, EXT_ALPN, &alpn);
 
-        let payload = &data[header_size..data.len() - footer_size];
-        Ok(payload.to_vec())
-    }
+        let versions = u16_list(&[TLS_1_3, TLS_1_2]);
+        // supported_versions uses a one-byte list length.
+        write_extension(&mut extensions, EXT_SUPPORTED_VERSIONS, &versions[1..]);
+        write_extension(&mut extensions, EXT_PSK_KEY_EXCHANGE_MODES, &[0x01, 0x01]);
 
-    fn generate_fake_certificates(&mut self) -> Result<(), TlsError> {
-        let fake_cert = vec![
-            0x30, 0x82, 0x01, 0x00, 0x30, 0x81, 0xB2, 0x02, 0x01, 0x01, 0x30, 0x0D, 0x06, 0x09,
-            0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0B, 0x05, 0x00, 0x30, 0x1A, 0x31,
-            0x18, 0x30, 0x16, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0C, 0x0F, 0x65, 0x78, 0x61, 0x6D,
-            0x70, 0x6C, 0x65, 0x2E, 0x63, 0x6F, 0x6D,
-        ];
+        let mut share = Vec::new();
+        put_u16(&mut share, GROUP_X25519 as usize);
+        put_u16(&mut share, 32);
+        share.extend_from_slice(&rng.random::<[u8; 32]>());
+        let mut key_share = Vec::new();
+        put_u16(&mut key_share, share.len());
+        key_share.extend_from_slice(&share);
+        write_extension(&mut extensions, EXT_KEY_SHARE, &key_share);
 
-        self.fake_certificates.push(fake_cert);
-        Ok(())
-    }
+        let mut hello = Vec::new();
+        put_u16(&mut hello, TLS_1_2 as usize);
+        hello.extend_from_slice(&rng.random::<[u8; 32]>());
+        hello.push(32);
+        hello.extend_from_slice(&rng.random::<[u8; 32]>());
+        let suites = u16_list(&CIPHER_SUITES);
+        hello.extend_from_slice(&suites);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        put_u16(&mut hello, extensions.len());
+        hello.extend_from_slice(&extensions);
 
-    fn create_tls_header(&self) -> Vec<u8> {
-        vec![0x16, 0x03, 0x03, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x01]
+        let mut record = Vec::new();
+        write_handshake_record(&mut record, TLS_1_0, HANDSHAKE_CLIENT_HELLO, &hello);
+        record
     }
 
-    fn create_tls_footer(&self) -> Vec<u8> {
-        vec![0x17, 0x03, 0x03, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01]
-    }
+    /// The server's first flight answering `client_hello`: a ServerHello
+    /// echoing its session id, the compatibility change_cipher_spec, and an
+    /// application_data record standing in for the encrypted certificate
+    /// messages.
+    pub fn create_server_hello(&self, client_hello: &[u8]) -> Result<Vec<u8>, TlsError> {
+        let session_id = client_session_id(client_hello)?;
+        let mut rng = rand::rng();
 
-    pub fn create_fake_handshake(&self) -> Vec<u8> {
-        let mut handshake = Vec::new();
-        handshake.extend_from_slice(&[0x16, 0x03, 0x03, 0x00, 0x86]);
-        handshake.extend_from_slice(&[0x01, 0x00, 0x00, 0x82]);
-        handshake.extend_from_slice(&[0x03, 0x03]);
-
-        let mut random = vec![0u8; 32];
-        for i in 0..32 {
-            random[i] = (i as u8) ^ 0xAA;
-        }
-        handshake.extend_from_slice(&random);
+        let mut extensions = Vec::new();
+        write_extension(
+            &mut extensions,
+            EXT_SUPPORTED_VERSIONS,
+            &TLS_1_3.to_be_bytes(),
+        );
+        let mut key_share = Vec::new();
+        put_u16(&mut key_share, GROUP_X25519 as usize);
+        put_u16(&mut key_share, 32);
+        key_share.extend_from_slice(&rng.random::<[u8; 32]>());
+        write_extension(&mut extensions, EXT_KEY_SHARE, &key_share);
+
+        let mut hello = Vec::new();
+        put_u16(&mut hello, TLS_1_2 as usize);
+        hello.extend_from_slice(&rng.random::<[u8; 32]>());
+        hello.push(session_id.len() as u8);
+        hello.extend_from_slice(&session_id);
+        put_u16(&mut hello, TLS_AES_128_GCM_SHA256 as usize);
+        hello.push(0x00);
+        put_u16(&mut hello, extensions.len());
+        hello.extend_from_slice(&extensions);
 
-        handshake.extend_from_slice(&[0x00]);
-        handshake.extend_from_slice(&[0x00, 0x08]);
-        handshake.extend_from_slice(&[0x00, 0x2F, 0x00, 0x35, 0x00, 0x0A, 0x00, 0x39]);
-        handshake.extend_from_slice(&[0x01, 0x00]);
+        let mut flight = Vec::new();
+        write_handshake_record(&mut flight, TLS_1_2, HANDSHAKE_SERVER_HELLO, &hello);
+        write_record_header(&mut flight, CONTENT_CHANGE_CIPHER_SPEC, TLS_1_2, 1);
+        flight.push(0x01);
 
-        handshake
+        // Roughly the size of EncryptedExtensions, a certificate chain,
+        // CertificateVerify and Finished.
+        let mut encrypted = vec![0u8; rng.random_range(2048..4096)];
+        rng.fill(&mut encrypted[..]);
+        write_record_header(
+            &mut flight,
+            CONTENT_APPLICATION_DATA,
+            TLS_1_2,
+            encrypted.len(),
+        );
+        flight.extend_from_slice(&encrypted);
+
+        Ok(flight)
     }
 
     pub fn validate_tls_frame(&self, data: &[u8]) -> bool {
@@ -130,9 +383,71 @@ impl TlsMasking {
         let version = u16::from_be_bytes([data[1], data[2]]);
         let length = u16::from_be_bytes([data[3], data[4]]) as usize;
 
-        content_type >= 0x14
-            && content_type <= 0x18
-            && (version == 0x0301 || version == 0x0302 || version == 0x0303)
+        (CONTENT_CHANGE_CIPHER_SPEC..=CONTENT_APPLICATION_DATA).contains(&content_type)
+            && (version == TLS_1_0 || version == 0x0302 || version == TLS_1_2)
+            && length <= MAX_CIPHERTEXT_SIZE
             && data.len() >= 5 + length
     }
 }
+
+impl Default for TlsMasking {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the legacy session id out of a ClientHello record.
+fn client_session_id(client_hello: &[u8]) -> Result<Vec<u8>, TlsError> {
+    let invalid = || TlsError::HandshakeFailed("Malformed ClientHello".to_string());
+
+    let records = parse_records(client_hello)?;
+    let record = records.first().ok_or_else(invalid)?;
+    if record.content_type != CONTENT_HANDSHAKE {
+        return Err(invalid());
+    }
+
+    let message = &record.payload;
+    // Handshake header (4), legacy_version (2), random (32), then the id.
+    if message.len() < 39 || message[0] != HANDSHAKE_CLIENT_HELLO {
+        return Err(invalid());
+    }
+    let id_len = message[38] as usize;
+    if id_len > 32 || message.len() < 39 + id_len {
+        return Err(invalid());
+    }
+
+    Ok(message[39..39 + id_len].to_vec())
+}
+
+fn write_record_header(out: &mut Vec<u8>, content_type: u8, version: u16, length: usize) {
+    out.push(content_type);
+    put_u16(out, version as usize);
+    put_u16(out, length);
+}
+
+fn write_handshake_record(out: &mut Vec<u8>, version: u16, msg_type: u8, body: &[u8]) {
+    write_record_header(out, CONTENT_HANDSHAKE, version, body.len() + 4);
+    out.push(msg_type);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(body);
+}
+
+fn write_extension(out: &mut Vec<u8>, extension_type: u16, data: &[u8]) {
+    put_u16(out, extension_type as usize);
+    put_u16(out, data.len());
+    out.extend_from_slice(data);
+}
+
+/// A u16-length-prefixed list of u16 values.
+fn u16_list(values: &[u16]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(2 + values.len() * 2);
+    put_u16(&mut out, values.len() * 2);
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+    out
+}
+
+fn put_u16(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u16).to_be_bytes());
+}
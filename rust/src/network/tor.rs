@@ -0,0 +1,372 @@
+use crate::network::types::*;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USER_PASS: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Tor only looks at SOCKS credentials to keep streams apart, so the
+/// password carries no secret.
+const ISOLATION_PASSWORD: &str = "shadowghost";
+
+/// Dials peers through a SOCKS5 proxy, normally the local Tor client.
+///
+/// Host names, `.onion` addresses included, are passed to the proxy
+/// unresolved so no DNS query leaves this machine. With stream isolation on,
+/// each contact's connections use their own credentials and so their own
+/// circuit, which keeps an exit or guard from linking contacts together.
+pub struct Socks5Dialer {
+    config: TorConfig,
+}
+
+impl Socks5Dialer {
+    pub fn new(config: TorConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &TorConfig {
+        &self.config
+    }
+
+    /// Connects to `host:port` through the proxy. Streams with the same
+    /// `isolation_key` may share a circuit; different keys never do.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        isolation_key: Option<&str>,
+    ) -> Result<TcpStream, TorError> {
+        tokio::time::timeout(
+            self.config.connect_timeout,
+            self.handshake(host, port, isolation_key),
+        )
+        .await
+        .map_err(|_| TorError::Timeout)?
+    }
+
+    /// Connects to a `host:port` address, e.g. a contact's onion address.
+    pub async fn connect_address(
+        &self,
+        address: &str,
+        isolation_key: Option<&str>,
+    ) -> Result<TcpStream, TorError> {
+        let (host, port) = split_host_port(address)?;
+        self.connect(host, port, isolation_key).await
+    }
+
+    /// Connects to a contact on a circuit of its own, through the first of
+    /// its addresses that Tor can reach.
+    pub async fn connect_contact(&self, contact: &Contact) -> Result<TcpStream, TorError> {
+        let (host, port) = contact
+            .addresses
+            .iter()
+            .find_map(|address| match address {
+                Endpoint::Tor { host, port } => Some((host.clone(), *port)),
+                Endpoint::Tcp(address) => Some((address.ip().to_string(), address.port())),
+                _ => None,
+            })
+            .ok_or_else(|| TorError::Protocol(format!("No address for {}", contact.id)))?;
+        self.connect(&host, port, Some(&contact.id)).await
+    }
+
+    async fn handshake(
+        &self,
+        host: &str,
+        port: u16,
+        isolation_key: Option<&str>,
+    ) -> Result<TcpStream, TorError> {
+        let mut stream = TcpStream::connect(self.config.socks_proxy).await?;
+
+        let credentials = match isolation_key {
+            Some(key) if self.config.isolate_streams => Some(key),
+            _ => None,
+        };
+        let method = match credentials {
+            Some(_) => SOCKS_USER_PASS,
+            None => SOCKS_NO_AUTH,
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != SOCKS_VERSION {
+            return Err(TorError::Protocol("Not a SOCKS5 proxy".to_string()));
+        }
+        if choice[1] == SOCKS_NO_ACCEPTABLE || choice[1] != method {
+            return Err(TorError::AuthenticationFailed(
+                "Proxy refused the authentication method".to_string(),
+            ));
+        }
+
+        if let Some(key) = credentials {
+            let username = truncate(key.as_bytes(), u8::MAX as usize);
+            let mut auth = vec![0x01, username.len() as u8];
+            auth.extend_from_slice(username);
+            auth.push(ISOLATION_PASSWORD.len() as u8);
+            auth.extend_from_slice(ISOLATION_PASSWORD.as_bytes());
+            stream.write_all(&auth).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(TorError::AuthenticationFailed(
+                    "Proxy rejected the credentials".to_string(),
+                ));
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0x00];
+        match host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.is_empty() || host.len() > u8::MAX as usize {
+                    return Err(TorError::Protocol(format!("Invalid host name: {}", host)));
+                }
+                request.push(ATYP_DOMAIN);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(TorError::Protocol("Malformed SOCKS5 reply".to_string()));
+        }
+        if reply[1] != 0x00 {
+            return Err(TorError::Socks(reply[1]));
+        }
+
+        // The bound address is of no use to us, but has to be consumed
+        let address_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            other => {
+                return Err(TorError::Protocol(format!(
+                    "Unknown address type {}",
+                    other
+                )))
+            }
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(stream)
+    }
+}
+
+/// An onion service published through the control port. Tor removes it
+/// when this is dropped, since it lives as long as the control connection.
+pub struct OnionService {
+    control: TorController,
+    service_id: String,
+    virtual_port: u16,
+    private_key: Option<String>,
+}
+
+impl OnionService {
+    pub fn service_id(&self) -> &str {
+        &self.service_id
+    }
+
+    /// `<service id>.onion:<port>`, ready to hand out in an SG link.
+    pub fn address(&self) -> String {
+        format!("{}.onion:{}", self.service_id, self.virtual_port)
+    }
+
+    /// The key Tor generated for a new service. Pass it to
+    /// [`TorController::publish_onion`] next time to keep the same address.
+    pub fn private_key(&self) -> Option<&str> {
+        self.private_key.as_deref()
+    }
+
+    pub async fn remove(mut self) -> Result<(), TorError> {
+        let command = format!("DEL_ONION {}", self.service_id);
+        self.control.command(&command).await.map(|_| ())
+    }
+}
+
+/// A connection to Tor's control port.
+pub struct TorController {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl TorController {
+    /// Connects and authenticates with the password if one is given,
+    /// otherwise with the cookie file or nothing, as Tor advertises.
+    pub async fn connect(config: &TorConfig) -> Result<Self, TorError> {
+        let stream = tokio::time::timeout(
+            config.connect_timeout,
+            TcpStream::connect(config.control_address),
+        )
+        .await
+        .map_err(|_| TorError::Timeout)??;
+        let (reader, writer) = stream.into_split();
+        let mut controller = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        let info = controller.command("PROTOCOLINFO 1").await?;
+        let auth = match &config.control_password {
+            Some(password) => format!("AUTHENTICATE {}", quote(password)),
+            None => match cookie_file(&info) {
+                Some(path) => {
+                    let cookie = tokio::fs::read(&path).await.map_err(|e| {
+                        TorError::AuthenticationFailed(format!(
+                            "Cannot read cookie {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    })?;
+                    let hex: String = cookie.iter().map(|b| format!("{:02x}", b)).collect();
+                    format!("AUTHENTICATE {}", hex)
+                }
+                None => "AUTHENTICATE".to_string(),
+            },
+        };
+
+        controller.command(&auth).await.map_err(|e| match e {
+            TorError::Control(_, msg) => TorError::AuthenticationFailed(msg),
+            other => other,
+        })?;
+        Ok(controller)
+    }
+
+    /// Publishes `target` as an onion service on `virtual_port`, reusing
+    /// `private_key` (as returned by [`OnionService::private_key`]) when
+    /// given so the address stays the same across restarts.
+    pub async fn publish_onion(
+        mut self,
+        virtual_port: u16,
+        target: SocketAddr,
+        private_key: Option<&str>,
+    ) -> Result<OnionService, TorError> {
+        let key = private_key.unwrap_or("NEW:ED25519-V3");
+        let command = format!("ADD_ONION {} Port={},{}", key, virtual_port, target);
+        let reply = self.command(&command).await?;
+
+        let mut service_id = None;
+        let mut new_key = None;
+        for line in &reply {
+            if let Some(id) = line.strip_prefix("ServiceID=") {
+                service_id = Some(id.to_string());
+            } else if let Some(key) = line.strip_prefix("PrivateKey=") {
+                new_key = Some(key.to_string());
+            }
+        }
+
+        match service_id {
+            Some(service_id) => Ok(OnionService {
+                control: self,
+                service_id,
+                virtual_port,
+                private_key: new_key.or_else(|| private_key.map(str::to_string)),
+            }),
+            None => Err(TorError::Protocol(
+                "ADD_ONION returned no ServiceID".to_string(),
+            )),
+        }
+    }
+
+    /// Sends one command and returns the text of its reply lines, without
+    /// status codes. Error statuses become [`TorError::Control`].
+    async fn command(&mut self, command: &str) -> Result<Vec<String>, TorError> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(TorError::Io("Control connection closed".to_string()));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.len() < 4 || !line.is_char_boundary(3) {
+                return Err(TorError::Protocol(line.to_string()));
+            }
+
+            let code: u16 = line[..3]
+                .parse()
+                .map_err(|_| TorError::Protocol(line.to_string()))?;
+            let (separator, text) = (line.as_bytes()[3], &line[4..]);
+            if code >= 400 {
+                return Err(TorError::Control(code, text.to_string()));
+            }
+            lines.push(text.to_string());
+
+            if separator == b'+' {
+                // Data reply, terminated by a lone "."
+                loop {
+                    let mut data = String::new();
+                    if self.reader.read_line(&mut data).await? == 0 {
+                        return Err(TorError::Io("Control connection closed".to_string()));
+                    }
+                    if data.trim_end_matches(['\r', '\n']) == "." {
+                        break;
+                    }
+                }
+            } else if separator == b' ' {
+                return Ok(lines);
+            }
+        }
+    }
+}
+
+/// Path of the authentication cookie if `PROTOCOLINFO` offers plain cookie
+/// auth. SAFECOOKIE alone is not supported; use a password then.
+fn cookie_file(info: &[String]) -> Option<PathBuf> {
+    let auth = info.iter().find(|line| line.starts_with("AUTH "))?;
+    let methods = auth
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("METHODS="))?;
+    if !methods.split(',').any(|m| m == "COOKIE") {
+        return None;
+    }
+
+    let start = auth.find("COOKIEFILE=\"")? + "COOKIEFILE=\"".len();
+    let end = start + auth[start..].find('"')?;
+    Some(PathBuf::from(auth[start..end].replace("\\\\", "\\")))
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn split_host_port(address: &str) -> Result<(&str, u16), TorError> {
+    let invalid = || TorError::Protocol(format!("Invalid address: {}", address));
+    let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    Ok((host, port))
+}
+
+fn truncate(bytes: &[u8], max: usize) -> &[u8] {
+    &bytes[..bytes.len().min(max)]
+}
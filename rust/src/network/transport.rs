@@ -0,0 +1,550 @@
+use crate::network::auth::{Opener, Sealer, Session};
+use crate::network::hole_punch::BoxFuture;
+use crate::network::obfuscation::TrafficObfuscator;
+use crate::network::protocol::ProtocolMessage;
+use crate::network::tor::Socks5Dialer;
+use crate::network::types::*;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Buffer of each direction of an in-memory connection.
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A way of reaching peers, selected by the scheme of their endpoints.
+pub trait Transport: Send + Sync {
+    /// Endpoint schemes this transport handles, e.g. `["tcp"]`.
+    fn schemes(&self) -> &'static [&'static str];
+
+    fn listen<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, TransportError>>;
+
+    fn dial<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Connection, TransportError>>;
+}
+
+/// Connections coming in on a listening endpoint.
+pub trait Listener: Send {
+    fn local_endpoint(&self) -> Endpoint;
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connection, TransportError>>;
+}
+
+/// A connection to one peer carrying protocol messages, whatever the
+/// transport underneath. Each message goes out as a 4-byte big-endian
+/// length followed by its encoding, or once an obfuscator is set, as the
+/// obfuscator's frames. Once the handshake has set a session, every message
+/// is encrypted with it.
+pub struct Connection {
+    remote: Endpoint,
+    peer: Option<String>,
+    reader: ConnectionReader,
+    writer: ConnectionWriter,
+}
+
+impl Connection {
+    pub fn new<R, W>(remote: Endpoint, reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            remote,
+            peer: None,
+            reader: ConnectionReader {
+                inner: Box::new(reader),
+                max_frame_size: MAX_MESSAGE_SIZE,
+                obfuscator: None,
+                opener: None,
+                pending: VecDeque::new(),
+            },
+            writer: ConnectionWriter {
+                inner: Box::new(writer),
+                obfuscator: None,
+                sealer: None,
+            },
+        }
+    }
+
+    pub fn from_stream<S>(remote: Endpoint, stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        Self::new(remote, reader, writer)
+    }
+
+    pub fn remote_endpoint(&self) -> &Endpoint {
+        &self.remote
+    }
+
+    /// The peer that proved who it is over this connection, if any.
+    pub fn authenticated_peer(&self) -> Option<&str> {
+        self.peer.as_deref()
+    }
+
+    pub(crate) fn set_authenticated_peer(&mut self, peer_id: &str) {
+        self.peer = Some(peer_id.to_string());
+    }
+
+    /// Frames both directions with `obfuscator` from now on. Both sides
+    /// have to agree on whether frames are TLS masked.
+    pub fn set_obfuscator(&mut self, obfuscator: Arc<TrafficObfuscator>) {
+        self.reader.obfuscator = Some(obfuscator.clone());
+        self.writer.obfuscator = Some(obfuscator);
+    }
+
+    /// Encrypts both directions with the keys the handshake agreed on from
+    /// now on.
+    pub fn set_session(&mut self, session: Session) {
+        self.reader.opener = Some(session.opener);
+        self.writer.sealer = Some(Arc::new(session.sealer));
+    }
+
+    pub async fn send(&mut self, message: &ProtocolMessage) -> Result<(), TransportError> {
+        self.writer.send(message).await
+    }
+
+    pub async fn send_sized(&mut self, message: &ProtocolMessage) -> Result<usize, TransportError> {
+        self.writer.send_sized(message).await
+    }
+
+    /// The next message, or `None` once the peer has closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<ProtocolMessage>, TransportError> {
+        self.reader.recv().await
+    }
+
+    pub async fn recv_sized(&mut self) -> Result<Option<(ProtocolMessage, usize)>, TransportError> {
+        self.reader.recv_sized().await
+    }
+
+    /// Frames longer than `size` are refused as malformed. Never raised
+    /// above [`MAX_MESSAGE_SIZE`].
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.reader.set_max_frame_size(size);
+    }
+
+    /// Separates the two directions, so they can be driven from different
+    /// tasks.
+    pub fn split(self) -> (ConnectionReader, ConnectionWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+pub struct ConnectionReader {
+    inner: Box<dyn AsyncRead + Send + Unpin>,
+    max_frame_size: usize,
+    obfuscator: Option<Arc<TrafficObfuscator>>,
+    opener: Option<Opener>,
+    /// The rest of a frame that carried more than one message.
+    pending: VecDeque<ProtocolMessage>,
+}
+
+impl ConnectionReader {
+    /// Not cancel safe: a frame half read when the future is dropped is
+    /// lost, and the stream with it.
+    pub async fn recv(&mut self) -> Result<Option<ProtocolMessage>, TransportError> {
+        Ok(self.recv_sized().await?.map(|(message, _)| message))
+    }
+
+    /// Like [`recv`](Self::recv), also returning how many bytes the
+    /// message took on the wire, length prefix included. Messages that
+    /// shared a frame with an earlier one took none of their own.
+    pub async fn recv_sized(&mut self) -> Result<Option<(ProtocolMessage, usize)>, TransportError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some((message, 0)));
+        }
+        let mut size = 0;
+        loop {
+            let (messages, frame_size) = match self.recv_frame().await? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            size += frame_size;
+            let mut messages = messages.into_iter();
+            if let Some(message) = messages.next() {
+                self.pending.extend(messages);
+                return Ok(Some((message, size)));
+            }
+        }
+    }
+
+    /// The messages of the next frame and how many bytes it took on the
+    /// wire. Obfuscated cover frames carry none.
+    pub async fn recv_frame(
+        &mut self,
+    ) -> Result<Option<(Vec<ProtocolMessage>, usize)>, TransportError> {
+        if !self.pending.is_empty() {
+            return Ok(Some((self.pending.drain(..).collect(), 0)));
+        }
+        let (messages, size) = match self.read_frame().await? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let messages = match &mut self.opener {
+            Some(opener) => messages
+                .into_iter()
+                .map(|message| opener.open(message))
+                .collect::<Result<_, _>>()?,
+            None => messages,
+        };
+        Ok(Some((messages, size)))
+    }
+
+    async fn read_frame(
+        &mut self,
+    ) -> Result<Option<(Vec<ProtocolMessage>, usize)>, TransportError> {
+        if let Some(obfuscator) = self.obfuscator.clone() {
+            return obfuscator
+                .read_frame(&mut self.inner, self.max_frame_size)
+                .await;
+        }
+
+        let mut length = [0u8; 4];
+        match self.inner.read_exact(&mut length).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > self.max_frame_size {
+            return Err(TransportError::Malformed(format!(
+                "Frame of {} bytes",
+                length
+            )));
+        }
+        let mut data = vec![0u8; length];
+        self.inner.read_exact(&mut data).await?;
+        ProtocolMessage::from_bytes(&data)
+            .map(|message| Some((vec![message], 4 + length)))
+            .map_err(|e| TransportError::Malformed(e.to_string()))
+    }
+
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size.min(MAX_MESSAGE_SIZE);
+    }
+}
+
+pub struct ConnectionWriter {
+    inner: Box<dyn AsyncWrite + Send + Unpin>,
+    obfuscator: Option<Arc<TrafficObfuscator>>,
+    sealer: Option<Arc<Sealer>>,
+}
+
+impl ConnectionWriter {
+    pub async fn send(&mut self, message: &ProtocolMessage) -> Result<(), TransportError> {
+        self.send_sized(message).await.map(|_| ())
+    }
+
+    /// Like [`send`](Self::send), returning how many bytes went on the
+    /// wire.
+    pub async fn send_sized(&mut self, message: &ProtocolMessage) -> Result<usize, TransportError> {
+        let sealed;
+        let message = match &self.sealer {
+            Some(sealer) => {
+                sealed = sealer.seal(message)?;
+                &sealed
+            }
+            None => message,
+        };
+        if let Some(obfuscator) = &self.obfuscator {
+            let frame = obfuscator
+                .encode(std::slice::from_ref(message))
+                .await
+                .map_err(|e| TransportError::Malformed(e.to_string()))?;
+            return self.send_frame(&frame).await;
+        }
+
+        let data = message
+            .to_bytes()
+            .map_err(|e| TransportError::Malformed(e.to_string()))?;
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(TransportError::Malformed(format!(
+                "Message of {} bytes",
+                data.len()
+            )));
+        }
+
+        let mut frame = Vec::with_capacity(4 + data.len());
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&data);
+        self.inner.write_all(&frame).await?;
+        self.inner.flush().await?;
+        Ok(frame.len())
+    }
+
+    /// What encrypts this connection's messages, once there is a session.
+    /// Messages framed elsewhere for [`send_frame`](Self::send_frame) have
+    /// to be sealed with it first.
+    pub fn sealer(&self) -> Option<Arc<Sealer>> {
+        self.sealer.clone()
+    }
+
+    /// Sends a frame made by the connection's obfuscator, such as the ones
+    /// an [`ObfuscatedLink`](crate::network::ObfuscatedLink) hands out.
+    /// Returns how many bytes went on the wire.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<usize, TransportError> {
+        self.inner.write_all(frame).await?;
+        self.inner.flush().await?;
+        Ok(frame.len())
+    }
+
+    pub async fn close(&mut self) -> Result<(), TransportError> {
+        self.inner.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Plain TCP, for `tcp://` endpoints.
+#[derive(Debug, Default, Clone)]
+pub struct TcpTransport;
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Transport for TcpTransport {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["tcp"]
+    }
+
+    fn listen<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, TransportError>> {
+        Box::pin(async move {
+            let address = match endpoint {
+                Endpoint::Tcp(address) => *address,
+                other => {
+                    return Err(TransportError::UnsupportedScheme(
+                        other.scheme().to_string(),
+                    ))
+                }
+            };
+            let listener = TcpListener::bind(address).await?;
+            Ok(Box::new(TcpConnectionListener { listener }) as Box<dyn Listener>)
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            let address = match endpoint {
+                Endpoint::Tcp(address) => *address,
+                other => {
+                    return Err(TransportError::UnsupportedScheme(
+                        other.scheme().to_string(),
+                    ))
+                }
+            };
+            let stream = TcpStream::connect(address).await?;
+            Ok(tcp_connection(stream, endpoint.clone()))
+        })
+    }
+}
+
+struct TcpConnectionListener {
+    listener: TcpListener,
+}
+
+impl Listener for TcpConnectionListener {
+    fn local_endpoint(&self) -> Endpoint {
+        match self.listener.local_addr() {
+            Ok(address) => Endpoint::Tcp(address),
+            Err(_) => Endpoint::Tcp(([0, 0, 0, 0], 0).into()),
+        }
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            let (stream, address) = self.listener.accept().await?;
+            Ok(tcp_connection(stream, Endpoint::Tcp(address)))
+        })
+    }
+}
+
+fn tcp_connection(stream: TcpStream, remote: Endpoint) -> Connection {
+    // Chat messages are small and should not wait for more to coalesce
+    let _ = stream.set_nodelay(true);
+    let (reader, writer) = stream.into_split();
+    Connection::new(remote, reader, writer)
+}
+
+/// Dials `tor://` endpoints through the Tor proxy. Every host gets its own
+/// circuit when stream isolation is on. Listening is done by publishing an
+/// onion service for a TCP listener instead.
+pub struct TorTransport {
+    dialer: Socks5Dialer,
+}
+
+impl TorTransport {
+    pub fn new(dialer: Socks5Dialer) -> Self {
+        Self { dialer }
+    }
+}
+
+impl Transport for TorTransport {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["tor"]
+    }
+
+    fn listen<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, TransportError>> {
+        Box::pin(async move {
+            Err(TransportError::UnsupportedScheme(format!(
+                "Cannot listen on {}",
+                endpoint
+            )))
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            let (host, port) = match endpoint {
+                Endpoint::Tor { host, port } => (host, *port),
+                other => {
+                    return Err(TransportError::UnsupportedScheme(
+                        other.scheme().to_string(),
+                    ))
+                }
+            };
+            let stream = self
+                .dialer
+                .connect(host, port, Some(host))
+                .await
+                .map_err(|e| TransportError::Io(e.to_string()))?;
+            Ok(tcp_connection(stream, endpoint.clone()))
+        })
+    }
+}
+
+type MemoryListeners = HashMap<String, mpsc::UnboundedSender<(String, DuplexStream)>>;
+
+/// An in-process network for tests: `memory://name` endpoints are
+/// reachable from every clone of the same transport.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<MemoryListeners>>,
+    next_dialer: Arc<AtomicU64>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["memory"]
+    }
+
+    fn listen<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Box<dyn Listener>, TransportError>> {
+        Box::pin(async move {
+            let name = match endpoint {
+                Endpoint::Memory(name) => name.clone(),
+                other => {
+                    return Err(TransportError::UnsupportedScheme(
+                        other.scheme().to_string(),
+                    ))
+                }
+            };
+
+            let mut listeners = self.listeners.lock().unwrap();
+            if listeners.get(&name).is_some_and(|l| !l.is_closed()) {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+            }
+            let (sender, incoming) = mpsc::unbounded_channel();
+            listeners.insert(name.clone(), sender);
+
+            Ok(Box::new(MemoryListener {
+                name,
+                incoming,
+                listeners: self.listeners.clone(),
+            }) as Box<dyn Listener>)
+        })
+    }
+
+    fn dial<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+    ) -> BoxFuture<'a, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            let name = match endpoint {
+                Endpoint::Memory(name) => name,
+                other => {
+                    return Err(TransportError::UnsupportedScheme(
+                        other.scheme().to_string(),
+                    ))
+                }
+            };
+            let listener = self.listeners.lock().unwrap().get(name).cloned();
+            let listener = match listener {
+                Some(listener) => listener,
+                None => return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into()),
+            };
+
+            let dialer = format!(
+                "dialer-{}",
+                self.next_dialer.fetch_add(1, Ordering::Relaxed)
+            );
+            let (local, remote) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+            listener
+                .send((dialer, remote))
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            Ok(Connection::from_stream(endpoint.clone(), local))
+        })
+    }
+}
+
+struct MemoryListener {
+    name: String,
+    incoming: mpsc::UnboundedReceiver<(String, DuplexStream)>,
+    listeners: Arc<Mutex<MemoryListeners>>,
+}
+
+impl Listener for MemoryListener {
+    fn local_endpoint(&self) -> Endpoint {
+        Endpoint::Memory(self.name.clone())
+    }
+
+    fn accept(&mut self) -> BoxFuture<'_, Result<Connection, TransportError>> {
+        Box::pin(async move {
+            match self.incoming.recv().await {
+                Some((dialer, stream)) => {
+                    Ok(Connection::from_stream(Endpoint::Memory(dialer), stream))
+                }
+                None => Err(TransportError::Closed),
+            }
+        })
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.remove(&self.name);
+        }
+    }
+}
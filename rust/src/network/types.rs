@@ -3,7 +3,8 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
 // Core network types
 #[derive(Debug)]
@@ -44,16 +45,171 @@ pub enum TrustLevel {
     Blocked,
 }
 
+impl TrustLevel {
+    /// Orders trust levels from blocked (0) to fully trusted.
+    pub fn rank(&self) -> u8 {
+        match self {
+            TrustLevel::Blocked => 0,
+            TrustLevel::Unknown => 1,
+            TrustLevel::Pending => 2,
+            TrustLevel::Low => 3,
+            TrustLevel::Medium => 4,
+            TrustLevel::High => 5,
+            TrustLevel::Trusted => 6,
+        }
+    }
+}
+
+/// Where a peer can be reached, written `scheme://address` much like a
+/// multiaddress. `tcp` and `quic` take a socket address, `tor` a
+/// `host:port` dialed through the Tor proxy and `memory` a name on an
+/// in-process network. A bare `host:port` reads as TCP, or as Tor for
+/// `.onion` hosts, which is what older profiles stored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Quic(SocketAddr),
+    Tor { host: String, port: u16 },
+    Memory(String),
+}
+
+impl Endpoint {
+    /// Selects the transport that handles this endpoint.
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Endpoint::Tcp(_) => "tcp",
+            Endpoint::Quic(_) => "quic",
+            Endpoint::Tor { .. } => "tor",
+            Endpoint::Memory(_) => "memory",
+        }
+    }
+
+    /// The address to connect to directly, for endpoints that have one.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Tcp(address) | Endpoint::Quic(address) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) | Endpoint::Quic(address) => {
+                write!(f, "{}://{}", self.scheme(), address)
+            }
+            Endpoint::Tor { host, port } => write!(f, "tor://{}:{}", host, port),
+            Endpoint::Memory(name) => write!(f, "memory://{}", name),
+        }
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = TransportError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransportError::InvalidEndpoint(value.to_string());
+        let (scheme, address) = match value.split_once("://") {
+            Some((scheme, address)) => (Some(scheme), address),
+            None => (None, value),
+        };
+
+        match scheme {
+            Some("tcp") => address.parse().map(Endpoint::Tcp).map_err(|_| invalid()),
+            Some("quic") => address.parse().map(Endpoint::Quic).map_err(|_| invalid()),
+            Some("tor") => {
+                let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+                let port = port.parse().map_err(|_| invalid())?;
+                if host.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Endpoint::Tor {
+                    host: host.to_string(),
+                    port,
+                })
+            }
+            Some("memory") if !address.is_empty() => Ok(Endpoint::Memory(address.to_string())),
+            Some(other) => Err(TransportError::UnsupportedScheme(other.to_string())),
+            None => match address.parse() {
+                Ok(address) => Ok(Endpoint::Tcp(address)),
+                Err(_)
+                    if address
+                        .split(':')
+                        .next()
+                        .is_some_and(|h| h.ends_with(".onion")) =>
+                {
+                    format!("tor://{}", address).parse()
+                }
+                Err(_) => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Reads a contact's endpoints from either a list or the single address
+/// string profiles used to store.
+fn deserialize_endpoints<'de, D>(deserializer: D) -> Result<Vec<Endpoint>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        One(String),
+        Many(Vec<Endpoint>),
+    }
+
+    match Stored::deserialize(deserializer)? {
+        Stored::One(address) if address.trim().is_empty() => Ok(Vec::new()),
+        Stored::One(address) => address
+            .parse()
+            .map(|endpoint| vec![endpoint])
+            .map_err(serde::de::Error::custom),
+        Stored::Many(endpoints) => Ok(endpoints),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Contact {
     pub id: String,
     pub name: String,
-    pub address: String,
+    /// In order of preference; the first one that works is used.
+    #[serde(alias = "address", deserialize_with = "deserialize_endpoints")]
+    pub addresses: Vec<Endpoint>,
+    /// Ed25519 identity key the contact has to prove it holds when it
+    /// connects. Empty while unknown, which keeps it from connecting.
+    #[serde(default)]
+    pub public_key: Vec<u8>,
     pub status: ContactStatus,
     pub trust_level: TrustLevel,
     pub last_seen: Option<DateTime<Utc>>,
 }
 
+impl Contact {
+    /// The preferred plain TCP address, which also serves the DHT and relay
+    /// sockets.
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        self.addresses.iter().find_map(|address| match address {
+            Endpoint::Tcp(address) => Some(*address),
+            _ => None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ChatMessageType {
     Text,
@@ -71,6 +227,13 @@ pub enum DeliveryStatus {
     Failed,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageReaction {
+    pub emoji: String,
+    pub count: u32,
+    pub users: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: String,
@@ -80,6 +243,14 @@ pub struct ChatMessage {
     pub msg_type: ChatMessageType,
     pub timestamp: u64,
     pub delivery_status: DeliveryStatus,
+    #[serde(default)]
+    pub reactions: Vec<MessageReaction>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Where the message falls among those from its sender, or 0 if
+    /// unnumbered.
+    #[serde(default)]
+    pub sequence: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -93,6 +264,38 @@ pub struct NetworkStats {
     pub messages_sent: u64,     // Required field
     pub messages_received: u64, // Required field
     pub total_connections: u32, // Required field
+    /// Message bytes handed to the obfuscation layer.
+    #[serde(default)]
+    pub payload_bytes_sent: u64,
+    /// Framing and padding added around those messages.
+    #[serde(default)]
+    pub padding_bytes_sent: u64,
+    /// Bytes spent on cover frames that carry no messages.
+    #[serde(default)]
+    pub cover_bytes_sent: u64,
+    /// Connections turned away by rate limits, bans or blocks.
+    #[serde(default)]
+    pub connections_refused: u64,
+    /// Messages dropped for exceeding a rate limit.
+    #[serde(default)]
+    pub messages_dropped: u64,
+    #[serde(default)]
+    pub rate_limit_violations: u64,
+    #[serde(default)]
+    pub peers_banned: u64,
+    /// Peers blocked automatically for repeated bans.
+    #[serde(default)]
+    pub peers_blocked: u64,
+}
+
+impl NetworkStats {
+    /// Extra bytes sent for obfuscation per byte of actual message data.
+    pub fn obfuscation_overhead(&self) -> f64 {
+        if self.payload_bytes_sent == 0 {
+            return 0.0;
+        }
+        (self.padding_bytes_sent + self.cover_bytes_sent) as f64 / self.payload_bytes_sent as f64
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -105,6 +308,12 @@ pub struct PeerData {
     pub last_seen: DateTime<Utc>,
     pub bytes_sent: u64,
     pub bytes_received: u64,
+    /// Where the peer can be reached through Tor, as `<id>.onion:<port>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onion_address: Option<String>,
+    /// Round-trip time measured by keepalive pings, once a pong came back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyStats>,
 }
 
 // Protocol types
@@ -200,7 +409,7 @@ pub struct ProtocolMessage {
 }
 
 // Discovery types
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiscoveredPeer {
     pub id: String,
     pub address: IpAddr,
@@ -221,6 +430,69 @@ pub struct AnnouncementMessage {
     pub protocol_version: u8,
     pub capabilities: Vec<String>,
     pub timestamp: u64,
+    #[serde(default)]
+    pub nonce: u64,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl AnnouncementMessage {
+    /// Bytes covered by the signature: the announcement with the signature
+    /// field left empty.
+    pub fn signing_payload(&self) -> Vec<u8> {
+        let unsigned = AnnouncementMessage {
+            signature: Vec::new(),
+            ..self.clone()
+        };
+        serde_json::to_vec(&unsigned).unwrap_or_default()
+    }
+}
+
+pub const DEFAULT_DISCOVERY_PORT: u16 = 9999;
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Three missed announcements before a peer is considered gone.
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(90);
+pub const DEFAULT_MAX_DISCOVERED_PEERS: usize = 256;
+pub const DEFAULT_MAX_ANNOUNCEMENTS_PER_IP: u32 = 20;
+/// Organization-local scope group used for LAN discovery.
+pub const DISCOVERY_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 83, 71);
+/// Link-local scope group used for LAN discovery over IPv6.
+pub const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x5347);
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub port: u16,
+    pub announce_interval: Duration,
+    pub multicast_v4: Ipv4Addr,
+    pub multicast_v6: Ipv6Addr,
+    pub enable_ipv6: bool,
+    /// Also advertise and browse `_shadowghost._tcp.local` over mDNS / DNS-SD.
+    pub enable_mdns: bool,
+    /// Upper bound on the size of the discovered peer table.
+    pub max_peers: usize,
+    /// Announcements accepted from one source address per rate-limit window.
+    pub max_announcements_per_ip: u32,
+    /// Peers not heard from for this long are dropped and reported lost.
+    pub peer_timeout: Duration,
+    /// Allows `get_external_ip` to ask public HTTP services. Off by default.
+    pub allow_http_ip_lookup: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_DISCOVERY_PORT,
+            announce_interval: DEFAULT_ANNOUNCE_INTERVAL,
+            multicast_v4: DISCOVERY_MULTICAST_V4,
+            multicast_v6: DISCOVERY_MULTICAST_V6,
+            enable_ipv6: true,
+            enable_mdns: true,
+            max_peers: DEFAULT_MAX_DISCOVERED_PEERS,
+            max_announcements_per_ip: DEFAULT_MAX_ANNOUNCEMENTS_PER_IP,
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            allow_http_ip_lookup: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -229,7 +501,784 @@ pub struct DiscoveryStatistics {
     pub active_peers: usize,
     pub unique_capabilities: usize,
     pub discovery_uptime: u64,
-    pub last_discovery: chrono::DateTime<chrono::Utc>,
+    pub last_discovery: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// NAT traversal types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CandidateKind {
+    /// Address of a local interface.
+    Host,
+    /// Public address as observed by a rendezvous server.
+    ServerReflexive,
+    /// Address a probe actually arrived from during hole punching.
+    PeerReflexive,
+}
+
+/// An endpoint a peer may be reachable at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+pub struct PunchConfig {
+    /// How often probes are sent to every remote candidate while punching.
+    pub probe_interval: Duration,
+    /// How long to keep probing before giving up on a direct path.
+    pub punch_timeout: Duration,
+    /// Resend delay for unacknowledged data on an established channel.
+    pub retransmit_timeout: Duration,
+    pub max_retransmits: u32,
+    /// Keeps NAT mappings open on idle channels and rendezvous registrations.
+    pub keepalive_interval: Duration,
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_millis(100),
+            punch_timeout: Duration::from_secs(10),
+            retransmit_timeout: Duration::from_millis(200),
+            max_retransmits: 25,
+            keepalive_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PunchError {
+    Io(String),
+    Timeout,
+    NoCandidates,
+    UnknownPeer(String),
+    MessageTooLarge(usize),
+    ChannelClosed,
+}
+
+impl fmt::Display for PunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PunchError::Io(msg) => write!(f, "Socket error: {}", msg),
+            PunchError::Timeout => write!(f, "No direct path found in time"),
+            PunchError::NoCandidates => write!(f, "No candidates to probe"),
+            PunchError::UnknownPeer(peer_id) => {
+                write!(f, "Peer not registered with rendezvous: {}", peer_id)
+            }
+            PunchError::MessageTooLarge(size) => write!(f, "Message too large: {} bytes", size),
+            PunchError::ChannelClosed => write!(f, "Direct channel closed"),
+        }
+    }
+}
+
+impl Error for PunchError {}
+
+impl From<std::io::Error> for PunchError {
+    fn from(error: std::io::Error) -> Self {
+        PunchError::Io(error.to_string())
+    }
+}
+
+/// How a NAT maps this host's socket, judged from what several mirrors saw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NatMapping {
+    /// Too few answers to tell.
+    Unknown,
+    /// Mirrors see the socket's own address.
+    NotNatted,
+    /// Every mirror sees the same public address, so it can be handed out.
+    EndpointIndependent,
+    /// Same public IP but a different port per mirror; the address one
+    /// mirror sees is useless to anybody else.
+    Symmetric,
+    /// Mirrors disagree on the IP; some may be lying or the host is multi-homed.
+    Inconsistent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorObservation {
+    pub mirror: SocketAddr,
+    pub observed: SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReflexiveReport {
+    pub local_address: SocketAddr,
+    pub observations: Vec<MirrorObservation>,
+    /// Public ip:port agreed on by enough mirrors.
+    pub public_address: Option<SocketAddr>,
+    /// Public IP agreed on by enough mirrors, even when the ports differ.
+    pub external_ip: Option<IpAddr>,
+    pub nat_mapping: NatMapping,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReflexiveConfig {
+    /// How long to wait for mirrors to answer.
+    pub request_timeout: Duration,
+    /// Resend delay for unanswered requests.
+    pub resend_interval: Duration,
+    /// Matching answers needed before an address is trusted.
+    pub min_agreement: usize,
+    /// Ask public HTTP services for the external IP when mirrors cannot
+    /// agree. Off by default: it reveals the user's IP to third parties.
+    pub allow_http_fallback: bool,
+}
+
+impl Default for ReflexiveConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(2),
+            resend_interval: Duration::from_millis(250),
+            min_agreement: 2,
+            allow_http_fallback: false,
+        }
+    }
+}
+
+// Relay types
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Bytes one peer may push through the relay per `quota_window`.
+    pub max_bytes_per_peer: u64,
+    /// Bytes the relay forwards for everybody together per `quota_window`.
+    pub max_total_bytes: u64,
+    pub quota_window: Duration,
+    /// Allocations not refreshed for this long are dropped.
+    pub allocation_timeout: Duration,
+    /// Allocation requests older than this are treated as replays.
+    pub max_clock_skew: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes_per_peer: 4 * 1024 * 1024,
+            max_total_bytes: 64 * 1024 * 1024,
+            quota_window: Duration::from_secs(60),
+            allocation_timeout: Duration::from_secs(60),
+            max_clock_skew: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Which contacts may carry our traffic when no direct path exists.
+#[derive(Debug, Clone)]
+pub struct RelayPolicy {
+    pub enabled: bool,
+    /// Contacts below this trust level are never used as relays.
+    pub min_trust_level: TrustLevel,
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_trust_level: TrustLevel::High,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RelayError {
+    Io(String),
+    Timeout,
+    Denied(String),
+    UnknownPeer(String),
+    QuotaExceeded,
+    Handshake(String),
+    Punch(PunchError),
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::Io(msg) => write!(f, "Socket error: {}", msg),
+            RelayError::Timeout => write!(f, "Relay did not answer in time"),
+            RelayError::Denied(reason) => write!(f, "Relay refused allocation: {}", reason),
+            RelayError::UnknownPeer(peer_id) => {
+                write!(f, "Peer not allocated on relay: {}", peer_id)
+            }
+            RelayError::QuotaExceeded => write!(f, "Relay quota exceeded"),
+            RelayError::Handshake(msg) => write!(f, "Relay handshake failed: {}", msg),
+            RelayError::Punch(e) => write!(f, "Direct path upgrade failed: {}", e),
+        }
+    }
+}
+
+impl Error for RelayError {}
+
+impl From<std::io::Error> for RelayError {
+    fn from(error: std::io::Error) -> Self {
+        RelayError::Io(error.to_string())
+    }
+}
+
+impl From<PunchError> for RelayError {
+    fn from(error: PunchError) -> Self {
+        RelayError::Punch(error)
+    }
+}
+
+// Mailbox types
+/// A message sealed for one recipient, held by a mailbox until they collect
+/// it. The mailbox sees who it is for, but not who sent it or what it says.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SealedEnvelope {
+    pub id: String,
+    pub recipient_id: String,
+    pub sealed: Vec<u8>,
+    pub expires_at: u64,
+}
+
+impl SealedEnvelope {
+    pub fn size(&self) -> u64 {
+        self.sealed.len() as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    pub max_envelope_size: u64,
+    pub max_envelopes_per_recipient: usize,
+    pub max_bytes_per_recipient: u64,
+    pub max_total_bytes: u64,
+    /// Envelopes asking to be kept longer are cut down to this.
+    pub max_ttl: Duration,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            max_envelope_size: 256 * 1024,
+            max_envelopes_per_recipient: 500,
+            max_bytes_per_recipient: 16 * 1024 * 1024,
+            max_total_bytes: 256 * 1024 * 1024,
+            max_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// How long senders ask mailboxes to keep undelivered messages.
+pub const MAILBOX_MESSAGE_TTL: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// How an outgoing message left this node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeliveryRoute {
+    Direct,
+    /// Held in the outbox; also deposited with these mailboxes of the recipient.
+    Queued {
+        mailboxes: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MailboxError {
+    NotServed(String),
+    TooLarge,
+    Expired,
+    Full,
+    Storage(String),
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailboxError::NotServed(peer_id) => write!(f, "No mailbox for peer: {}", peer_id),
+            MailboxError::TooLarge => write!(f, "Envelope exceeds the size limit"),
+            MailboxError::Expired => write!(f, "Envelope already expired"),
+            MailboxError::Full => write!(f, "Mailbox is full"),
+            MailboxError::Storage(msg) => write!(f, "Mailbox storage error: {}", msg),
+        }
+    }
+}
+
+impl Error for MailboxError {}
+
+// DHT types
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Bucket size, and how many nodes keep a copy of each record.
+    pub k: usize,
+    /// Requests in flight per step of a lookup.
+    pub alpha: usize,
+    pub request_timeout: Duration,
+    /// How long published records stay valid.
+    pub record_ttl: Duration,
+    /// Records asking to live longer than this are refused.
+    pub max_record_ttl: Duration,
+    pub max_records: usize,
+    /// How often our own record is published again and old ones dropped.
+    pub republish_interval: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            k: 8,
+            alpha: 3,
+            request_timeout: Duration::from_millis(800),
+            record_ttl: Duration::from_secs(60 * 60),
+            max_record_ttl: Duration::from_secs(24 * 60 * 60),
+            max_records: 10_000,
+            republish_interval: Duration::from_secs(20 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DhtError {
+    Io(String),
+    Signing(String),
+    NoPeers,
+    NotFound,
+    Unreachable(String),
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DhtError::Io(msg) => write!(f, "Socket error: {}", msg),
+            DhtError::Signing(msg) => write!(f, "Could not sign record: {}", msg),
+            DhtError::NoPeers => write!(f, "No DHT nodes known"),
+            DhtError::NotFound => write!(f, "No record found"),
+            DhtError::Unreachable(peer_id) => write!(f, "Peer unreachable: {}", peer_id),
+        }
+    }
+}
+
+impl Error for DhtError {}
+
+impl From<std::io::Error> for DhtError {
+    fn from(error: std::io::Error) -> Self {
+        DhtError::Io(error.to_string())
+    }
+}
+
+// Obfuscation types
+#[derive(Debug, Clone)]
+pub struct ObfuscationConfig {
+    /// Pad every frame up to the next of `bucket_sizes`.
+    pub padding: bool,
+    /// Frame sizes in ascending order. Larger frames are padded to a
+    /// multiple of the last one.
+    pub bucket_sizes: Vec<usize>,
+    /// Send one frame per `cover_interval` whether or not there is anything
+    /// to say; queued messages ride in the next slot.
+    pub cover_traffic: bool,
+    pub cover_interval: Duration,
+    /// Outgoing messages wait a random delay up to this long so that
+    /// whatever else is sent meanwhile goes out in the same frame.
+    pub batch_jitter: Duration,
+}
+
+impl ObfuscationConfig {
+    /// Plain framing: no padding, cover traffic or batching delay.
+    pub fn disabled() -> Self {
+        Self {
+            padding: false,
+            cover_traffic: false,
+            batch_jitter: Duration::ZERO,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ObfuscationConfig {
+    fn default() -> Self {
+        Self {
+            padding: true,
+            bucket_sizes: vec![1024, 4096, 16 * 1024, 64 * 1024],
+            cover_traffic: false,
+            cover_interval: Duration::from_millis(500),
+            batch_jitter: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObfuscationError {
+    Malformed(String),
+    Encoding(String),
+    Masking(String),
+}
+
+impl fmt::Display for ObfuscationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObfuscationError::Malformed(msg) => write!(f, "Malformed frame: {}", msg),
+            ObfuscationError::Encoding(msg) => write!(f, "Could not encode message: {}", msg),
+            ObfuscationError::Masking(msg) => write!(f, "TLS masking failed: {}", msg),
+        }
+    }
+}
+
+impl Error for ObfuscationError {}
+
+// Tor types
+#[derive(Debug, Clone)]
+pub struct TorConfig {
+    pub socks_proxy: SocketAddr,
+    pub control_address: SocketAddr,
+    /// Used for `HashedControlPassword`; otherwise cookie or no
+    /// authentication is tried, whichever Tor offers.
+    pub control_password: Option<String>,
+    /// Give every contact its own circuit by sending its id as SOCKS
+    /// credentials, which Tor isolates by default.
+    pub isolate_streams: bool,
+    pub connect_timeout: Duration,
+}
+
+impl Default for TorConfig {
+    fn default() -> Self {
+        Self {
+            socks_proxy: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9050),
+            control_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9051),
+            control_password: None,
+            isolate_streams: true,
+            connect_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorError {
+    Io(String),
+    /// The proxy refused the request, with the SOCKS5 reply code.
+    Socks(u8),
+    Protocol(String),
+    AuthenticationFailed(String),
+    /// The control port answered with an error status.
+    Control(u16, String),
+    Timeout,
+}
+
+impl fmt::Display for TorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorError::Io(msg) => write!(f, "Tor connection error: {}", msg),
+            TorError::Socks(code) => {
+                let reason = match code {
+                    0x01 => "general failure",
+                    0x02 => "connection not allowed",
+                    0x03 => "network unreachable",
+                    0x04 => "host unreachable",
+                    0x05 => "connection refused",
+                    0x06 => "TTL expired",
+                    0x07 => "command not supported",
+                    0x08 => "address type not supported",
+                    _ => "unknown error",
+                };
+                write!(f, "SOCKS5 proxy: {} ({:#04x})", reason, code)
+            }
+            TorError::Protocol(msg) => write!(f, "Unexpected reply: {}", msg),
+            TorError::AuthenticationFailed(msg) => write!(f, "Authentication failed: {}", msg),
+            TorError::Control(code, msg) => write!(f, "Tor control error {}: {}", code, msg),
+            TorError::Timeout => write!(f, "Timed out"),
+        }
+    }
+}
+
+impl Error for TorError {}
+
+impl From<std::io::Error> for TorError {
+    fn from(error: std::io::Error) -> Self {
+        TorError::Io(error.to_string())
+    }
+}
+
+// QUIC types
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+    pub idle_timeout: Duration,
+    /// Keeps NAT bindings open and lets a migrated path be validated
+    /// before the peer gives up on it.
+    pub keep_alive_interval: Duration,
+    /// Try to send the first messages in 0-RTT when a session ticket from
+    /// an earlier connection to the same peer is available.
+    pub zero_rtt: bool,
+    /// How often local interfaces are checked for an address change that
+    /// calls for migrating to a new socket.
+    pub migration_check_interval: Duration,
+    pub max_file_size: u64,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(30),
+            keep_alive_interval: Duration::from_secs(10),
+            zero_rtt: true,
+            migration_check_interval: Duration::from_secs(5),
+            max_file_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuicError {
+    Io(String),
+    Tls(String),
+    Connect(String),
+    /// The connection was closed or lost.
+    Connection(String),
+    Stream(String),
+    /// The peer's certificate does not match the pinned fingerprint.
+    CertificateMismatch,
+    TooLarge(u64),
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuicError::Io(msg) => write!(f, "QUIC socket error: {}", msg),
+            QuicError::Tls(msg) => write!(f, "QUIC TLS error: {}", msg),
+            QuicError::Connect(msg) => write!(f, "QUIC connect failed: {}", msg),
+            QuicError::Connection(msg) => write!(f, "QUIC connection lost: {}", msg),
+            QuicError::Stream(msg) => write!(f, "QUIC stream error: {}", msg),
+            QuicError::CertificateMismatch => write!(f, "Peer certificate does not match"),
+            QuicError::TooLarge(size) => write!(f, "Payload of {} bytes is too large", size),
+        }
+    }
+}
+
+impl Error for QuicError {}
+
+impl From<std::io::Error> for QuicError {
+    fn from(error: std::io::Error) -> Self {
+        QuicError::Io(error.to_string())
+    }
+}
+
+// Keepalive types
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    pub interval: Duration,
+    /// Pings in a row that may go unanswered before the connection is
+    /// considered dead.
+    pub max_missed: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            max_missed: 3,
+        }
+    }
+}
+
+/// Latency to a peer measured from keepalive pongs, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub last_rtt_ms: u64,
+    /// Moves an eighth of the way towards each new sample, as TCP's does.
+    pub smoothed_rtt_ms: f64,
+    /// Mean difference between consecutive samples, smoothed like RTP's
+    /// interarrival jitter.
+    pub jitter_ms: f64,
+    pub samples: u64,
+}
+
+// Supervisor types
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    /// Most supervised contacts kept connected at once.
+    pub max_peers: usize,
+    pub check_interval: Duration,
+    pub connect_timeout: Duration,
+    /// Wait after the first failed attempt; it doubles with every further
+    /// failure up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 50,
+            check_interval: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(10),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl SupervisorConfig {
+    /// How long to wait before redialing after `failures` failed attempts
+    /// in a row.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+// Rate limiting types
+/// Token-bucket limits applied to every peer and, separately, to every IP
+/// address, so a peer cannot dodge them by changing identity.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Connections accepted per second from one IP address.
+    pub connections_per_second: f64,
+    pub connection_burst: f64,
+    pub messages_per_second: f64,
+    pub message_burst: f64,
+    pub bytes_per_second: f64,
+    /// Should be at least `max_frame_size`, or frames that large never
+    /// get through.
+    pub byte_burst: f64,
+    /// Largest frame read from a connection. A larger one closes the
+    /// connection and counts as a violation.
+    pub max_frame_size: usize,
+    /// Violations that earn a temporary ban.
+    pub violations_before_ban: u32,
+    pub ban_duration: Duration,
+    /// Bans after which a peer is blocked for good.
+    pub bans_before_block: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            connections_per_second: 1.0,
+            connection_burst: 10.0,
+            messages_per_second: 50.0,
+            message_burst: 200.0,
+            bytes_per_second: 1024.0 * 1024.0,
+            byte_burst: 4.0 * 1024.0 * 1024.0,
+            max_frame_size: MAX_MESSAGE_SIZE,
+            violations_before_ban: 20,
+            ban_duration: Duration::from_secs(600),
+            bans_before_block: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimitError {
+    Blocked,
+    Banned {
+        remaining: Duration,
+    },
+    TooManyConnections,
+    TooManyMessages,
+    TooManyBytes,
+    /// A frame over `max_frame_size`, or one that does not decode.
+    MalformedFrame,
+    /// A connection that did not prove it holds the key of the peer it
+    /// claimed to be.
+    FailedHandshake,
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::Blocked => write!(f, "Peer is blocked"),
+            RateLimitError::Banned { remaining } => {
+                write!(f, "Banned for another {}s", remaining.as_secs())
+            }
+            RateLimitError::TooManyConnections => write!(f, "Too many connections"),
+            RateLimitError::TooManyMessages => write!(f, "Too many messages"),
+            RateLimitError::TooManyBytes => write!(f, "Too much data"),
+            RateLimitError::MalformedFrame => write!(f, "Oversized or malformed frame"),
+            RateLimitError::FailedHandshake => write!(f, "Failed handshake"),
+        }
+    }
+}
+
+impl Error for RateLimitError {}
+
+// Bandwidth types
+/// Caps in bytes per second. `None` leaves a direction uncapped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthConfig {
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    /// Caps for each peer on its own, within the overall ones.
+    pub peer_upload_limit: Option<u64>,
+    pub peer_download_limit: Option<u64>,
+    /// While metered, messages larger than this, and file chunks of any
+    /// size, wait for an unmetered link.
+    pub large_message_size: usize,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            upload_limit: None,
+            download_limit: None,
+            peer_upload_limit: None,
+            peer_download_limit: None,
+            large_message_size: 64 * 1024,
+        }
+    }
+}
+
+// Sequencing types
+/// How chat messages arriving out of order are put back in order.
+#[derive(Debug, Clone)]
+pub struct SequencingConfig {
+    /// How far past a gap messages are held back. One further ahead
+    /// gives up on the gap at once.
+    pub reorder_window: u64,
+    /// How long a gap may stay open before the messages held back behind
+    /// it are delivered without it.
+    pub gap_timeout: Duration,
+}
+
+impl Default for SequencingConfig {
+    fn default() -> Self {
+        Self {
+            reorder_window: 64,
+            gap_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+// Transport types
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportError {
+    Io(String),
+    /// No transport is registered for the endpoint's scheme.
+    UnsupportedScheme(String),
+    InvalidEndpoint(String),
+    /// A frame that is too large or does not hold a protocol message.
+    Malformed(String),
+    /// Turned away before the handshake, or cut off for abuse.
+    Refused(RateLimitError),
+    /// The peer did not prove it holds the identity key it is known by.
+    Unauthenticated(String),
+    Closed,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(msg) => write!(f, "Transport error: {}", msg),
+            TransportError::UnsupportedScheme(scheme) => {
+                write!(f, "No transport for scheme {}", scheme)
+            }
+            TransportError::InvalidEndpoint(endpoint) => {
+                write!(f, "Invalid endpoint: {}", endpoint)
+            }
+            TransportError::Malformed(msg) => write!(f, "Malformed frame: {}", msg),
+            TransportError::Refused(reason) => write!(f, "Connection refused: {}", reason),
+            TransportError::Unauthenticated(msg) => write!(f, "Authentication failed: {}", msg),
+            TransportError::Closed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl Error for TransportError {}
+
+impl From<std::io::Error> for TransportError {
+    fn from(error: std::io::Error) -> Self {
+        TransportError::Io(error.to_string())
+    }
 }
 
 // TLS Masking types
@@ -267,6 +1316,58 @@ pub fn is_protocol_compatible(version: u8) -> bool {
     version == PROTOCOL_VERSION
 }
 
+impl ChatMessage {
+    /// Whether both are numbered messages from the same sender and this
+    /// one was sent first.
+    pub fn sent_before(&self, other: &ChatMessage) -> bool {
+        self.sequence > 0
+            && other.sequence > 0
+            && self.from == other.from
+            && self.sequence < other.sequence
+    }
+
+    pub fn add_reaction(&mut self, user: &str, emoji: &str) -> bool {
+        if let Some(reaction) = self.reactions.iter_mut().find(|r| r.emoji == emoji) {
+            if reaction.users.iter().any(|u| u == user) {
+                return false;
+            }
+            reaction.users.push(user.to_string());
+            reaction.count = reaction.users.len() as u32;
+        } else {
+            self.reactions.push(MessageReaction {
+                emoji: emoji.to_string(),
+                count: 1,
+                users: vec![user.to_string()],
+            });
+        }
+        true
+    }
+
+    pub fn remove_reaction(&mut self, user: &str, emoji: &str) -> bool {
+        if let Some(pos) = self.reactions.iter().position(|r| r.emoji == emoji) {
+            let reaction = &mut self.reactions[pos];
+            let original_len = reaction.users.len();
+            reaction.users.retain(|u| u != user);
+
+            if reaction.users.len() == original_len {
+                return false;
+            }
+
+            reaction.count = reaction.users.len() as u32;
+            if reaction.users.is_empty() {
+                self.reactions.remove(pos);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get_reaction_count(&self) -> u32 {
+        self.reactions.iter().map(|r| r.count).sum()
+    }
+}
+
 impl ProtocolMessage {
     pub fn new(
         message_type: MessageType,
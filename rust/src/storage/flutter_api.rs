@@ -8,6 +8,8 @@ pub async fn save_message(chat_id: String, message: ChatMessage) -> Result<(), S
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .save_message(&chat_id, &message)
         .await
         .map_err(|e| e.to_string())
@@ -18,6 +20,8 @@ pub async fn get_messages(chat_id: String) -> Result<Vec<ChatMessage>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .get_messages(&chat_id)
         .await
         .map_err(|e| e.to_string())
@@ -28,6 +32,8 @@ pub async fn delete_message(message_id: String) -> Result<(), String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .delete_message(&message_id)
         .await
         .map_err(|e| e.to_string())
@@ -38,6 +44,8 @@ pub async fn delete_chat(chat_id: String) -> Result<(), String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .delete_chat(&chat_id)
         .await
         .map_err(|e| e.to_string())
@@ -51,6 +59,8 @@ pub async fn update_message_status(
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .update_message_status(&message_id, new_status)
         .await
         .map_err(|e| e.to_string())
@@ -61,6 +71,8 @@ pub async fn save_contact(contact: Contact) -> Result<(), String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .save_contact(&contact)
         .await
         .map_err(|e| e.to_string())
@@ -71,6 +83,8 @@ pub async fn get_contacts() -> Result<Vec<Contact>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .get_contacts()
         .await
         .map_err(|e| e.to_string())
@@ -81,6 +95,8 @@ pub async fn delete_contact(contact_id: String) -> Result<(), String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .delete_contact(&contact_id)
         .await
         .map_err(|e| e.to_string())
@@ -89,7 +105,13 @@ pub async fn delete_contact(contact_id: String) -> Result<(), String> {
 #[frb]
 pub async fn create_backup() -> Result<String, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
-    engine.storage().backup().await.map_err(|e| e.to_string())
+    engine
+        .storage()
+        .read()
+        .await
+        .backup()
+        .await
+        .map_err(|e| e.to_string())
 }
 
 #[frb]
@@ -97,6 +119,8 @@ pub async fn restore_from_backup(backup_path: String) -> Result<(), String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .restore_from_backup(&backup_path)
         .await
         .map_err(|e| e.to_string())
@@ -107,6 +131,8 @@ pub async fn get_storage_stats() -> Result<StorageStats, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .get_stats()
         .await
         .map_err(|e| e.to_string())
@@ -117,6 +143,8 @@ pub async fn validate_storage() -> Result<Vec<String>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .validate_chats()
         .await
         .map_err(|e| e.to_string())
@@ -127,6 +155,8 @@ pub async fn export_chat(chat_id: String, format: String) -> Result<String, Stri
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     engine
         .storage()
+        .read()
+        .await
         .export_chat_data(&chat_id, &format)
         .await
         .map_err(|e| e.to_string())
@@ -137,6 +167,8 @@ pub async fn get_chat_message_count(chat_id: String) -> Result<u64, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     let messages = engine
         .storage()
+        .read()
+        .await
         .get_messages(&chat_id)
         .await
         .map_err(|e| e.to_string())?;
@@ -153,6 +185,8 @@ pub async fn search_messages_by_content(
     if let Some(chat_id) = chat_id {
         let messages = engine
             .storage()
+            .read()
+            .await
             .get_messages(&chat_id)
             .await
             .map_err(|e| e.to_string())?;
@@ -176,6 +210,8 @@ pub async fn get_failed_messages() -> Result<Vec<ChatMessage>, String> {
     let engine = ENGINE.get().ok_or("Engine not initialized")?;
     let stats = engine
         .storage()
+        .read()
+        .await
         .get_stats()
         .await
         .map_err(|e| e.to_string())?;
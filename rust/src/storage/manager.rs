@@ -1,3 +1,4 @@
+use crate::chats::{Chat, DraftMessage};
 use crate::events::types::{AppEvent, EventBus, StorageEvent};
 use crate::network::{ChatMessage, Contact, DeliveryStatus};
 use crate::storage::types::*;
@@ -57,6 +58,7 @@ impl StorageManager {
 
         // Save to disk
         self.save_chats_to_disk(&chat_storage).await?;
+        drop(chat_storage);
         self.update_stats().await?;
 
         self.event_bus
@@ -73,6 +75,16 @@ impl StorageManager {
         Ok(chat_storage.get_messages(chat_id))
     }
 
+    /// The chat a stored message belongs to.
+    pub async fn find_message_chat(&self, message_id: &str) -> Option<String> {
+        let chat_storage = self.chat_storage.read().await;
+        chat_storage
+            .messages
+            .iter()
+            .find(|(_, messages)| messages.iter().any(|m| m.id == message_id))
+            .map(|(chat_id, _)| chat_id.clone())
+    }
+
     pub async fn delete_message(&self, message_id: &str) -> Result<(), StorageError> {
         let mut chat_storage = self.chat_storage.write().await;
         let mut message_found = false;
@@ -93,6 +105,7 @@ impl StorageManager {
         }
 
         self.save_chats_to_disk(&chat_storage).await?;
+        drop(chat_storage);
         self.update_stats().await?;
         self.event_bus
             .emit(AppEvent::Storage(StorageEvent::ChatHistorySaved {
@@ -108,6 +121,7 @@ impl StorageManager {
         chat_storage.metadata.remove(chat_id);
 
         self.save_chats_to_disk(&chat_storage).await?;
+        drop(chat_storage);
         self.update_stats().await?;
 
         Ok(())
@@ -140,6 +154,174 @@ impl StorageManager {
         Ok(())
     }
 
+    pub async fn update_reaction(
+        &self,
+        message_id: &str,
+        user: &str,
+        emoji: &str,
+        added: bool,
+    ) -> Result<ChatMessage, StorageError> {
+        let mut chat_storage = self.chat_storage.write().await;
+        let mut updated = None;
+
+        for (chat_id, messages) in chat_storage.messages.iter_mut() {
+            if let Some(message) = messages.iter_mut().find(|m| m.id == message_id) {
+                let changed = if added {
+                    message.add_reaction(user, emoji)
+                } else {
+                    message.remove_reaction(user, emoji)
+                };
+                updated = Some((chat_id.clone(), message.clone(), changed));
+                break;
+            }
+        }
+
+        let (chat_id, message, changed) = updated
+            .ok_or_else(|| StorageError::NotFound(format!("Message {} not found", message_id)))?;
+
+        if changed {
+            self.save_chats_to_disk(&chat_storage).await?;
+            self.event_bus
+                .emit(AppEvent::Storage(StorageEvent::ChatHistorySaved {
+                    chat_id,
+                    message_count: 1,
+                }));
+        }
+
+        Ok(message)
+    }
+
+    pub async fn set_disappearing_timer(
+        &self,
+        chat_id: &str,
+        timer: Option<u64>,
+        changed_at: u64,
+    ) -> Result<bool, StorageError> {
+        let mut chat_storage = self.chat_storage.write().await;
+        let applied = chat_storage.set_disappearing_timer(chat_id, timer, changed_at);
+
+        if applied {
+            self.save_chats_to_disk(&chat_storage).await?;
+        }
+
+        Ok(applied)
+    }
+
+    pub async fn get_disappearing_timer(&self, chat_id: &str) -> Option<u64> {
+        let chat_storage = self.chat_storage.read().await;
+        chat_storage.get_disappearing_timer(chat_id)
+    }
+
+    /// Deletes messages whose disappearing timer has elapsed and emits a
+    /// `MessagesExpired` event per affected chat.
+    pub async fn purge_expired_messages(
+        &self,
+    ) -> Result<HashMap<String, Vec<String>>, StorageError> {
+        let now = Utc::now().timestamp() as u64;
+        let mut chat_storage = self.chat_storage.write().await;
+        let expired = chat_storage.remove_expired(now);
+
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+
+        self.save_chats_to_disk(&chat_storage).await?;
+        drop(chat_storage);
+        self.update_stats().await?;
+
+        for (chat_id, message_ids) in &expired {
+            self.event_bus
+                .emit(AppEvent::Storage(StorageEvent::MessagesExpired {
+                    chat_id: chat_id.clone(),
+                    message_ids: message_ids.clone(),
+                }));
+        }
+
+        Ok(expired)
+    }
+
+    pub async fn mark_read(
+        &self,
+        chat_id: &str,
+        message_id: Option<&str>,
+    ) -> Result<Vec<ChatMessage>, StorageError> {
+        let mut chat_storage = self.chat_storage.write().await;
+        let newly_read = chat_storage.mark_read(chat_id, message_id);
+
+        if !newly_read.is_empty() {
+            self.save_chats_to_disk(&chat_storage).await?;
+        }
+
+        Ok(newly_read)
+    }
+
+    pub async fn get_read_marker(&self, chat_id: &str) -> Option<String> {
+        let chat_storage = self.chat_storage.read().await;
+        chat_storage.get_read_marker(chat_id)
+    }
+
+    pub async fn count_unread(&self, chat_id: &str, local_user: Option<&str>) -> u64 {
+        let chat_storage = self.chat_storage.read().await;
+        chat_storage.count_unread(chat_id, local_user)
+    }
+
+    pub async fn save_chat_list(&self, chats: &HashMap<String, Chat>) -> Result<(), StorageError> {
+        let chat_list_file = self.data_path.join("chat_list.json");
+
+        let content = serde_json::to_string_pretty(chats)
+            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+
+        tokio::fs::write(&chat_list_file, content)
+            .await
+            .map_err(|e| StorageError::PermissionDenied(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn load_chat_list(&self) -> Result<HashMap<String, Chat>, StorageError> {
+        let chat_list_file = self.data_path.join("chat_list.json");
+
+        if !chat_list_file.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let content = tokio::fs::read_to_string(&chat_list_file)
+            .await
+            .map_err(|e| StorageError::FileNotFound(e.to_string()))?;
+
+        serde_json::from_str(&content).map_err(|e| StorageError::SerializationError(e.to_string()))
+    }
+
+    pub async fn save_drafts(
+        &self,
+        drafts: &HashMap<String, DraftMessage>,
+    ) -> Result<(), StorageError> {
+        let drafts_file = self.data_path.join("drafts.json");
+
+        let content = serde_json::to_string_pretty(drafts)
+            .map_err(|e| StorageError::SerializationError(e.to_string()))?;
+
+        tokio::fs::write(&drafts_file, content)
+            .await
+            .map_err(|e| StorageError::PermissionDenied(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn load_drafts(&self) -> Result<HashMap<String, DraftMessage>, StorageError> {
+        let drafts_file = self.data_path.join("drafts.json");
+
+        if !drafts_file.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let content = tokio::fs::read_to_string(&drafts_file)
+            .await
+            .map_err(|e| StorageError::FileNotFound(e.to_string()))?;
+
+        serde_json::from_str(&content).map_err(|e| StorageError::SerializationError(e.to_string()))
+    }
+
     pub async fn get_messages_by_status(
         &self,
         chat_id: &str,
@@ -272,6 +454,7 @@ impl StorageManager {
         contacts.insert(contact.id.clone(), contact.clone());
 
         self.save_contacts_to_disk(&contacts).await?;
+        drop(contacts);
         self.update_stats().await?;
 
         Ok(())
@@ -289,6 +472,7 @@ impl StorageManager {
             .ok_or_else(|| StorageError::NotFound(format!("Contact {} not found", contact_id)))?;
 
         self.save_contacts_to_disk(&contacts).await?;
+        drop(contacts);
         self.update_stats().await?;
 
         Ok(())
@@ -349,6 +533,8 @@ impl StorageManager {
         // Save restored data
         self.save_chats_to_disk(&chat_storage).await?;
         self.save_contacts_to_disk(&contacts).await?;
+        drop(chat_storage);
+        drop(contacts);
         self.update_stats().await?;
 
         Ok(())
@@ -405,7 +591,7 @@ impl StorageManager {
                 issues.push(format!("Contact {} has empty name", contact_id));
             }
 
-            if contact.address.trim().is_empty() {
+            if contact.addresses.is_empty() {
                 issues.push(format!("Contact {} has empty address", contact_id));
             }
         }
@@ -423,16 +609,18 @@ impl StorageManager {
             "json" => serde_json::to_string_pretty(&messages)
                 .map_err(|e| StorageError::SerializationError(e.to_string())),
             "csv" => {
-                let mut csv_data = String::from("timestamp,from,to,content,type,status\n");
+                let mut csv_data =
+                    String::from("timestamp,from,to,content,type,status,reactions\n");
                 for message in messages {
                     csv_data.push_str(&format!(
-                        "{},{},{},{},{:?},{:?}\n",
+                        "{},{},{},{},{:?},{:?},{}\n",
                         message.timestamp,
                         message.from,
                         message.to,
                         message.content.replace(',', ";"),
                         message.msg_type,
-                        message.delivery_status
+                        message.delivery_status,
+                        format_reactions(&message).replace(',', ";")
                     ));
                 }
                 Ok(csv_data)
@@ -445,9 +633,13 @@ impl StorageManager {
                         .unwrap_or_else(|| "Unknown time".to_string());
 
                     text_data.push_str(&format!(
-                        "[{}] {}: {}\n",
+                        "[{}] {}: {}",
                         time, message.from, message.content
                     ));
+                    if !message.reactions.is_empty() {
+                        text_data.push_str(&format!(" [{}]", format_reactions(&message)));
+                    }
+                    text_data.push('\n');
                 }
                 Ok(text_data)
             }
@@ -459,9 +651,13 @@ impl StorageManager {
                         .unwrap_or_else(|| "Unknown time".to_string());
 
                     html_data.push_str(&format!(
-                        "<p><strong>[{}] {}:</strong> {}</p>",
+                        "<p><strong>[{}] {}:</strong> {}",
                         time, message.from, message.content
                     ));
+                    if !message.reactions.is_empty() {
+                        html_data.push_str(&format!(" <em>{}</em>", format_reactions(&message)));
+                    }
+                    html_data.push_str("</p>");
                 }
                 html_data.push_str("</div></body></html>");
                 Ok(html_data)
@@ -669,6 +865,15 @@ impl StorageManager {
     }
 }
 
+fn format_reactions(message: &ChatMessage) -> String {
+    message
+        .reactions
+        .iter()
+        .map(|r| format!("{} {}", r.emoji, r.count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct BackupData {
     chat_storage: ChatStorage,
@@ -29,6 +29,14 @@ pub struct ChatMetadata {
     pub created_at: DateTime<Utc>,
     pub last_message_at: Option<DateTime<Utc>>,
     pub message_count: u64,
+    #[serde(default)]
+    pub disappearing_timer: Option<u64>,
+    #[serde(default)]
+    pub disappearing_timer_changed_at: u64,
+    #[serde(default)]
+    pub last_read_message_id: Option<String>,
+    #[serde(default)]
+    pub last_read_at: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -137,29 +145,177 @@ impl ChatStorage {
         }
     }
 
-    pub fn add_message(&mut self, chat_id: &str, message: ChatMessage) {
+    pub fn add_message(&mut self, chat_id: &str, mut message: ChatMessage) {
+        // Update metadata
+        let metadata = self
+            .metadata
+            .entry(chat_id.to_string())
+            .or_insert_with(|| ChatMetadata::new(chat_id));
+
+        metadata.message_count += 1;
+        metadata.last_message_at = Some(Utc::now());
+
+        // Messages inherit the chat's disappearing timer at the time they are stored
+        if message.expires_at.is_none() {
+            if let Some(timer) = metadata.disappearing_timer {
+                message.expires_at = Some(Utc::now().timestamp() as u64 + timer);
+            }
+        }
+
+        // A message that arrives late goes before those its sender sent after it
         let messages = self
             .messages
             .entry(chat_id.to_string())
             .or_insert_with(Vec::new);
-        messages.push(message);
+        let position = messages
+            .iter()
+            .position(|existing| message.sent_before(existing))
+            .unwrap_or(messages.len());
+        messages.insert(position, message);
         self.updated_at = Utc::now();
+    }
+
+    /// Applies a disappearing-message timer change. Changes older than the
+    /// one already applied are ignored so both participants converge on the
+    /// most recent setting. Returns true if the setting was applied.
+    pub fn set_disappearing_timer(
+        &mut self,
+        chat_id: &str,
+        timer: Option<u64>,
+        changed_at: u64,
+    ) -> bool {
+        let metadata = self
+            .metadata
+            .entry(chat_id.to_string())
+            .or_insert_with(|| ChatMetadata::new(chat_id));
+
+        if changed_at < metadata.disappearing_timer_changed_at {
+            return false;
+        }
+
+        metadata.disappearing_timer = timer;
+        metadata.disappearing_timer_changed_at = changed_at;
+        self.updated_at = Utc::now();
+        true
+    }
+
+    pub fn get_disappearing_timer(&self, chat_id: &str) -> Option<u64> {
+        self.metadata
+            .get(chat_id)
+            .and_then(|metadata| metadata.disappearing_timer)
+    }
+
+    /// Moves the read marker of a chat forward to `message_id`, or to the
+    /// latest message when `None`. Returns the messages that became read;
+    /// the marker never moves backwards.
+    pub fn mark_read(&mut self, chat_id: &str, message_id: Option<&str>) -> Vec<ChatMessage> {
+        let messages = match self.messages.get(chat_id) {
+            Some(messages) if !messages.is_empty() => messages,
+            _ => return Vec::new(),
+        };
+
+        let target = match message_id {
+            Some(id) => match messages.iter().position(|m| m.id == id) {
+                Some(position) => position,
+                None => return Vec::new(),
+            },
+            None => messages.len() - 1,
+        };
+
+        let first_unread = self.first_unread_position(chat_id);
+        if target < first_unread {
+            return Vec::new();
+        }
+
+        let newly_read = messages[first_unread..=target].to_vec();
+        let marker = &messages[target];
+        let (marker_id, marker_timestamp) = (marker.id.clone(), marker.timestamp);
 
-        // Update metadata
         let metadata = self
             .metadata
             .entry(chat_id.to_string())
-            .or_insert_with(|| ChatMetadata {
-                chat_id: chat_id.to_string(),
-                name: format!("Chat {}", chat_id),
-                participants: Vec::new(),
-                created_at: Utc::now(),
-                last_message_at: None,
-                message_count: 0,
+            .or_insert_with(|| ChatMetadata::new(chat_id));
+        metadata.last_read_message_id = Some(marker_id);
+        metadata.last_read_at = marker_timestamp;
+        self.updated_at = Utc::now();
+
+        newly_read
+    }
+
+    pub fn get_read_marker(&self, chat_id: &str) -> Option<String> {
+        self.metadata
+            .get(chat_id)
+            .and_then(|metadata| metadata.last_read_message_id.clone())
+    }
+
+    /// Counts messages after the read marker that were not sent by `local_user`.
+    pub fn count_unread(&self, chat_id: &str, local_user: Option<&str>) -> u64 {
+        let messages = match self.messages.get(chat_id) {
+            Some(messages) => messages,
+            None => return 0,
+        };
+
+        messages[self.first_unread_position(chat_id)..]
+            .iter()
+            .filter(|m| local_user != Some(m.from.as_str()))
+            .count() as u64
+    }
+
+    fn first_unread_position(&self, chat_id: &str) -> usize {
+        let messages = match self.messages.get(chat_id) {
+            Some(messages) => messages,
+            None => return 0,
+        };
+        let metadata = match self.metadata.get(chat_id) {
+            Some(metadata) => metadata,
+            None => return 0,
+        };
+
+        if let Some(marker) = &metadata.last_read_message_id {
+            if let Some(position) = messages.iter().position(|m| &m.id == marker) {
+                return position + 1;
+            }
+        }
+
+        // The marker message may have expired or been deleted; fall back to its timestamp
+        if metadata.last_read_at == 0 {
+            return 0;
+        }
+        messages
+            .iter()
+            .position(|m| m.timestamp > metadata.last_read_at)
+            .unwrap_or(messages.len())
+    }
+
+    /// Removes every message whose expiry time has passed, returning the
+    /// removed message ids grouped by chat.
+    pub fn remove_expired(&mut self, now: u64) -> HashMap<String, Vec<String>> {
+        let mut expired: HashMap<String, Vec<String>> = HashMap::new();
+
+        for (chat_id, messages) in self.messages.iter_mut() {
+            messages.retain(|message| match message.expires_at {
+                Some(expires_at) if expires_at <= now => {
+                    expired
+                        .entry(chat_id.clone())
+                        .or_default()
+                        .push(message.id.clone());
+                    false
+                }
+                _ => true,
             });
+        }
 
-        metadata.message_count += 1;
-        metadata.last_message_at = Some(Utc::now());
+        for (chat_id, ids) in &expired {
+            if let Some(metadata) = self.metadata.get_mut(chat_id) {
+                metadata.message_count = metadata.message_count.saturating_sub(ids.len() as u64);
+            }
+        }
+
+        if !expired.is_empty() {
+            self.updated_at = Utc::now();
+        }
+
+        expired
     }
 
     pub fn get_messages(&self, chat_id: &str) -> Vec<ChatMessage> {
@@ -182,6 +338,23 @@ impl ChatStorage {
     }
 }
 
+impl ChatMetadata {
+    pub fn new(chat_id: &str) -> Self {
+        Self {
+            chat_id: chat_id.to_string(),
+            name: format!("Chat {}", chat_id),
+            participants: Vec::new(),
+            created_at: Utc::now(),
+            last_message_at: None,
+            message_count: 0,
+            disappearing_timer: None,
+            disappearing_timer_changed_at: 0,
+            last_read_message_id: None,
+            last_read_at: 0,
+        }
+    }
+}
+
 impl StorageStats {
     pub fn new() -> Self {
         Self {
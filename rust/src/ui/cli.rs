@@ -37,6 +37,7 @@ impl CliInterface {
                 "help" => self.show_help(),
                 "init" => self.initialize_engine().await?,
                 "status" => self.show_status().await?,
+                "ping" => self.ping(args).await?,
                 "quit" | "exit" | "q" => {
                     println!("👋 Goodbye!");
                     break;
@@ -63,6 +64,7 @@ impl CliInterface {
         println!("├─────────────────────────┼──────────────────────────────────────────────┤");
         println!("│ init                    │ Initialize application                       │");
         println!("│ status                  │ Show current status                          │");
+        println!("│ ping <contact>          │ Measure round-trip time to a contact         │");
         println!("│ clear                   │ Clear screen                                 │");
         println!("│ help                    │ Show this help                               │");
         println!("│ quit/exit/q             │ Exit application                             │");
@@ -75,6 +77,39 @@ impl CliInterface {
         Ok(())
     }
 
+    async fn ping(&self, contact: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let engine = match crate::core::ENGINE.get() {
+            Some(engine) => engine,
+            None => {
+                println!("⚠️ Engine not initialized. Use 'init' command first.");
+                return Ok(());
+            }
+        };
+        if contact.is_empty() {
+            println!("❓ Usage: ping <contact>");
+            return Ok(());
+        }
+
+        // Peers go by their id once connected, so look contacts up by name
+        let peer_id = engine
+            .contacts()
+            .read()
+            .await
+            .get_contacts()
+            .into_iter()
+            .find(|c| c.name == contact)
+            .map(|c| c.id)
+            .unwrap_or_else(|| contact.to_string());
+        match engine.network().ping(&peer_id).await {
+            Ok(latency) => println!(
+                "🏓 Pong from {}: {} ms (average {:.1} ms, jitter {:.1} ms)",
+                contact, latency.last_rtt_ms, latency.smoothed_rtt_ms, latency.jitter_ms
+            ),
+            Err(e) => println!("❌ Ping failed: {}", e),
+        }
+        Ok(())
+    }
+
     async fn show_status(&self) -> Result<(), Box<dyn std::error::Error>> {
         println!("\n📊 Current status:");
         println!("┌─────────────────────┬─────────────────────────────────┐");
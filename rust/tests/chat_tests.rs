@@ -0,0 +1,1160 @@
+use shadowghost::chats::{ChatError, Manager, MessageFilter, MAX_DISAPPEARING_TIMER};
+use shadowghost::core::Peer;
+use shadowghost::events::types::{AppEvent, EventBus, NetworkEvent, StorageEvent};
+use shadowghost::network::{
+    ChatMessage, ChatMessageType, DeliveryStatus, MessagePayload, NetworkManager,
+};
+use shadowghost::storage::StorageManager;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::RwLock;
+
+struct ChatFixture {
+    dir: TempDir,
+    event_bus: EventBus,
+    storage: Arc<RwLock<StorageManager>>,
+    manager: Manager,
+}
+
+async fn setup() -> ChatFixture {
+    setup_in(TempDir::new().unwrap()).await
+}
+
+async fn setup_in(dir: TempDir) -> ChatFixture {
+    let event_bus = EventBus::new();
+    let storage = StorageManager::new(dir.path(), event_bus.clone()).unwrap();
+    storage.initialize().await.unwrap();
+    let storage = Arc::new(RwLock::new(storage));
+
+    let mut manager = Manager::new(storage.clone(), event_bus.clone()).unwrap();
+    manager.set_local_user("alice");
+    manager.set_local_peer_id("alice-id");
+    manager.initialize().await.unwrap();
+
+    ChatFixture {
+        dir,
+        event_bus,
+        storage,
+        manager,
+    }
+}
+
+/// Simulates an application restart on the same profile directory.
+async fn restart(fixture: ChatFixture) -> ChatFixture {
+    let ChatFixture { dir, .. } = fixture;
+    setup_in(dir).await
+}
+
+async fn send_text(fixture: &ChatFixture, chat_id: &str) -> String {
+    fixture
+        .manager
+        .send_message(chat_id, "alice", "bob", "hello", ChatMessageType::Text)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_reaction_counts_per_emoji() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let message_id = send_text(&fixture, &chat.id).await;
+
+    let storage = fixture.storage.read().await;
+    for emoji in ["👍", "❤"] {
+        storage
+            .update_reaction(&message_id, "bob", emoji, true)
+            .await
+            .unwrap();
+    }
+    drop(storage);
+    let message = fixture
+        .manager
+        .add_reaction(&message_id, "👍")
+        .await
+        .unwrap();
+
+    assert_eq!(message.get_reaction_count(), 3);
+    let thumbs = message.reactions.iter().find(|r| r.emoji == "👍").unwrap();
+    assert_eq!(thumbs.count, 2);
+    assert_eq!(
+        thumbs.users,
+        vec!["bob".to_string(), "alice-id".to_string()]
+    );
+}
+
+#[tokio::test]
+async fn test_reaction_is_idempotent_and_removable() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let message_id = send_text(&fixture, &chat.id).await;
+
+    fixture
+        .manager
+        .add_reaction(&message_id, "👍")
+        .await
+        .unwrap();
+    let message = fixture
+        .manager
+        .add_reaction(&message_id, "👍")
+        .await
+        .unwrap();
+    assert_eq!(message.get_reaction_count(), 1);
+
+    let message = fixture
+        .manager
+        .remove_reaction(&message_id, "👍")
+        .await
+        .unwrap();
+    assert!(message.reactions.is_empty());
+
+    let reactions = fixture
+        .manager
+        .get_reactions(&chat.id, &message_id)
+        .await
+        .unwrap();
+    assert!(reactions.is_empty());
+}
+
+#[tokio::test]
+async fn test_reaction_rejects_invalid_input() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let message_id = send_text(&fixture, &chat.id).await;
+
+    let empty = fixture.manager.add_reaction(&message_id, " ").await;
+    assert!(matches!(empty, Err(ChatError::InvalidInput(_))));
+
+    let long = "👍".repeat(9);
+    let oversized = fixture.manager.add_reaction(&message_id, &long).await;
+    assert!(matches!(oversized, Err(ChatError::InvalidInput(_))));
+
+    let missing = fixture.manager.add_reaction("missing", "👍").await;
+    assert!(matches!(missing, Err(ChatError::MessageNotFound(_))));
+}
+
+#[tokio::test]
+async fn test_reactions_survive_export_and_backup() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let message_id = send_text(&fixture, &chat.id).await;
+
+    let storage = fixture.storage.read().await;
+    storage
+        .update_reaction(&message_id, "bob", "🎉", true)
+        .await
+        .unwrap();
+    let json = storage.export_chat_data(&chat.id, "json").await.unwrap();
+    assert!(json.contains("🎉"));
+    let csv = storage.export_chat_data(&chat.id, "csv").await.unwrap();
+    assert!(csv.lines().next().unwrap().ends_with(",reactions"));
+    assert!(csv.contains("🎉 1"));
+
+    let backup_path = storage.backup().await.unwrap();
+    storage
+        .update_reaction(&message_id, "bob", "🎉", false)
+        .await
+        .unwrap();
+    storage.restore_from_backup(&backup_path).await.unwrap();
+
+    let messages = storage.get_messages(&chat.id).await.unwrap();
+    assert_eq!(messages[0].reactions.len(), 1);
+    assert_eq!(messages[0].reactions[0].users, vec!["bob".to_string()]);
+}
+
+#[tokio::test]
+async fn test_remote_reaction_applied_from_network_event() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_direct_chat("bob", "Bob".into())
+        .await
+        .unwrap();
+    let message_id = send_text(&fixture, &chat.id).await;
+
+    // Peers outside the chat cannot react to its messages
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::ReactionReceived {
+            peer_id: "mallory".to_string(),
+            message_id: message_id.clone(),
+            emoji: "👎".to_string(),
+            added: true,
+        });
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::ReactionReceived {
+            peer_id: "bob".to_string(),
+            message_id: message_id.clone(),
+            emoji: "😂".to_string(),
+            added: true,
+        });
+
+    let mut applied = Vec::new();
+    for _ in 0..50 {
+        applied = fixture
+            .manager
+            .get_reactions(&chat.id, &message_id)
+            .await
+            .unwrap();
+        if !applied.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Events are handled in order, so mallory's was seen and dropped
+    assert_eq!(applied.len(), 1);
+    assert_eq!(applied[0].emoji, "😂");
+    assert_eq!(applied[0].users, ["bob"]);
+}
+
+#[tokio::test]
+async fn test_reactions_are_kept_by_peer_id() {
+    let fixture = setup().await;
+    // Only named after bob, so it is not a chat with him
+    let named = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let direct = fixture
+        .manager
+        .create_direct_chat("bob-id", "Bob".into())
+        .await
+        .unwrap();
+    let in_named = send_text(&fixture, &named.id).await;
+    let in_direct = send_text(&fixture, &direct.id).await;
+
+    for message_id in [&in_named, &in_direct] {
+        fixture
+            .event_bus
+            .emit_network(NetworkEvent::ReactionReceived {
+                peer_id: "bob-id".to_string(),
+                message_id: message_id.clone(),
+                emoji: "👍".to_string(),
+                added: true,
+            });
+    }
+    let mut applied = Vec::new();
+    for _ in 0..50 {
+        applied = fixture
+            .manager
+            .get_reactions(&direct.id, &in_direct)
+            .await
+            .unwrap();
+        if !applied.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(applied[0].users, ["bob-id"]);
+    let ignored = fixture
+        .manager
+        .get_reactions(&named.id, &in_named)
+        .await
+        .unwrap();
+    assert!(ignored.is_empty());
+
+    // Our own reaction is kept under our peer id, and goes to bob's
+    let message = fixture
+        .manager
+        .add_reaction(&in_direct, "👍")
+        .await
+        .unwrap();
+    assert_eq!(message.reactions[0].users, ["bob-id", "alice-id"]);
+    let recipients = fixture
+        .manager
+        .reaction_recipients(&in_direct)
+        .await
+        .unwrap();
+    assert_eq!(recipients, ["bob-id"]);
+}
+
+#[tokio::test]
+async fn test_reaction_round_trip_between_peers() {
+    let alice_bus = EventBus::new();
+    let bob_bus = EventBus::new();
+    let alice_peer = Peer::new("alice".into(), "127.0.0.1:9001".into());
+    let bob_peer = Peer::new("bob".into(), "127.0.0.1:9002".into());
+    let (alice_id, bob_id) = (alice_peer.id.clone(), bob_peer.id.clone());
+
+    let mut alice = NetworkManager::new(alice_peer, alice_bus).unwrap();
+    let mut bob = NetworkManager::new(bob_peer, bob_bus.clone()).unwrap();
+    alice.start().unwrap();
+    bob.start().unwrap();
+
+    let mut link = alice.attach_connection(&bob_id).await;
+    let mut events = bob_bus.subscribe();
+
+    alice
+        .send_reaction(&bob_id, "msg-1", "👍", true)
+        .await
+        .unwrap();
+    // The first frame on a fresh connection is our presence announcement
+    let presence = link.recv().await.unwrap();
+    assert!(matches!(presence.payload, MessagePayload::Presence(_)));
+
+    let wire = link.recv().await.unwrap();
+    bob.handle_incoming_message(wire).await;
+
+    match events.recv().await.unwrap() {
+        AppEvent::Network(NetworkEvent::ReactionReceived {
+            peer_id,
+            message_id,
+            emoji,
+            added,
+        }) => {
+            assert_eq!(peer_id, alice_id);
+            assert_eq!(message_id, "msg-1");
+            assert_eq!(emoji, "👍");
+            assert!(added);
+        }
+        other => panic!("unexpected event: {:?}", other),
+    }
+
+    alice.detach_connection(&bob_id).await;
+    assert!(alice
+        .send_reaction(&bob_id, "msg-1", "👍", false)
+        .await
+        .is_err());
+}
+
+fn expired_message(id: &str) -> ChatMessage {
+    ChatMessage {
+        id: id.to_string(),
+        from: "bob".to_string(),
+        to: "alice".to_string(),
+        content: "secret".to_string(),
+        msg_type: ChatMessageType::Text,
+        timestamp: chrono::Utc::now().timestamp() as u64 - 60,
+        delivery_status: DeliveryStatus::Delivered,
+        reactions: Vec::new(),
+        expires_at: Some(chrono::Utc::now().timestamp() as u64 - 1),
+        sequence: 0,
+    }
+}
+
+#[tokio::test]
+async fn test_disappearing_timer_validation_and_chat_info() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+
+    for invalid in [0, 1, MAX_DISAPPEARING_TIMER + 1] {
+        let result = fixture
+            .manager
+            .set_disappearing_timer(&chat.id, Some(invalid))
+            .await;
+        assert!(matches!(result, Err(ChatError::InvalidInput(_))));
+    }
+    let missing = fixture
+        .manager
+        .set_disappearing_timer("missing", Some(60))
+        .await;
+    assert!(matches!(missing, Err(ChatError::ChatNotFound(_))));
+
+    fixture
+        .manager
+        .set_disappearing_timer(&chat.id, Some(3600))
+        .await
+        .unwrap();
+    let info = fixture.manager.get_chat_info(&chat.id).await.unwrap();
+    assert_eq!(info.disappearing_timer, Some(3600));
+
+    fixture
+        .manager
+        .set_disappearing_timer(&chat.id, None)
+        .await
+        .unwrap();
+    let info = fixture.manager.get_chat_info(&chat.id).await.unwrap();
+    assert_eq!(info.disappearing_timer, None);
+}
+
+#[tokio::test]
+async fn test_messages_inherit_disappearing_timer() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_direct_chat("bob", "Bob".into())
+        .await
+        .unwrap();
+    let before = send_text(&fixture, &chat.id).await;
+
+    fixture
+        .manager
+        .set_disappearing_timer(&chat.id, Some(60))
+        .await
+        .unwrap();
+    let after = send_text(&fixture, &chat.id).await;
+    // So do the ones that come in from the peer
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::MessageReceived {
+            peer_id: "bob".to_string(),
+            message: incoming("received", 1_000),
+        });
+
+    let mut messages = Vec::new();
+    for _ in 0..50 {
+        messages = fixture
+            .manager
+            .get_messages(&chat.id, None, None)
+            .await
+            .unwrap();
+        if messages.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let before = messages.iter().find(|m| m.id == before).unwrap();
+    let now = chrono::Utc::now().timestamp() as u64;
+
+    assert_eq!(before.expires_at, None);
+    for id in [after.as_str(), "received"] {
+        let message = messages.iter().find(|m| m.id == id).unwrap();
+        let expires_at = message.expires_at.unwrap();
+        assert!(expires_at > now && expires_at <= now + 60);
+    }
+}
+
+#[tokio::test]
+async fn test_expired_messages_are_purged_with_event() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let kept = send_text(&fixture, &chat.id).await;
+    let mut events = fixture.event_bus.subscribe();
+
+    {
+        let storage = fixture.storage.read().await;
+        storage
+            .save_message(&chat.id, &expired_message("gone"))
+            .await
+            .unwrap();
+    }
+
+    fixture.manager.purge_expired_messages().await.unwrap();
+
+    let messages = fixture
+        .manager
+        .get_messages(&chat.id, None, None)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, kept);
+
+    let found = fixture
+        .manager
+        .search_messages(MessageFilter {
+            chat_id: Some(chat.id.clone()),
+            content_search: Some("secret".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(found.is_empty());
+
+    let expired = loop {
+        if let AppEvent::Storage(StorageEvent::MessagesExpired {
+            chat_id,
+            message_ids,
+        }) = events.recv().await.unwrap()
+        {
+            break (chat_id, message_ids);
+        }
+    };
+    assert_eq!(expired, (chat.id.clone(), vec!["gone".to_string()]));
+}
+
+#[tokio::test]
+async fn test_background_task_expires_messages() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    {
+        let storage = fixture.storage.read().await;
+        storage
+            .save_message(&chat.id, &expired_message("gone"))
+            .await
+            .unwrap();
+    }
+
+    let mut remaining = 1;
+    for _ in 0..30 {
+        remaining = fixture
+            .manager
+            .get_messages(&chat.id, None, None)
+            .await
+            .unwrap()
+            .len();
+        if remaining == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(remaining, 0);
+}
+
+#[tokio::test]
+async fn test_remote_timer_change_uses_latest_setting() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_direct_chat("bob", "Bob".into())
+        .await
+        .unwrap();
+    let changed_at = fixture
+        .manager
+        .set_disappearing_timer(&chat.id, Some(60))
+        .await
+        .unwrap();
+
+    // Someone outside the chat cannot change it
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::ChatSettingsReceived {
+            peer_id: "mallory".to_string(),
+            chat_id: chat.id.clone(),
+            disappearing_timer: Some(5),
+            changed_at: changed_at + 20,
+        });
+    // A stale change from the peer is ignored
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::ChatSettingsReceived {
+            peer_id: "bob".to_string(),
+            chat_id: chat.id.clone(),
+            disappearing_timer: Some(300),
+            changed_at: changed_at - 10,
+        });
+    // A newer one wins
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::ChatSettingsReceived {
+            peer_id: "bob".to_string(),
+            chat_id: chat.id.clone(),
+            disappearing_timer: Some(86400),
+            changed_at: changed_at + 10,
+        });
+
+    let mut timer = None;
+    for _ in 0..50 {
+        timer = fixture.manager.get_disappearing_timer(&chat.id).await;
+        if timer == Some(86400) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(timer, Some(86400));
+
+    // A change from far in the future only counts as a little ahead
+    let now = chrono::Utc::now().timestamp() as u64;
+    for (disappearing_timer, changed_at) in [(Some(5), now + 365 * 86400), (None, now + 120)] {
+        fixture
+            .event_bus
+            .emit_network(NetworkEvent::ChatSettingsReceived {
+                peer_id: "bob".to_string(),
+                chat_id: chat.id.clone(),
+                disappearing_timer,
+                changed_at,
+            });
+    }
+    for _ in 0..50 {
+        timer = fixture.manager.get_disappearing_timer(&chat.id).await;
+        if timer.is_none() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(timer, None);
+
+    // Ours is older than that, so it is refused rather than sent out
+    let refused = fixture
+        .manager
+        .set_disappearing_timer(&chat.id, Some(60))
+        .await;
+    assert!(matches!(refused, Err(ChatError::InvalidInput(_))));
+    assert_eq!(fixture.manager.get_disappearing_timer(&chat.id).await, None);
+}
+
+fn incoming(id: &str, timestamp: u64) -> ChatMessage {
+    ChatMessage {
+        id: id.to_string(),
+        from: "bob".to_string(),
+        to: "alice".to_string(),
+        content: format!("message {}", id),
+        msg_type: ChatMessageType::Text,
+        timestamp,
+        delivery_status: DeliveryStatus::Delivered,
+        reactions: Vec::new(),
+        expires_at: None,
+        sequence: 0,
+    }
+}
+
+async fn receive(fixture: &ChatFixture, chat_id: &str, ids: &[&str]) {
+    let storage = fixture.storage.read().await;
+    for (i, id) in ids.iter().enumerate() {
+        storage
+            .save_message(chat_id, &incoming(id, 1_000 + i as u64))
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_late_messages_are_stored_in_sender_order() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let numbered = |id: &str, sequence: u64| ChatMessage {
+        sequence,
+        ..incoming(id, 1_000)
+    };
+    let from_carol = ChatMessage {
+        from: "carol".to_string(),
+        sequence: 1,
+        ..incoming("carol", 1_002)
+    };
+
+    let storage = fixture.storage.read().await;
+    for message in [
+        numbered("first", 41),
+        numbered("third", 43),
+        incoming("unnumbered", 1_001),
+        numbered("second", 42),
+        from_carol,
+    ] {
+        storage.save_message(&chat.id, &message).await.unwrap();
+    }
+
+    // Only messages from the same sender are put back in order
+    let ids: Vec<String> = storage
+        .get_messages(&chat.id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.id)
+        .collect();
+    assert_eq!(ids, ["first", "second", "third", "unnumbered", "carol"]);
+}
+
+#[tokio::test]
+async fn test_received_messages_are_stored_in_sender_order() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_direct_chat("bob", "Bob".into())
+        .await
+        .unwrap();
+    let numbered = |id: &str, sequence: u64| ChatMessage {
+        sequence,
+        ..incoming(id, 1_000)
+    };
+
+    // The second message overtook the first on the way
+    for message in [numbered("second", 2), numbered("first", 1)] {
+        fixture
+            .event_bus
+            .emit_network(NetworkEvent::MessageReceived {
+                peer_id: "bob".to_string(),
+                message,
+            });
+    }
+    // Someone without a chat yet gets one
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::MessageReceived {
+            peer_id: "carol".to_string(),
+            message: incoming("from carol", 1_001),
+        });
+
+    let mut chats = Vec::new();
+    for _ in 0..50 {
+        chats = fixture.manager.get_all_chats().await;
+        if chats.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(chats.len(), 2);
+
+    let ids: Vec<String> = fixture
+        .manager
+        .get_messages(&chat.id, None, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.id)
+        .collect();
+    assert_eq!(ids, ["first", "second"]);
+
+    let carol = chats.iter().find(|c| c.has_participant("carol")).unwrap();
+    assert_eq!(carol.message_count, 1);
+    let messages = fixture
+        .manager
+        .get_messages(&carol.id, None, None)
+        .await
+        .unwrap();
+    assert_eq!(messages[0].id, "from carol");
+}
+
+#[tokio::test]
+async fn test_draft_save_load_and_clear() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+
+    let draft = fixture
+        .manager
+        .save_draft(&chat.id, "half a thought")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(draft.content, "half a thought");
+
+    let updated = fixture
+        .manager
+        .save_draft(&chat.id, "a whole thought")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(updated.created_at, draft.created_at);
+    assert_eq!(
+        fixture.manager.get_draft(&chat.id).await.unwrap().content,
+        "a whole thought"
+    );
+
+    // Saving empty content clears the draft
+    assert!(fixture
+        .manager
+        .save_draft(&chat.id, "  ")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(fixture.manager.get_draft(&chat.id).await.is_none());
+
+    let missing = fixture.manager.save_draft("missing", "text").await;
+    assert!(matches!(missing, Err(ChatError::ChatNotFound(_))));
+}
+
+#[tokio::test]
+async fn test_drafts_survive_restart_and_clear_on_send() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    fixture
+        .manager
+        .save_draft(&chat.id, "unsent")
+        .await
+        .unwrap();
+
+    let fixture = restart(fixture).await;
+    let draft = fixture.manager.get_draft(&chat.id).await.unwrap();
+    assert_eq!(draft.content, "unsent");
+    assert_eq!(fixture.manager.get_all_drafts().await.len(), 1);
+
+    let chat = fixture
+        .manager
+        .create_chat("carol".into(), false)
+        .await
+        .unwrap();
+    fixture
+        .manager
+        .save_draft(&chat.id, "about to send")
+        .await
+        .unwrap();
+    send_text(&fixture, &chat.id).await;
+    assert!(fixture.manager.get_draft(&chat.id).await.is_none());
+}
+
+#[tokio::test]
+async fn test_read_marker_drives_unread_count() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    receive(&fixture, &chat.id, &["m1", "m2", "m3"]).await;
+    send_text(&fixture, &chat.id).await;
+
+    // Our own message does not count as unread
+    let info = fixture.manager.get_chat_info(&chat.id).await.unwrap();
+    assert_eq!(info.unread_count, 3);
+
+    let acknowledged = fixture
+        .manager
+        .mark_read(&chat.id, Some("m2"))
+        .await
+        .unwrap();
+    let ids: Vec<_> = acknowledged.iter().map(|m| m.id.as_str()).collect();
+    assert_eq!(ids, vec!["m1", "m2"]);
+    assert_eq!(
+        fixture
+            .manager
+            .get_chat_info(&chat.id)
+            .await
+            .unwrap()
+            .unread_count,
+        1
+    );
+
+    // The marker never moves backwards
+    assert!(fixture
+        .manager
+        .mark_read(&chat.id, Some("m1"))
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        fixture.manager.get_read_marker(&chat.id).await,
+        Some("m2".to_string())
+    );
+
+    let acknowledged = fixture.manager.mark_read(&chat.id, None).await.unwrap();
+    assert_eq!(acknowledged.len(), 1);
+    assert_eq!(acknowledged[0].id, "m3");
+    assert_eq!(
+        fixture
+            .manager
+            .get_chat_info(&chat.id)
+            .await
+            .unwrap()
+            .unread_count,
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_mark_all_read_and_receipts_toggle() {
+    let fixture = setup().await;
+    let first = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let second = fixture
+        .manager
+        .create_chat("carol".into(), false)
+        .await
+        .unwrap();
+    receive(&fixture, &first.id, &["a1", "a2"]).await;
+    receive(&fixture, &second.id, &["b1"]).await;
+
+    fixture.manager.set_read_receipts_enabled(false);
+    let acknowledged = fixture.manager.mark_all_read().await.unwrap();
+    assert!(acknowledged.is_empty());
+
+    for chat_id in [&first.id, &second.id] {
+        let info = fixture.manager.get_chat_info(chat_id).await.unwrap();
+        assert_eq!(info.unread_count, 0);
+    }
+
+    fixture.manager.set_read_receipts_enabled(true);
+    receive(&fixture, &second.id, &["b2"]).await;
+    let acknowledged = fixture.manager.mark_all_read().await.unwrap();
+    assert_eq!(acknowledged.len(), 1);
+    assert_eq!(acknowledged[0].id, "b2");
+}
+
+#[tokio::test]
+async fn test_read_marker_survives_restart() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    receive(&fixture, &chat.id, &["m1", "m2"]).await;
+    fixture
+        .manager
+        .mark_read(&chat.id, Some("m1"))
+        .await
+        .unwrap();
+
+    let fixture = restart(fixture).await;
+    let storage = fixture.storage.read().await;
+    assert_eq!(
+        storage.get_read_marker(&chat.id).await,
+        Some("m1".to_string())
+    );
+    assert_eq!(storage.count_unread(&chat.id, Some("alice")).await, 1);
+}
+
+#[tokio::test]
+async fn test_read_receipt_updates_delivery_status() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_direct_chat("bob", "Bob".into())
+        .await
+        .unwrap();
+    let first = send_text(&fixture, &chat.id).await;
+    let second = send_text(&fixture, &chat.id).await;
+    receive(&fixture, &chat.id, &["from bob"]).await;
+
+    // Nobody else can acknowledge ours, and bob cannot acknowledge his own
+    for (peer_id, message_id) in [("mallory", &first), ("bob", &"from bob".to_string())] {
+        fixture
+            .event_bus
+            .emit_network(NetworkEvent::ReceiptReceived {
+                peer_id: peer_id.to_string(),
+                message_id: message_id.clone(),
+                status: DeliveryStatus::Read,
+            });
+    }
+    fixture
+        .event_bus
+        .emit_network(NetworkEvent::ReceiptReceived {
+            peer_id: "bob".to_string(),
+            message_id: second.clone(),
+            status: DeliveryStatus::Read,
+        });
+
+    let status_of = |messages: &[ChatMessage], id: &str| {
+        messages
+            .iter()
+            .find(|m| m.id == id)
+            .unwrap()
+            .delivery_status
+            .clone()
+    };
+    let mut messages = Vec::new();
+    for _ in 0..50 {
+        messages = fixture
+            .manager
+            .get_messages(&chat.id, None, None)
+            .await
+            .unwrap();
+        if status_of(&messages, &second) == DeliveryStatus::Read {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(status_of(&messages, &second), DeliveryStatus::Read);
+    assert_eq!(status_of(&messages, &first), DeliveryStatus::Pending);
+    assert_eq!(status_of(&messages, "from bob"), DeliveryStatus::Delivered);
+}
+
+fn chat_ids(chats: &[shadowghost::chats::Chat]) -> Vec<String> {
+    chats.iter().map(|c| c.id.clone()).collect()
+}
+
+#[tokio::test]
+async fn test_chats_sorted_pinned_then_by_activity() {
+    let fixture = setup().await;
+    let bob = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let carol = fixture
+        .manager
+        .create_chat("carol".into(), false)
+        .await
+        .unwrap();
+    let dave = fixture
+        .manager
+        .create_chat("dave".into(), false)
+        .await
+        .unwrap();
+
+    send_text(&fixture, &bob.id).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    send_text(&fixture, &carol.id).await;
+
+    let chats = fixture.manager.get_all_chats().await;
+    assert_eq!(
+        chat_ids(&chats),
+        vec![carol.id.clone(), bob.id.clone(), dave.id.clone()]
+    );
+
+    fixture.manager.pin_chat(&dave.id).await.unwrap();
+    fixture.manager.pin_chat(&bob.id).await.unwrap();
+    let chats = fixture.manager.get_all_chats().await;
+    assert_eq!(
+        chat_ids(&chats),
+        vec![dave.id.clone(), bob.id.clone(), carol.id.clone()]
+    );
+
+    fixture
+        .manager
+        .reorder_pinned_chats(&[bob.id.clone()])
+        .await
+        .unwrap();
+    let chats = fixture.manager.get_all_chats().await;
+    assert_eq!(
+        chat_ids(&chats),
+        vec![bob.id.clone(), dave.id.clone(), carol.id.clone()]
+    );
+
+    let unpinned = fixture.manager.unpin_chat(&bob.id).await.unwrap();
+    assert!(!unpinned.is_pinned());
+    assert_eq!(
+        fixture
+            .manager
+            .get_chat(&dave.id)
+            .await
+            .unwrap()
+            .pinned_position,
+        Some(0)
+    );
+
+    let not_pinned = fixture
+        .manager
+        .reorder_pinned_chats(&[carol.id.clone()])
+        .await;
+    assert!(matches!(not_pinned, Err(ChatError::InvalidInput(_))));
+}
+
+#[tokio::test]
+async fn test_mute_with_expiry() {
+    let fixture = setup().await;
+    let chat = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    assert!(!fixture.manager.is_chat_muted(&chat.id).await.unwrap());
+
+    let muted = fixture.manager.mute_chat(&chat.id, Some(1)).await.unwrap();
+    assert!(muted.muted_until.is_some());
+    assert!(fixture.manager.is_chat_muted(&chat.id).await.unwrap());
+    assert!(
+        fixture
+            .manager
+            .get_chat_info(&chat.id)
+            .await
+            .unwrap()
+            .is_muted
+    );
+
+    // A temporary mute lapses on its own
+    let later = muted.muted_until.unwrap() + chrono::Duration::seconds(1);
+    assert!(!muted.is_muted_at(later));
+
+    let forever = fixture.manager.mute_chat(&chat.id, None).await.unwrap();
+    assert!(forever.is_muted_at(later + chrono::Duration::days(365)));
+
+    fixture.manager.unmute_chat(&chat.id).await.unwrap();
+    assert!(!fixture.manager.is_chat_muted(&chat.id).await.unwrap());
+
+    let invalid = fixture.manager.mute_chat(&chat.id, Some(0)).await;
+    assert!(matches!(invalid, Err(ChatError::InvalidInput(_))));
+}
+
+#[tokio::test]
+async fn test_archive_hides_chat_and_drops_pin() {
+    let fixture = setup().await;
+    let bob = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let carol = fixture
+        .manager
+        .create_chat("carol".into(), false)
+        .await
+        .unwrap();
+    fixture.manager.pin_chat(&bob.id).await.unwrap();
+
+    let archived = fixture.manager.archive_chat(&bob.id).await.unwrap();
+    assert!(archived.is_archived);
+    assert!(!archived.is_pinned());
+    assert_eq!(
+        chat_ids(&fixture.manager.get_active_chats().await),
+        vec![carol.id.clone()]
+    );
+    assert_eq!(
+        chat_ids(&fixture.manager.get_archived_chats().await),
+        vec![bob.id.clone()]
+    );
+    assert_eq!(fixture.manager.get_all_chats().await.len(), 2);
+
+    // Pinning an archived chat brings it back
+    let pinned = fixture.manager.pin_chat(&bob.id).await.unwrap();
+    assert!(!pinned.is_archived);
+    assert!(fixture.manager.get_archived_chats().await.is_empty());
+
+    fixture.manager.archive_chat(&carol.id).await.unwrap();
+    fixture.manager.unarchive_chat(&carol.id).await.unwrap();
+    assert_eq!(fixture.manager.get_active_chats().await.len(), 2);
+}
+
+#[tokio::test]
+async fn test_chat_organization_survives_restart() {
+    let fixture = setup().await;
+    let bob = fixture
+        .manager
+        .create_chat("bob".into(), false)
+        .await
+        .unwrap();
+    let carol = fixture
+        .manager
+        .create_chat("carol".into(), false)
+        .await
+        .unwrap();
+    let dave = fixture
+        .manager
+        .create_chat("dave".into(), false)
+        .await
+        .unwrap();
+    send_text(&fixture, &bob.id).await;
+    fixture.manager.pin_chat(&carol.id).await.unwrap();
+    fixture.manager.mute_chat(&bob.id, None).await.unwrap();
+    fixture.manager.archive_chat(&dave.id).await.unwrap();
+
+    let fixture = restart(fixture).await;
+    assert_eq!(
+        chat_ids(&fixture.manager.get_all_chats().await),
+        vec![carol.id.clone(), bob.id.clone(), dave.id.clone()]
+    );
+
+    let bob = fixture.manager.get_chat(&bob.id).await.unwrap();
+    assert!(bob.is_muted);
+    assert_eq!(bob.message_count, 1);
+    assert!(
+        fixture
+            .manager
+            .get_chat(&dave.id)
+            .await
+            .unwrap()
+            .is_archived
+    );
+    assert_eq!(fixture.manager.get_statistics().await.total_chats, 3);
+}
@@ -12,7 +12,7 @@ pub async fn wait_for_message_received(
     let result = timeout(timeout_duration, async {
         loop {
             match receiver.recv().await {
-                Ok(AppEvent::Network(NetworkEvent::MessageReceived { message })) => {
+                Ok(AppEvent::Network(NetworkEvent::MessageReceived { message, .. })) => {
                     if message.content == expected_content {
                         return Ok(message);
                     }
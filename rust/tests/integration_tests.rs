@@ -270,6 +270,9 @@ async fn test_storage_optimization() {
         msg_type: ChatMessageType::Text,
         timestamp: chrono::Utc::now().timestamp() as u64,
         delivery_status: DeliveryStatus::Delivered,
+        reactions: Vec::new(),
+        expires_at: None,
+        sequence: 0,
     };
 
     storage.save_message("test_chat", &message).await.unwrap();
@@ -0,0 +1,5296 @@
+use shadowghost::contacts::{generate_sg_link, parse_sg_link, ContactManager};
+use shadowghost::core::types::{Config, NetworkConfig};
+use shadowghost::core::MetricsCollector;
+use shadowghost::core::Peer;
+use shadowghost::crypto::CryptoManager;
+use shadowghost::events::types::EventReceiver;
+use shadowghost::events::{AppEvent, EventBus, NetworkEvent};
+use shadowghost::network::announcement::{
+    sign_announcement, verify_announcement, MAX_ANNOUNCEMENT_AGE,
+};
+use shadowghost::network::auth::{self, KeyShare, Transcript};
+use shadowghost::network::hole_punch::BoxFuture;
+use shadowghost::network::mdns::MdnsDiscovery;
+use shadowghost::network::protocol::FilePayload;
+use shadowghost::network::{
+    bootstrap_addresses, parse_records, select_relay, AddressMirror, AnnouncementGuard,
+    AnnouncementMessage, AnnouncementRejection, AuthPayload, BandwidthConfig, BandwidthLimiter,
+    Candidate, CandidateKind, Connection, ConnectionAcceptor, ConnectionSupervisor, Contact,
+    ContactStatus, DatagramSocket, DeliveryRoute, DhtConfig, DhtError, DhtNode, DhtPacket,
+    DirectChannel, DiscoveredPeer, DiscoveryConfig, Endpoint, HolePuncher, Keepalive,
+    KeepaliveConfig, Listener, Mailbox, MailboxConfig, MailboxError, MailboxPayload,
+    MemoryTransport, MessagePayload, MirrorObservation, NatEmulator, NatMapping, NatType,
+    NetworkDiscovery, NetworkManager, NodeId, NodeInfo, ObfuscatedLink, ObfuscationConfig,
+    PeerData, PeerRecord, PresenceTracker, ProtocolMessage, PunchConfig, PunchError, PunchPacket,
+    RateLimitConfig, RateLimitError, RateLimiter, ReflexiveConfig, ReflexiveDiscovery, RelayClient,
+    RelayConfig, RelayError, RelayPacket, RelayPolicy, RelayServer, RelayedLink, RendezvousServer,
+    RoutingTable, SealedEnvelope, Sequencer, SequencingConfig, Socks5Dialer, SupervisorConfig,
+    TcpTransport, TlsMasking, TokenBucket, TorConfig, TorController, TorError, TrafficDirection,
+    TrafficObfuscator, Transport, TransportError, TrustLevel, DEFAULT_DISCOVERY_PORT,
+    MAX_RECORD_SIZE,
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, RwLock};
+
+fn manager(name: &str) -> (NetworkManager, EventBus) {
+    let event_bus = EventBus::new();
+    let mut peer = Peer::new(name.to_string(), "127.0.0.1:0".to_string());
+    peer.id = format!("{}-id", name);
+    let mut manager = NetworkManager::new(peer, event_bus.clone()).unwrap();
+    let (crypto, key) = identity();
+    manager.set_identity(crypto);
+    manager.set_public_key(key);
+    manager.start().unwrap();
+    (manager, event_bus)
+}
+
+/// Lets two managers know each other's identity keys, so they can connect.
+async fn introduce(a: &NetworkManager, b: &NetworkManager) {
+    let (a_peer, b_peer) = (a.get_peer().await, b.get_peer().await);
+    a.set_peer_key(&b_peer.id, b_peer.public_key).await;
+    b.set_peer_key(&a_peer.id, a_peer.public_key).await;
+}
+
+/// Frames a raw connection the way a manager with obfuscation off does.
+fn plain_frames(connection: &mut Connection) {
+    connection.set_obfuscator(Arc::new(TrafficObfuscator::new(
+        ObfuscationConfig::disabled(),
+    )));
+}
+
+/// Opens a raw connection to bob as `id` the way a dialing manager would,
+/// proving who it is with `crypto`, and encrypts it with the agreed key.
+async fn dial_handshake(connection: &mut Connection, crypto: &CryptoManager, id: &str) {
+    plain_frames(connection);
+    let share = KeyShare::new();
+    let hello = ProtocolMessage::create_auth(
+        id.to_string(),
+        "bob-id".to_string(),
+        AuthPayload::Hello {
+            nonce: auth::new_nonce(),
+            share: share.public(),
+        },
+    );
+    connection.send(&hello).await.unwrap();
+    let (nonce, acceptor_share) = match connection.recv().await.unwrap().unwrap().payload {
+        MessagePayload::Auth(AuthPayload::Challenge { nonce, share, .. }) => (nonce, share),
+        _ => panic!("expected a challenge"),
+    };
+    let dialer_share = share.public();
+    let transcript = Transcript {
+        dialer_id: id,
+        acceptor_id: "bob-id",
+        dialer_share: &dialer_share,
+        acceptor_share: &acceptor_share,
+    };
+    let signature = crypto
+        .sign_data(&auth::dial_context(&nonce, &transcript))
+        .unwrap();
+    let proof = ProtocolMessage::create_auth(
+        id.to_string(),
+        "bob-id".to_string(),
+        AuthPayload::Proof { signature },
+    );
+    connection.send(&proof).await.unwrap();
+    connection.set_session(share.session(&transcript, true).unwrap());
+}
+
+/// The first handshake message a dialer sends to bob as `id`.
+fn hello(id: &str) -> ProtocolMessage {
+    ProtocolMessage::create_auth(
+        id.to_string(),
+        "bob-id".to_string(),
+        AuthPayload::Hello {
+            nonce: auth::new_nonce(),
+            share: KeyShare::new().public(),
+        },
+    )
+}
+
+/// Answers a dialing manager over a raw connection as `id`, proving who it
+/// is with `crypto`, and encrypts it with the agreed key.
+async fn accept_handshake(connection: &mut Connection, crypto: &CryptoManager, id: &str) {
+    plain_frames(connection);
+    let hello = connection.recv().await.unwrap().unwrap();
+    let (nonce, dialer_share) = match hello.payload {
+        MessagePayload::Auth(AuthPayload::Hello { nonce, share }) => (nonce, share),
+        _ => panic!("expected a hello"),
+    };
+    let share = KeyShare::new();
+    let acceptor_share = share.public();
+    let transcript = Transcript {
+        dialer_id: &hello.sender_id,
+        acceptor_id: id,
+        dialer_share: &dialer_share,
+        acceptor_share: &acceptor_share,
+    };
+    let signature = crypto
+        .sign_data(&auth::accept_context(&nonce, &transcript))
+        .unwrap();
+    let challenge = ProtocolMessage::create_auth(
+        id.to_string(),
+        hello.sender_id.clone(),
+        AuthPayload::Challenge {
+            nonce: auth::new_nonce(),
+            share: acceptor_share.clone(),
+            signature,
+        },
+    );
+    connection.send(&challenge).await.unwrap();
+    assert!(matches!(
+        connection.recv().await.unwrap().unwrap().payload,
+        MessagePayload::Auth(AuthPayload::Proof { .. })
+    ));
+    connection.set_session(share.session(&transcript, false).unwrap());
+}
+
+async fn next_network_event(events: &mut EventReceiver) -> NetworkEvent {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .expect("timed out waiting for event")
+            .unwrap();
+        if let AppEvent::Network(event) = event {
+            return event;
+        }
+    }
+}
+
+fn presence_of(message: &ProtocolMessage) -> Option<ContactStatus> {
+    match &message.payload {
+        MessagePayload::Presence(presence) => Some(presence.status.clone()),
+        _ => None,
+    }
+}
+
+async fn drain(link: &mut mpsc::UnboundedReceiver<ProtocolMessage>) -> Vec<ProtocolMessage> {
+    let mut messages = Vec::new();
+    while let Ok(message) = link.try_recv() {
+        messages.push(message);
+    }
+    messages
+}
+
+#[test]
+fn test_auto_away_after_idle_and_back_on_activity() {
+    let mut tracker = PresenceTracker::new(Duration::from_millis(20), Duration::from_secs(3));
+    assert_eq!(tracker.check_idle(), None);
+
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(tracker.check_idle(), Some(ContactStatus::Away));
+    assert_eq!(tracker.effective_status(), ContactStatus::Away);
+    assert_eq!(tracker.check_idle(), None);
+
+    assert_eq!(tracker.record_activity(), Some(ContactStatus::Online));
+    assert_eq!(tracker.effective_status(), ContactStatus::Online);
+    assert_eq!(tracker.record_activity(), None);
+}
+
+#[test]
+fn test_auto_away_leaves_busy_alone() {
+    let mut tracker = PresenceTracker::new(Duration::from_millis(10), Duration::from_secs(3));
+    tracker.set_status(ContactStatus::Busy);
+
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(tracker.check_idle(), None);
+    assert_eq!(tracker.effective_status(), ContactStatus::Busy);
+}
+
+#[test]
+fn test_typing_indicator_is_throttled() {
+    let mut tracker = PresenceTracker::new(Duration::from_secs(300), Duration::from_millis(30));
+
+    assert!(tracker.allow_typing("bob", "chat", true));
+    assert!(!tracker.allow_typing("bob", "chat", true));
+    assert!(tracker.allow_typing("bob", "other-chat", true));
+
+    assert!(tracker.allow_typing("bob", "chat", false));
+    assert!(!tracker.allow_typing("bob", "chat", false));
+
+    assert!(tracker.allow_typing("bob", "chat", true));
+    std::thread::sleep(Duration::from_millis(40));
+    assert!(tracker.allow_typing("bob", "chat", true));
+}
+
+#[tokio::test]
+async fn test_presence_announced_on_connect_and_broadcast_on_change() {
+    let (alice, _) = manager("alice");
+    let mut to_bob = alice.attach_connection("bob-id").await;
+    let mut to_carol = alice.attach_connection("carol-id").await;
+
+    let hello = to_bob.recv().await.unwrap();
+    assert_eq!(presence_of(&hello), Some(ContactStatus::Online));
+    assert_eq!(hello.recipient_id, "bob-id");
+    drain(&mut to_carol).await;
+
+    assert_eq!(alice.set_presence_status(ContactStatus::Busy).await, 2);
+    assert_eq!(
+        presence_of(&to_bob.recv().await.unwrap()),
+        Some(ContactStatus::Busy)
+    );
+    assert_eq!(
+        presence_of(&to_carol.recv().await.unwrap()),
+        Some(ContactStatus::Busy)
+    );
+
+    // Setting the same status again is not rebroadcast
+    assert_eq!(alice.set_presence_status(ContactStatus::Busy).await, 0);
+    assert!(drain(&mut to_bob).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_idle_check_broadcasts_away() {
+    let (alice, alice_bus) = manager("alice");
+    let mut events = alice_bus.subscribe();
+    let mut to_bob = alice.attach_connection("bob-id").await;
+    drain(&mut to_bob).await;
+
+    alice.set_auto_away_after(Duration::from_millis(10)).await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    assert_eq!(alice.check_idle().await, Some(ContactStatus::Away));
+    assert_eq!(
+        presence_of(&to_bob.recv().await.unwrap()),
+        Some(ContactStatus::Away)
+    );
+    match next_network_event(&mut events).await {
+        NetworkEvent::LocalPresenceChanged { status } => assert_eq!(status, ContactStatus::Away),
+        other => panic!("unexpected event: {:?}", other),
+    }
+
+    alice.record_user_activity().await;
+    assert_eq!(
+        presence_of(&to_bob.recv().await.unwrap()),
+        Some(ContactStatus::Online)
+    );
+}
+
+#[tokio::test]
+async fn test_remote_presence_updates_contact_book() {
+    let dir = TempDir::new().unwrap();
+    let (alice, _) = manager("alice");
+    let (bob, bob_bus) = manager("bob");
+    let bob = Arc::new(bob);
+    let alice_id = alice.get_peer().await.id;
+
+    let contacts = Arc::new(RwLock::new(ContactManager::new(dir.path()).unwrap()));
+    contacts
+        .write()
+        .await
+        .add_contact(Contact {
+            id: alice_id.clone(),
+            name: "alice".to_string(),
+            addresses: vec!["tcp://127.0.0.1:0".parse().unwrap()],
+            public_key: Vec::new(),
+            status: ContactStatus::Offline,
+            trust_level: TrustLevel::Trusted,
+            last_seen: None,
+        })
+        .unwrap();
+    let listener =
+        ContactManager::spawn_presence_listener(contacts.clone(), bob.clone(), bob_bus.clone());
+    let mut events = bob_bus.subscribe();
+
+    let mut link = alice.attach_connection("bob-id").await;
+    bob.handle_incoming_message(link.recv().await.unwrap())
+        .await;
+
+    loop {
+        if let NetworkEvent::ContactStatusChanged { contact } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(contact.id, alice_id);
+            assert_eq!(contact.status, ContactStatus::Online);
+            break;
+        }
+    }
+    let stored = contacts.read().await.get_contact(&alice_id).unwrap();
+    assert_eq!(stored.status, ContactStatus::Online);
+    assert!(stored.last_seen.is_some());
+
+    // A dropped connection takes the contact offline
+    bob.attach_connection(&alice_id).await;
+    bob.detach_connection(&alice_id).await;
+    loop {
+        if let NetworkEvent::ContactStatusChanged { contact } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(contact.status, ContactStatus::Offline);
+            break;
+        }
+    }
+
+    listener.abort();
+}
+
+#[tokio::test]
+async fn test_typing_indicator_delivered_as_event_only() {
+    let (alice, _) = manager("alice");
+    let (bob, bob_bus) = manager("bob");
+    let mut events = bob_bus.subscribe();
+    let mut link = alice.attach_connection("bob-id").await;
+    drain(&mut link).await;
+
+    assert!(alice.send_typing("bob-id", "chat-1", true).await.unwrap());
+    assert!(!alice.send_typing("bob-id", "chat-1", true).await.unwrap());
+    assert!(alice.send_typing("bob-id", "chat-1", false).await.unwrap());
+
+    let typing = link.recv().await.unwrap();
+    bob.handle_incoming_message(typing).await;
+    match next_network_event(&mut events).await {
+        NetworkEvent::TypingChanged {
+            chat_id, is_typing, ..
+        } => {
+            assert_eq!(chat_id, "chat-1");
+            assert!(is_typing);
+        }
+        other => panic!("unexpected event: {:?}", other),
+    }
+
+    let stopped = link.recv().await.unwrap();
+    assert!(matches!(
+        stopped.payload,
+        MessagePayload::Typing(ref t) if !t.is_typing
+    ));
+    assert!(drain(&mut link).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_stop_announces_offline() {
+    let (mut alice, _) = manager("alice");
+    let mut link = alice.attach_connection("bob-id").await;
+    drain(&mut link).await;
+
+    alice.stop().unwrap();
+    assert_eq!(
+        presence_of(&link.recv().await.unwrap()),
+        Some(ContactStatus::Offline)
+    );
+}
+
+fn free_udp_port() -> u16 {
+    std::net::UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn discovery(name: &str, config: DiscoveryConfig) -> NetworkDiscovery {
+    NetworkDiscovery::new(
+        8000,
+        format!("{}-id", name),
+        name.to_string(),
+        Arc::new(RwLock::new(CryptoManager::new().unwrap())),
+    )
+    .with_config(config)
+}
+
+#[test]
+fn test_subnet_broadcast_address() {
+    let broadcast = |ip: [u8; 4], mask: [u8; 4]| {
+        NetworkDiscovery::subnet_broadcast(Ipv4Addr::from(ip), Ipv4Addr::from(mask))
+    };
+
+    assert_eq!(
+        broadcast([192, 168, 1, 10], [255, 255, 255, 0]),
+        Some(Ipv4Addr::new(192, 168, 1, 255))
+    );
+    assert_eq!(
+        broadcast([10, 1, 2, 3], [255, 0, 0, 0]),
+        Some(Ipv4Addr::new(10, 255, 255, 255))
+    );
+    assert_eq!(
+        broadcast([172, 16, 5, 4], [255, 255, 240, 0]),
+        Some(Ipv4Addr::new(172, 16, 15, 255))
+    );
+    assert_eq!(broadcast([10, 0, 0, 1], [255, 255, 255, 254]), None);
+    assert_eq!(broadcast([10, 0, 0, 1], [255, 255, 255, 255]), None);
+}
+
+#[test]
+fn test_discovery_settings_from_network_config() {
+    let config: NetworkConfig =
+        toml::from_str("port = 8123\nmax_peers = 10\nenable_discovery = true\n").unwrap();
+    assert_eq!(config.discovery_port, DEFAULT_DISCOVERY_PORT);
+    assert!(config.enable_ipv6_discovery);
+
+    let config = NetworkConfig {
+        discovery_port: 40000,
+        discovery_interval_secs: 5,
+        enable_ipv6_discovery: false,
+        ..config
+    };
+    let discovery = config.discovery_config();
+    assert_eq!(discovery.port, 40000);
+    assert_eq!(discovery.announce_interval, Duration::from_secs(5));
+    assert_eq!(discovery.peer_timeout, Duration::from_secs(15));
+    assert!(!discovery.enable_ipv6);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_instances_share_discovery_port_and_find_each_other() {
+    let config = DiscoveryConfig {
+        port: free_udp_port(),
+        announce_interval: Duration::from_millis(100),
+        enable_mdns: false,
+        ..DiscoveryConfig::default()
+    };
+    let mut alice = discovery("alice", config.clone());
+    let mut bob = discovery("bob", config);
+
+    alice.start_discovery().await.unwrap();
+    bob.start_discovery().await.unwrap();
+
+    let mut found = false;
+    for _ in 0..50 {
+        if alice.find_peer_by_id("bob-id").await.is_some()
+            && bob.find_peer_by_id("alice-id").await.is_some()
+        {
+            found = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(found, "instances did not discover each other");
+
+    // Own announcements are not reported as peers
+    assert!(alice.find_peer_by_id("alice-id").await.is_none());
+    assert_eq!(bob.find_peer_by_name("alice").await.unwrap().port, 8000);
+
+    alice.stop_discovery().await;
+    bob.stop_discovery().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_mdns_discovery_merges_into_peer_map() {
+    // Different UDP discovery ports, so only mDNS can connect the two
+    let config = |port| DiscoveryConfig {
+        port,
+        announce_interval: Duration::from_secs(1),
+        peer_timeout: Duration::from_secs(3),
+        enable_mdns: true,
+        ..DiscoveryConfig::default()
+    };
+    let mut alice = discovery("alice", config(free_udp_port()));
+    let mut bob = discovery("bob", config(free_udp_port()));
+
+    alice.start_discovery().await.unwrap();
+    bob.start_discovery().await.unwrap();
+
+    let mut found = None;
+    for _ in 0..100 {
+        found = alice.find_peer_by_id("bob-id").await;
+        if found.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let bob_peer = found.expect("mDNS peer not discovered");
+    assert_eq!(bob_peer.name, "bob");
+    assert_eq!(bob_peer.port, 8000);
+    assert_eq!(bob_peer.protocol_version, 1);
+    assert!(bob_peer.capabilities.contains(&"chat".to_string()));
+
+    // Re-signed records keep the peer around past the peer timeout
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert!(alice.find_peer_by_id("bob-id").await.is_some());
+
+    // Stopping withdraws the service, which removes the peer
+    bob.stop_discovery().await;
+    let mut removed = false;
+    for _ in 0..50 {
+        if alice.find_peer_by_id("bob-id").await.is_none() {
+            removed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(removed, "mDNS goodbye not processed");
+
+    alice.stop_discovery().await;
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn signed_announcement(crypto: &CryptoManager, peer_id: &str, nonce: u64) -> AnnouncementMessage {
+    let mut announcement = AnnouncementMessage {
+        peer_id: peer_id.to_string(),
+        peer_name: peer_id.trim_end_matches("-id").to_string(),
+        port: 8000,
+        public_key: Vec::new(),
+        protocol_version: 1,
+        capabilities: vec!["chat".to_string()],
+        timestamp: now_secs(),
+        nonce,
+        signature: Vec::new(),
+    };
+    sign_announcement(crypto, &mut announcement).unwrap();
+    announcement
+}
+
+#[test]
+fn test_mdns_record_is_checked_like_an_announcement() {
+    let crypto = CryptoManager::new().unwrap();
+    let record = signed_announcement(&crypto, "bob-id", 7);
+    let service = MdnsDiscovery::service_info(&record).unwrap();
+    let resolved = MdnsDiscovery::record_from_service(&service).unwrap();
+    assert_eq!(resolved.timestamp, record.timestamp);
+    assert_eq!(resolved.nonce, 7);
+
+    let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+    let mut guard = AnnouncementGuard::new(10);
+    assert!(guard.check(&resolved, from, now_secs()).is_ok());
+    assert_eq!(
+        guard.check(&resolved, from, now_secs()),
+        Err(AnnouncementRejection::Replayed)
+    );
+
+    // A captured record stops working once it is old
+    let mut later = AnnouncementGuard::new(10);
+    assert_eq!(
+        later.check(&resolved, from, now_secs() + MAX_ANNOUNCEMENT_AGE + 1),
+        Err(AnnouncementRejection::Stale)
+    );
+
+    // The timestamp and nonce are covered by the signature
+    let rewound = AnnouncementMessage {
+        timestamp: resolved.timestamp - 1,
+        nonce: 8,
+        ..resolved
+    };
+    assert_eq!(
+        guard.check(&rewound, from, now_secs()),
+        Err(AnnouncementRejection::BadSignature)
+    );
+}
+
+#[test]
+fn test_announcement_signature_covers_all_fields() {
+    let crypto = CryptoManager::new().unwrap();
+    let announcement = signed_announcement(&crypto, "bob-id", 1);
+    assert_eq!(announcement.public_key.len(), 32);
+    assert!(verify_announcement(&announcement));
+
+    let mut tampered = announcement.clone();
+    tampered.port = 9000;
+    assert!(!verify_announcement(&tampered));
+
+    // Swapping in another key without its private half breaks the signature
+    let other = CryptoManager::new().unwrap();
+    let mut impersonation = announcement.clone();
+    impersonation.public_key = other.get_public_key().key_data;
+    assert!(!verify_announcement(&impersonation));
+    assert!(!CryptoManager::verify_with_key(
+        &other.get_public_key().key_data,
+        b"data",
+        &crypto.sign_data(b"data").unwrap()
+    ));
+}
+
+#[test]
+fn test_announcement_guard_rejects_stale_replayed_and_flooding() {
+    let crypto = CryptoManager::new().unwrap();
+    let source: IpAddr = "192.0.2.10".parse().unwrap();
+    let mut guard = AnnouncementGuard::new(3);
+
+    let announcement = signed_announcement(&crypto, "bob-id", 1);
+    assert_eq!(guard.check(&announcement, source, now_secs()), Ok(()));
+    assert_eq!(
+        guard.check(&announcement, source, now_secs()),
+        Err(AnnouncementRejection::Replayed)
+    );
+
+    let mut stale = signed_announcement(&crypto, "bob-id", 2);
+    stale.timestamp -= 600;
+    sign_announcement(&crypto, &mut stale).unwrap();
+    assert_eq!(
+        guard.check(&stale, source, now_secs()),
+        Err(AnnouncementRejection::Stale)
+    );
+
+    let mut unsigned = signed_announcement(&crypto, "bob-id", 3);
+    unsigned.signature.clear();
+    assert_eq!(
+        guard.check(&unsigned, source, now_secs()),
+        Err(AnnouncementRejection::BadSignature)
+    );
+
+    let flood = signed_announcement(&crypto, "bob-id", 4);
+    assert_eq!(guard.check(&flood, source, now_secs()), Ok(()));
+    let flood = signed_announcement(&crypto, "bob-id", 5);
+    assert_eq!(
+        guard.check(&flood, source, now_secs()),
+        Err(AnnouncementRejection::RateLimited)
+    );
+
+    // Other sources are not affected
+    let elsewhere: IpAddr = "192.0.2.11".parse().unwrap();
+    assert_eq!(guard.check(&flood, elsewhere, now_secs()), Ok(()));
+}
+
+async fn send_datagram(port: u16, announcement: &AnnouncementMessage) {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket
+        .send_to(
+            &serde_json::to_vec(announcement).unwrap(),
+            ("127.0.0.1", port),
+        )
+        .await
+        .unwrap();
+}
+
+async fn wait_for_peer(discovery: &NetworkDiscovery, peer_id: &str) -> bool {
+    for _ in 0..50 {
+        if discovery.find_peer_by_id(peer_id).await.is_some() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    false
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spoofed_contact_announcement_raises_security_event() {
+    let port = free_udp_port();
+    let event_bus = EventBus::new();
+    let mut events = event_bus.subscribe();
+    let mut alice = discovery(
+        "alice",
+        DiscoveryConfig {
+            port,
+            announce_interval: Duration::from_secs(60),
+            enable_mdns: false,
+            max_peers: 2,
+            ..DiscoveryConfig::default()
+        },
+    )
+    .with_event_bus(event_bus);
+    alice.start_discovery().await.unwrap();
+
+    let bob = CryptoManager::new().unwrap();
+    let mallory = CryptoManager::new().unwrap();
+    alice
+        .set_known_key("bob-id", bob.get_public_key().key_data)
+        .await;
+
+    send_datagram(port, &signed_announcement(&mallory, "bob-id", 1)).await;
+    match next_network_event(&mut events).await {
+        NetworkEvent::PeerKeyMismatch {
+            peer_id,
+            known_key,
+            announced_key,
+            ..
+        } => {
+            assert_eq!(peer_id, "bob-id");
+            assert_eq!(known_key, bob.get_public_key().key_data);
+            assert_eq!(announced_key, mallory.get_public_key().key_data);
+        }
+        other => panic!("unexpected event: {:?}", other),
+    }
+    assert!(alice.find_peer_by_id("bob-id").await.is_none());
+
+    send_datagram(port, &signed_announcement(&bob, "bob-id", 2)).await;
+    assert!(wait_for_peer(&alice, "bob-id").await);
+
+    alice.stop_discovery().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_discovery_pins_keys_and_caps_peer_table() {
+    let port = free_udp_port();
+    let mut alice = discovery(
+        "alice",
+        DiscoveryConfig {
+            port,
+            announce_interval: Duration::from_secs(60),
+            enable_mdns: false,
+            max_peers: 2,
+            ..DiscoveryConfig::default()
+        },
+    );
+    alice.start_discovery().await.unwrap();
+
+    let carol = CryptoManager::new().unwrap();
+    send_datagram(port, &signed_announcement(&carol, "carol-id", 1)).await;
+    assert!(wait_for_peer(&alice, "carol-id").await);
+
+    // A different key for an already discovered id does not take it over
+    let mallory = CryptoManager::new().unwrap();
+    let mut hijack = signed_announcement(&mallory, "carol-id", 2);
+    hijack.port = 6666;
+    sign_announcement(&mallory, &mut hijack).unwrap();
+    send_datagram(port, &hijack).await;
+
+    // Unsigned announcements are ignored outright
+    let mut unsigned = signed_announcement(&carol, "ghost-id", 3);
+    unsigned.signature.clear();
+    send_datagram(port, &unsigned).await;
+
+    for (i, id) in ["dave-id", "erin-id"].iter().enumerate() {
+        let key = CryptoManager::new().unwrap();
+        send_datagram(port, &signed_announcement(&key, id, 10 + i as u64)).await;
+    }
+    assert!(wait_for_peer(&alice, "dave-id").await);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(alice.get_peer_count().await, 2);
+    assert!(alice.find_peer_by_id("erin-id").await.is_none());
+    assert!(alice.find_peer_by_id("ghost-id").await.is_none());
+    assert_eq!(alice.find_peer_by_id("carol-id").await.unwrap().port, 8000);
+
+    alice.stop_discovery().await;
+}
+
+async fn next_peer_event(events: &mut EventReceiver, within: Duration) -> NetworkEvent {
+    tokio::time::timeout(within, async {
+        loop {
+            if let AppEvent::Network(
+                event @ (NetworkEvent::PeerDiscovered { .. }
+                | NetworkEvent::PeerUpdated { .. }
+                | NetworkEvent::PeerLost { .. }),
+            ) = events.recv().await.unwrap()
+            {
+                return event;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for peer event")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_discovery_reports_peer_lifecycle_and_expires_silent_peers() {
+    let port = free_udp_port();
+    let event_bus = EventBus::new();
+    let mut events = event_bus.subscribe();
+    let mut alice = discovery(
+        "alice",
+        DiscoveryConfig {
+            port,
+            announce_interval: Duration::from_secs(60),
+            enable_mdns: false,
+            peer_timeout: Duration::from_secs(2),
+            ..DiscoveryConfig::default()
+        },
+    )
+    .with_event_bus(event_bus);
+    assert!(alice
+        .get_discovery_statistics()
+        .await
+        .last_discovery
+        .is_none());
+    alice.start_discovery().await.unwrap();
+
+    let carol = CryptoManager::new().unwrap();
+    send_datagram(port, &signed_announcement(&carol, "carol-id", 1)).await;
+    match next_peer_event(&mut events, Duration::from_secs(1)).await {
+        NetworkEvent::PeerDiscovered { peer } => {
+            assert_eq!(peer.id, "carol-id");
+            assert_eq!(peer.address, IpAddr::from(Ipv4Addr::LOCALHOST));
+        }
+        other => panic!("unexpected event: {:?}", other),
+    }
+
+    // A plain refresh is silent; a changed port is an update
+    send_datagram(port, &signed_announcement(&carol, "carol-id", 2)).await;
+    let mut moved = signed_announcement(&carol, "carol-id", 3);
+    moved.port = 9000;
+    sign_announcement(&carol, &mut moved).unwrap();
+    send_datagram(port, &moved).await;
+    match next_peer_event(&mut events, Duration::from_secs(1)).await {
+        NetworkEvent::PeerUpdated { peer } => assert_eq!(peer.port, 9000),
+        other => panic!("unexpected event: {:?}", other),
+    }
+
+    // Nothing more is heard from carol, so the expiry task drops her
+    match next_peer_event(&mut events, Duration::from_secs(5)).await {
+        NetworkEvent::PeerLost { peer_id } => assert_eq!(peer_id, "carol-id"),
+        other => panic!("unexpected event: {:?}", other),
+    }
+    assert!(alice.find_peer_by_id("carol-id").await.is_none());
+
+    let statistics = alice.get_discovery_statistics().await;
+    assert!(statistics.discovery_uptime >= 1);
+    assert!(statistics.last_discovery.is_some());
+
+    alice.stop_discovery().await;
+    assert_eq!(alice.get_discovery_statistics().await.discovery_uptime, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_discovered_peers_mark_contacts_online_and_offline() {
+    let dir = TempDir::new().unwrap();
+    let port = free_udp_port();
+    let event_bus = EventBus::new();
+    let mut alice = discovery(
+        "alice",
+        DiscoveryConfig {
+            port,
+            announce_interval: Duration::from_secs(60),
+            enable_mdns: false,
+            ..DiscoveryConfig::default()
+        },
+    )
+    .with_event_bus(event_bus.clone());
+
+    let contacts = Arc::new(RwLock::new(ContactManager::new(dir.path()).unwrap()));
+    contacts
+        .write()
+        .await
+        .add_contact(Contact {
+            id: "carol-id".to_string(),
+            name: "carol".to_string(),
+            addresses: vec!["tcp://198.51.100.7:8000".parse().unwrap()],
+            public_key: Vec::new(),
+            status: ContactStatus::Offline,
+            trust_level: TrustLevel::Trusted,
+            last_seen: None,
+        })
+        .unwrap();
+    let network = Arc::new(manager("alice").0);
+    let listener = ContactManager::spawn_presence_listener(
+        contacts.clone(),
+        network.clone(),
+        event_bus.clone(),
+    );
+    let mut events = event_bus.subscribe();
+    alice.start_discovery().await.unwrap();
+
+    // Peers that are not contacts are tracked but leave the book alone
+    let dave = CryptoManager::new().unwrap();
+    send_datagram(port, &signed_announcement(&dave, "dave-id", 1)).await;
+    let carol = CryptoManager::new().unwrap();
+    send_datagram(port, &signed_announcement(&carol, "carol-id", 2)).await;
+
+    loop {
+        if let NetworkEvent::ContactStatusChanged { contact } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(contact.id, "carol-id");
+            assert_eq!(contact.status, ContactStatus::Online);
+            assert_eq!(
+                contact.tcp_address(),
+                Some("127.0.0.1:8000".parse().unwrap())
+            );
+            break;
+        }
+    }
+    assert_eq!(contacts.read().await.get_contacts().len(), 1);
+
+    // Out of sight on the LAN but still connected, so still online
+    let _link = network.attach_connection("carol-id").await;
+    alice.stop_discovery().await;
+    loop {
+        if let NetworkEvent::PeerLost { peer_id } = next_network_event(&mut events).await {
+            if peer_id == "carol-id" {
+                break;
+            }
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stored = contacts.read().await.get_contact("carol-id").unwrap();
+    assert_eq!(stored.status, ContactStatus::Online);
+
+    network.detach_connection("carol-id").await;
+    alice.start_discovery().await.unwrap();
+    send_datagram(port, &signed_announcement(&carol, "carol-id", 3)).await;
+    loop {
+        if let NetworkEvent::ContactStatusChanged { .. } = next_network_event(&mut events).await {
+            break;
+        }
+    }
+
+    // Stopping discovery loses every peer, so the contact goes offline
+    alice.stop_discovery().await;
+    loop {
+        if let NetworkEvent::ContactStatusChanged { contact } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(contact.id, "carol-id");
+            assert_eq!(contact.status, ContactStatus::Offline);
+            break;
+        }
+    }
+    let stored = contacts.read().await.get_contact("carol-id").unwrap();
+    assert_eq!(stored.status, ContactStatus::Offline);
+
+    listener.abort();
+}
+
+#[test]
+fn test_discovery_sighting_under_another_key_is_ignored() {
+    let dir = TempDir::new().unwrap();
+    let mut contacts = ContactManager::new(dir.path()).unwrap();
+    let address: Endpoint = "tcp://198.51.100.9:8000".parse().unwrap();
+    contacts
+        .add_contact(Contact {
+            id: "erin-id".to_string(),
+            name: "erin".to_string(),
+            addresses: vec![address.clone()],
+            public_key: vec![1; 32],
+            status: ContactStatus::Offline,
+            trust_level: TrustLevel::Trusted,
+            last_seen: None,
+        })
+        .unwrap();
+    let sighting = |public_key| DiscoveredPeer {
+        id: "erin-id".to_string(),
+        address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port: 8000,
+        name: "erin".to_string(),
+        last_seen: now_secs(),
+        public_key,
+        protocol_version: 1,
+        capabilities: Vec::new(),
+    };
+
+    assert!(contacts.apply_discovery(&sighting(vec![2; 32])).is_none());
+    let stored = contacts.get_contact("erin-id").unwrap();
+    assert_eq!(stored.status, ContactStatus::Offline);
+    assert_eq!(stored.addresses, [address]);
+
+    let contact = contacts.apply_discovery(&sighting(vec![1; 32])).unwrap();
+    assert_eq!(contact.status, ContactStatus::Online);
+    assert_eq!(
+        contact.tcp_address(),
+        Some("127.0.0.1:8000".parse().unwrap())
+    );
+}
+
+#[tokio::test]
+async fn test_discovery_lifecycle_on_current_thread_runtime() {
+    let port = free_udp_port();
+    let event_bus = EventBus::new();
+    let mut events = event_bus.subscribe();
+    let mut alice = discovery(
+        "alice",
+        DiscoveryConfig {
+            port,
+            announce_interval: Duration::from_secs(60),
+            enable_mdns: false,
+            enable_ipv6: false,
+            ..DiscoveryConfig::default()
+        },
+    )
+    .with_event_bus(event_bus);
+    assert!(!alice.is_running());
+
+    alice.start_discovery().await.unwrap();
+    alice.start_discovery().await.unwrap();
+    assert!(alice.is_running());
+
+    let carol = CryptoManager::new().unwrap();
+    send_datagram(port, &signed_announcement(&carol, "carol-id", 1)).await;
+    assert!(matches!(
+        next_peer_event(&mut events, Duration::from_secs(1)).await,
+        NetworkEvent::PeerDiscovered { .. }
+    ));
+
+    alice.stop_discovery().await;
+    assert!(!alice.is_running());
+    assert!(matches!(
+        next_peer_event(&mut events, Duration::from_secs(1)).await,
+        NetworkEvent::PeerLost { .. }
+    ));
+
+    // Every task has exited, so nothing holds the discovery port any more
+    std::net::UdpSocket::bind(("0.0.0.0", port)).unwrap();
+
+    // A second stop is a no-op and discovery can be started again
+    alice.stop_discovery().await;
+    assert!(events.try_recv().is_err());
+    alice.start_discovery().await.unwrap();
+    assert!(alice.is_running());
+    alice.stop_discovery().await;
+}
+
+#[test]
+fn test_punch_packets_round_trip() {
+    let candidates = vec![
+        Candidate {
+            kind: CandidateKind::Host,
+            address: "10.0.0.2:4000".parse().unwrap(),
+        },
+        Candidate {
+            kind: CandidateKind::ServerReflexive,
+            address: "[2001:db8::1]:5000".parse().unwrap(),
+        },
+    ];
+    let packets = [
+        PunchPacket::Bind { txn: 7 },
+        PunchPacket::BindResponse {
+            txn: 7,
+            observed: "203.0.113.5:6000".parse().unwrap(),
+        },
+        PunchPacket::Register {
+            peer_id: "alice-id".to_string(),
+            candidates: candidates.clone(),
+        },
+        PunchPacket::Introduction {
+            peer_id: "bob-id".to_string(),
+            session: u64::MAX,
+            candidates,
+        },
+        PunchPacket::Data {
+            session: 1,
+            seq: 2,
+            more: true,
+            payload: b"fragment".to_vec(),
+        },
+        PunchPacket::Ack { session: 1, seq: 2 },
+    ];
+
+    for packet in packets {
+        let encoded = packet.encode();
+        assert_eq!(PunchPacket::decode(&encoded), Some(packet.clone()));
+        assert_ne!(
+            PunchPacket::decode(&encoded[..encoded.len() - 1]),
+            Some(packet)
+        );
+    }
+    assert_eq!(PunchPacket::decode(b"not a packet"), None);
+}
+
+fn fast_punch_config() -> PunchConfig {
+    PunchConfig {
+        probe_interval: Duration::from_millis(20),
+        punch_timeout: Duration::from_secs(5),
+        retransmit_timeout: Duration::from_millis(50),
+        max_retransmits: 60,
+        keepalive_interval: Duration::from_secs(1),
+    }
+}
+
+fn nat_puncher(nat: &NatEmulator, peer_id: &str) -> HolePuncher {
+    HolePuncher::new(Arc::new(nat.bind()), peer_id.to_string()).with_config(fast_punch_config())
+}
+
+async fn rendezvous() -> RendezvousServer {
+    let mut server = RendezvousServer::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    server.start();
+    server
+}
+
+async fn candidates_from(events: &mut EventReceiver, peer_id: &str) -> (u64, Vec<Candidate>) {
+    loop {
+        if let NetworkEvent::CandidatesReceived {
+            peer_id: from,
+            session_id,
+            candidates,
+        } = next_network_event(events).await
+        {
+            assert_eq!(from, peer_id);
+            return (session_id, candidates);
+        }
+    }
+}
+
+async fn assert_channel_works(alice: &DirectChannel, bob: &DirectChannel) {
+    alice.send(b"hello bob").await.unwrap();
+    assert_eq!(bob.recv().await.unwrap(), b"hello bob");
+
+    let message = ProtocolMessage::create_text_message(
+        bob.remote_peer_id().to_string(),
+        alice.remote_peer_id().to_string(),
+        "hello alice".to_string(),
+        "msg-1".to_string(),
+    );
+    bob.send_message(&message).await.unwrap();
+    let received = alice.recv_message().await.unwrap();
+    assert_eq!(received.get_text_content().as_deref(), Some("hello alice"));
+
+    // Larger than one datagram, so it travels as several fragments
+    let large: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    alice.send(&large).await.unwrap();
+    assert_eq!(bob.recv().await.unwrap(), large);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_hole_punch_through_port_restricted_nats_over_existing_connection() {
+    let server = rendezvous().await;
+    let server_addr = server.local_addr().unwrap();
+    let (alice_net, alice_bus) = manager("alice");
+    let (bob_net, bob_bus) = manager("bob");
+    let alice_id = alice_net.get_peer().await.id;
+    let bob_id = bob_net.get_peer().await.id;
+    let mut alice_events = alice_bus.subscribe();
+    let mut bob_events = bob_bus.subscribe();
+    let mut to_bob = alice_net.attach_connection(&bob_id).await;
+    let mut to_alice = bob_net.attach_connection(&alice_id).await;
+
+    let alice_nat = NatEmulator::new(NatType::PortRestricted);
+    let bob_nat = NatEmulator::new(NatType::PortRestricted);
+    let alice = nat_puncher(&alice_nat, &alice_id).with_event_bus(alice_bus.clone());
+    let bob = nat_puncher(&bob_nat, &bob_id);
+
+    // Alice offers her candidates over the existing connection...
+    let session = HolePuncher::new_session_id();
+    let alice_candidates = alice.gather_candidates(Some(server_addr)).await.unwrap();
+    assert!(alice_candidates
+        .iter()
+        .any(|c| c.kind == CandidateKind::ServerReflexive));
+    alice_net
+        .send_candidates(&bob_id, session, alice_candidates)
+        .await
+        .unwrap();
+    for message in drain(&mut to_bob).await {
+        bob_net.handle_incoming_message(message).await;
+    }
+    let (bob_session, offered) = candidates_from(&mut bob_events, &alice_id).await;
+    assert_eq!(bob_session, session);
+
+    // ...Bob answers with his, and both start probing at once
+    let bob_candidates = bob.gather_candidates(Some(server_addr)).await.unwrap();
+    bob_net
+        .send_candidates(&alice_id, session, bob_candidates)
+        .await
+        .unwrap();
+    for message in drain(&mut to_alice).await {
+        alice_net.handle_incoming_message(message).await;
+    }
+    let (_, answered) = candidates_from(&mut alice_events, &bob_id).await;
+
+    let (alice_channel, bob_channel) = tokio::join!(
+        alice.punch(&bob_id, session, &answered),
+        bob.punch(&alice_id, session, &offered)
+    );
+    let (alice_channel, bob_channel) = (alice_channel.unwrap(), bob_channel.unwrap());
+    assert!(alice_channel.remote_addr().ip().is_loopback());
+    loop {
+        if let NetworkEvent::DirectPathEstablished { peer_id, .. } =
+            next_network_event(&mut alice_events).await
+        {
+            assert_eq!(peer_id, bob_id);
+            break;
+        }
+    }
+
+    assert_channel_works(&alice_channel, &bob_channel).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rendezvous_peer_introduces_symmetric_and_full_cone_peers() {
+    let server = rendezvous().await;
+    let server_addr = server.local_addr().unwrap();
+
+    let bob_nat = NatEmulator::new(NatType::FullCone);
+    let bob = nat_puncher(&bob_nat, "bob-id");
+    let accepting = tokio::spawn(bob.accept_via_rendezvous(server_addr));
+    for _ in 0..100 {
+        if server
+            .registered_peers()
+            .await
+            .contains(&"bob-id".to_string())
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let alice_nat = NatEmulator::new(NatType::Symmetric);
+    let stranger = nat_puncher(&alice_nat, "alice-id");
+    assert!(matches!(
+        stranger
+            .connect_via_rendezvous(server_addr, "nobody-id")
+            .await,
+        Err(PunchError::UnknownPeer(_))
+    ));
+
+    let alice = nat_puncher(&alice_nat, "alice-id");
+    let alice_channel = alice
+        .connect_via_rendezvous(server_addr, "bob-id")
+        .await
+        .unwrap();
+    let bob_channel = accepting.await.unwrap().unwrap();
+    assert_eq!(bob_channel.remote_peer_id(), "alice-id");
+    assert_eq!(alice_channel.session(), bob_channel.session());
+
+    assert_channel_works(&alice_channel, &bob_channel).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_hole_punch_gives_up_between_symmetric_nats() {
+    let server = rendezvous().await;
+    let server_addr = server.local_addr().unwrap();
+    let config = PunchConfig {
+        punch_timeout: Duration::from_millis(500),
+        ..fast_punch_config()
+    };
+
+    let alice_nat = NatEmulator::new(NatType::Symmetric);
+    let bob_nat = NatEmulator::new(NatType::Symmetric);
+    let alice = nat_puncher(&alice_nat, "alice-id").with_config(config.clone());
+    let bob = nat_puncher(&bob_nat, "bob-id").with_config(config);
+
+    let session = HolePuncher::new_session_id();
+    let alice_candidates = alice.gather_candidates(Some(server_addr)).await.unwrap();
+    let bob_candidates = bob.gather_candidates(Some(server_addr)).await.unwrap();
+
+    // Each side's mapping towards the server is not the one used towards
+    // the peer, so neither side's probes get through
+    let (alice_result, bob_result) = tokio::join!(
+        alice.punch("bob-id", session, &bob_candidates),
+        bob.punch("alice-id", session, &alice_candidates)
+    );
+    assert!(matches!(alice_result, Err(PunchError::Timeout)));
+    assert!(matches!(bob_result, Err(PunchError::Timeout)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_direct_channel_delivers_in_order_despite_packet_loss() {
+    let server = rendezvous().await;
+    let server_addr = server.local_addr().unwrap();
+    let alice_nat = NatEmulator::new(NatType::FullCone).with_packet_loss(0.3);
+    let bob_nat = NatEmulator::new(NatType::FullCone).with_packet_loss(0.3);
+    let alice = nat_puncher(&alice_nat, "alice-id");
+    let bob = nat_puncher(&bob_nat, "bob-id");
+
+    let session = HolePuncher::new_session_id();
+    let alice_candidates = alice.gather_candidates(Some(server_addr)).await.unwrap();
+    let bob_candidates = bob.gather_candidates(Some(server_addr)).await.unwrap();
+    let (alice_channel, bob_channel) = tokio::join!(
+        alice.punch("bob-id", session, &bob_candidates),
+        bob.punch("alice-id", session, &alice_candidates)
+    );
+    let (alice_channel, bob_channel) = (alice_channel.unwrap(), bob_channel.unwrap());
+
+    let large: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let sending = async {
+        for i in 0..20u32 {
+            alice_channel.send(&i.to_be_bytes()).await.unwrap();
+        }
+        alice_channel.send(&large).await.unwrap();
+    };
+    let receiving = async {
+        let mut received = Vec::new();
+        for _ in 0..21 {
+            received.push(bob_channel.recv().await.unwrap());
+        }
+        received
+    };
+    let ((), received) = tokio::join!(sending, receiving);
+
+    for (i, message) in received[..20].iter().enumerate() {
+        assert_eq!(message, &(i as u32).to_be_bytes());
+    }
+    assert_eq!(received[20], large);
+}
+
+async fn mirrors(count: usize) -> Vec<AddressMirror> {
+    let mut mirrors = Vec::new();
+    for _ in 0..count {
+        let mut mirror = AddressMirror::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        mirror.start();
+        mirrors.push(mirror);
+    }
+    mirrors
+}
+
+fn mirror_addrs(mirrors: &[AddressMirror]) -> Vec<SocketAddr> {
+    mirrors.iter().map(|m| m.local_addr().unwrap()).collect()
+}
+
+fn fast_reflexive_config() -> ReflexiveConfig {
+    ReflexiveConfig {
+        request_timeout: Duration::from_secs(2),
+        resend_interval: Duration::from_millis(50),
+        ..ReflexiveConfig::default()
+    }
+}
+
+#[test]
+fn test_reflexive_consensus_rules() {
+    let local: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+    let seen = |mirror: &str, observed: &str| MirrorObservation {
+        mirror: mirror.parse().unwrap(),
+        observed: observed.parse().unwrap(),
+    };
+
+    // One lying mirror is outvoted
+    let report = ReflexiveDiscovery::evaluate(
+        local,
+        vec![
+            seen("1.1.1.1:1", "203.0.113.5:6000"),
+            seen("2.2.2.2:1", "203.0.113.5:6000"),
+            seen("3.3.3.3:1", "198.51.100.9:1234"),
+        ],
+        2,
+    );
+    assert_eq!(
+        report.public_address,
+        Some("203.0.113.5:6000".parse().unwrap())
+    );
+    assert_eq!(report.nat_mapping, NatMapping::EndpointIndependent);
+
+    let report = ReflexiveDiscovery::evaluate(
+        local,
+        vec![
+            seen("1.1.1.1:1", "203.0.113.5:6000"),
+            seen("2.2.2.2:1", "203.0.113.5:6001"),
+            seen("3.3.3.3:1", "203.0.113.5:6002"),
+        ],
+        2,
+    );
+    assert_eq!(report.public_address, None);
+    assert_eq!(report.external_ip, Some("203.0.113.5".parse().unwrap()));
+    assert_eq!(report.nat_mapping, NatMapping::Symmetric);
+
+    let report = ReflexiveDiscovery::evaluate(
+        local,
+        vec![
+            seen("1.1.1.1:1", "203.0.113.5:6000"),
+            seen("2.2.2.2:1", "198.51.100.9:6000"),
+        ],
+        2,
+    );
+    assert_eq!(report.external_ip, None);
+    assert_eq!(report.nat_mapping, NatMapping::Inconsistent);
+
+    // A single mirror is never trusted on its own
+    let report =
+        ReflexiveDiscovery::evaluate(local, vec![seen("1.1.1.1:1", "203.0.113.5:6000")], 2);
+    assert_eq!(report.public_address, None);
+    assert_eq!(report.nat_mapping, NatMapping::Unknown);
+
+    let report = ReflexiveDiscovery::evaluate(
+        local,
+        vec![
+            seen("1.1.1.1:1", "10.0.0.2:40000"),
+            seen("2.2.2.2:1", "10.0.0.2:40000"),
+        ],
+        2,
+    );
+    assert_eq!(report.nat_mapping, NatMapping::NotNatted);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reflexive_discovery_agrees_behind_cone_nat() {
+    let mirrors = mirrors(3).await;
+    let nat = NatEmulator::new(NatType::PortRestricted);
+    let socket = Arc::new(nat.bind());
+
+    let report = ReflexiveDiscovery::new(socket.clone())
+        .with_config(fast_reflexive_config())
+        .discover(&mirror_addrs(&mirrors))
+        .await
+        .unwrap();
+
+    assert_eq!(report.observations.len(), 3);
+    assert_eq!(report.nat_mapping, NatMapping::EndpointIndependent);
+    assert_eq!(report.public_address, Some(socket.public_addrs().await[0]));
+    assert_eq!(report.external_ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reflexive_discovery_detects_symmetric_nat() {
+    let mirrors = mirrors(3).await;
+    let nat = NatEmulator::new(NatType::Symmetric);
+    let puncher = nat_puncher(&nat, "alice");
+
+    let report = puncher
+        .discover_reflexive(&mirror_addrs(&mirrors), fast_reflexive_config())
+        .await
+        .unwrap();
+
+    assert_eq!(report.observations.len(), 3);
+    assert_eq!(report.nat_mapping, NatMapping::Symmetric);
+    assert_eq!(report.public_address, None);
+    assert_eq!(report.external_ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reflexive_discovery_without_nat_and_unreachable_mirrors() {
+    let mirrors = mirrors(2).await;
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let local = socket.local_addr().unwrap();
+    let silent = free_udp_port();
+
+    let mut targets = mirror_addrs(&mirrors);
+    targets.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), silent));
+    let report = ReflexiveDiscovery::new(socket)
+        .with_config(ReflexiveConfig {
+            request_timeout: Duration::from_millis(500),
+            ..fast_reflexive_config()
+        })
+        .discover(&targets)
+        .await
+        .unwrap();
+
+    assert_eq!(report.observations.len(), 2);
+    assert_eq!(report.public_address, Some(local));
+    assert_eq!(report.nat_mapping, NatMapping::NotNatted);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_direct_channel_peers_act_as_mirrors() {
+    let server = rendezvous().await;
+    let server_addr = server.local_addr().unwrap();
+    let alice_nat = NatEmulator::new(NatType::PortRestricted);
+    let bob_nat = NatEmulator::new(NatType::FullCone);
+    let alice_socket = Arc::new(alice_nat.bind());
+    let alice = HolePuncher::new(alice_socket.clone(), "alice".to_string())
+        .with_config(fast_punch_config());
+    let bob = nat_puncher(&bob_nat, "bob");
+
+    let accept = tokio::spawn(async move { bob.accept_via_rendezvous(server_addr).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let alice_channel = alice
+        .connect_via_rendezvous(server_addr, "bob")
+        .await
+        .unwrap();
+    let bob_channel = accept.await.unwrap().unwrap();
+
+    let observed = alice_channel.reflexive_address().await.unwrap();
+    assert_eq!(observed, bob_channel.remote_addr());
+    assert!(alice_socket.public_addrs().await.contains(&observed));
+
+    // Bob's punched endpoint also answers third parties
+    let stranger = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let report = ReflexiveDiscovery::new(stranger.clone())
+        .with_config(fast_reflexive_config())
+        .discover(&[alice_channel.remote_addr(), server_addr])
+        .await
+        .unwrap();
+    assert_eq!(report.public_address, Some(stranger.local_addr().unwrap()));
+}
+
+#[tokio::test]
+async fn test_http_ip_lookup_is_opt_in() {
+    let config: NetworkConfig =
+        toml::from_str("port = 8123\nmax_peers = 10\nenable_discovery = true\n").unwrap();
+    assert!(!config.allow_http_ip_lookup);
+    assert!(!config.discovery_config().allow_http_ip_lookup);
+    let opted_in = NetworkConfig {
+        allow_http_ip_lookup: true,
+        ..config
+    };
+    assert!(opted_in.discovery_config().allow_http_ip_lookup);
+    assert!(!DiscoveryConfig::default().allow_http_ip_lookup);
+
+    let discovery = discovery("alice", DiscoveryConfig::default());
+    let started = std::time::Instant::now();
+    let error = discovery.get_external_ip().await.unwrap_err();
+    assert!(error.to_string().contains("disabled"));
+    assert!(!discovery.test_connectivity().await);
+    assert!(started.elapsed() < Duration::from_millis(100));
+}
+
+/// Records everything a peer sends, standing in for a curious relay.
+struct SniffingSocket {
+    inner: Arc<dyn DatagramSocket>,
+    sent: std::sync::Mutex<Vec<Vec<u8>>>,
+}
+
+impl DatagramSocket for SniffingSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        self.sent.lock().unwrap().push(buf.to_vec());
+        self.inner.send_to(buf, target)
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        self.inner.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+async fn loopback_socket() -> Arc<dyn DatagramSocket> {
+    Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+}
+
+fn identity() -> (Arc<RwLock<CryptoManager>>, Vec<u8>) {
+    let crypto = CryptoManager::new().unwrap();
+    let key = crypto.get_public_key().key_data;
+    (Arc::new(RwLock::new(crypto)), key)
+}
+
+async fn relay(config: RelayConfig) -> RelayServer {
+    let mut server = RelayServer::bind_with_config("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    server.start();
+    server
+}
+
+async fn relay_client(
+    socket: Arc<dyn DatagramSocket>,
+    relay: &RelayServer,
+    peer_id: &str,
+    identity: Arc<RwLock<CryptoManager>>,
+) -> Result<RelayClient, RelayError> {
+    let mut client = RelayClient::new(
+        socket,
+        relay.local_addr().unwrap(),
+        peer_id.to_string(),
+        identity,
+    )
+    .with_config(fast_punch_config());
+    client.register().await?;
+    Ok(client)
+}
+
+/// Alice and Bob behind symmetric NATs, both trusted by the relay and
+/// linked through it.
+async fn relayed_pair(
+    relay: &RelayServer,
+    alice_socket: Arc<dyn DatagramSocket>,
+    alice_bus: Option<EventBus>,
+) -> (RelayClient, RelayClient, RelayedLink, RelayedLink) {
+    let (alice_id, alice_key) = identity();
+    let (bob_id, bob_key) = identity();
+    relay.trust_peer("alice", alice_key.clone()).await;
+    relay.trust_peer("bob", bob_key.clone()).await;
+
+    let mut alice = RelayClient::new(
+        alice_socket,
+        relay.local_addr().unwrap(),
+        "alice".to_string(),
+        alice_id,
+    )
+    .with_config(fast_punch_config());
+    if let Some(bus) = alice_bus {
+        alice = alice.with_event_bus(bus);
+    }
+    alice.register().await.unwrap();
+
+    let bob_nat = NatEmulator::new(NatType::Symmetric);
+    let bob = relay_client(Arc::new(bob_nat.bind()), relay, "bob", bob_id)
+        .await
+        .unwrap();
+
+    let known_keys = HashMap::from([("alice".to_string(), alice_key)]);
+    let (alice_link, bob_link) = tokio::join!(alice.connect("bob", &bob_key), async {
+        bob.accept(&known_keys).await
+    });
+    (alice, bob, alice_link.unwrap(), bob_link.unwrap())
+}
+
+#[test]
+fn test_relay_packets_round_trip() {
+    let packets = vec![
+        RelayPacket::Allocate {
+            peer_id: "alice".to_string(),
+            timestamp: 1_700_000_000,
+            signature: vec![7; 64],
+        },
+        RelayPacket::Allocated { quota: 4096 },
+        RelayPacket::Denied {
+            reason: "peer not trusted by this relay".to_string(),
+        },
+        RelayPacket::Send {
+            target: "bob".to_string(),
+            payload: vec![1; 1100],
+        },
+        RelayPacket::Deliver {
+            source: "alice".to_string(),
+            payload: vec![],
+        },
+        RelayPacket::UnknownTarget {
+            target: "carol".to_string(),
+        },
+        RelayPacket::QuotaExceeded {
+            target: "bob".to_string(),
+        },
+    ];
+
+    for packet in packets {
+        let encoded = packet.encode();
+        assert_eq!(RelayPacket::decode(&encoded), Some(packet));
+        assert_eq!(RelayPacket::decode(&encoded[..encoded.len() - 1]), None);
+    }
+    assert_eq!(RelayPacket::decode(b"SGP\x00"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relay_forwards_end_to_end_encrypted_frames() {
+    let relay = relay(RelayConfig::default()).await;
+    let alice_nat = NatEmulator::new(NatType::Symmetric);
+    let sniffer = Arc::new(SniffingSocket {
+        inner: Arc::new(alice_nat.bind()),
+        sent: std::sync::Mutex::new(Vec::new()),
+    });
+    let bus = EventBus::new();
+    let mut events = bus.subscribe();
+
+    let (_alice, _bob, alice_link, bob_link) =
+        relayed_pair(&relay, sniffer.clone(), Some(bus)).await;
+    assert!(!alice_link.is_direct());
+    assert_eq!(alice_link.remote_peer_id(), "bob");
+    assert_eq!(bob_link.remote_peer_id(), "alice");
+
+    alice_link.send(b"top secret relay message").await.unwrap();
+    assert_eq!(bob_link.recv().await.unwrap(), b"top secret relay message");
+
+    let message = ProtocolMessage::create_text_message(
+        "bob".to_string(),
+        "alice".to_string(),
+        "hello through the relay".to_string(),
+        "msg-1".to_string(),
+    );
+    bob_link.send_message(&message).await.unwrap();
+    let received = alice_link.recv_message().await.unwrap();
+    assert_eq!(
+        received.get_text_content().as_deref(),
+        Some("hello through the relay")
+    );
+
+    let large: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    alice_link.send(&large).await.unwrap();
+    assert_eq!(bob_link.recv().await.unwrap(), large);
+
+    // Everything that left Alice's socket is opaque to the relay
+    let sent = sniffer.sent.lock().unwrap().concat();
+    assert!(!sent.windows(10).any(|w| w == b"top secret"));
+    assert!(!sent.windows(large.len().min(64)).any(|w| w == &large[..64]));
+    assert!(relay.relayed_bytes("alice").await > large.len() as u64);
+
+    loop {
+        if let NetworkEvent::RelayPathEstablished {
+            peer_id,
+            relay: via,
+        } = next_network_event(&mut events).await
+        {
+            assert_eq!(peer_id, "bob");
+            assert_eq!(via, relay.local_addr().unwrap().to_string());
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relay_refuses_untrusted_peers_and_forged_handshakes() {
+    let relay = relay(RelayConfig::default()).await;
+    let (alice_id, alice_key) = identity();
+    let (bob_id, bob_key) = identity();
+    let (mallory_id, mallory_key) = identity();
+    relay.trust_peer("alice", alice_key.clone()).await;
+    relay.trust_peer("bob", bob_key).await;
+
+    // Not trusted, and claiming someone else's id without their key
+    let untrusted = relay_client(
+        loopback_socket().await,
+        &relay,
+        "mallory",
+        mallory_id.clone(),
+    )
+    .await;
+    assert!(matches!(untrusted, Err(RelayError::Denied(_))));
+    let impostor = relay_client(loopback_socket().await, &relay, "bob", mallory_id).await;
+    assert!(matches!(impostor, Err(RelayError::Denied(_))));
+    assert!(relay.allocated_peers().await.is_empty());
+
+    let alice = relay_client(loopback_socket().await, &relay, "alice", alice_id)
+        .await
+        .unwrap();
+    let bob = relay_client(loopback_socket().await, &relay, "bob", bob_id)
+        .await
+        .unwrap();
+
+    let unknown = alice.connect("carol", &mallory_key).await;
+    assert!(matches!(unknown, Err(RelayError::UnknownPeer(_))));
+
+    // Bob's handshake is not signed by the key Alice expects, as it would
+    // be if the relay swapped in its own
+    let known_keys = HashMap::from([("alice".to_string(), alice_key)]);
+    let accepting = tokio::spawn(async move {
+        let link = bob.accept(&known_keys).await;
+        (bob, link)
+    });
+    let forged = alice.connect("bob", &mallory_key).await;
+    assert!(matches!(forged, Err(RelayError::Timeout)));
+    accepting.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relay_enforces_per_peer_quota() {
+    let relay = relay(RelayConfig {
+        max_bytes_per_peer: 8 * 1024,
+        ..RelayConfig::default()
+    })
+    .await;
+    let alice_nat = NatEmulator::new(NatType::Symmetric);
+    let bus = EventBus::new();
+    let mut events = bus.subscribe();
+    let (alice, _bob, alice_link, bob_link) =
+        relayed_pair(&relay, Arc::new(alice_nat.bind()), Some(bus)).await;
+
+    alice_link.send(b"small enough").await.unwrap();
+    assert_eq!(bob_link.recv().await.unwrap(), b"small enough");
+
+    let large = vec![0u8; 20_000];
+    assert!(matches!(
+        alice_link.send(&large).await,
+        Err(PunchError::Timeout)
+    ));
+    assert!(alice.quota_exceeded());
+    assert!(relay.relayed_bytes("alice").await <= 8 * 1024);
+
+    loop {
+        if let NetworkEvent::RelayQuotaExceeded { relay: via } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(via, relay.local_addr().unwrap().to_string());
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relayed_link_upgrades_to_direct_path() {
+    let relay = relay(RelayConfig::default()).await;
+    let relay_addr = relay.local_addr().unwrap();
+    let alice_nat = NatEmulator::new(NatType::Symmetric);
+    let bus = EventBus::new();
+    let mut events = bus.subscribe();
+    let (_alice, _bob, mut alice_link, mut bob_link) =
+        relayed_pair(&relay, Arc::new(alice_nat.bind()), Some(bus)).await;
+
+    // Dedicated punching sockets, behind NATs that do allow a direct path
+    let alice_punch_nat = NatEmulator::new(NatType::PortRestricted);
+    let bob_punch_nat = NatEmulator::new(NatType::PortRestricted);
+    bob_link.enable_upgrade(
+        Arc::new(bob_punch_nat.bind()),
+        Some(relay_addr),
+        Duration::from_secs(1),
+    );
+    alice_link.enable_upgrade(
+        Arc::new(alice_punch_nat.bind()),
+        Some(relay_addr),
+        Duration::from_secs(1),
+    );
+
+    let upgraded = tokio::time::timeout(Duration::from_secs(10), async {
+        while !(alice_link.is_direct() && bob_link.is_direct()) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(upgraded.is_ok(), "link never left the relay");
+    assert_ne!(alice_link.direct_addr(), Some(relay_addr));
+
+    let before = relay.relayed_bytes("alice").await;
+    alice_link.send(b"now direct").await.unwrap();
+    assert_eq!(bob_link.recv().await.unwrap(), b"now direct");
+    bob_link.send(b"direct reply").await.unwrap();
+    assert_eq!(alice_link.recv().await.unwrap(), b"direct reply");
+    assert!(relay.relayed_bytes("alice").await - before < 100);
+
+    loop {
+        if let NetworkEvent::DirectPathEstablished { peer_id, .. } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(peer_id, "bob");
+            break;
+        }
+    }
+    alice_link.close().await;
+    bob_link.close().await;
+}
+
+#[test]
+fn test_relay_selection_prefers_trusted_online_contacts() {
+    let dir = TempDir::new().unwrap();
+    let mut contacts = ContactManager::new(dir.path()).unwrap();
+    let contact = |id: &str, address: &str, trust_level, status| Contact {
+        id: id.to_string(),
+        name: id.to_string(),
+        addresses: vec![address.parse().unwrap()],
+        public_key: Vec::new(),
+        status,
+        trust_level,
+        last_seen: None,
+    };
+    for contact in [
+        contact(
+            "target",
+            "10.0.0.1:8000",
+            TrustLevel::Trusted,
+            ContactStatus::Online,
+        ),
+        contact(
+            "medium",
+            "10.0.0.2:8000",
+            TrustLevel::Medium,
+            ContactStatus::Online,
+        ),
+        contact(
+            "offline",
+            "10.0.0.3:8000",
+            TrustLevel::Trusted,
+            ContactStatus::Offline,
+        ),
+        contact(
+            "high",
+            "10.0.0.4:8000",
+            TrustLevel::High,
+            ContactStatus::Online,
+        ),
+        contact(
+            "blocked",
+            "10.0.0.5:8000",
+            TrustLevel::Trusted,
+            ContactStatus::Online,
+        ),
+        contact(
+            "no-address",
+            "relayexample.onion:9000",
+            TrustLevel::Trusted,
+            ContactStatus::Online,
+        ),
+    ] {
+        contacts.add_contact(contact).unwrap();
+    }
+    contacts.block_contact("blocked").unwrap();
+
+    assert!(select_relay(&RelayPolicy::default(), &contacts, "target")
+        .unwrap()
+        .is_none());
+
+    let policy = RelayPolicy {
+        enabled: true,
+        ..RelayPolicy::default()
+    };
+    let (relay, address) = select_relay(&policy, &contacts, "target").unwrap().unwrap();
+    assert_eq!(relay.id, "high");
+    assert_eq!(address, "10.0.0.4:8000".parse::<SocketAddr>().unwrap());
+
+    contacts.set_trust_level("high", TrustLevel::Low).unwrap();
+    let (relay, _) = select_relay(&policy, &contacts, "target").unwrap().unwrap();
+    assert_eq!(relay.id, "offline");
+
+    // Blocked contacts never relay, so they cannot be the minimum either
+    assert!(contacts.relay_candidates(&TrustLevel::Blocked).is_err());
+    let blocked = RelayPolicy {
+        min_trust_level: TrustLevel::Blocked,
+        ..policy
+    };
+    assert!(select_relay(&blocked, &contacts, "target").is_err());
+    let everyone = contacts.relay_candidates(&TrustLevel::Unknown).unwrap();
+    assert!(everyone.iter().all(|c| c.id != "blocked"));
+}
+
+fn envelope(id: &str, recipient: &str, size: usize, expires_at: u64) -> SealedEnvelope {
+    SealedEnvelope {
+        id: id.to_string(),
+        recipient_id: recipient.to_string(),
+        sealed: vec![0u8; size],
+        expires_at,
+    }
+}
+
+#[test]
+fn test_mailbox_enforces_recipients_size_and_ttl() {
+    let mut mailbox = Mailbox::new(MailboxConfig {
+        max_envelope_size: 100,
+        max_envelopes_per_recipient: 2,
+        max_bytes_per_recipient: 150,
+        max_total_bytes: 1000,
+        max_ttl: Duration::from_secs(60),
+    });
+    let now = 1_000;
+
+    assert_eq!(
+        mailbox.deposit(envelope("a", "bob", 10, now + 10), now),
+        Err(MailboxError::NotServed("bob".to_string()))
+    );
+    mailbox.serve("bob").unwrap();
+    assert_eq!(
+        mailbox.deposit(envelope("a", "bob", 101, now + 10), now),
+        Err(MailboxError::TooLarge)
+    );
+    assert_eq!(
+        mailbox.deposit(envelope("a", "bob", 10, now), now),
+        Err(MailboxError::Expired)
+    );
+
+    // Asking for a week is cut down to the mailbox's limit
+    mailbox
+        .deposit(envelope("a", "bob", 100, now + 7 * 86_400), now)
+        .unwrap();
+    assert_eq!(mailbox.pending_for("bob", now)[0].expires_at, now + 60);
+
+    // Depositing the same envelope again is harmless
+    mailbox
+        .deposit(envelope("a", "bob", 100, now + 10), now)
+        .unwrap();
+    assert_eq!(mailbox.envelope_count(), 1);
+
+    assert_eq!(
+        mailbox.deposit(envelope("b", "bob", 60, now + 10), now),
+        Err(MailboxError::Full)
+    );
+    mailbox
+        .deposit(envelope("b", "bob", 50, now + 10), now)
+        .unwrap();
+    assert_eq!(
+        mailbox.deposit(envelope("c", "bob", 1, now + 10), now),
+        Err(MailboxError::Full)
+    );
+
+    // Expired envelopes are no longer handed out and make room again
+    assert_eq!(mailbox.pending_for("bob", now + 30).len(), 1);
+    assert_eq!(mailbox.purge_expired(now + 30).unwrap(), 1);
+    assert_eq!(mailbox.stored_bytes(), 100);
+
+    assert_eq!(mailbox.acknowledge("carol", &["a".to_string()]).unwrap(), 0);
+    assert_eq!(mailbox.acknowledge("bob", &["a".to_string()]).unwrap(), 1);
+    assert_eq!(mailbox.envelope_count(), 0);
+}
+
+#[test]
+fn test_mailbox_persists_across_reopen() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("mailbox.json");
+    let now = now_secs();
+
+    let mut mailbox = Mailbox::open(&path, MailboxConfig::default()).unwrap();
+    mailbox.serve("bob").unwrap();
+    mailbox
+        .deposit(envelope("a", "bob", 10, now + 60), now)
+        .unwrap();
+    drop(mailbox);
+
+    let mut mailbox = Mailbox::open(&path, MailboxConfig::default()).unwrap();
+    assert!(mailbox.serves("bob"));
+    assert_eq!(mailbox.pending_for("bob", now).len(), 1);
+
+    mailbox.stop_serving("bob").unwrap();
+    let mailbox = Mailbox::open(&path, MailboxConfig::default()).unwrap();
+    assert!(!mailbox.serves("bob"));
+    assert_eq!(mailbox.envelope_count(), 0);
+}
+
+#[tokio::test]
+async fn test_sealed_mail_opens_only_for_recipient() {
+    let (alice, alice_key) = identity();
+    let (bob, bob_key) = identity();
+    let (carol, _) = identity();
+
+    let sealed = alice
+        .read()
+        .await
+        .seal_for(&bob_key, b"meet at noon")
+        .unwrap();
+    assert!(!sealed.windows(4).any(|w| w == b"meet"));
+
+    let (sender, plaintext) = bob.read().await.open_sealed(&sealed).unwrap();
+    assert_eq!(sender, alice_key);
+    assert_eq!(plaintext, b"meet at noon");
+
+    assert!(carol.read().await.open_sealed(&sealed).is_err());
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(bob.read().await.open_sealed(&tampered).is_err());
+}
+
+struct MailboxFixture {
+    alice: NetworkManager,
+    bob: NetworkManager,
+    daemon: NetworkManager,
+    alice_id: String,
+    bob_id: String,
+    daemon_id: String,
+    bob_key: Vec<u8>,
+    bob_bus: EventBus,
+}
+
+async fn mailbox_fixture() -> MailboxFixture {
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    let (mut daemon, _) = manager("daemon");
+    let (alice_crypto, alice_key) = identity();
+    let (bob_crypto, bob_key) = identity();
+    let (daemon_crypto, _) = identity();
+    alice.set_identity(alice_crypto);
+    bob.set_identity(bob_crypto);
+    daemon.set_identity(daemon_crypto);
+
+    let alice_id = alice.get_peer().await.id;
+    let bob_id = bob.get_peer().await.id;
+    let daemon_id = daemon.get_peer().await.id;
+    alice.set_peer_key(&bob_id, bob_key.clone()).await;
+    bob.set_peer_key(&alice_id, alice_key).await;
+
+    let mut mailbox = Mailbox::new(MailboxConfig::default());
+    mailbox.serve(&bob_id).unwrap();
+    daemon.host_mailbox(mailbox);
+
+    MailboxFixture {
+        alice,
+        bob,
+        daemon,
+        alice_id,
+        bob_id,
+        daemon_id,
+        bob_key,
+        bob_bus,
+    }
+}
+
+async fn pump(link: &mut mpsc::UnboundedReceiver<ProtocolMessage>, to: &NetworkManager) {
+    for message in drain(link).await {
+        to.handle_incoming_message(message).await;
+    }
+}
+
+async fn stored_mail(daemon: &NetworkManager) -> usize {
+    daemon.mailbox().unwrap().read().await.envelope_count()
+}
+
+#[tokio::test]
+async fn test_offline_message_delivered_through_mailbox() {
+    let fixture = mailbox_fixture().await;
+    let (alice, bob, daemon) = (&fixture.alice, &fixture.bob, &fixture.daemon);
+    let mut bob_events = fixture.bob_bus.subscribe();
+
+    // Bob tells Alice where to leave mail, then goes offline
+    bob.set_mailboxes(vec![fixture.daemon_id.clone()]).await;
+    let mut bob_to_alice = bob.attach_connection(&fixture.alice_id).await;
+    alice.attach_connection(&fixture.bob_id).await;
+    pump(&mut bob_to_alice, alice).await;
+    assert_eq!(
+        alice.mailboxes_of(&fixture.bob_id).await,
+        vec![fixture.daemon_id.clone()]
+    );
+    alice.detach_connection(&fixture.bob_id).await;
+
+    // Queued while neither Bob nor his mailbox is reachable...
+    let first = ProtocolMessage::create_text_message(
+        fixture.alice_id.clone(),
+        fixture.bob_id.clone(),
+        "first".to_string(),
+        "msg-1".to_string(),
+    );
+    assert_eq!(
+        alice.send_or_queue(first).await.unwrap(),
+        DeliveryRoute::Queued { mailboxes: vec![] }
+    );
+
+    // ...deposited as soon as the mailbox connects, and directly afterwards
+    let mut alice_to_daemon = alice.attach_connection(&fixture.daemon_id).await;
+    let second = ProtocolMessage::create_text_message(
+        fixture.alice_id.clone(),
+        fixture.bob_id.clone(),
+        "second".to_string(),
+        "msg-2".to_string(),
+    );
+    assert_eq!(
+        alice.send_or_queue(second).await.unwrap(),
+        DeliveryRoute::Queued {
+            mailboxes: vec![fixture.daemon_id.clone()]
+        }
+    );
+    daemon.attach_connection(&fixture.alice_id).await;
+    pump(&mut alice_to_daemon, daemon).await;
+    assert_eq!(stored_mail(daemon).await, 2);
+    assert_eq!(alice.outbox_len().await, 2);
+
+    // The mailbox only ever sees ciphertext
+    let held = daemon
+        .mailbox()
+        .unwrap()
+        .read()
+        .await
+        .pending_for(&fixture.bob_id, now_secs());
+    assert!(held
+        .iter()
+        .all(|e| !e.sealed.windows(5).any(|w| w == b"first")));
+
+    // Bob connects to his mailbox and gets both, then they are dropped there
+    let mut daemon_to_bob = daemon.attach_connection(&fixture.bob_id).await;
+    let mut bob_to_daemon = bob.attach_connection(&fixture.daemon_id).await;
+    pump(&mut daemon_to_bob, bob).await;
+
+    let mut received = Vec::new();
+    loop {
+        match next_network_event(&mut bob_events).await {
+            NetworkEvent::MailboxDelivered { mailbox, count } => {
+                assert_eq!(mailbox, fixture.daemon_id);
+                assert_eq!(count, 2);
+            }
+            NetworkEvent::MessageReceived { message, .. } => {
+                received.push(message.content);
+                if received.len() == 2 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    received.sort();
+    assert_eq!(received, vec!["first", "second"]);
+
+    pump(&mut bob_to_daemon, daemon).await;
+    assert_eq!(stored_mail(daemon).await, 0);
+
+    // Alice's outbox still hands them over directly once Bob is reachable,
+    // but Bob does not show them twice
+    let mut alice_to_bob = alice.attach_connection(&fixture.bob_id).await;
+    assert_eq!(alice.outbox_len().await, 0);
+    pump(&mut alice_to_bob, bob).await;
+    let chat = bob.get_chat_messages(&fixture.alice_id).await.unwrap();
+    assert_eq!(chat.len(), 2);
+}
+
+#[tokio::test]
+async fn test_mailbox_rejects_unserved_and_drops_forged_mail() {
+    let fixture = mailbox_fixture().await;
+    let (bob, daemon) = (&fixture.bob, &fixture.daemon);
+    let mut bob_events = fixture.bob_bus.subscribe();
+
+    // The daemon keeps mail for Bob only
+    let mut daemon_to_alice = daemon.attach_connection(&fixture.alice_id).await;
+    daemon
+        .handle_incoming_message(ProtocolMessage::create_mailbox(
+            fixture.alice_id.clone(),
+            fixture.daemon_id.clone(),
+            MailboxPayload::Deposit {
+                envelope: envelope("for-carol", "carol-id", 3, now_secs() + 60),
+            },
+        ))
+        .await;
+    assert!(drain(&mut daemon_to_alice).await.iter().any(|m| matches!(
+        &m.payload,
+        MessagePayload::Mailbox(MailboxPayload::Rejected { envelope_id, .. })
+            if envelope_id == "for-carol"
+    )));
+    assert_eq!(stored_mail(daemon).await, 0);
+
+    // Mallory seals a message for Bob claiming to be from Alice
+    let (mallory, _) = identity();
+    let forged = ProtocolMessage::create_text_message(
+        fixture.alice_id.clone(),
+        fixture.bob_id.clone(),
+        "send money".to_string(),
+        "forged".to_string(),
+    );
+    let sealed = mallory
+        .read()
+        .await
+        .seal_for(&fixture.bob_key, &forged.to_bytes().unwrap())
+        .unwrap();
+    let mut bob_to_daemon = bob.attach_connection(&fixture.daemon_id).await;
+    drain(&mut bob_to_daemon).await;
+
+    bob.handle_incoming_message(ProtocolMessage::create_mailbox(
+        fixture.daemon_id.clone(),
+        fixture.bob_id.clone(),
+        MailboxPayload::Deliver {
+            envelopes: vec![SealedEnvelope {
+                id: "forged-envelope".to_string(),
+                recipient_id: fixture.bob_id.clone(),
+                sealed,
+                expires_at: now_secs() + 60,
+            }],
+        },
+    ))
+    .await;
+
+    loop {
+        if let NetworkEvent::MailboxDelivered { count, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(count, 0);
+            break;
+        }
+    }
+    assert!(bob
+        .get_chat_messages(&fixture.alice_id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // It is still acknowledged, so the mailbox does not keep resending it
+    assert!(drain(&mut bob_to_daemon).await.iter().any(|m| matches!(
+        &m.payload,
+        MessagePayload::Mailbox(MailboxPayload::Acknowledge { envelope_ids })
+            if envelope_ids == &vec!["forged-envelope".to_string()]
+    )));
+}
+
+#[tokio::test]
+async fn test_mailbox_takes_acknowledgements_only_from_the_recipient() {
+    let fixture = mailbox_fixture().await;
+    let daemon = &fixture.daemon;
+    let acknowledge = |from: &str| {
+        ProtocolMessage::create_mailbox(
+            from.to_string(),
+            fixture.daemon_id.clone(),
+            MailboxPayload::Acknowledge {
+                envelope_ids: vec!["for-bob".to_string()],
+            },
+        )
+    };
+
+    daemon.attach_connection(&fixture.alice_id).await;
+    daemon
+        .handle_incoming_message(ProtocolMessage::create_mailbox(
+            fixture.alice_id.clone(),
+            fixture.daemon_id.clone(),
+            MailboxPayload::Deposit {
+                envelope: envelope("for-bob", &fixture.bob_id, 3, now_secs() + 60),
+            },
+        ))
+        .await;
+    assert_eq!(stored_mail(daemon).await, 1);
+
+    // Not by a peer it was not addressed to, nor by someone who is not
+    // connected as the recipient
+    daemon
+        .handle_incoming_message(acknowledge(&fixture.alice_id))
+        .await;
+    daemon
+        .handle_incoming_message(acknowledge(&fixture.bob_id))
+        .await;
+    assert_eq!(stored_mail(daemon).await, 1);
+
+    // Nor are mailboxes taken from someone who is not connected
+    daemon
+        .handle_incoming_message(ProtocolMessage::create_mailbox(
+            fixture.bob_id.clone(),
+            fixture.daemon_id.clone(),
+            MailboxPayload::Advertise {
+                mailboxes: vec!["mallory-id".to_string()],
+            },
+        ))
+        .await;
+    assert!(daemon.mailboxes_of(&fixture.bob_id).await.is_empty());
+
+    daemon.attach_connection(&fixture.bob_id).await;
+    daemon
+        .handle_incoming_message(acknowledge(&fixture.bob_id))
+        .await;
+    assert_eq!(stored_mail(daemon).await, 0);
+}
+
+fn fast_dht_config() -> DhtConfig {
+    DhtConfig {
+        k: 4,
+        alpha: 3,
+        request_timeout: Duration::from_millis(300),
+        record_ttl: Duration::from_secs(60),
+        max_record_ttl: Duration::from_secs(120),
+        max_records: 100,
+        republish_interval: Duration::from_secs(60),
+    }
+}
+
+/// In-process DHT: every node on its own loopback socket, all joined
+/// through the first one.
+struct DhtSwarm {
+    nodes: Vec<DhtNode>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl DhtSwarm {
+    async fn spawn(size: usize) -> Self {
+        let mut swarm = DhtSwarm {
+            nodes: Vec::new(),
+            keys: Vec::new(),
+        };
+        for i in 0..size {
+            let (crypto, key) = identity();
+            let mut node = DhtNode::bind_with_config(
+                "127.0.0.1:0".parse().unwrap(),
+                &format!("peer-{}", i),
+                crypto,
+                fast_dht_config(),
+            )
+            .await
+            .unwrap();
+            node.start();
+            if let Some(seed) = swarm.nodes.first() {
+                node.bootstrap(&[seed.local_addr().unwrap()]).await.unwrap();
+            }
+            swarm.nodes.push(node);
+            swarm.keys.push(key);
+        }
+        swarm
+    }
+
+    fn addr(&self, i: usize) -> SocketAddr {
+        self.nodes[i].local_addr().unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_dht_routing_table_and_packets() {
+    let (_, key) = identity();
+    let own = NodeId::from_public_key(&key);
+    assert_eq!(own.bucket_index(&own), None);
+
+    let mut near = own;
+    near.0[31] ^= 1;
+    let mut far = own;
+    far.0[0] ^= 0x80;
+    assert_eq!(own.bucket_index(&near), Some(255));
+    assert_eq!(own.bucket_index(&far), Some(0));
+
+    let node = |id: NodeId, port: u16| NodeInfo {
+        id,
+        address: SocketAddr::from(([127, 0, 0, 1], port)),
+    };
+    let mut table = RoutingTable::new(own, 2);
+    assert!(!table.insert(node(own, 1)));
+    assert!(table.insert(node(far, 2)));
+    assert!(table.insert(node(near, 3)));
+
+    // A full bucket keeps the nodes it already has
+    let mut far2 = far;
+    far2.0[1] ^= 1;
+    let mut far3 = far;
+    far3.0[2] ^= 1;
+    assert!(table.insert(node(far2, 4)));
+    assert!(!table.insert(node(far3, 5)));
+    assert_eq!(table.len(), 3);
+
+    let closest = table.closest(&own, 3);
+    assert_eq!(closest[0].id, near);
+    assert_eq!(table.closest(&far, 1)[0].id, far);
+    assert!(table.remove(&far2));
+    assert!(table.insert(node(far3, 5)));
+
+    let (crypto, _) = identity();
+    let record = PeerRecord::sign(
+        &*crypto.read().await,
+        "peer-id",
+        vec![
+            "10.0.0.1:4000".parse().unwrap(),
+            "[::1]:4000".parse().unwrap(),
+        ],
+        Duration::from_secs(60),
+    )
+    .unwrap();
+    assert!(record.verify(now_secs()));
+    assert!(!record.verify(now_secs() + 61));
+
+    let packets = vec![
+        DhtPacket::Ping {
+            txn: 1,
+            sender: own,
+        },
+        DhtPacket::Nodes {
+            txn: 2,
+            sender: own,
+            nodes: vec![node(near, 7)],
+        },
+        DhtPacket::FindValue {
+            txn: 3,
+            sender: own,
+            key: far,
+        },
+        DhtPacket::Store {
+            txn: 4,
+            sender: own,
+            record: record.clone(),
+        },
+        DhtPacket::Stored {
+            txn: 5,
+            sender: own,
+            accepted: true,
+        },
+    ];
+    for packet in packets {
+        assert_eq!(DhtPacket::decode(&packet.encode()), Some(packet));
+    }
+    assert_eq!(DhtPacket::decode(b"SGD\x09"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_dht_swarm_publishes_and_resolves_records() {
+    let swarm = DhtSwarm::spawn(16).await;
+    for node in &swarm.nodes {
+        assert!(node.routing_table_size() > 0);
+    }
+
+    let first: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+    let stored = swarm.nodes[3].publish(vec![first]).await.unwrap();
+    assert!(stored >= 2, "record stored on {} nodes", stored);
+
+    let record = swarm.nodes[12].resolve(&swarm.keys[3]).await.unwrap();
+    assert_eq!(record.peer_id, "peer-3");
+    assert_eq!(record.endpoints, vec![first]);
+    assert!(record.verify(now_secs()));
+
+    // Publishing again replaces the old endpoints everywhere
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let moved: SocketAddr = "198.51.100.9:4100".parse().unwrap();
+    swarm.nodes[3].publish(vec![moved]).await.unwrap();
+    let record = swarm.nodes[9].resolve(&swarm.keys[3]).await.unwrap();
+    assert_eq!(record.endpoints, vec![moved]);
+
+    let (_, stranger) = identity();
+    assert!(matches!(
+        swarm.nodes[5].resolve(&stranger).await,
+        Err(DhtError::NotFound)
+    ));
+}
+
+async fn store_raw(to: SocketAddr, record: PeerRecord) -> bool {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_, key) = identity();
+    let packet = DhtPacket::Store {
+        txn: 42,
+        sender: NodeId::from_public_key(&key),
+        record,
+    };
+    socket.send_to(&packet.encode(), to).await.unwrap();
+
+    let mut buffer = [0u8; 1500];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    match DhtPacket::decode(&buffer[..len]) {
+        Some(DhtPacket::Stored {
+            txn: 42, accepted, ..
+        }) => accepted,
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_dht_rejects_forged_stale_and_overlong_records() {
+    let swarm = DhtSwarm::spawn(2).await;
+    let target = swarm.addr(1);
+    let (alice, alice_key) = identity();
+    let (mallory, _) = identity();
+    let endpoint: SocketAddr = "203.0.113.1:4000".parse().unwrap();
+    let sign = |crypto: &CryptoManager, ttl| {
+        PeerRecord::sign(crypto, "alice", vec![endpoint], Duration::from_secs(ttl)).unwrap()
+    };
+
+    let genuine = sign(&*alice.read().await, 60);
+    assert!(store_raw(target, genuine.clone()).await);
+
+    // Mallory cannot publish endpoints under Alice's key
+    let mut forged = sign(&*mallory.read().await, 60);
+    forged.public_key = alice_key.clone();
+    assert!(!store_raw(target, forged).await);
+    let mut tampered = genuine.clone();
+    tampered.endpoints = vec!["192.0.2.66:4000".parse().unwrap()];
+    assert!(!store_raw(target, tampered).await);
+
+    // Replaying an older record does not roll the newer one back
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let newer = sign(&*alice.read().await, 60);
+    assert!(store_raw(target, newer.clone()).await);
+    assert!(!store_raw(target, genuine).await);
+
+    // Records may not outlive the node's limit
+    assert!(!store_raw(target, sign(&*alice.read().await, 3600)).await);
+
+    let resolved = swarm.nodes[0].resolve(&alice_key).await.unwrap();
+    assert_eq!(resolved.sequence, newer.sequence);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_contact_re_resolved_through_dht_after_address_change() {
+    let dir = TempDir::new().unwrap();
+    let swarm = DhtSwarm::spawn(8).await;
+
+    // Bob moved: his old address is dead, the new one is in the DHT
+    let stale = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let current = listener.local_addr().unwrap();
+    let (bob_crypto, bob_key) = identity();
+    let mut bob = DhtNode::bind_with_config(
+        "127.0.0.1:0".parse().unwrap(),
+        "bob-id",
+        bob_crypto,
+        fast_dht_config(),
+    )
+    .await
+    .unwrap();
+    bob.start();
+    bob.bootstrap(&[swarm.addr(2)]).await.unwrap();
+    assert!(bob.publish(vec![current]).await.unwrap() > 0);
+
+    // Alice joins through her contacts, then reaches Bob
+    let contacts = RwLock::new(ContactManager::new(dir.path()).unwrap());
+    let contact = |id: &str, address: SocketAddr| Contact {
+        id: id.to_string(),
+        name: id.to_string(),
+        addresses: vec![Endpoint::Tcp(address)],
+        public_key: Vec::new(),
+        status: ContactStatus::Offline,
+        trust_level: TrustLevel::Trusted,
+        last_seen: None,
+    };
+    {
+        let mut contacts = contacts.write().await;
+        contacts.add_contact(contact("bob-id", stale)).unwrap();
+        contacts
+            .add_contact(contact("carol-id", swarm.addr(5)))
+            .unwrap();
+    }
+    let (alice_crypto, _) = identity();
+    let mut alice = DhtNode::bind_with_config(
+        "127.0.0.1:0".parse().unwrap(),
+        "alice-id",
+        alice_crypto,
+        fast_dht_config(),
+    )
+    .await
+    .unwrap();
+    alice.start();
+    let seeds = bootstrap_addresses(&contacts.read().await.get_contacts());
+    assert!(alice.bootstrap(&seeds).await.unwrap() > 0);
+
+    let accept = tokio::spawn(async move { listener.accept().await.unwrap().1 });
+    let stream = alice
+        .connect_contact(&contacts, "bob-id", &bob_key, |address| {
+            tokio::net::TcpStream::connect(address)
+        })
+        .await
+        .unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), current);
+    accept.await.unwrap();
+    assert_eq!(
+        contacts
+            .read()
+            .await
+            .get_contact("bob-id")
+            .unwrap()
+            .addresses[0],
+        Endpoint::Tcp(current)
+    );
+
+    // A record for someone else's key does not redirect the contact
+    assert!(alice
+        .connect_contact(&contacts, "carol-id", &bob_key, |address| async move {
+            if address == current {
+                Ok(())
+            } else {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            }
+        })
+        .await
+        .is_err());
+}
+
+/// Minimal TLS record parser, independent of the one in `tls_masking`, so
+/// the masked output is checked against the wire format rather than
+/// against itself.
+fn parse_tls_stream(mut data: &[u8]) -> Vec<(u8, u16, Vec<u8>)> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        assert!(data.len() >= 5, "truncated record header");
+        let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+        assert!(data.len() >= 5 + length, "length field overruns the stream");
+        records.push((
+            data[0],
+            u16::from_be_bytes([data[1], data[2]]),
+            data[5..5 + length].to_vec(),
+        ));
+        data = &data[5 + length..];
+    }
+    records
+}
+
+struct ParsedHello {
+    msg_type: u8,
+    random: Vec<u8>,
+    session_id: Vec<u8>,
+    extensions: HashMap<u16, Vec<u8>>,
+}
+
+/// Parses a ClientHello or ServerHello handshake message, requiring every
+/// length prefix to add up exactly.
+fn parse_hello(message: &[u8]) -> ParsedHello {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> &'a [u8] {
+        assert!(data.len() >= n, "field overruns the message");
+        let (head, tail) = data.split_at(n);
+        *data = tail;
+        head
+    }
+    fn take_u16(data: &mut &[u8]) -> usize {
+        let bytes = take(data, 2);
+        u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+    }
+
+    let mut data = message;
+    let msg_type = take(&mut data, 1)[0];
+    let length = take(&mut data, 3);
+    let length = u32::from_be_bytes([0, length[0], length[1], length[2]]) as usize;
+    assert_eq!(length, data.len(), "handshake length mismatch");
+
+    assert_eq!(take_u16(&mut data), 0x0303);
+    let random = take(&mut data, 32).to_vec();
+    let id_len = take(&mut data, 1)[0] as usize;
+    let session_id = take(&mut data, id_len).to_vec();
+    if msg_type == 0x01 {
+        let suites = take_u16(&mut data);
+        assert!(suites > 0 && suites % 2 == 0);
+        take(&mut data, suites);
+        let compression = take(&mut data, 1)[0] as usize;
+        assert_eq!(take(&mut data, compression), &[0x00]);
+    } else {
+        take(&mut data, 2);
+        assert_eq!(take(&mut data, 1)[0], 0x00);
+    }
+
+    let extensions_len = take_u16(&mut data);
+    assert_eq!(extensions_len, data.len(), "extensions length mismatch");
+    let mut extensions = HashMap::new();
+    while !data.is_empty() {
+        let extension_type = take_u16(&mut data) as u16;
+        let len = take_u16(&mut data);
+        let body = take(&mut data, len).to_vec();
+        assert!(
+            extensions.insert(extension_type, body).is_none(),
+            "duplicate extension"
+        );
+    }
+
+    ParsedHello {
+        msg_type,
+        random,
+        session_id,
+        extensions,
+    }
+}
+
+fn enabled_masking(server_name: &str) -> TlsMasking {
+    let mut masking = TlsMasking::new().with_server_name(server_name);
+    masking.enable().unwrap();
+    masking
+}
+
+#[tokio::test]
+async fn test_tls_masking_frames_application_data_records() {
+    let masking = enabled_masking("www.example.com");
+    let payload: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+
+    let wrapped = masking.wrap_connection(&payload).await.unwrap();
+    let records = parse_tls_stream(&wrapped);
+
+    assert_eq!(records.len(), 3);
+    let mut carried = 0;
+    for (content_type, version, body) in &records {
+        assert_eq!(*content_type, 0x17);
+        assert_eq!(*version, 0x0303);
+        assert!(body.len() <= MAX_RECORD_SIZE);
+        // Inner content type and a 16-byte tag follow the data.
+        assert_eq!(body[body.len() - 17], 0x17);
+        carried += body.len() - 17;
+    }
+    assert_eq!(carried, payload.len());
+    assert_eq!(parse_records(&wrapped).unwrap().len(), records.len());
+
+    assert_eq!(masking.unwrap_connection(&wrapped).await.unwrap(), payload);
+
+    // Small messages get a single record whose header matches its body.
+    let wrapped = masking.wrap_connection(b"hello").await.unwrap();
+    assert!(masking.validate_tls_frame(&wrapped));
+    assert_eq!(
+        u16::from_be_bytes([wrapped[3], wrapped[4]]) as usize,
+        5 + 17
+    );
+    assert_eq!(wrapped.len(), 5 + 5 + 17);
+
+    // Same input, different bytes on the wire.
+    let again = masking.wrap_connection(b"hello").await.unwrap();
+    assert_ne!(wrapped, again);
+}
+
+#[tokio::test]
+async fn test_tls_masking_unwrap_respects_record_boundaries() {
+    let masking = enabled_masking("www.example.com");
+
+    let mut stream = masking.wrap_connection(b"first ").await.unwrap();
+    stream.extend(masking.wrap_connection(b"second").await.unwrap());
+    assert_eq!(
+        masking.unwrap_connection(&stream).await.unwrap(),
+        b"first second"
+    );
+
+    let truncated = &stream[..stream.len() - 3];
+    assert!(masking.unwrap_connection(truncated).await.is_err());
+
+    let mut bad_length = stream.clone();
+    bad_length[4] = bad_length[4].wrapping_add(1);
+    assert!(masking.unwrap_connection(&bad_length).await.is_err());
+
+    let alert = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+    assert!(masking.unwrap_connection(&alert).await.is_err());
+
+    let disabled = TlsMasking::new();
+    assert_eq!(disabled.wrap_connection(b"raw").await.unwrap(), b"raw");
+}
+
+#[test]
+fn test_tls_client_hello_is_well_formed_and_random() {
+    let masking = enabled_masking("chat.example.org");
+
+    let first = masking.create_client_hello();
+    let second = masking.create_fake_handshake();
+
+    let records = parse_tls_stream(&first);
+    assert_eq!(records.len(), 1);
+    let (content_type, version, body) = &records[0];
+    assert_eq!(*content_type, 0x16);
+    assert_eq!(*version, 0x0301);
+
+    let hello = parse_hello(body);
+    assert_eq!(hello.msg_type, 0x01);
+    assert_eq!(hello.session_id.len(), 32);
+
+    let sni = &hello.extensions[&0x0000];
+    assert_eq!(&sni[5..], b"chat.example.org");
+    for extension in [0x000a, 0x000d, 0x0010, 0x002b, 0x002d, 0x0033] {
+        assert!(hello.extensions.contains_key(&extension));
+    }
+    let versions = &hello.extensions[&0x002b];
+    assert_eq!(&versions[1..3], &[0x03, 0x04]);
+
+    let other = parse_hello(&parse_tls_stream(&second)[0].2);
+    assert_ne!(hello.random, other.random);
+    assert_ne!(hello.session_id, other.session_id);
+    assert_ne!(hello.extensions[&0x0033], other.extensions[&0x0033]);
+}
+
+#[tokio::test]
+async fn test_tls_server_flight_answers_client_hello() {
+    let masking = enabled_masking("chat.example.org");
+    let client_hello = masking.create_client_hello();
+    let client_session = parse_hello(&parse_tls_stream(&client_hello)[0].2).session_id;
+
+    let flight = masking.create_server_hello(&client_hello).unwrap();
+    let records = parse_tls_stream(&flight);
+    let types: Vec<u8> = records.iter().map(|r| r.0).collect();
+    assert_eq!(types, vec![0x16, 0x14, 0x17]);
+
+    let hello = parse_hello(&records[0].2);
+    assert_eq!(hello.msg_type, 0x02);
+    assert_eq!(hello.session_id, client_session);
+    assert_eq!(hello.extensions[&0x002b], vec![0x03, 0x04]);
+
+    assert!(masking
+        .create_server_hello(&masking.wrap_connection(b"x").await.unwrap())
+        .is_err());
+}
+
+fn text_message(content: &str, id: &str) -> ProtocolMessage {
+    ProtocolMessage::create_text_message(
+        "alice-id".to_string(),
+        "bob-id".to_string(),
+        content.to_string(),
+        id.to_string(),
+    )
+}
+
+fn text_of(message: &ProtocolMessage) -> String {
+    match &message.payload {
+        MessagePayload::Text(text) => text.content.clone(),
+        _ => panic!("not a text message"),
+    }
+}
+
+#[tokio::test]
+async fn test_obfuscator_pads_frames_to_size_buckets() {
+    let obfuscator = TrafficObfuscator::new(ObfuscationConfig::default());
+
+    let mut sizes = Vec::new();
+    for length in [1, 300, 5_000, 70_000] {
+        let content = "x".repeat(length);
+        let message = text_message(&content, "msg");
+        let frame = obfuscator
+            .encode(std::slice::from_ref(&message))
+            .await
+            .unwrap();
+
+        let needed = 9 + 4 + message.to_bytes().unwrap().len();
+        assert!(frame.len() >= needed);
+        assert_eq!(frame.len(), obfuscator.padded_size(needed - 9));
+        assert!([1024, 4096, 16 * 1024].contains(&frame.len()) || frame.len() % (64 * 1024) == 0);
+        sizes.push(frame.len());
+
+        let decoded = obfuscator.decode(&frame).await.unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(text_of(&decoded[0]), content);
+    }
+    assert!(sizes.windows(2).all(|w| w[0] <= w[1]));
+    assert!(sizes[3] >= 128 * 1024);
+
+    // Short messages of different lengths cannot be told apart
+    let short = obfuscator
+        .encode(&[text_message("a", "msg")])
+        .await
+        .unwrap();
+    let longer = obfuscator
+        .encode(&[text_message(&"b".repeat(100), "msg")])
+        .await
+        .unwrap();
+    assert_eq!(short.len(), longer.len());
+    sizes.push(short.len());
+    sizes.push(longer.len());
+
+    // Cover frames are the size of the smallest bucket and carry nothing
+    let cover = obfuscator.cover_frame().await.unwrap();
+    assert_eq!(cover.len(), 1024);
+    assert!(obfuscator.decode(&cover).await.unwrap().is_empty());
+
+    let stats = obfuscator.stats();
+    assert_eq!(stats.cover_bytes, 1024);
+    assert_eq!(
+        stats.payload_bytes + stats.padding_bytes,
+        sizes.iter().sum::<usize>() as u64
+    );
+
+    // Without padding only the frame header is added
+    let plain = TrafficObfuscator::new(ObfuscationConfig::disabled());
+    let message = text_message("hi", "msg");
+    let frame = plain.encode(std::slice::from_ref(&message)).await.unwrap();
+    assert_eq!(frame.len(), 9 + 4 + message.to_bytes().unwrap().len());
+
+    assert!(obfuscator.decode(&frame[..3]).await.is_err());
+    let mut bad_kind = frame.clone();
+    bad_kind[0] = 0x7f;
+    assert!(plain.decode(&bad_kind).await.is_err());
+    let mut overlong = frame;
+    overlong[1] = 0xff;
+    assert!(plain.decode(&overlong).await.is_err());
+}
+
+#[tokio::test]
+async fn test_obfuscated_frames_travel_as_tls_records() {
+    let mut masking = TlsMasking::new();
+    masking.enable().unwrap();
+    let obfuscator = TrafficObfuscator::new(ObfuscationConfig::default()).with_tls_masking(masking);
+
+    let batch = [text_message("one", "msg-1"), text_message("two", "msg-2")];
+    let frame = obfuscator.encode(&batch).await.unwrap();
+
+    let records = parse_records(&frame).unwrap();
+    assert!(records.iter().all(|r| r.content_type == 0x17));
+    // One bucket of content plus the record header and trailer
+    assert!([1024, 4096].contains(&(frame.len() - 5 - 17)));
+
+    let decoded = obfuscator.decode(&frame).await.unwrap();
+    let texts: Vec<String> = decoded.iter().map(text_of).collect();
+    assert_eq!(texts, vec!["one", "two"]);
+}
+
+#[tokio::test]
+async fn test_connections_carry_masked_padded_frames() {
+    use tokio::io::AsyncReadExt;
+
+    let masked = || {
+        let mut masking = TlsMasking::new();
+        masking.enable().unwrap();
+        TrafficObfuscator::new(ObfuscationConfig {
+            batch_jitter: Duration::from_millis(10),
+            ..ObfuscationConfig::default()
+        })
+        .with_tls_masking(masking)
+    };
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network.clone()));
+    alice.set_obfuscation(masked());
+    bob.set_obfuscation(masked());
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let mut bob_events = bob_bus.subscribe();
+
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+    let connection = alice.dial(std::slice::from_ref(&endpoint)).await.unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    while !alice.has_connection("bob-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    alice
+        .send_protocol_message(text_message("masked", "msg-1"))
+        .await
+        .unwrap();
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.content, "masked");
+            break;
+        }
+    }
+    let stats = alice.get_network_stats().await.unwrap();
+    assert!(stats.payload_bytes_sent > 0 && stats.padding_bytes_sent > 0);
+
+    // On the socket, even the handshake is a TLS record of a bucket's size
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let connection = alice
+        .dial(&[format!("tcp://{}", address).parse().unwrap()])
+        .await
+        .unwrap();
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut header = [0u8; 5];
+    socket.read_exact(&mut header).await.unwrap();
+    let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let mut record = header.to_vec();
+    record.resize(5 + length, 0);
+    socket.read_exact(&mut record[5..]).await.unwrap();
+    let records = parse_records(&record).unwrap();
+    assert_eq!(records[0].content_type, 0x17);
+    assert_eq!(length - 17, 1024);
+}
+
+#[tokio::test]
+async fn test_cover_traffic_sends_at_constant_rate() {
+    let obfuscator = Arc::new(TrafficObfuscator::new(ObfuscationConfig {
+        cover_traffic: true,
+        cover_interval: Duration::from_millis(20),
+        ..ObfuscationConfig::default()
+    }));
+    let (outgoing, queue) = mpsc::unbounded_channel();
+    let (wire, mut frames) = mpsc::unbounded_channel();
+    let mut link = ObfuscatedLink::spawn(obfuscator.clone(), queue, wire);
+
+    let mut collected = Vec::new();
+    for round in 0..8 {
+        if round == 4 {
+            outgoing.send(text_message("hello", "msg-1")).unwrap();
+        }
+        let frame = tokio::time::timeout(Duration::from_secs(1), frames.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        collected.push(frame);
+    }
+    link.stop().await;
+
+    // Every frame looks the same from outside; exactly one carries the message
+    assert!(collected.iter().all(|f| f.len() == 1024));
+    let mut delivered = Vec::new();
+    for frame in &collected {
+        delivered.extend(obfuscator.decode(frame).await.unwrap());
+    }
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(text_of(&delivered[0]), "hello");
+
+    let stats = obfuscator.stats();
+    assert_eq!(stats.cover_bytes, 7 * 1024);
+}
+
+#[tokio::test]
+async fn test_jittered_batching_coalesces_messages() {
+    let obfuscator = Arc::new(TrafficObfuscator::new(ObfuscationConfig {
+        batch_jitter: Duration::from_millis(100),
+        ..ObfuscationConfig::default()
+    }));
+    let (outgoing, queue) = mpsc::unbounded_channel();
+    let (wire, mut frames) = mpsc::unbounded_channel();
+    let _link = ObfuscatedLink::spawn(obfuscator.clone(), queue, wire);
+
+    // Whatever is queued before the first message's delay runs out goes
+    // out together
+    for i in 0..3 {
+        outgoing
+            .send(text_message(&format!("part {}", i), &format!("msg-{}", i)))
+            .unwrap();
+    }
+    let frame = tokio::time::timeout(Duration::from_secs(1), frames.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let texts: Vec<String> = obfuscator
+        .decode(&frame)
+        .await
+        .unwrap()
+        .iter()
+        .map(text_of)
+        .collect();
+    assert_eq!(texts, vec!["part 0", "part 1", "part 2"]);
+
+    // No cover traffic unless asked for
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), frames.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_obfuscated_connection_between_managers_reports_overhead() {
+    let config: NetworkConfig =
+        toml::from_str("port = 8123\nmax_peers = 10\nenable_discovery = true\n").unwrap();
+    assert!(!config.tls_masking);
+    assert!(!config.obfuscation_config().padding);
+    let config = NetworkConfig {
+        tls_masking: true,
+        traffic_obfuscation: true,
+        ..config
+    };
+    let obfuscation = config.obfuscation_config();
+    assert!(obfuscation.padding && !obfuscation.cover_traffic);
+    assert!(!obfuscation.batch_jitter.is_zero());
+
+    let obfuscated = || {
+        let mut masking = TlsMasking::new();
+        masking.enable().unwrap();
+        TrafficObfuscator::new(ObfuscationConfig {
+            batch_jitter: Duration::from_millis(10),
+            ..config.obfuscation_config()
+        })
+        .with_tls_masking(masking)
+    };
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    alice.set_obfuscation(obfuscated());
+    bob.set_obfuscation(obfuscated());
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let mut bob_events = bob_bus.subscribe();
+
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+    let connection = alice.dial(&[endpoint]).await.unwrap();
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let client = alice.clone();
+    let serving = tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    while !alice.has_connection("bob-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    alice
+        .send_protocol_message(text_message("over the wire", "msg-1"))
+        .await
+        .unwrap();
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.content, "over the wire");
+            break;
+        }
+    }
+
+    let stats = alice.get_network_stats().await.unwrap();
+    assert!(stats.payload_bytes_sent > 0);
+    assert!(stats.padding_bytes_sent > 0);
+    assert!(stats.obfuscation_overhead() > 0.0);
+
+    alice.detach_connection("bob-id").await;
+    serving.await.unwrap().unwrap();
+    accepted.await.unwrap().unwrap();
+}
+
+/// What a client asked the SOCKS5 stand-in for.
+#[derive(Debug, Clone, PartialEq)]
+struct SocksRequest {
+    username: Option<String>,
+    address_type: u8,
+    host: String,
+    port: u16,
+}
+
+/// A local SOCKS5 proxy standing in for Tor. It records each request,
+/// refuses connections to `refused_port` and otherwise echoes what it
+/// is sent.
+async fn socks_stand_in(refused_port: u16) -> (SocketAddr, Arc<RwLock<Vec<SocksRequest>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(RwLock::new(Vec::new()));
+    let recorded = requests.clone();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                stream.read_exact(&mut greeting).await?;
+                let mut methods = vec![0u8; greeting[1] as usize];
+                stream.read_exact(&mut methods).await?;
+                let method = if methods.contains(&0x02) { 0x02 } else { 0x00 };
+                stream.write_all(&[0x05, method]).await?;
+
+                let mut username = None;
+                if method == 0x02 {
+                    let mut header = [0u8; 2];
+                    stream.read_exact(&mut header).await?;
+                    let mut user = vec![0u8; header[1] as usize];
+                    stream.read_exact(&mut user).await?;
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len).await?;
+                    let mut password = vec![0u8; len[0] as usize];
+                    stream.read_exact(&mut password).await?;
+                    username = Some(String::from_utf8(user).unwrap());
+                    stream.write_all(&[0x01, 0x00]).await?;
+                }
+
+                let mut request = [0u8; 4];
+                stream.read_exact(&mut request).await?;
+                let host = match request[3] {
+                    0x01 => {
+                        let mut ip = [0u8; 4];
+                        stream.read_exact(&mut ip).await?;
+                        Ipv4Addr::from(ip).to_string()
+                    }
+                    0x03 => {
+                        let mut len = [0u8; 1];
+                        stream.read_exact(&mut len).await?;
+                        let mut name = vec![0u8; len[0] as usize];
+                        stream.read_exact(&mut name).await?;
+                        String::from_utf8(name).unwrap()
+                    }
+                    _ => panic!("unexpected address type"),
+                };
+                let mut port = [0u8; 2];
+                stream.read_exact(&mut port).await?;
+                let port = u16::from_be_bytes(port);
+                recorded.write().await.push(SocksRequest {
+                    username,
+                    address_type: request[3],
+                    host,
+                    port,
+                });
+
+                let status = if port == refused_port { 0x05 } else { 0x00 };
+                stream
+                    .write_all(&[0x05, status, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await?;
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                Ok::<_, io::Error>(())
+            });
+        }
+    });
+
+    (addr, requests)
+}
+
+fn tor_config(socks_proxy: SocketAddr) -> TorConfig {
+    TorConfig {
+        socks_proxy,
+        connect_timeout: Duration::from_secs(2),
+        ..TorConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_dialer_resolves_remotely_and_isolates_contacts() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (proxy, requests) = socks_stand_in(0).await;
+    let dialer = Socks5Dialer::new(tor_config(proxy));
+
+    let onion = "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx.onion";
+    let mut stream = dialer
+        .connect(onion, 8080, Some("contact-a"))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut echo = [0u8; 4];
+    stream.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"ping");
+
+    dialer
+        .connect_address("peer.example.com:9000", Some("contact-b"))
+        .await
+        .unwrap();
+    dialer.connect("10.1.2.3", 7000, None).await.unwrap();
+
+    let requests = requests.read().await.clone();
+    assert_eq!(
+        requests,
+        vec![
+            // Names reach the proxy unresolved
+            SocksRequest {
+                username: Some("contact-a".to_string()),
+                address_type: 0x03,
+                host: onion.to_string(),
+                port: 8080,
+            },
+            SocksRequest {
+                username: Some("contact-b".to_string()),
+                address_type: 0x03,
+                host: "peer.example.com".to_string(),
+                port: 9000,
+            },
+            SocksRequest {
+                username: None,
+                address_type: 0x01,
+                host: "10.1.2.3".to_string(),
+                port: 7000,
+            },
+        ]
+    );
+
+    // Without isolation every stream may share a circuit
+    let shared = Socks5Dialer::new(TorConfig {
+        isolate_streams: false,
+        ..tor_config(proxy)
+    });
+    shared
+        .connect("peer.example.com", 9000, Some("contact-a"))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_socks5_dialer_reports_refusal() {
+    let (proxy, _) = socks_stand_in(4444).await;
+    let dialer = Socks5Dialer::new(tor_config(proxy));
+
+    let error = dialer
+        .connect("gone.example.com", 4444, Some("contact-a"))
+        .await
+        .unwrap_err();
+    assert_eq!(error, TorError::Socks(0x05));
+    assert!(error.to_string().contains("connection refused"));
+
+    assert!(dialer.connect_address("no-port", None).await.is_err());
+
+    // Nothing listening where the proxy should be
+    let unused = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let dialer = Socks5Dialer::new(tor_config(unused));
+    assert!(matches!(
+        dialer.connect("peer.example.com", 1, None).await,
+        Err(TorError::Io(_))
+    ));
+}
+
+/// A Tor control port stand-in that accepts `password` (or the cookie, when
+/// one is given) and answers ADD_ONION with a fixed service.
+async fn control_stand_in(
+    password: &'static str,
+    cookie: Option<std::path::PathBuf>,
+) -> (SocketAddr, Arc<RwLock<Vec<String>>>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let commands = Arc::new(RwLock::new(Vec::new()));
+    let recorded = commands.clone();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let recorded = recorded.clone();
+            let cookie = cookie.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    recorded.write().await.push(line.clone());
+                    let reply = if line == "PROTOCOLINFO 1" {
+                        match &cookie {
+                            Some(path) => format!(
+                                "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"{}\"\r\n250-VERSION Tor=\"0.4.8.9\"\r\n250 OK\r\n",
+                                path.display()
+                            ),
+                            None => "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n250-VERSION Tor=\"0.4.8.9\"\r\n250 OK\r\n".to_string(),
+                        }
+                    } else if line == format!("AUTHENTICATE \"{}\"", password)
+                        || (cookie.is_some() && line == "AUTHENTICATE 636f6f6b6965")
+                    {
+                        "250 OK\r\n".to_string()
+                    } else if line.starts_with("AUTHENTICATE") {
+                        "515 Authentication failed: Password did not match\r\n".to_string()
+                    } else if line.starts_with("ADD_ONION NEW:") {
+                        "250-ServiceID=exampleonionserviceid\r\n250-PrivateKey=ED25519-V3:c2VjcmV0\r\n250 OK\r\n".to_string()
+                    } else if line.starts_with("ADD_ONION ") {
+                        "250-ServiceID=exampleonionserviceid\r\n250 OK\r\n".to_string()
+                    } else if line.starts_with("DEL_ONION ") {
+                        "250 OK\r\n".to_string()
+                    } else {
+                        "510 Unrecognized command\r\n".to_string()
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    (addr, commands)
+}
+
+#[tokio::test]
+async fn test_onion_service_published_and_shared_in_sg_link() {
+    let (control_address, commands) = control_stand_in("secret", None).await;
+    let config = TorConfig {
+        control_address,
+        control_password: Some("secret".to_string()),
+        ..tor_config("127.0.0.1:9".parse().unwrap())
+    };
+
+    let onion = TorController::connect(&config)
+        .await
+        .unwrap()
+        .publish_onion(8080, "127.0.0.1:8080".parse().unwrap(), None)
+        .await
+        .unwrap();
+    assert_eq!(onion.address(), "exampleonionserviceid.onion:8080");
+    assert_eq!(onion.private_key(), Some("ED25519-V3:c2VjcmV0"));
+
+    // The link carries the onion address, and contacts made from it dial it
+    let mut peer = Peer::new("alice".to_string(), "0.0.0.0:8080".to_string());
+    peer.set_onion_address(Some(onion.address()));
+    let link = generate_sg_link(&peer).unwrap();
+    let contact = parse_sg_link(&link, "bob").unwrap();
+    assert_eq!(
+        contact.addresses,
+        vec![Endpoint::Tor {
+            host: "exampleonionserviceid.onion".to_string(),
+            port: 8080
+        }]
+    );
+
+    let (proxy, requests) = socks_stand_in(0).await;
+    Socks5Dialer::new(tor_config(proxy))
+        .connect_contact(&contact)
+        .await
+        .unwrap();
+    assert_eq!(
+        requests.read().await[0],
+        SocksRequest {
+            username: Some(contact.id.clone()),
+            address_type: 0x03,
+            host: "exampleonionserviceid.onion".to_string(),
+            port: 8080,
+        }
+    );
+
+    // Links without an onion keep the plain address
+    let plain = Peer::new("carol".to_string(), "192.0.2.1:9000".to_string());
+    let contact = parse_sg_link(&generate_sg_link(&plain).unwrap(), "bob").unwrap();
+    assert_eq!(
+        contact.addresses,
+        vec!["tcp://192.0.2.1:9000".parse().unwrap()]
+    );
+
+    onion.remove().await.unwrap();
+    let commands = commands.read().await.clone();
+    assert_eq!(
+        commands,
+        vec![
+            "PROTOCOLINFO 1".to_string(),
+            "AUTHENTICATE \"secret\"".to_string(),
+            "ADD_ONION NEW:ED25519-V3 Port=8080,127.0.0.1:8080".to_string(),
+            "DEL_ONION exampleonionserviceid".to_string(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_tor_control_authentication() {
+    let temp = TempDir::new().unwrap();
+    let cookie = temp.path().join("control_auth_cookie");
+    std::fs::write(&cookie, b"cookie").unwrap();
+
+    // Cookie authentication, and a saved key brings back the same service
+    let (control_address, commands) = control_stand_in("secret", Some(cookie)).await;
+    let config = TorConfig {
+        control_address,
+        ..TorConfig::default()
+    };
+    let onion = TorController::connect(&config)
+        .await
+        .unwrap()
+        .publish_onion(
+            9000,
+            "127.0.0.1:9001".parse().unwrap(),
+            Some("ED25519-V3:c2VjcmV0"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(onion.service_id(), "exampleonionserviceid");
+    assert_eq!(onion.private_key(), Some("ED25519-V3:c2VjcmV0"));
+    assert_eq!(
+        commands.read().await[1..],
+        [
+            "AUTHENTICATE 636f6f6b6965".to_string(),
+            "ADD_ONION ED25519-V3:c2VjcmV0 Port=9000,127.0.0.1:9001".to_string(),
+        ]
+    );
+
+    // A wrong password is reported as such
+    let (control_address, _) = control_stand_in("secret", None).await;
+    let config = TorConfig {
+        control_address,
+        control_password: Some("wrong".to_string()),
+        ..TorConfig::default()
+    };
+    assert!(matches!(
+        TorController::connect(&config).await,
+        Err(TorError::AuthenticationFailed(_))
+    ));
+}
+
+#[test]
+fn test_endpoints_parse_by_scheme_and_read_legacy_contacts() {
+    for text in [
+        "tcp://127.0.0.1:8000",
+        "quic://[::1]:9000",
+        "tor://exampleonionserviceid.onion:80",
+        "memory://bob",
+    ] {
+        let endpoint: Endpoint = text.parse().unwrap();
+        assert_eq!(endpoint.to_string(), text);
+    }
+    assert_eq!(
+        "10.0.0.1:8000".parse::<Endpoint>().unwrap(),
+        Endpoint::Tcp("10.0.0.1:8000".parse().unwrap())
+    );
+    assert_eq!(
+        "exampleonionserviceid.onion:80"
+            .parse::<Endpoint>()
+            .unwrap()
+            .scheme(),
+        "tor"
+    );
+    assert!(matches!(
+        "ws://10.0.0.1:80".parse::<Endpoint>(),
+        Err(TransportError::UnsupportedScheme(scheme)) if scheme == "ws"
+    ));
+    assert!(matches!(
+        "tcp://nowhere".parse::<Endpoint>(),
+        Err(TransportError::InvalidEndpoint(_))
+    ));
+
+    // Profiles used to store a single address string
+    let legacy: Contact = serde_json::from_str(
+        r#"{"id":"carol-id","name":"carol","address":"10.0.0.1:8000",
+            "status":"Online","trust_level":"Trusted","last_seen":null}"#,
+    )
+    .unwrap();
+    assert_eq!(legacy.tcp_address(), Some("10.0.0.1:8000".parse().unwrap()));
+
+    let stored = serde_json::to_value(&legacy).unwrap();
+    assert_eq!(
+        stored["addresses"],
+        serde_json::json!(["tcp://10.0.0.1:8000"])
+    );
+    let restored: Contact = serde_json::from_value(stored).unwrap();
+    assert_eq!(restored.addresses, legacy.addresses);
+}
+
+#[tokio::test]
+async fn test_tcp_transport_frames_protocol_messages() {
+    let transport = TcpTransport::new();
+    let mut listener = transport
+        .listen(&"tcp://127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let endpoint = listener.local_endpoint();
+
+    let (dialed, accepted) = tokio::join!(transport.dial(&endpoint), listener.accept());
+    let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+    assert_eq!(dialed.remote_endpoint(), &endpoint);
+    assert_eq!(accepted.remote_endpoint().scheme(), "tcp");
+
+    for i in 0..3 {
+        dialed
+            .send(&text_message(
+                &format!("message {}", i),
+                &format!("msg-{}", i),
+            ))
+            .await
+            .unwrap();
+    }
+    for i in 0..3 {
+        let message = accepted.recv().await.unwrap().unwrap();
+        assert_eq!(text_of(&message), format!("message {}", i));
+    }
+    accepted
+        .send(&text_message("reply", "msg-reply"))
+        .await
+        .unwrap();
+    assert_eq!(text_of(&dialed.recv().await.unwrap().unwrap()), "reply");
+
+    drop(dialed);
+    assert!(accepted.recv().await.unwrap().is_none());
+
+    // An oversized length prefix is refused rather than allocated
+    let (mut raw, stream) = tokio::io::duplex(64);
+    let mut connection = Connection::from_stream(Endpoint::Memory("raw".to_string()), stream);
+    tokio::io::AsyncWriteExt::write_all(&mut raw, &u32::MAX.to_be_bytes())
+        .await
+        .unwrap();
+    assert!(matches!(
+        connection.recv().await,
+        Err(TransportError::Malformed(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_acceptor_serves_dialers_until_stopped() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, _) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let listener = bob.listen(&endpoint).await.unwrap();
+    let mut acceptor = ConnectionAcceptor::start(bob.clone(), listener);
+    assert_eq!(acceptor.local_endpoint(), &endpoint);
+
+    let connection = alice.dial(std::slice::from_ref(&endpoint)).await.unwrap();
+    let client = alice.clone();
+    let serving = tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !bob.has_connection("alice-id").await {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // Stopping ends the connection and lets go of bob
+    acceptor.stop().await;
+    assert!(serving.await.unwrap().is_ok());
+    assert_eq!(Arc::strong_count(&bob), 1);
+    assert!(alice.dial(std::slice::from_ref(&endpoint)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_managers_exchange_messages_over_memory_transport() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let alice_id = alice.get_peer().await.id;
+    let mut bob_events = bob_bus.subscribe();
+
+    let mut listener = bob.listen(&"memory://bob".parse().unwrap()).await.unwrap();
+    assert_eq!(listener.local_endpoint().to_string(), "memory://bob");
+    assert!(bob.listen(&"memory://bob".parse().unwrap()).await.is_err());
+
+    // Endpoints without a registered transport are skipped
+    let endpoints: Vec<Endpoint> = vec![
+        "quic://127.0.0.1:9".parse().unwrap(),
+        "memory://bob".parse().unwrap(),
+    ];
+    assert!(matches!(
+        alice.dial(&endpoints[..1]).await,
+        Err(TransportError::UnsupportedScheme(_))
+    ));
+    let connection = alice.dial(&endpoints).await.unwrap();
+    assert_eq!(connection.remote_endpoint(), &endpoints[1]);
+
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let client = alice.clone();
+    let serving = tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+
+    while !alice.has_connection("bob-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    alice
+        .send_protocol_message(text_message("in memory", "msg-1"))
+        .await
+        .unwrap();
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.content, "in memory");
+            break;
+        }
+    }
+    assert!(bob.has_connection(&alice_id).await);
+
+    // Detaching closes the connection, and the other side notices
+    alice.detach_connection("bob-id").await;
+    serving.await.unwrap().unwrap();
+    accepted.await.unwrap().unwrap();
+    assert!(!bob.has_connection(&alice_id).await);
+}
+
+#[tokio::test]
+async fn test_removed_peer_key_refuses_the_peer() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, _) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network.clone()));
+    introduce(&alice, &bob).await;
+    bob.remove_peer_key("alice-id").await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+
+    let connection = alice.dial(std::slice::from_ref(&endpoint)).await.unwrap();
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let _ = alice.serve_connection("bob-id", connection).await;
+    assert!(matches!(
+        accepted.await.unwrap(),
+        Err(TransportError::Unauthenticated(_))
+    ));
+    assert!(!bob.has_connection("alice-id").await);
+}
+
+#[tokio::test]
+async fn test_contact_without_key_needs_verification() {
+    let network = MemoryTransport::new();
+    let (mut alice, alice_bus) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network.clone()));
+    // Each saved the other before contacts carried keys
+    alice.set_peer_key("bob-id", Vec::new()).await;
+    bob.set_peer_key("alice-id", Vec::new()).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let (mut alice_events, mut bob_events) = (alice_bus.subscribe(), bob_bus.subscribe());
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+
+    // Dialing such a contact is refused up front
+    let connection = alice.dial(std::slice::from_ref(&endpoint)).await.unwrap();
+    assert!(matches!(
+        alice.serve_connection("bob-id", connection).await,
+        Err(TransportError::Unauthenticated(_))
+    ));
+    loop {
+        if let NetworkEvent::ContactNeedsVerification { peer_id } =
+            next_network_event(&mut alice_events).await
+        {
+            assert_eq!(peer_id, "bob-id");
+            break;
+        }
+    }
+    let _ = listener.accept().await.unwrap();
+
+    // So is being dialed by one
+    let (alice_crypto, alice_key) = identity();
+    let mut connection = network.dial(&endpoint).await.unwrap();
+    plain_frames(&mut connection);
+    connection.send(&hello("alice-id")).await.unwrap();
+    assert!(matches!(
+        bob.accept_connection(listener.accept().await.unwrap())
+            .await,
+        Err(TransportError::Unauthenticated(_))
+    ));
+    loop {
+        if let NetworkEvent::ContactNeedsVerification { peer_id } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(peer_id, "alice-id");
+            break;
+        }
+    }
+
+    // Once added again with a key, the contact connects
+    bob.set_peer_key("alice-id", alice_key).await;
+    let mut connection = network.dial(&endpoint).await.unwrap();
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        server
+            .accept_connection(listener.accept().await.unwrap())
+            .await
+    });
+    dial_handshake(&mut connection, &*alice_crypto.read().await, "alice-id").await;
+    while !bob.has_connection("alice-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    drop(connection);
+    let _ = accepted.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connections_are_authenticated_against_known_keys() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    // Claims to be alice, without her key
+    let (mut mallory, _) = manager("alice");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network.clone()));
+    mallory.register_transport(Arc::new(network.clone()));
+    introduce(&alice, &bob).await;
+    mallory
+        .set_peer_key("bob-id", bob.get_peer().await.public_key)
+        .await;
+    let (carol_crypto, carol_key) = identity();
+    bob.set_peer_key("carol", carol_key).await;
+    let (alice, bob, mallory) = (Arc::new(alice), Arc::new(bob), Arc::new(mallory));
+    let mut bob_events = bob_bus.subscribe();
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+
+    // Mail waiting for alice is not handed to whoever claims to be her
+    bob.send_or_queue(ProtocolMessage::create_text_message(
+        "bob-id".to_string(),
+        "alice-id".to_string(),
+        "for alice".to_string(),
+        "waiting".to_string(),
+    ))
+    .await
+    .unwrap();
+    let connection = mallory.dial(std::slice::from_ref(&endpoint)).await.unwrap();
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        let result = server.accept_connection(connection).await;
+        (listener, result)
+    });
+    let _ = mallory.serve_connection("bob-id", connection).await;
+    let (mut listener, result) = accepted.await.unwrap();
+    assert!(matches!(result, Err(TransportError::Unauthenticated(_))));
+    assert!(!bob.has_connection("alice-id").await);
+    assert_eq!(bob.outbox_len().await, 1);
+
+    // Peers we hold no key for are refused as well
+    let mut stranger = network.dial(&endpoint).await.unwrap();
+    plain_frames(&mut stranger);
+    stranger.send(&hello("stranger")).await.unwrap();
+    assert!(matches!(
+        bob.accept_connection(listener.accept().await.unwrap())
+            .await,
+        Err(TransportError::Unauthenticated(_))
+    ));
+    assert!(stranger.recv().await.unwrap().is_none());
+
+    // The real alice gets her mail
+    let connection = alice.dial(std::slice::from_ref(&endpoint)).await.unwrap();
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await.unwrap();
+        listener
+    });
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    while bob.outbox_len().await > 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert!(bob.has_connection("alice-id").await);
+    bob.detach_connection("alice-id").await;
+    let mut listener = accepted.await.unwrap();
+
+    // A dialed peer has to prove it is who we meant to reach
+    let mut impostor = network
+        .listen(&"memory://not-bob".parse().unwrap())
+        .await
+        .unwrap();
+    let connection = alice
+        .dial(&["memory://not-bob".parse().unwrap()])
+        .await
+        .unwrap();
+    let (impostor_crypto, _) = identity();
+    tokio::spawn(async move {
+        let mut connection = impostor.accept().await.unwrap();
+        accept_handshake(&mut connection, &*impostor_crypto.read().await, "bob-id").await;
+    });
+    assert!(matches!(
+        alice.serve_connection("bob-id", connection).await,
+        Err(TransportError::Unauthenticated(_))
+    ));
+
+    // Messages are taken only as coming from the peer that proved itself
+    let mut carol = network.dial(&endpoint).await.unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    dial_handshake(&mut carol, &*carol_crypto.read().await, "carol").await;
+    carol
+        .send(&message_from("alice-id", "forged"))
+        .await
+        .unwrap();
+    carol.send(&message_from("carol", "genuine")).await.unwrap();
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.id, "genuine");
+            break;
+        }
+    }
+}
+
+/// Relays messages from `dialer` to `acceptor` and back, letting `tamper`
+/// see and change each one on its way to the acceptor.
+fn relay_messages(
+    dialer: Connection,
+    acceptor: Connection,
+    mut tamper: impl FnMut(&mut ProtocolMessage) + Send + 'static,
+) {
+    let (mut dialer_reader, mut dialer_writer) = dialer.split();
+    let (mut acceptor_reader, mut acceptor_writer) = acceptor.split();
+    tokio::spawn(async move {
+        while let Ok(Some(mut message)) = dialer_reader.recv().await {
+            tamper(&mut message);
+            if acceptor_writer.send(&message).await.is_err() {
+                break;
+            }
+        }
+        let _ = acceptor_writer.close().await;
+    });
+    tokio::spawn(async move {
+        while let Ok(Some(message)) = acceptor_reader.recv().await {
+            if dialer_writer.send(&message).await.is_err() {
+                break;
+            }
+        }
+        let _ = dialer_writer.close().await;
+    });
+}
+
+/// Dials bob as `alice` through a middlebox listening on
+/// `memory://middle`, which relays what it sees through `tamper`.
+async fn dial_through(
+    alice: &NetworkManager,
+    network: &MemoryTransport,
+    middlebox: &mut Box<dyn Listener>,
+    tamper: Box<dyn FnMut(&mut ProtocolMessage) + Send>,
+) -> Connection {
+    let connection = alice
+        .dial(&["memory://middle".parse().unwrap()])
+        .await
+        .unwrap();
+    let mut inbound = middlebox.accept().await.unwrap();
+    let mut outbound = network
+        .dial(&"memory://bob".parse().unwrap())
+        .await
+        .unwrap();
+    plain_frames(&mut inbound);
+    plain_frames(&mut outbound);
+    relay_messages(inbound, outbound, tamper);
+    connection
+}
+
+#[tokio::test]
+async fn test_session_keys_defeat_a_relaying_middlebox() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network.clone()));
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let mut bob_events = bob_bus.subscribe();
+
+    let bob_endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&bob_endpoint).await.unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        while let Ok(connection) = listener.accept().await {
+            let server = server.clone();
+            tokio::spawn(async move { server.accept_connection(connection).await });
+        }
+    });
+    let mut middlebox = network
+        .listen(&"memory://middle".parse().unwrap())
+        .await
+        .unwrap();
+
+    // Passed through unchanged, the handshake succeeds, but nothing after
+    // it can be read on the way
+    let (seen_tx, mut seen) = mpsc::unbounded_channel();
+    let connection = dial_through(
+        &alice,
+        &network,
+        &mut middlebox,
+        Box::new(move |message: &mut ProtocolMessage| {
+            let _ = seen_tx.send(message.clone());
+        }),
+    )
+    .await;
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    while !alice.has_connection("bob-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    alice
+        .send_protocol_message(text_message("top secret", "msg-1"))
+        .await
+        .unwrap();
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.content, "top secret");
+            break;
+        }
+    }
+    let mut after_handshake = 0;
+    while let Ok(message) = seen.try_recv() {
+        match message.payload {
+            MessagePayload::Auth(_) => {}
+            MessagePayload::Sealed(sealed) => {
+                assert!(!sealed
+                    .ciphertext
+                    .windows(b"top secret".len())
+                    .any(|window| window == b"top secret"));
+                after_handshake += 1;
+            }
+            _ => panic!("plaintext went past the middlebox"),
+        }
+    }
+    assert!(after_handshake > 0);
+    alice.detach_connection("bob-id").await;
+    while bob.has_connection("alice-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Altering what it relays gets the connection dropped, and nothing
+    // altered is taken
+    alice
+        .send_or_queue(text_message("altered", "msg-2"))
+        .await
+        .unwrap();
+    let connection = dial_through(
+        &alice,
+        &network,
+        &mut middlebox,
+        Box::new(|message: &mut ProtocolMessage| {
+            if let MessagePayload::Sealed(sealed) = &mut message.payload {
+                sealed.ciphertext[0] ^= 0x01;
+            }
+        }),
+    )
+    .await;
+    alice.serve_connection("bob-id", connection).await.unwrap();
+    while bob.has_connection("alice-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Swapping in a key share of its own fails the handshake
+    let connection = dial_through(
+        &alice,
+        &network,
+        &mut middlebox,
+        Box::new(|message: &mut ProtocolMessage| {
+            if let MessagePayload::Auth(AuthPayload::Hello { share, .. }) = &mut message.payload {
+                *share = KeyShare::new().public();
+            }
+        }),
+    )
+    .await;
+    assert!(matches!(
+        alice.serve_connection("bob-id", connection).await,
+        Err(TransportError::Unauthenticated(_))
+    ));
+
+    while let Ok(event) = tokio::time::timeout(Duration::from_millis(50), bob_events.recv()).await {
+        if let Ok(AppEvent::Network(NetworkEvent::MessageReceived { message, .. })) = event {
+            assert_ne!(message.content, "altered");
+        }
+    }
+}
+
+#[test]
+fn test_keepalive_tracks_rtt_jitter_and_missed_pings() {
+    let mut keepalive = Keepalive::new(KeepaliveConfig {
+        interval: Duration::from_secs(1),
+        max_missed: 2,
+    });
+    assert_eq!(keepalive.next_ping(), Some(1));
+    assert!(keepalive.record_pong(1, 1_000, 1_100).is_some());
+    assert_eq!(keepalive.next_ping(), Some(2));
+    let latency = keepalive.record_pong(2, 2_000, 2_140).unwrap().clone();
+    assert_eq!(latency.last_rtt_ms, 140);
+    assert_eq!(latency.smoothed_rtt_ms, 105.0);
+    assert_eq!(latency.jitter_ms, 2.5);
+    assert_eq!(latency.samples, 2);
+
+    // Repeated, stale and unsolicited pongs are ignored
+    assert!(keepalive.record_pong(2, 2_000, 2_500).is_none());
+    assert!(keepalive.record_pong(1, 1_000, 2_500).is_none());
+    assert!(keepalive.record_pong(9, 1_000, 2_500).is_none());
+    assert_eq!(keepalive.latency(), Some(&latency));
+
+    assert_eq!(keepalive.next_ping(), Some(3));
+    assert_eq!(keepalive.next_ping(), Some(4));
+    assert_eq!(keepalive.missed(), 1);
+    // A late answer still counts and resets the streak
+    assert!(keepalive.record_pong(3, 3_000, 3_200).is_some());
+    assert_eq!(keepalive.missed(), 0);
+    assert_eq!(keepalive.next_ping(), Some(5));
+    assert_eq!(keepalive.next_ping(), None);
+}
+
+#[tokio::test]
+async fn test_keepalive_measures_latency_between_managers() {
+    let network = MemoryTransport::new();
+    let (mut alice, alice_bus) = manager("alice");
+    let (mut bob, _) = manager("bob");
+    alice.set_keepalive(KeepaliveConfig {
+        interval: Duration::from_millis(30),
+        max_missed: 3,
+    });
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    let bob_id = bob.get_peer().await.id;
+    alice.add_peer(PeerData {
+        id: bob_id.clone(),
+        name: "bob".to_string(),
+        address: "memory://bob".to_string(),
+        public_key: Vec::new(),
+        connected_at: chrono::Utc::now(),
+        last_seen: chrono::Utc::now(),
+        bytes_sent: 0,
+        bytes_received: 0,
+        onion_address: None,
+        latency: None,
+    });
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let mut events = alice_bus.subscribe();
+
+    assert!(alice.ping("bob").await.is_err());
+
+    let mut listener = bob.listen(&"memory://bob".parse().unwrap()).await.unwrap();
+    let connection = alice
+        .dial(&["memory://bob".parse().unwrap()])
+        .await
+        .unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let (client, served) = (alice.clone(), bob_id.clone());
+    tokio::spawn(async move { client.serve_connection(&served, connection).await });
+
+    loop {
+        if let NetworkEvent::LatencyMeasured {
+            peer_id, latency, ..
+        } = next_network_event(&mut events).await
+        {
+            assert_eq!(peer_id, bob_id);
+            assert_eq!(latency.samples, 1);
+            break;
+        }
+    }
+
+    // An explicit ping is answered like the periodic ones
+    let latency = alice.ping("bob").await.unwrap();
+    assert!(latency.samples >= 2);
+    assert!(latency.last_rtt_ms < 1_000);
+    let peers = alice.get_connected_peers();
+    assert!(peers[0].latency.as_ref().unwrap().samples >= latency.samples);
+    assert!(alice.latency_of(&bob_id).await.is_some());
+}
+
+#[tokio::test]
+async fn test_connected_peers_and_metrics_follow_connections() {
+    let network = MemoryTransport::new();
+    let (mut alice, alice_bus) = manager("alice");
+    let (mut bob, _) = manager("bob");
+    alice.set_keepalive(KeepaliveConfig {
+        interval: Duration::from_millis(30),
+        max_missed: 3,
+    });
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let dir = TempDir::new().unwrap();
+    let config = Config::load(&dir.path().to_path_buf()).unwrap();
+    let metrics = Arc::new(RwLock::new(MetricsCollector::new(config)));
+    let listener_task =
+        MetricsCollector::spawn_event_listener(metrics.clone(), alice.clone(), alice_bus.clone());
+    assert_eq!(alice.get_peer_count(), 0);
+
+    let mut listener = bob.listen(&"memory://bob".parse().unwrap()).await.unwrap();
+    let connection = alice
+        .dial(&["memory://bob".parse().unwrap()])
+        .await
+        .unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+
+    let mut measured = false;
+    for _ in 0..50 {
+        let current = metrics.read().await.get_current_metrics().await.unwrap();
+        if current.peer_latency.contains_key("bob-id") {
+            assert_eq!(current.active_connections, 1);
+            measured = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(measured);
+    let peers = alice.get_connected_peers();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].id, "bob-id");
+    assert_eq!(peers[0].address, "memory://bob");
+    assert!(peers[0].latency.is_some());
+    assert_eq!(alice.get_network_stats().await.unwrap().connected_peers, 1);
+    assert!(alice.ping("bob-id").await.is_ok());
+
+    alice.detach_connection("bob-id").await;
+    assert!(!alice.is_peer_connected("bob-id"));
+    let mut forgotten = false;
+    for _ in 0..50 {
+        let current = metrics.read().await.get_current_metrics().await.unwrap();
+        if current.peer_latency.is_empty() {
+            assert_eq!(current.active_connections, 0);
+            forgotten = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(forgotten);
+    listener_task.abort();
+}
+
+#[tokio::test]
+async fn test_keepalive_drops_connection_after_missed_pongs() {
+    let network = MemoryTransport::new();
+    let (mut alice, alice_bus) = manager("alice");
+    alice.set_keepalive(KeepaliveConfig {
+        interval: Duration::from_millis(20),
+        max_missed: 3,
+    });
+    alice.register_transport(Arc::new(network.clone()));
+    let (silent_crypto, silent_key) = identity();
+    alice.set_peer_key("silent-id", silent_key).await;
+    let alice = Arc::new(alice);
+    let mut events = alice_bus.subscribe();
+
+    let mut listener = network
+        .listen(&"memory://silent".parse().unwrap())
+        .await
+        .unwrap();
+    let connection = alice
+        .dial(&["memory://silent".parse().unwrap()])
+        .await
+        .unwrap();
+    let mut silent = listener.accept().await.unwrap();
+    let client = alice.clone();
+    let serving =
+        tokio::spawn(async move { client.serve_connection("silent-id", connection).await });
+    accept_handshake(&mut silent, &*silent_crypto.read().await, "silent-id").await;
+
+    // The peer reads everything but never answers
+    let (pings_tx, mut pings) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(Some(message)) = silent.recv().await {
+            if let MessagePayload::Ping(ping) = message.get_payload() {
+                let _ = pings_tx.send(ping.sequence);
+            }
+        }
+    });
+
+    loop {
+        if let NetworkEvent::ConnectionTimedOut { peer_id, missed } =
+            next_network_event(&mut events).await
+        {
+            assert_eq!(peer_id, "silent-id");
+            assert_eq!(missed, 3);
+            break;
+        }
+    }
+    assert!(!alice.has_connection("silent-id").await);
+    assert!(alice.latency_of("silent-id").await.is_none());
+    tokio::time::timeout(Duration::from_secs(1), serving)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+
+    let mut sequences = Vec::new();
+    while let Ok(sequence) = pings.try_recv() {
+        sequences.push(sequence);
+    }
+    assert_eq!(sequences, vec![1, 2, 3]);
+}
+
+fn supervised_contact(id: &str, endpoints: &[&str], trust_level: TrustLevel) -> Contact {
+    Contact {
+        id: id.to_string(),
+        name: id.to_string(),
+        addresses: endpoints.iter().map(|e| e.parse().unwrap()).collect(),
+        public_key: Vec::new(),
+        status: ContactStatus::Offline,
+        trust_level,
+        last_seen: None,
+    }
+}
+
+async fn next_contact_change(events: &mut EventReceiver) -> Contact {
+    loop {
+        if let NetworkEvent::ContactStatusChanged { contact } = next_network_event(events).await {
+            return contact;
+        }
+    }
+}
+
+fn fast_supervision(max_peers: usize) -> SupervisorConfig {
+    SupervisorConfig {
+        max_peers,
+        check_interval: Duration::from_millis(20),
+        connect_timeout: Duration::from_millis(500),
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+    }
+}
+
+#[test]
+fn test_supervisor_backoff_and_contact_priority() {
+    let config = SupervisorConfig::default();
+    assert_eq!(config.backoff(1), Duration::from_secs(1));
+    assert_eq!(config.backoff(2), Duration::from_secs(2));
+    assert_eq!(config.backoff(4), Duration::from_secs(8));
+    assert_eq!(config.backoff(40), config.max_backoff);
+
+    let dir = TempDir::new().unwrap();
+    let mut contacts = ContactManager::new(dir.path()).unwrap();
+    for contact in [
+        supervised_contact("high", &["memory://high"], TrustLevel::High),
+        supervised_contact("trusted", &["memory://trusted"], TrustLevel::Trusted),
+        supervised_contact("favorite", &["memory://favorite"], TrustLevel::Low),
+        supervised_contact("medium", &["memory://medium"], TrustLevel::Medium),
+        supervised_contact("blocked", &["memory://blocked"], TrustLevel::Trusted),
+        supervised_contact("nowhere", &[], TrustLevel::Trusted),
+    ] {
+        contacts.add_contact(contact).unwrap();
+    }
+    contacts.set_favorite("favorite", true).unwrap();
+    contacts.block_contact("blocked").unwrap();
+    assert!(contacts.set_favorite("unknown", true).is_err());
+
+    let ids: Vec<String> = contacts
+        .supervised_contacts()
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    assert_eq!(ids, vec!["favorite", "trusted", "high"]);
+}
+
+#[tokio::test]
+async fn test_favorites_are_saved_with_contact_book() {
+    let dir = TempDir::new().unwrap();
+    let mut contacts = ContactManager::new(dir.path()).unwrap();
+    contacts
+        .add_contact(supervised_contact(
+            "erin",
+            &["memory://erin"],
+            TrustLevel::Low,
+        ))
+        .unwrap();
+    contacts
+        .add_contact(supervised_contact(
+            "frank",
+            &["memory://frank"],
+            TrustLevel::Low,
+        ))
+        .unwrap();
+    contacts.set_favorite("erin", true).unwrap();
+    contacts.block_contact("frank").unwrap();
+    contacts.save_contacts().await.unwrap();
+
+    let mut reopened = ContactManager::new(dir.path()).unwrap();
+    assert_eq!(reopened.get_contacts().len(), 2);
+    assert!(reopened.is_favorite("erin"));
+    assert!(!reopened.is_favorite("frank"));
+    assert!(reopened.is_contact_blocked("frank"));
+
+    // Loading again drops what was changed since the last save
+    reopened.set_favorite("erin", false).unwrap();
+    reopened.load_contacts().await.unwrap();
+    assert!(reopened.is_favorite("erin"));
+}
+
+#[tokio::test]
+async fn test_supervisor_reconnects_dropped_contacts_with_backoff() {
+    let network = MemoryTransport::new();
+    let (mut alice, alice_bus) = manager("alice");
+    let (mut bob, _) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    let alice_id = alice.get_peer().await.id;
+    let bob_id = bob.get_peer().await.id;
+
+    let mut listener = bob.listen(&"memory://bob".parse().unwrap()).await.unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        while let Ok(connection) = listener.accept().await {
+            let server = server.clone();
+            tokio::spawn(async move { server.accept_connection(connection).await });
+        }
+    });
+
+    let dir = TempDir::new().unwrap();
+    let contacts = Arc::new(RwLock::new(ContactManager::new(dir.path()).unwrap()));
+    contacts
+        .write()
+        .await
+        .add_contact(supervised_contact(
+            &bob_id,
+            &["memory://gone", "memory://bob"],
+            TrustLevel::Trusted,
+        ))
+        .unwrap();
+
+    let mut events = alice_bus.subscribe();
+    let mut supervisor = ConnectionSupervisor::new(
+        alice.clone(),
+        contacts.clone(),
+        alice_bus.clone(),
+        fast_supervision(5),
+    );
+    supervisor.start();
+
+    let contact = next_contact_change(&mut events).await;
+    assert_eq!(contact.status, ContactStatus::Online);
+    assert!(contact.last_seen.is_some());
+    // The endpoint that worked is tried first from now on
+    assert_eq!(contact.addresses[0].to_string(), "memory://bob");
+    assert!(alice.has_connection(&bob_id).await);
+
+    bob.detach_connection(&alice_id).await;
+    let contact = next_contact_change(&mut events).await;
+    assert_eq!(contact.status, ContactStatus::Offline);
+    assert!(!alice.has_connection(&bob_id).await);
+
+    let contact = next_contact_change(&mut events).await;
+    assert_eq!(contact.status, ContactStatus::Online);
+    assert!(alice.has_connection(&bob_id).await);
+
+    supervisor.stop().await;
+    assert!(!alice.has_connection(&bob_id).await);
+}
+
+#[tokio::test]
+async fn test_supervisor_keeps_at_most_max_peers_connected() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    alice.register_transport(Arc::new(network.clone()));
+    for id in ["carol", "dave", "erin"] {
+        alice.set_peer_key(id, identity().1).await;
+    }
+    let alice = Arc::new(alice);
+
+    let dir = TempDir::new().unwrap();
+    let contacts = Arc::new(RwLock::new(ContactManager::new(dir.path()).unwrap()));
+    let (accepted_tx, mut accepted) = mpsc::unbounded_channel();
+    for (id, trust_level) in [
+        ("carol", TrustLevel::High),
+        ("dave", TrustLevel::Trusted),
+        ("erin", TrustLevel::Medium),
+    ] {
+        let endpoint = format!("memory://{}", id);
+        let mut listener = network.listen(&endpoint.parse().unwrap()).await.unwrap();
+        let accepted_tx = accepted_tx.clone();
+        tokio::spawn(async move {
+            // Hold on to connections without answering
+            let mut held = Vec::new();
+            while let Ok(connection) = listener.accept().await {
+                held.push(connection);
+                let _ = accepted_tx.send(id);
+            }
+        });
+        contacts
+            .write()
+            .await
+            .add_contact(supervised_contact(id, &[&endpoint], trust_level))
+            .unwrap();
+    }
+    contacts.write().await.set_favorite("erin", true).unwrap();
+
+    let mut supervisor = ConnectionSupervisor::new(
+        alice.clone(),
+        contacts,
+        EventBus::new(),
+        fast_supervision(2),
+    );
+    supervisor.start();
+
+    let mut dialed = vec![
+        accepted.recv().await.unwrap(),
+        accepted.recv().await.unwrap(),
+    ];
+    dialed.sort();
+    assert_eq!(dialed, vec!["dave", "erin"]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(accepted.try_recv().is_err());
+    assert!(!alice.has_connection("carol").await);
+
+    supervisor.stop().await;
+    assert!(!alice.has_connection("dave").await);
+    assert!(!alice.has_connection("erin").await);
+}
+
+#[cfg(feature = "quic")]
+mod quic {
+    use super::*;
+    use shadowghost::network::{QuicConfig, QuicConnection, QuicError, QuicLink, QuicTransport};
+
+    fn transport() -> QuicTransport {
+        QuicTransport::bind("127.0.0.1:0".parse().unwrap(), QuicConfig::default()).unwrap()
+    }
+
+    async fn connect_pair(
+        client: &QuicTransport,
+        server: &QuicTransport,
+    ) -> (QuicConnection, QuicConnection) {
+        let address = server.local_addr().unwrap();
+        let (dialed, accepted) = tokio::join!(client.connect(address, None), server.accept());
+        (dialed.unwrap(), accepted.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_quic_file_transfer_does_not_block_messages() {
+        let server = transport();
+        let client = transport();
+        let (dialed, accepted) = connect_pair(&client, &server).await;
+        let dialed = Arc::new(dialed);
+
+        // Nobody reads the file yet, so flow control stalls its stream
+        let file: Vec<u8> = (0..8 * 1024 * 1024).map(|i| i as u8).collect();
+        let sender = dialed.clone();
+        let expected = file.clone();
+        let transfer = tokio::spawn(async move { sender.send_file("video.mp4", &file).await });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        dialed.send(b"still here").await.unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(2), accepted.recv())
+            .await
+            .expect("message stuck behind the file")
+            .unwrap();
+        assert_eq!(message, b"still here");
+        assert!(!transfer.is_finished());
+
+        let (name, data) = accepted.accept_file().await.unwrap();
+        assert_eq!(name, "video.mp4");
+        assert_eq!(data, expected);
+        transfer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_reconnects_with_zero_rtt() {
+        let server = transport();
+        let client = transport();
+
+        let (first, accepted) = connect_pair(&client, &server).await;
+        assert!(!first.used_zero_rtt());
+        // The session ticket arrives after the handshake
+        first.send(b"hello").await.unwrap();
+        accepted.recv().await.unwrap();
+        accepted.send(b"hello back").await.unwrap();
+        first.recv().await.unwrap();
+        first.close();
+
+        let (second, accepted) = connect_pair(&client, &server).await;
+        assert!(second.used_zero_rtt());
+        second.send(b"again").await.unwrap();
+        assert_eq!(accepted.recv().await.unwrap(), b"again");
+    }
+
+    #[tokio::test]
+    async fn test_quic_certificate_pinning() {
+        let server = transport();
+        let client = transport();
+        let address = server.local_addr().unwrap();
+
+        // The server only completes handshakes it accepts
+        let (wrong, _) = tokio::join!(client.connect(address, Some([0u8; 32])), server.accept());
+        assert!(matches!(wrong, Err(QuicError::CertificateMismatch)));
+
+        let (dialed, accepted) = tokio::join!(
+            client.connect(address, Some(server.fingerprint())),
+            server.accept()
+        );
+        let dialed = dialed.unwrap();
+        accepted.unwrap();
+        assert_eq!(dialed.peer_fingerprint(), Some(server.fingerprint()));
+    }
+
+    #[tokio::test]
+    async fn test_quic_connection_migrates_to_new_socket() {
+        let server = transport();
+        let client = transport();
+        let (dialed, accepted) = connect_pair(&client, &server).await;
+        let before = accepted.remote_address();
+
+        client.rebind("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert_ne!(client.local_addr().unwrap().port(), before.port());
+
+        let dialed = Arc::new(dialed);
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, _incoming) = mpsc::unbounded_channel();
+        let mut link = QuicLink::spawn(dialed.clone(), outgoing_rx, incoming_tx);
+
+        outgoing
+            .send(ProtocolMessage::create_presence(
+                "alice".to_string(),
+                "bob".to_string(),
+                ContactStatus::Online,
+            ))
+            .unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), accepted.recv_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.header.sender_id, "alice");
+        assert_eq!(accepted.remote_address(), client.local_addr().unwrap());
+        link.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_quic_transport_carries_protocol_messages() {
+        let server = transport();
+        let client = transport();
+        let local: Endpoint = format!("quic://{}", server.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let mut listener = server.listen(&local).await.unwrap();
+        assert_eq!(listener.local_endpoint(), local);
+        assert!(server
+            .listen(&"quic://127.0.0.1:1".parse().unwrap())
+            .await
+            .is_err());
+
+        let (dialed, accepted) = tokio::join!(
+            async {
+                let mut connection = client.dial(&local).await?;
+                connection.send(&text_message("over quic", "msg-1")).await?;
+                Ok::<_, TransportError>(connection)
+            },
+            listener.accept()
+        );
+        let (mut dialed, mut accepted) = (dialed.unwrap(), accepted.unwrap());
+        let message = accepted.recv().await.unwrap().unwrap();
+        assert_eq!(text_of(&message), "over quic");
+
+        accepted
+            .send(&text_message("reply", "msg-reply"))
+            .await
+            .unwrap();
+        assert_eq!(text_of(&dialed.recv().await.unwrap().unwrap()), "reply");
+    }
+}
+
+fn strict_limits() -> RateLimitConfig {
+    RateLimitConfig {
+        connections_per_second: 0.01,
+        connection_burst: 2.0,
+        messages_per_second: 0.01,
+        message_burst: 5.0,
+        bytes_per_second: 10.0,
+        byte_burst: 4096.0,
+        max_frame_size: 1024,
+        violations_before_ban: 3,
+        ban_duration: Duration::from_millis(100),
+        bans_before_block: 2,
+    }
+}
+
+fn message_from(sender: &str, id: &str) -> ProtocolMessage {
+    ProtocolMessage::create_text_message(
+        sender.to_string(),
+        "bob-id".to_string(),
+        "flood".to_string(),
+        id.to_string(),
+    )
+}
+
+#[test]
+fn test_rate_limiter_bans_and_blocks_repeat_offenders() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(2.0, 4.0, start);
+    assert!(bucket.try_take(4.0, start));
+    assert!(!bucket.try_take(1.0, start));
+    assert!(bucket.try_take(1.0, start + Duration::from_millis(500)));
+    assert_eq!(bucket.available(start + Duration::from_secs(60)), 4.0);
+
+    let mut limiter = RateLimiter::new(strict_limits());
+    let ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+
+    // Connections are limited per address, except from loopback
+    assert!(limiter.admit_connection(ip, start).is_ok());
+    assert!(limiter.admit_connection(ip, start).is_ok());
+    assert_eq!(
+        limiter.admit_connection(ip, start),
+        Err(RateLimitError::TooManyConnections)
+    );
+    for _ in 0..10 {
+        let loopback = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(limiter.admit_connection(loopback, start).is_ok());
+    }
+
+    // Messages and bytes per peer, and per address across peers
+    for _ in 0..5 {
+        assert!(limiter.admit_message("mallory", None, 10, start).is_ok());
+    }
+    assert_eq!(
+        limiter.admit_message("mallory", None, 10, start),
+        Err(RateLimitError::TooManyMessages)
+    );
+    assert_eq!(
+        limiter.admit_message("trudy", None, 5000, start),
+        Err(RateLimitError::TooManyBytes)
+    );
+    for _ in 0..5 {
+        assert!(limiter.admit_message("carol", ip, 10, start).is_ok());
+    }
+    assert_eq!(
+        limiter.admit_message("dave", ip, 10, start),
+        Err(RateLimitError::TooManyMessages)
+    );
+    assert!(limiter.admit_message("dave", None, 10, start).is_ok());
+
+    // The third violation earns a ban, which runs out
+    assert!(matches!(
+        limiter.admit_message("mallory", None, 10, start),
+        Err(RateLimitError::TooManyMessages)
+    ));
+    assert!(matches!(
+        limiter.admit_message("mallory", None, 10, start),
+        Err(RateLimitError::Banned { .. })
+    ));
+    assert!(matches!(
+        limiter.admit_peer("mallory", start),
+        Err(RateLimitError::Banned { .. })
+    ));
+    let later = start + Duration::from_millis(150);
+    assert!(limiter.admit_peer("mallory", later).is_ok());
+    assert!(limiter.take_newly_blocked().is_empty());
+
+    // Banned twice means blocked, until unblocked
+    limiter.report("mallory", RateLimitError::MalformedFrame, later);
+    limiter.report("mallory", RateLimitError::MalformedFrame, later);
+    assert_eq!(
+        limiter.report("mallory", RateLimitError::MalformedFrame, later),
+        RateLimitError::Blocked
+    );
+    let blocked = limiter.take_newly_blocked();
+    assert_eq!(blocked.len(), 1);
+    assert_eq!(blocked[0].0, "mallory");
+    assert!(blocked[0].1.contains("Banned 2 times"));
+    let much_later = start + Duration::from_secs(3600);
+    assert_eq!(
+        limiter.admit_peer("mallory", much_later),
+        Err(RateLimitError::Blocked)
+    );
+    limiter.unblock("mallory");
+    assert!(limiter.admit_peer("mallory", much_later).is_ok());
+
+    let stats = limiter.stats();
+    assert_eq!(stats.connections_refused, 3);
+    assert_eq!(stats.messages_dropped, 5);
+    assert_eq!(stats.violations, 9);
+    assert_eq!(stats.bans, 2);
+    assert_eq!(stats.blocks, 1);
+}
+
+#[test]
+fn test_rate_limiter_charges_failed_handshakes_to_the_address() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(strict_limits());
+    let ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+
+    // However often an address fails, it is only ever banned
+    for round in 0..4 {
+        let now = start + Duration::from_millis(150) * round;
+        limiter.report_ip(ip, RateLimitError::FailedHandshake, now);
+        limiter.report_ip(ip, RateLimitError::FailedHandshake, now);
+        assert!(matches!(
+            limiter.report_ip(ip, RateLimitError::FailedHandshake, now),
+            RateLimitError::Banned { .. }
+        ));
+        assert!(matches!(
+            limiter.admit_connection(ip, now),
+            Err(RateLimitError::Banned { .. })
+        ));
+    }
+    assert!(limiter.take_newly_blocked().is_empty());
+    assert_eq!(limiter.stats().blocks, 0);
+
+    // Nobody it claimed to be pays for it
+    let later = start + Duration::from_secs(3600);
+    assert!(limiter.admit_peer("alice", later).is_ok());
+    assert!(limiter.admit_message("alice", None, 10, later).is_ok());
+
+    // Loopback carries every onion peer, so it is never banned
+    let loopback = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    for _ in 0..10 {
+        assert_eq!(
+            limiter.report_ip(loopback, RateLimitError::FailedHandshake, later),
+            RateLimitError::FailedHandshake
+        );
+    }
+    assert!(limiter.admit_connection(loopback, later).is_ok());
+}
+
+#[tokio::test]
+async fn test_flooding_peer_is_blocked_and_refused() {
+    let network = MemoryTransport::new();
+    let (mut bob, bob_bus) = manager("bob");
+    bob.register_transport(Arc::new(network.clone()));
+    let (mallory_crypto, mallory_key) = identity();
+    let (oscar_crypto, oscar_key) = identity();
+    bob.set_peer_key("mallory", mallory_key).await;
+    bob.set_peer_key("oscar", oscar_key).await;
+    let (mallory_crypto, oscar_crypto) = (mallory_crypto.read().await, oscar_crypto.read().await);
+    let bob = Arc::new(bob);
+    // Room for the whole message burst once each message is sealed
+    bob.set_rate_limits(RateLimitConfig {
+        byte_burst: 8192.0,
+        ..strict_limits()
+    });
+    let dir = TempDir::new().unwrap();
+    let contacts = Arc::new(RwLock::new(ContactManager::new(dir.path()).unwrap()));
+    let _listener =
+        ContactManager::spawn_presence_listener(contacts.clone(), bob.clone(), bob_bus.clone());
+    let mut events = bob_bus.subscribe();
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+
+    // Each flood gets the burst through, then three messages over the
+    // limit earn a ban that closes the connection
+    for round in 0..2 {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let mut mallory = network.dial(&endpoint).await.unwrap();
+        let server = bob.clone();
+        let accepted = listener.accept().await.unwrap();
+        let serving = tokio::spawn(async move { server.accept_connection(accepted).await });
+        dial_handshake(&mut mallory, &mallory_crypto, "mallory").await;
+        for i in 0..20 {
+            let id = format!("flood-{}-{}", round, i);
+            if mallory.send(&message_from("mallory", &id)).await.is_err() {
+                break;
+            }
+        }
+        serving.await.unwrap().unwrap();
+        assert!(!bob.has_connection("mallory").await);
+    }
+
+    let mut received = 0;
+    let reason = loop {
+        match next_network_event(&mut events).await {
+            NetworkEvent::MessageReceived { .. } => received += 1,
+            NetworkEvent::PeerBlocked { peer_id, reason } => {
+                assert_eq!(peer_id, "mallory");
+                break reason;
+            }
+            _ => {}
+        }
+    };
+    // Five in the first round; in the second the bucket is still empty
+    assert_eq!(received, 5);
+    assert!(bob.is_peer_blocked("mallory"));
+
+    loop {
+        if let Some(info) = contacts.read().await.get_blocked_info("mallory") {
+            assert_eq!(info.reason, reason);
+            assert!(!info.blocked_by_user);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Refused as soon as it says who it is, before bob signs anything
+    let mut mallory = network.dial(&endpoint).await.unwrap();
+    plain_frames(&mut mallory);
+    let server = bob.clone();
+    let accepted = listener.accept().await.unwrap();
+    let refused = tokio::spawn(async move { server.accept_connection(accepted).await });
+    mallory.send(&hello("mallory")).await.unwrap();
+    assert!(matches!(
+        refused.await.unwrap(),
+        Err(TransportError::Refused(RateLimitError::Blocked))
+    ));
+    assert!(mallory.recv().await.unwrap().is_none());
+
+    // An oversized frame closes the connection and counts as a violation
+    let mut oscar = network.dial(&endpoint).await.unwrap();
+    let server = bob.clone();
+    let accepted = listener.accept().await.unwrap();
+    let serving = tokio::spawn(async move { server.accept_connection(accepted).await });
+    dial_handshake(&mut oscar, &oscar_crypto, "oscar").await;
+    oscar.send(&message_from("oscar", "hello")).await.unwrap();
+    let mut oversized = message_from("oscar", "oversized");
+    if let MessagePayload::Text(text) = &mut oversized.payload {
+        text.content = "x".repeat(2048);
+    }
+    oscar.send(&oversized).await.unwrap();
+    serving.await.unwrap().unwrap();
+
+    let stats = bob.get_network_stats().await.unwrap();
+    assert_eq!(stats.messages_dropped, 6);
+    assert_eq!(stats.rate_limit_violations, 7);
+    assert_eq!(stats.peers_banned, 2);
+    assert_eq!(stats.peers_blocked, 1);
+    assert_eq!(stats.connections_refused, 1);
+}
+
+fn file_chunk(id: &str) -> ProtocolMessage {
+    let mut message = text_message("file", id);
+    message.payload = MessagePayload::File(FilePayload {
+        file_name: "photo.jpg".to_string(),
+        file_size: 3,
+        file_hash: "hash".to_string(),
+        chunk_data: vec![1, 2, 3],
+        chunk_index: 0,
+        total_chunks: 1,
+    });
+    message
+}
+
+#[test]
+fn test_bandwidth_limiter_paces_traffic_and_defers_while_metered() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(1000.0, 1000.0, start);
+    assert_eq!(bucket.borrow(600.0, start), Duration::ZERO);
+    assert_eq!(bucket.borrow(900.0, start), Duration::from_millis(500));
+
+    let mut limiter = BandwidthLimiter::new(BandwidthConfig {
+        upload_limit: Some(2000),
+        peer_upload_limit: Some(500),
+        large_message_size: 1024,
+        ..BandwidthConfig::default()
+    });
+
+    // The tighter of the overall and the peer's cap decides the pause
+    let pause = limiter.account(Some("bob"), TrafficDirection::Upload, 1000, start);
+    assert_eq!(pause, Duration::from_secs(1));
+    let pause = limiter.account(None, TrafficDirection::Upload, 2000, start);
+    assert_eq!(pause, Duration::from_millis(500));
+    let pause = limiter.account(Some("bob"), TrafficDirection::Download, 5000, start);
+    assert_eq!(pause, Duration::ZERO);
+    assert_eq!(limiter.traffic().sent, 3000);
+    assert_eq!(limiter.traffic().received, 5000);
+    assert_eq!(limiter.peer_traffic("bob").sent, 1000);
+    assert_eq!(limiter.peer_traffic("bob").received, 5000);
+    assert_eq!(limiter.peer_traffic("carol").sent, 0);
+
+    // Only large messages and file chunks wait for an unmetered link
+    let large = text_message(&"x".repeat(2000), "large");
+    assert!(limiter.defer(large.clone()).is_some());
+    limiter.set_metered(true);
+    assert!(limiter.defer(large).is_none());
+    assert!(limiter.defer(file_chunk("chunk")).is_none());
+    assert!(limiter.defer(text_message("small", "small")).is_some());
+    assert_eq!(limiter.deferred_len(), 2);
+    let released: Vec<String> = limiter
+        .set_metered(false)
+        .into_iter()
+        .map(|m| m.message_id)
+        .collect();
+    assert_eq!(released, vec!["large", "chunk"]);
+    assert_eq!(limiter.deferred_len(), 0);
+}
+
+#[tokio::test]
+async fn test_upload_cap_paces_connection_and_bytes_are_counted() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network));
+    alice.add_peer(PeerData {
+        id: "bob-id".to_string(),
+        name: "bob".to_string(),
+        address: "memory://bob".to_string(),
+        public_key: Vec::new(),
+        connected_at: chrono::Utc::now(),
+        last_seen: chrono::Utc::now(),
+        bytes_sent: 0,
+        bytes_received: 0,
+        onion_address: None,
+        latency: None,
+    });
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    alice.set_bandwidth_limits(BandwidthConfig {
+        upload_limit: Some(10_000),
+        ..BandwidthConfig::default()
+    });
+    let mut bob_events = bob_bus.subscribe();
+
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+    let connection = alice.dial(&[endpoint]).await.unwrap();
+    let server = bob.clone();
+    let accepted = tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let client = alice.clone();
+    let serving = tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    while !alice.has_connection("bob-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Some 25 kB against 10 kB/s: the first second's worth goes at once,
+    // and the last message waits until the debt of those before it is paid.
+    // They are spaced out so each goes in a frame of its own.
+    let started = Instant::now();
+    for i in 0..5 {
+        alice
+            .send_protocol_message(text_message(&"x".repeat(1000), &format!("msg-{}", i)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut received = 0;
+    while received < 5 {
+        if let NetworkEvent::MessageReceived { .. } = next_network_event(&mut bob_events).await {
+            received += 1;
+        }
+    }
+    assert!(started.elapsed() >= Duration::from_millis(800));
+
+    // Both ends count the same frames, once the last write is accounted
+    let alice_stats = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            let alice_stats = alice.get_network_stats().await.unwrap();
+            let bob_stats = bob.get_network_stats().await.unwrap();
+            if alice_stats.bytes_sent == bob_stats.bytes_received
+                && alice_stats.bytes_received == bob_stats.bytes_sent
+            {
+                return alice_stats;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(alice_stats.bytes_sent > 25_000);
+    let peers = alice.get_connected_peers();
+    assert_eq!(peers[0].bytes_sent, alice_stats.bytes_sent);
+    assert_eq!(peers[0].bytes_received, alice_stats.bytes_received);
+
+    alice.detach_connection("bob-id").await;
+    serving.await.unwrap().unwrap();
+    accepted.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_metered_link_defers_large_transfers_and_cover_traffic() {
+    let network = MemoryTransport::new();
+    let (mut alice, _) = manager("alice");
+    let (mut bob, bob_bus) = manager("bob");
+    alice.register_transport(Arc::new(network.clone()));
+    bob.register_transport(Arc::new(network.clone()));
+    alice.set_obfuscation(TrafficObfuscator::new(ObfuscationConfig {
+        cover_traffic: true,
+        cover_interval: Duration::from_millis(20),
+        ..ObfuscationConfig::default()
+    }));
+    introduce(&alice, &bob).await;
+    let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+    alice.set_bandwidth_limits(BandwidthConfig {
+        large_message_size: 1024,
+        ..BandwidthConfig::default()
+    });
+    let mut bob_events = bob_bus.subscribe();
+
+    let endpoint: Endpoint = "memory://bob".parse().unwrap();
+    let mut listener = bob.listen(&endpoint).await.unwrap();
+    let connection = alice.dial(&[endpoint]).await.unwrap();
+    let server = bob.clone();
+    tokio::spawn(async move {
+        let connection = listener.accept().await.unwrap();
+        server.accept_connection(connection).await
+    });
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("bob-id", connection).await });
+    while !alice.has_connection("bob-id").await {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    assert_eq!(alice.set_metered(true).await, 0);
+    assert!(alice.is_metered());
+    for message in [
+        text_message(&"x".repeat(4000), "large"),
+        file_chunk("chunk"),
+        text_message("small", "small"),
+    ] {
+        alice.send_protocol_message(message).await.unwrap();
+    }
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.content, "small");
+            break;
+        }
+    }
+    assert_eq!(alice.deferred_len(), 2);
+
+    // Past the presence announcement, no cover frames while metered, not
+    // even on connections made later
+    let (carol_crypto, carol_key) = identity();
+    alice.set_peer_key("carol-id", carol_key).await;
+    let carol_endpoint: Endpoint = "memory://carol".parse().unwrap();
+    let mut carol_listener = network.listen(&carol_endpoint).await.unwrap();
+    let connection = alice.dial(&[carol_endpoint]).await.unwrap();
+    let client = alice.clone();
+    tokio::spawn(async move { client.serve_connection("carol-id", connection).await });
+    let mut carol = carol_listener.accept().await.unwrap();
+    accept_handshake(&mut carol, &*carol_crypto.read().await, "carol-id").await;
+    let (mut frames, _carol_writer) = carol.split();
+    let (announcement, _) = frames.recv_frame().await.unwrap().unwrap();
+    assert_eq!(announcement.len(), 1);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), frames.recv_frame())
+            .await
+            .is_err()
+    );
+
+    assert_eq!(alice.set_metered(false).await, 2);
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } =
+            next_network_event(&mut bob_events).await
+        {
+            assert_eq!(message.content.len(), 4000);
+            break;
+        }
+    }
+    assert_eq!(alice.deferred_len(), 0);
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), frames.recv_frame())
+            .await
+            .unwrap()
+            .unwrap()
+            .is_some()
+    );
+}
+
+fn sequenced(sequencer: &mut Sequencer, content: &str) -> ProtocolMessage {
+    let mut message = text_message(content, content);
+    sequencer.stamp(&mut message);
+    message
+}
+
+#[test]
+fn test_sequencer_reorders_within_window_and_gives_up_on_gaps() {
+    let config = SequencingConfig {
+        reorder_window: 8,
+        gap_timeout: Duration::from_secs(1),
+    };
+    let mut alice = Sequencer::new(config.clone());
+    let messages: Vec<_> = (0..8)
+        .map(|i| sequenced(&mut alice, &format!("m{}", i)))
+        .collect();
+    let seq = |i: usize| messages[i].get_sequence_number();
+    assert!(seq(0) > 0);
+    assert!((1..8).all(|i| seq(i) == seq(0) + i as u64));
+    // Each conversation is numbered on its own
+    let mut to_carol = ProtocolMessage::create_text_message(
+        "alice-id".to_string(),
+        "carol-id".to_string(),
+        "hi".to_string(),
+        "to-carol".to_string(),
+    );
+    alice.stamp(&mut to_carol);
+    assert!(to_carol.get_sequence_number() < seq(1));
+
+    let ids = |delivered: &[ProtocolMessage]| -> Vec<String> {
+        delivered.iter().map(|m| m.message_id.clone()).collect()
+    };
+    let start = Instant::now();
+    let mut bob = Sequencer::new(config);
+    assert_eq!(
+        ids(&bob.receive(messages[0].clone(), start).deliver),
+        ["m0"]
+    );
+
+    // A gap holds back what follows and asks for what is missing, once
+    let arrival = bob.receive(messages[2].clone(), start);
+    assert!(arrival.deliver.is_empty());
+    assert_eq!(arrival.missing, [seq(1)]);
+    assert!(arrival.gap_opened);
+    let arrival = bob.receive(messages[3].clone(), start);
+    assert!(arrival.missing.is_empty());
+    assert!(!arrival.gap_opened);
+    assert!(bob.receive(messages[3].clone(), start).deliver.is_empty());
+    assert_eq!(bob.held_len("alice-id"), 2);
+
+    let resent = alice.retransmissions("bob-id", &[seq(1)]);
+    assert_eq!(ids(&resent), ["m1"]);
+    assert_eq!(
+        ids(&bob.receive(resent[0].clone(), start).deliver),
+        ["m1", "m2", "m3"]
+    );
+    assert_eq!(bob.held_len("alice-id"), 0);
+
+    // An open gap is given up on after the timeout, and what was missing
+    // is still delivered when it turns up
+    assert_eq!(bob.receive(messages[5].clone(), start).missing, [seq(4)]);
+    assert!(bob
+        .release_expired("alice-id", start + Duration::from_millis(500))
+        .is_empty());
+    assert_eq!(
+        ids(&bob.release_expired("alice-id", start + Duration::from_secs(1))),
+        ["m5"]
+    );
+    assert_eq!(
+        ids(&bob.receive(messages[4].clone(), start).deliver),
+        ["m4"]
+    );
+
+    // Overrunning the window gives up on the gap at once
+    assert!(bob.receive(messages[7].clone(), start).deliver.is_empty());
+    let mut far = text_message("far", "far");
+    far.set_sequence_number(seq(6) + 8);
+    let arrival = bob.receive(far, start);
+    assert_eq!(ids(&arrival.deliver), ["m7", "far"]);
+    assert!(arrival.missing.is_empty());
+
+    // Unnumbered messages pass straight through
+    assert_eq!(
+        ids(&bob.receive(text_message("old", "old"), start).deliver),
+        ["old"]
+    );
+}
+
+async fn next_text(events: &mut EventReceiver) -> String {
+    loop {
+        if let NetworkEvent::MessageReceived { message, .. } = next_network_event(events).await {
+            return message.content;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_out_of_order_chat_messages_are_delivered_once_and_in_order() {
+    let (bob, bob_bus) = manager("bob");
+    bob.set_sequencing(SequencingConfig {
+        reorder_window: 16,
+        gap_timeout: Duration::from_millis(200),
+    });
+    let mut events = bob_bus.subscribe();
+    let mut to_alice = bob.attach_connection("alice-id").await;
+    drain(&mut to_alice).await;
+
+    let mut alice = Sequencer::new(SequencingConfig::default());
+    let messages: Vec<_> = ["one", "two", "three", "four", "five"]
+        .iter()
+        .map(|content| sequenced(&mut alice, content))
+        .collect();
+
+    bob.handle_incoming_message(messages[0].clone()).await;
+    assert_eq!(next_text(&mut events).await, "one");
+
+    // "three" waits for "two", which is asked for again
+    bob.handle_incoming_message(messages[2].clone()).await;
+    bob.handle_incoming_message(messages[2].clone()).await;
+    assert_eq!(bob.held_back_len("alice-id"), 1);
+    let requests = drain(&mut to_alice).await;
+    assert_eq!(requests.len(), 1);
+    match &requests[0].payload {
+        MessagePayload::Retransmit(request) => {
+            assert_eq!(request.sequences, [messages[1].get_sequence_number()])
+        }
+        other => panic!("unexpected payload: {:?}", other),
+    }
+
+    // The copy that comes another way later is dropped
+    bob.handle_incoming_message(messages[1].clone()).await;
+    bob.handle_incoming_message(messages[1].clone()).await;
+    assert_eq!(next_text(&mut events).await, "two");
+    assert_eq!(next_text(&mut events).await, "three");
+
+    // "five" goes through once the gap before it times out, and "four"
+    // still lands in its place when it turns up
+    bob.handle_incoming_message(messages[4].clone()).await;
+    assert_eq!(next_text(&mut events).await, "five");
+    bob.handle_incoming_message(messages[3].clone()).await;
+    assert_eq!(next_text(&mut events).await, "four");
+
+    let chat: Vec<String> = bob
+        .get_chat_messages("alice-id")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.content)
+        .collect();
+    assert_eq!(chat, ["one", "two", "three", "four", "five"]);
+}
+
+#[tokio::test]
+async fn test_retransmit_request_resends_from_history() {
+    let (alice, _) = manager("alice");
+    let alice_id = alice.get_peer().await.id;
+    let mut to_bob = alice.attach_connection("bob-id").await;
+    drain(&mut to_bob).await;
+
+    let bob = supervised_contact("bob-id", &[], TrustLevel::Trusted);
+    let first = alice.send_chat_message(&bob, "first").await.unwrap();
+    alice.send_chat_message(&bob, "second").await.unwrap();
+    let sent = drain(&mut to_bob).await;
+    assert_eq!(sent.len(), 2);
+    let sequence = sent[0].get_sequence_number();
+    assert_eq!(sent[1].get_sequence_number(), sequence + 1);
+
+    alice
+        .handle_incoming_message(ProtocolMessage::create_retransmit_request(
+            "bob-id".to_string(),
+            alice_id,
+            vec![sequence],
+        ))
+        .await;
+    let resent = drain(&mut to_bob).await;
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].message_id, first);
+    assert_eq!(resent[0].get_sequence_number(), sequence);
+}
@@ -144,6 +144,9 @@ mod stress_tests {
                         msg_type: ChatMessageType::Text,
                         timestamp: 1234567890 + (i * 10 + k) as u64,
                         delivery_status: DeliveryStatus::Delivered,
+                        reactions: Vec::new(),
+                        expires_at: None,
+                        sequence: 0,
                     })
                     .collect();
 
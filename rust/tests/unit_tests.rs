@@ -172,6 +172,9 @@ mod tests {
             msg_type: ChatMessageType::Text,
             timestamp: 1234567890,
             delivery_status: DeliveryStatus::Delivered,
+            reactions: Vec::new(),
+            expires_at: None,
+            sequence: 0,
         };
 
         let messages = vec![chat_message];